@@ -0,0 +1,1777 @@
+use crate::apu_inspect::ApuWriteLog;
+use crate::apu_mixer::{self, FilterConfig, OutputFilter, Resampler};
+use crate::console::Region;
+use crate::savestate::State;
+use crate::wav::WavWriter;
+use std::path::Path;
+
+// $4003/$4007/$400B/$400F の上位5bitで選ぶ長さカウンタの初期値
+pub const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, //
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+pub struct LengthCounter {
+    pub enabled: bool,
+    pub halt: bool,
+    pub counter: u8,
+}
+
+impl LengthCounter {
+    pub fn new() -> Self {
+        LengthCounter {
+            enabled: false,
+            halt: false,
+            counter: 0,
+        }
+    }
+
+    // $4015で無効化されている間はロードしても0のまま
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(index & 0x1f) as usize];
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    // フレームカウンタのハーフフレームごとに呼ばれる
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.counter > 0
+    }
+
+    pub fn state(&mut self, state: &mut State) {
+        state.bool(&mut self.enabled);
+        state.bool(&mut self.halt);
+        state.u8(&mut self.counter);
+    }
+}
+
+impl Default for LengthCounter {
+    fn default() -> Self {
+        LengthCounter::new()
+    }
+}
+
+pub struct Envelope {
+    pub start: bool,
+    pub loop_flag: bool,
+    pub constant_volume: bool,
+    // 定音量のときは音量、そうでなければ分周器の周期
+    pub volume: u8,
+    pub decay: u8,
+    divider: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Envelope {
+            start: false,
+            loop_flag: false,
+            constant_volume: false,
+            volume: 0,
+            decay: 0,
+            divider: 0,
+        }
+    }
+
+    // $4000/$4004/$400C の書き込み (--LC VVVV)
+    pub fn write(&mut self, value: u8) {
+        self.loop_flag = value & 0b0010_0000 != 0;
+        self.constant_volume = value & 0b0001_0000 != 0;
+        self.volume = value & 0b0000_1111;
+    }
+
+    // クォーターフレームごとに呼ばれる
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.loop_flag {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+
+    pub fn state(&mut self, state: &mut State) {
+        state.bool(&mut self.start);
+        state.bool(&mut self.loop_flag);
+        state.bool(&mut self.constant_volume);
+        state.u8(&mut self.volume);
+        state.u8(&mut self.decay);
+        state.u8(&mut self.divider);
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Envelope::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PulseChannel {
+    One,
+    Two,
+}
+
+pub struct Sweep {
+    pub channel: PulseChannel,
+    pub enabled: bool,
+    pub period: u8,
+    pub negate: bool,
+    pub shift: u8,
+    reload: bool,
+    divider: u8,
+}
+
+impl Sweep {
+    pub fn new(channel: PulseChannel) -> Self {
+        Sweep {
+            channel,
+            enabled: false,
+            period: 0,
+            negate: false,
+            shift: 0,
+            reload: false,
+            divider: 0,
+        }
+    }
+
+    // $4001/$4005 の書き込み (EPPP NSSS)
+    pub fn write(&mut self, value: u8) {
+        self.enabled = value & 0b1000_0000 != 0;
+        self.period = (value >> 4) & 0b111;
+        self.negate = value & 0b0000_1000 != 0;
+        self.shift = value & 0b0000_0111;
+        self.reload = true;
+    }
+
+    // パルス1は1の補数、パルス2は2の補数で減算するので結果が1ずれる
+    pub fn target_period(&self, timer: u16) -> u16 {
+        let change = timer >> self.shift;
+        if !self.negate {
+            return timer + change;
+        }
+        match self.channel {
+            PulseChannel::One => timer.saturating_sub(change + 1),
+            PulseChannel::Two => timer - change,
+        }
+    }
+
+    // 周期が短すぎるか、目標周期が11bitを超えるとチャンネルは無音になる
+    pub fn is_muting(&self, timer: u16) -> bool {
+        timer < 8 || self.target_period(timer) > 0x7ff
+    }
+
+    // ハーフフレームごとに呼ばれる
+    pub fn clock(&mut self, timer: &mut u16) {
+        if self.divider == 0 && self.enabled && self.shift > 0 && !self.is_muting(*timer) {
+            *timer = self.target_period(*timer);
+        }
+        if self.divider == 0 || self.reload {
+            self.divider = self.period;
+            self.reload = false;
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn state(&mut self, state: &mut State) {
+        state.bool(&mut self.enabled);
+        state.u8(&mut self.period);
+        state.bool(&mut self.negate);
+        state.u8(&mut self.shift);
+        state.bool(&mut self.reload);
+        state.u8(&mut self.divider);
+    }
+}
+
+// デューティ比 12.5%, 25%, 50%, 25%反転の波形
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+// 矩形波チャンネル ($4000-$4003, $4004-$4007)
+pub struct Pulse {
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    pub sweep: Sweep,
+    pub duty: u8,
+    // 11bitのタイマーの周期
+    pub period: u16,
+    timer: u16,
+    sequence: u8,
+}
+
+impl Pulse {
+    pub fn new(channel: PulseChannel) -> Self {
+        Pulse {
+            length: LengthCounter::new(),
+            envelope: Envelope::new(),
+            sweep: Sweep::new(channel),
+            duty: 0,
+            period: 0,
+            timer: 0,
+            sequence: 0,
+        }
+    }
+
+    // addrの下位2bitでレジスタを選ぶ
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr & 0b11 {
+            // DDLC VVVV
+            0 => {
+                self.duty = data >> 6;
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            1 => self.sweep.write(data),
+            2 => self.period = (self.period & 0x700) | data as u16,
+            // LLLL LHHH: 長さを読み込み、波形の先頭から鳴らし直す
+            _ => {
+                self.period = (self.period & 0xff) | ((data as u16 & 0b111) << 8);
+                self.length.load(data >> 3);
+                self.sequence = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // APUサイクル (CPU 2サイクル) ごとに呼ばれる
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.sequence = (self.sequence + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+        self.sweep.clock(&mut self.period);
+    }
+
+    // 0-15
+    pub fn output(&self) -> u8 {
+        if DUTY_TABLE[self.duty as usize][self.sequence as usize] == 0
+            || !self.length.is_active()
+            || self.sweep.is_muting(self.period)
+        {
+            return 0;
+        }
+        self.envelope.output()
+    }
+
+    pub fn state(&mut self, state: &mut State) {
+        self.length.state(state);
+        self.envelope.state(state);
+        self.sweep.state(state);
+        state.u8(&mut self.duty);
+        state.u16(&mut self.period);
+        state.u16(&mut self.timer);
+        state.u8(&mut self.sequence);
+    }
+}
+
+// 15から0に下がり、0から15に上がる32ステップの波形
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, //
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// 三角波チャンネル ($4008-$400B)
+pub struct Triangle {
+    pub length: LengthCounter,
+    // 長さカウンタの停止と線形カウンタの制御を兼ねる
+    pub control: bool,
+    pub linear_reload_value: u8,
+    pub linear_counter: u8,
+    linear_reload: bool,
+    pub period: u16,
+    timer: u16,
+    sequence: u8,
+}
+
+impl Triangle {
+    pub fn new() -> Self {
+        Triangle {
+            length: LengthCounter::new(),
+            control: false,
+            linear_reload_value: 0,
+            linear_counter: 0,
+            linear_reload: false,
+            period: 0,
+            timer: 0,
+            sequence: 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr & 0b11 {
+            // CRRR RRRR
+            0 => {
+                self.control = data & 0x80 != 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = data & 0x7f;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0x700) | data as u16,
+            _ => {
+                self.period = (self.period & 0xff) | ((data as u16 & 0b111) << 8);
+                self.length.load(data >> 3);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    // CPUサイクルごとに呼ばれる。
+    // 周期が2未満だと超音波になり、実機でも平均すると中間の値にしか聞こえない。
+    // ここでは波形を進めずに止めて、プチノイズが出ないようにする
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.linear_counter > 0 && self.length.is_active() && self.period >= 2 {
+                self.sequence = (self.sequence + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    // 0-15。止まっている間も最後の値を出し続ける
+    pub fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.sequence as usize]
+    }
+
+    pub fn state(&mut self, state: &mut State) {
+        self.length.state(state);
+        state.bool(&mut self.control);
+        state.u8(&mut self.linear_reload_value);
+        state.u8(&mut self.linear_counter);
+        state.bool(&mut self.linear_reload);
+        state.u16(&mut self.period);
+        state.u16(&mut self.timer);
+        state.u8(&mut self.sequence);
+    }
+}
+
+impl Default for Triangle {
+    fn default() -> Self {
+        Triangle::new()
+    }
+}
+
+// ノイズの周期 (CPUサイクル)
+const NOISE_PERIODS: [[u16; 16]; 2] = [
+    [
+        4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+    ],
+    [
+        4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+    ],
+];
+
+// ノイズチャンネル ($400C-$400F)
+pub struct Noise {
+    pub region: Region,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    // trueならbit6をフィードバックに使う短い周期 (93ステップ) のモード
+    pub mode: bool,
+    pub period_index: u8,
+    timer: u16,
+    // 15bitの線形帰還シフトレジスタ
+    shift: u16,
+}
+
+impl Noise {
+    pub fn new(region: Region) -> Self {
+        Noise {
+            region,
+            length: LengthCounter::new(),
+            envelope: Envelope::new(),
+            mode: false,
+            period_index: 0,
+            timer: 0,
+            shift: 1,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr & 0b11 {
+            // --LC VVVV
+            0 => {
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            1 => {}
+            // M--- PPPP
+            2 => {
+                self.mode = data & 0x80 != 0;
+                self.period_index = data & 0x0f;
+            }
+            _ => {
+                self.length.load(data >> 3);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    pub fn period(&self) -> u16 {
+        let table = (self.region == Region::Pal) as usize;
+        NOISE_PERIODS[table][self.period_index as usize]
+    }
+
+    // CPUサイクルごとに呼ばれる
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period() - 1;
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // 0-15。シフトレジスタのbit0が1のときは無音
+    pub fn output(&self) -> u8 {
+        if self.shift & 1 != 0 || !self.length.is_active() {
+            return 0;
+        }
+        self.envelope.output()
+    }
+
+    pub fn state(&mut self, state: &mut State) {
+        self.length.state(state);
+        self.envelope.state(state);
+        state.bool(&mut self.mode);
+        state.u8(&mut self.period_index);
+        state.u16(&mut self.timer);
+        state.u16(&mut self.shift);
+    }
+}
+
+// DMCの出力の周期 (CPUサイクル)
+const DMC_RATES: [[u16; 16]; 2] = [
+    [
+        428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+    ],
+    [
+        398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+    ],
+];
+
+// 拡張音源を全開にしたとき、パルス2つ分くらいの音量にする
+const EXPANSION_GAIN: f32 = 0.25;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+// DMCがサンプルを1バイト読む間、CPUが止められるサイクル数
+pub const DMC_STALL_CYCLES: u8 = 4;
+
+// デルタ変調チャンネル ($4010-$4013)。
+// サンプルはバスから読むので、読み込みはfetch_address/load_sampleでBusに任せる
+pub struct Dmc {
+    pub region: Region,
+    pub irq_enabled: bool,
+    pub loop_flag: bool,
+    pub rate_index: u8,
+    // 0-127
+    pub level: u8,
+    pub sample_address: u16,
+    pub sample_length: u16,
+    pub irq: bool,
+    current_address: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+    timer: u16,
+}
+
+impl Dmc {
+    pub fn new(region: Region) -> Self {
+        Dmc {
+            region,
+            irq_enabled: false,
+            loop_flag: false,
+            rate_index: 0,
+            level: 0,
+            sample_address: 0xc000,
+            sample_length: 1,
+            irq: false,
+            current_address: 0xc000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+            timer: 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr & 0b11 {
+            // IL-- RRRR
+            0 => {
+                self.irq_enabled = data & 0x80 != 0;
+                self.loop_flag = data & 0x40 != 0;
+                self.rate_index = data & 0x0f;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            1 => self.level = data & 0x7f,
+            2 => self.sample_address = 0xc000 | (data as u16) << 6,
+            _ => self.sample_length = (data as u16) << 4 | 1,
+        }
+    }
+
+    // $4015のbit4。0なら止め、1なら終わっていたときだけ最初から鳴らす
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    pub fn bytes_remaining(&self) -> u16 {
+        self.bytes_remaining
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn rate(&self) -> u16 {
+        let table = (self.region == Region::Pal) as usize;
+        DMC_RATES[table][self.rate_index as usize]
+    }
+
+    // バッファが空で残りがあれば次に読むアドレス
+    pub fn fetch_address(&self) -> Option<u16> {
+        if self.buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    // 読んだバイトをバッファに入れる。最後のバイトならループするかIRQを立てる
+    pub fn load_sample(&mut self, value: u8) {
+        self.buffer = Some(value);
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // CPUサイクルごとに呼ばれる
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.rate() - 1;
+
+        if !self.silence {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(value) => {
+                    self.silence = false;
+                    self.shift = value;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // 0-127
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+
+    pub fn state(&mut self, state: &mut State) {
+        state.bool(&mut self.irq_enabled);
+        state.bool(&mut self.loop_flag);
+        state.u8(&mut self.rate_index);
+        state.u8(&mut self.level);
+        state.u16(&mut self.sample_address);
+        state.u16(&mut self.sample_length);
+        state.bool(&mut self.irq);
+        state.u16(&mut self.current_address);
+        state.u16(&mut self.bytes_remaining);
+        state.option(&mut self.buffer, |state, value| state.u8(value));
+        state.u8(&mut self.shift);
+        state.u8(&mut self.bits_remaining);
+        state.bool(&mut self.silence);
+        state.u16(&mut self.timer);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameMode {
+    FourStep,
+    FiveStep,
+}
+
+// フレームカウンタの各ステップのCPUサイクル (NTSC, PAL)。
+// 4ステップは最後の3サイクルでIRQを立て、5ステップは最後の1サイクルで一周する
+const FOUR_STEP: [[u32; 6]; 2] = [
+    [7457, 14913, 22371, 29828, 29829, 29830],
+    [8313, 16627, 24939, 33252, 33253, 33254],
+];
+const FIVE_STEP: [[u32; 5]; 2] = [
+    [7457, 14913, 22371, 37281, 37282],
+    [8313, 16627, 24939, 41565, 41566],
+];
+
+// ミュートやソロの対象。Expansionはカートリッジの拡張音源
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+    Expansion,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 6] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Triangle,
+        Channel::Noise,
+        Channel::Dmc,
+        Channel::Expansion,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+            Channel::Expansion => "expansion",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        Channel::ALL
+            .iter()
+            .find(|channel| channel.name() == name)
+            .copied()
+            .ok_or_else(|| format!("unknown channel: {}", name))
+    }
+}
+
+// 録音中のWAV。ステムありなら1チャンネル目がミックス、続いてChannel::ALLの順
+struct Recording {
+    wav: WavWriter,
+    // チャンネルごとのリサンプラーと最後に渡した値
+    stems: Vec<(Resampler, f32)>,
+    // 書き込みに失敗したらstop_recordingで返す
+    error: Option<String>,
+}
+
+// $4000-$4017 のうち音を出す部分。チャンネルと
+// フレームカウンタ ($4017)、状態レジスタ ($4015) を持つ
+pub struct Apu {
+    pub region: Region,
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: Dmc,
+    pub frame_mode: FrameMode,
+    pub irq_inhibit: bool,
+    frame_irq: bool,
+    // フレームカウンタの位置 (CPUサイクル)
+    frame_cycle: u32,
+    // $4017への書き込みからリセットまでの残りサイクル
+    reset_delay: u8,
+    cycles: u64,
+    // カートリッジの拡張音源の出力 (0.0-1.0)
+    pub expansion: f32,
+    resampler: Resampler,
+    filter: OutputFilter,
+    last_output: f32,
+    // 最後にリサンプラーへ渡してから進んだサイクル
+    pending_cycles: u32,
+    // Channel::ALLの順
+    muted: [bool; 6],
+    solo: Option<Channel>,
+    // 0.0-1.0
+    pub master_volume: f32,
+    recording: Option<Recording>,
+    pub write_log: ApuWriteLog,
+}
+
+impl Apu {
+    pub fn new(region: Region) -> Self {
+        Apu {
+            region,
+            pulse1: Pulse::new(PulseChannel::One),
+            pulse2: Pulse::new(PulseChannel::Two),
+            triangle: Triangle::new(),
+            noise: Noise::new(region),
+            dmc: Dmc::new(region),
+            frame_mode: FrameMode::FourStep,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            reset_delay: 0,
+            cycles: 0,
+            expansion: 0.0,
+            resampler: Resampler::new(region.cpu_clock(), DEFAULT_SAMPLE_RATE),
+            filter: OutputFilter::new(FilterConfig::default(), DEFAULT_SAMPLE_RATE),
+            last_output: 0.0,
+            pending_cycles: 0,
+            muted: [false; 6],
+            solo: None,
+            master_volume: 1.0,
+            recording: None,
+            write_log: ApuWriteLog::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.resampler.sample_rate()
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        let clock_rate = self.region.cpu_clock();
+        self.resampler.set_rates(clock_rate, sample_rate);
+        self.filter = OutputFilter::new(self.filter.config(), sample_rate);
+        for (resampler, _) in self.stems_mut() {
+            resampler.set_rates(clock_rate, sample_rate);
+        }
+    }
+
+    // 出力のバッファの溜まり具合に合わせて、サンプルを作る速さを少しだけ変える
+    pub fn set_rate_adjustment(&mut self, adjustment: f64) {
+        self.resampler.set_adjustment(adjustment);
+        for (resampler, _) in self.stems_mut() {
+            resampler.set_adjustment(adjustment);
+        }
+    }
+
+    pub fn filter_config(&self) -> FilterConfig {
+        self.filter.config()
+    }
+
+    // 出力にかけるフィルタを変える。FilterConfig::NONEで生の出力になる
+    pub fn set_filters(&mut self, config: FilterConfig) {
+        self.filter = OutputFilter::new(config, self.sample_rate());
+    }
+
+    // ROMを入れ替えるときに、サンプルレートや音量、ミュートなど外から決めた設定だけを引き継ぐ
+    pub fn copy_settings(&mut self, from: &Apu) {
+        self.set_sample_rate(from.sample_rate());
+        self.set_filters(from.filter_config());
+        self.muted = from.muted;
+        self.solo = from.solo;
+        self.master_volume = from.master_volume;
+        self.write_log.enabled = from.write_log.enabled;
+    }
+
+    fn stems_mut(&mut self) -> std::slice::IterMut<'_, (Resampler, f32)> {
+        match self.recording.as_mut() {
+            Some(recording) => recording.stems.iter_mut(),
+            None => [].iter_mut(),
+        }
+    }
+
+    // take_samplesで取り出した出力をpathへ16bitのWAVで書く
+    pub fn start_recording<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+        self.begin_recording(path.as_ref(), false)
+    }
+
+    // ミックスに加えて、ミュートや音量、フィルタを通す前の各チャンネルも別のチャンネルとして書く
+    pub fn start_recording_stems<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+        self.begin_recording(path.as_ref(), true)
+    }
+
+    fn begin_recording(&mut self, path: &Path, stems: bool) -> Result<(), String> {
+        self.stop_recording()?;
+        let channels = if stems { 1 + Channel::ALL.len() } else { 1 };
+        let wav = WavWriter::create(path, channels as u16, self.sample_rate())?;
+        // ステムのリサンプラーをミックスと同じ位置から始める
+        self.resampler.advance(self.pending_cycles);
+        self.pending_cycles = 0;
+        let stems = if stems {
+            Channel::ALL
+                .iter()
+                .map(|_| (self.resampler.silent_copy(), 0.0))
+                .collect()
+        } else {
+            vec![]
+        };
+        self.recording = Some(Recording {
+            wav,
+            stems,
+            error: None,
+        });
+        Ok(())
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    // 録音していなければ何もしない
+    pub fn stop_recording(&mut self) -> Result<(), String> {
+        match self.recording.take() {
+            Some(recording) => {
+                let finished = recording.wav.finish();
+                match recording.error {
+                    Some(error) => Err(error),
+                    None => finished,
+                }
+            }
+            None => Ok(()),
+        }
+    }
+
+    // ステム用の各チャンネル単独の出力
+    fn channel_output(&self, channel: Channel) -> f32 {
+        match channel {
+            Channel::Pulse1 => apu_mixer::mix(self.pulse1.output(), 0, 0, 0, 0),
+            Channel::Pulse2 => apu_mixer::mix(0, self.pulse2.output(), 0, 0, 0),
+            Channel::Triangle => apu_mixer::mix(0, 0, self.triangle.output(), 0, 0),
+            Channel::Noise => apu_mixer::mix(0, 0, 0, self.noise.output(), 0),
+            Channel::Dmc => apu_mixer::mix(0, 0, 0, 0, self.dmc.output()),
+            Channel::Expansion => self.expansion * EXPANSION_GAIN,
+        }
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    pub fn toggle_mute(&mut self, channel: Channel) {
+        self.muted[channel as usize] = !self.muted[channel as usize];
+    }
+
+    // ソロ中は他のチャンネルのミュート設定に関係なくそのチャンネルだけ鳴らす
+    pub fn set_solo(&mut self, solo: Option<Channel>) {
+        self.solo = solo;
+    }
+
+    pub fn solo(&self) -> Option<Channel> {
+        self.solo
+    }
+
+    pub fn is_audible(&self, channel: Channel) -> bool {
+        match self.solo {
+            Some(solo) => solo == channel,
+            None => !self.is_muted(channel),
+        }
+    }
+
+    // ミキサーを通した今の出力。聞こえないチャンネルは0として混ぜる
+    pub fn output(&self) -> f32 {
+        let level = |channel: Channel, value: u8| {
+            if self.is_audible(channel) {
+                value
+            } else {
+                0
+            }
+        };
+        let expansion = if self.is_audible(Channel::Expansion) {
+            self.expansion * EXPANSION_GAIN
+        } else {
+            0.0
+        };
+        let mixed = apu_mixer::mix(
+            level(Channel::Pulse1, self.pulse1.output()),
+            level(Channel::Pulse2, self.pulse2.output()),
+            level(Channel::Triangle, self.triangle.output()),
+            level(Channel::Noise, self.noise.output()),
+            level(Channel::Dmc, self.dmc.output()),
+        ) + expansion;
+        mixed * self.master_volume
+    }
+
+    // 前回から溜まったサンプルをoutの後ろに足す
+    pub fn take_samples(&mut self, out: &mut Vec<f32>) {
+        let start = out.len();
+        let pending_cycles = self.pending_cycles;
+        self.resampler.advance(pending_cycles);
+        self.pending_cycles = 0;
+        self.resampler.take_samples(out);
+        self.filter.process(&mut out[start..]);
+
+        let recording = match self.recording.as_mut() {
+            Some(recording) if recording.error.is_none() => recording,
+            _ => return,
+        };
+        let stems: Vec<Vec<f32>> = recording
+            .stems
+            .iter_mut()
+            .map(|(resampler, _)| {
+                let mut samples = vec![];
+                resampler.advance(pending_cycles);
+                resampler.take_samples(&mut samples);
+                samples
+            })
+            .collect();
+        let mut frame = vec![0.0; 1 + stems.len()];
+        for (i, sample) in out[start..].iter().enumerate() {
+            frame[0] = *sample;
+            for (value, stem) in frame[1..].iter_mut().zip(stems.iter()) {
+                *value = stem[i];
+            }
+            if let Err(e) = recording.wav.write_frame(&frame) {
+                recording.error = Some(e);
+                return;
+            }
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.noise.region = region;
+        self.dmc.region = region;
+        let sample_rate = self.sample_rate();
+        self.resampler.set_rates(region.cpu_clock(), sample_rate);
+        for (resampler, _) in self.stems_mut() {
+            resampler.set_rates(region.cpu_clock(), sample_rate);
+        }
+    }
+
+    // 電源投入からのCPUサイクル
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        self.write_log.record(self.cycles, addr, data);
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr, data),
+            0x4004..=0x4007 => self.pulse2.write(addr, data),
+            0x4008..=0x400b => self.triangle.write(addr, data),
+            0x400c..=0x400f => self.noise.write(addr, data),
+            0x4010..=0x4013 => self.dmc.write(addr, data),
+            0x4015 => self.write_status(data),
+            0x4017 => self.write_frame_counter(data),
+            _ => {}
+        }
+    }
+
+    // 読むとフレームIRQのフラグが消える
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        status
+    }
+
+    // 副作用のない$4015
+    pub fn peek_status(&self) -> u8 {
+        let mut status = 0;
+        for (i, length) in self.length_counters().iter().enumerate() {
+            if length.is_active() {
+                status |= 1 << i;
+            }
+        }
+        if self.dmc.is_active() {
+            status |= 0x10;
+        }
+        if self.frame_irq {
+            status |= 0x40;
+        }
+        if self.dmc.irq {
+            status |= 0x80;
+        }
+        status
+    }
+
+    // パルス1, パルス2, 三角波, ノイズの順
+    fn length_counters(&self) -> [&LengthCounter; 4] {
+        [
+            &self.pulse1.length,
+            &self.pulse2.length,
+            &self.triangle.length,
+            &self.noise.length,
+        ]
+    }
+
+    fn write_status(&mut self, data: u8) {
+        self.pulse1.length.set_enabled(data & 0x01 != 0);
+        self.pulse2.length.set_enabled(data & 0x02 != 0);
+        self.triangle.length.set_enabled(data & 0x04 != 0);
+        self.noise.length.set_enabled(data & 0x08 != 0);
+        self.dmc.set_enabled(data & 0x10 != 0);
+    }
+
+    // 書き込みの3サイクル後 (奇数サイクルなら4サイクル後) に数え直す
+    fn write_frame_counter(&mut self, data: u8) {
+        self.frame_mode = if data & 0x80 != 0 {
+            FrameMode::FiveStep
+        } else {
+            FrameMode::FourStep
+        };
+        self.irq_inhibit = data & 0x40 != 0;
+        if self.irq_inhibit {
+            self.frame_irq = false;
+        }
+        self.reset_delay = if self.cycles % 2 == 1 { 4 } else { 3 };
+    }
+
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.step();
+        }
+    }
+
+    fn step(&mut self) {
+        self.cycles += 1;
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycles & 1 == 0 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.frame_counter_step();
+
+        self.pending_cycles += 1;
+        let output = self.output();
+        let levels = match &self.recording {
+            Some(recording) if !recording.stems.is_empty() => {
+                Some(Channel::ALL.map(|channel| self.channel_output(channel)))
+            }
+            _ => None,
+        };
+        let stems_changed = levels.is_some_and(|levels| {
+            self.recording
+                .as_ref()
+                .unwrap()
+                .stems
+                .iter()
+                .zip(levels)
+                .any(|((_, last), level)| *last != level)
+        });
+        if output != self.last_output || stems_changed {
+            // ステムとミックスは同じ位置で進めてサンプル数を揃える
+            let pending_cycles = self.pending_cycles;
+            self.pending_cycles = 0;
+            self.resampler.advance(pending_cycles);
+            if output != self.last_output {
+                self.resampler.add_delta(output - self.last_output);
+                self.last_output = output;
+            }
+            if let Some(levels) = levels {
+                for ((resampler, last), level) in self.stems_mut().zip(levels) {
+                    resampler.advance(pending_cycles);
+                    if *last != level {
+                        resampler.add_delta(level - *last);
+                        *last = level;
+                    }
+                }
+            }
+        }
+    }
+
+    fn frame_counter_step(&mut self) {
+        if self.reset_delay > 0 {
+            self.reset_delay -= 1;
+            if self.reset_delay == 0 {
+                self.frame_cycle = 0;
+                // 5ステップにしたときはすぐにクォーターとハーフを鳴らす
+                if self.frame_mode == FrameMode::FiveStep {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+                return;
+            }
+        }
+
+        self.frame_cycle += 1;
+        let table = (self.region == Region::Pal) as usize;
+        match self.frame_mode {
+            FrameMode::FourStep => {
+                let steps = FOUR_STEP[table];
+                let step = steps.iter().position(|&c| c == self.frame_cycle);
+                match step {
+                    Some(0) | Some(2) => self.quarter_frame(),
+                    Some(1) | Some(4) => {
+                        self.quarter_frame();
+                        self.half_frame();
+                    }
+                    _ => {}
+                }
+                if matches!(step, Some(3..=5)) && !self.irq_inhibit {
+                    self.frame_irq = true;
+                }
+                if step == Some(5) {
+                    self.frame_cycle = 0;
+                }
+            }
+            FrameMode::FiveStep => {
+                let steps = FIVE_STEP[table];
+                match steps.iter().position(|&c| c == self.frame_cycle) {
+                    Some(0) | Some(2) => self.quarter_frame(),
+                    Some(1) | Some(3) => {
+                        self.quarter_frame();
+                        self.half_frame();
+                    }
+                    Some(4) => self.frame_cycle = 0,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // エンベロープと三角波の線形カウンタ
+    fn quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.triangle.clock_linear_counter();
+        self.noise.envelope.clock();
+    }
+
+    // 長さカウンタとスイープ
+    fn half_frame(&mut self) {
+        self.pulse1.clock_half_frame();
+        self.pulse2.clock_half_frame();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+    }
+
+    // セーブステート。音量やミュート、録音などの設定は含めない
+    pub fn state(&mut self, state: &mut State) {
+        self.core_state(state);
+        self.output_state(state);
+    }
+
+    // チャンネルとフレームカウンタ。ゲームから見える状態はここまで
+    pub fn core_state(&mut self, state: &mut State) {
+        self.pulse1.state(state);
+        self.pulse2.state(state);
+        self.triangle.state(state);
+        self.noise.state(state);
+        self.dmc.state(state);
+        state.map_u8(
+            &mut self.frame_mode,
+            |mode| (mode == FrameMode::FiveStep) as u8,
+            |byte| {
+                if byte != 0 {
+                    FrameMode::FiveStep
+                } else {
+                    FrameMode::FourStep
+                }
+            },
+        );
+        state.bool(&mut self.irq_inhibit);
+        state.bool(&mut self.frame_irq);
+        state.u32(&mut self.frame_cycle);
+        state.u8(&mut self.reset_delay);
+        state.u64(&mut self.cycles);
+    }
+
+    // リサンプラーやフィルターの状態。サンプリングレートなどの設定で変わる
+    fn output_state(&mut self, state: &mut State) {
+        state.f32(&mut self.expansion);
+        self.resampler.state(state);
+        self.filter.state(state);
+        state.f32(&mut self.last_output);
+        state.u32(&mut self.pending_cycles);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_length_table() {
+        // nesdev wikiの表: bit3が1なら (index - 1)、ただしindex 1は254
+        for index in (1..32).step_by(2) {
+            let expected = if index == 1 { 254 } else { index as u8 - 1 };
+            assert_eq!(LENGTH_TABLE[index], expected, "index {}", index);
+        }
+        // bit3が0の行は4/4拍子と3/4拍子の音符長 (テンポ90と75)
+        let even = [
+            10, 20, 40, 80, 160, 60, 14, 26, 12, 24, 48, 96, 192, 72, 16, 32,
+        ];
+        for (i, expected) in even.iter().enumerate() {
+            assert_eq!(LENGTH_TABLE[i * 2], *expected, "index {}", i * 2);
+        }
+    }
+
+    #[test]
+    fn test_length_counter_counts_down_table_value() {
+        for index in 0..32u8 {
+            let mut length = LengthCounter::new();
+            length.set_enabled(true);
+            length.load(index);
+            let mut clocks = 0;
+            while length.is_active() {
+                length.clock();
+                clocks += 1;
+            }
+            assert_eq!(clocks, LENGTH_TABLE[index as usize] as usize);
+        }
+    }
+
+    #[test]
+    fn test_length_counter_halt_and_disable() {
+        let mut length = LengthCounter::new();
+        length.load(1);
+        assert_eq!(length.counter, 0);
+
+        length.set_enabled(true);
+        length.load(1);
+        length.halt = true;
+        length.clock();
+        assert_eq!(length.counter, 254);
+
+        length.halt = false;
+        length.clock();
+        assert_eq!(length.counter, 253);
+
+        length.set_enabled(false);
+        assert!(!length.is_active());
+    }
+
+    #[test]
+    fn test_sweep_target_period() {
+        for shift in 0..8u8 {
+            for negate in [false, true] {
+                let value = 0x80 | (negate as u8) << 3 | shift;
+                let mut one = Sweep::new(PulseChannel::One);
+                let mut two = Sweep::new(PulseChannel::Two);
+                one.write(value);
+                two.write(value);
+                for timer in 0..=0x7ffu16 {
+                    let change = timer >> shift;
+                    if negate {
+                        assert_eq!(two.target_period(timer), timer - change);
+                        assert_eq!(one.target_period(timer), (timer - change).saturating_sub(1));
+                    } else {
+                        assert_eq!(one.target_period(timer), timer + change);
+                        assert_eq!(two.target_period(timer), timer + change);
+                    }
+                }
+            }
+        }
+
+        // nesdev wikiの例: 周期$100, シフト1, 減算
+        let mut one = Sweep::new(PulseChannel::One);
+        one.write(0b1000_1001);
+        assert_eq!(one.target_period(0x100), 0x07f);
+        let mut two = Sweep::new(PulseChannel::Two);
+        two.write(0b1000_1001);
+        assert_eq!(two.target_period(0x100), 0x080);
+    }
+
+    #[test]
+    fn test_sweep_muting() {
+        let mut sweep = Sweep::new(PulseChannel::Two);
+        // 無効でも加算結果が$7FFを超えればミュート
+        sweep.write(0b0000_0000);
+        assert!(sweep.is_muting(0x400));
+        assert!(!sweep.is_muting(0x3ff));
+        assert!(sweep.is_muting(7));
+        assert!(!sweep.is_muting(8));
+
+        sweep.write(0b0000_1000);
+        assert!(!sweep.is_muting(0x7ff));
+    }
+
+    #[test]
+    fn test_sweep_clock() {
+        let mut sweep = Sweep::new(PulseChannel::One);
+        // 有効, 周期1 (2ハーフフレームごと), シフト1, 加算
+        sweep.write(0b1001_0001);
+        let mut timer = 0x100;
+
+        let mut periods = vec![];
+        for _ in 0..12 {
+            sweep.clock(&mut timer);
+            periods.push(timer);
+        }
+        // 分周器が0のクロックで更新され、目標が$7FFを超えると止まる
+        assert_eq!(
+            periods,
+            vec![
+                0x180, 0x180, 0x240, 0x240, 0x360, 0x360, 0x510, 0x510, 0x798, 0x798, 0x798, 0x798
+            ]
+        );
+    }
+
+    #[test]
+    fn test_envelope_decay_timing() {
+        for period in 0..16u8 {
+            let mut envelope = Envelope::new();
+            envelope.write(period);
+            envelope.start = true;
+
+            envelope.clock();
+            assert_eq!(envelope.output(), 15);
+            // 音量は (period + 1) クロックごとに1ずつ下がる
+            for volume in (0..15).rev() {
+                for _ in 0..period {
+                    envelope.clock();
+                    assert_eq!(envelope.output(), volume + 1);
+                }
+                envelope.clock();
+                assert_eq!(envelope.output(), volume);
+            }
+            for _ in 0..(period as usize + 1) * 4 {
+                envelope.clock();
+                assert_eq!(envelope.output(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_envelope_loop_and_constant_volume() {
+        let mut envelope = Envelope::new();
+        envelope.write(0b0010_0000);
+        envelope.start = true;
+        for _ in 0..16 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.output(), 0);
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+
+        envelope.write(0b0001_0111);
+        assert_eq!(envelope.output(), 7);
+    }
+
+    fn tick_cycles(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles {
+            apu.tick(1);
+        }
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = Apu::new(Region::Ntsc);
+        tick_cycles(&mut apu, 29827);
+        assert!(!apu.irq());
+        tick_cycles(&mut apu, 1);
+        assert!(apu.irq());
+        assert_eq!(apu.read_status(), 0x40);
+        // 読んで消しても最後の2サイクルでまた立つ
+        assert!(!apu.irq());
+        tick_cycles(&mut apu, 2);
+        assert!(apu.irq());
+        apu.read_status();
+        tick_cycles(&mut apu, 29827);
+        assert!(!apu.irq());
+
+        // 禁止ビットを立てるとフラグも消える
+        tick_cycles(&mut apu, 1);
+        assert!(apu.irq());
+        apu.write_register(0x4017, 0x40);
+        assert!(!apu.irq());
+        tick_cycles(&mut apu, 29830 * 2);
+        assert!(!apu.irq());
+
+        // 5ステップではIRQは起きない
+        let mut apu = Apu::new(Region::Pal);
+        apu.write_register(0x4017, 0x80);
+        tick_cycles(&mut apu, 41566 * 2);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_status_and_length() {
+        let mut apu = Apu::new(Region::Ntsc);
+        // 無効なチャンネルには長さをロードできない
+        apu.write_register(0x4003, 0b0000_1000);
+        assert_eq!(apu.peek_status(), 0);
+
+        apu.write_register(0x4015, 0b0001_0101);
+        apu.write_register(0x4003, 0b0001_1000);
+        apu.write_register(0x400b, 0b0000_1000);
+        apu.write_register(0x400f, 0b0001_1000);
+        assert_eq!(apu.peek_status(), 0b0001_0101);
+        assert!(apu.dmc.is_active());
+
+        // 5ステップに切り替えるとすぐにハーフフレームが来る
+        apu.write_register(0x4017, 0x80);
+        tick_cycles(&mut apu, 3);
+        assert_eq!(apu.pulse1.length.counter, 1);
+        assert_eq!(apu.triangle.length.counter, 253);
+        // 1周 (37282サイクル) でハーフフレームは2回
+        tick_cycles(&mut apu, 37282);
+        assert_eq!(apu.peek_status(), 0b0001_0100);
+        assert_eq!(apu.triangle.length.counter, 251);
+
+        apu.write_register(0x4015, 0);
+        assert_eq!(apu.peek_status(), 0);
+    }
+
+    #[test]
+    fn test_pulse_duty() {
+        for duty in 0..4u8 {
+            let mut pulse = Pulse::new(PulseChannel::One);
+            pulse.length.set_enabled(true);
+            // 定音量15, 周期8 (9 APUサイクルで1ステップ)
+            pulse.write(0x4000, duty << 6 | 0b0011_1111);
+            pulse.write(0x4002, 8);
+            pulse.write(0x4003, 0);
+            let mut wave = vec![];
+            for _ in 0..8 {
+                wave.push(pulse.output() / 15);
+                for _ in 0..9 {
+                    pulse.clock_timer();
+                }
+            }
+            assert_eq!(wave, DUTY_TABLE[duty as usize]);
+        }
+
+        let mut pulse = Pulse::new(PulseChannel::Two);
+        pulse.length.set_enabled(true);
+        pulse.write(0x4004, 0b1111_1111);
+        pulse.write(0x4006, 7);
+        pulse.write(0x4007, 0);
+        // 周期が8未満だとミュート
+        assert_eq!(pulse.output(), 0);
+        pulse.write(0x4006, 8);
+        assert_eq!(pulse.output(), 15);
+        pulse.length.set_enabled(false);
+        assert_eq!(pulse.output(), 0);
+    }
+
+    #[test]
+    fn test_pulse_in_apu() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write_register(0x4015, 0b11);
+        // エンベロープ周期0, スイープ有効 (周期0, シフト1, 減算)
+        apu.write_register(0x4000, 0b1000_0000);
+        apu.write_register(0x4001, 0b1000_1001);
+        apu.write_register(0x4002, 0x00);
+        apu.write_register(0x4003, 0b0000_1001);
+        assert_eq!(apu.pulse1.period, 0x100);
+        assert_eq!(apu.peek_status(), 0b01);
+
+        // クォーターフレームでエンベロープが15から始まり、ハーフフレームでスイープが掛かる
+        tick_cycles(&mut apu, 7457);
+        assert_eq!(apu.pulse1.envelope.output(), 15);
+        tick_cycles(&mut apu, 14913 - 7457);
+        assert_eq!(apu.pulse1.envelope.output(), 14);
+        assert_eq!(apu.pulse1.period, 0x07f);
+    }
+
+    #[test]
+    fn test_triangle() {
+        let mut triangle = Triangle::new();
+        triangle.length.set_enabled(true);
+        // 線形カウンタ2, 周期2 (3 CPUサイクルで1ステップ)
+        triangle.write(0x4008, 0x02);
+        triangle.write(0x400a, 2);
+        triangle.write(0x400b, 0b0000_1000);
+
+        // 線形カウンタが0の間は進まない
+        for _ in 0..3 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.output(), 15);
+
+        triangle.clock_linear_counter();
+        assert_eq!(triangle.linear_counter, 2);
+        let mut wave = vec![];
+        for _ in 0..32 {
+            for _ in 0..3 {
+                triangle.clock_timer();
+            }
+            wave.push(triangle.output());
+        }
+        assert_eq!(wave[..31], TRIANGLE_TABLE[1..]);
+        assert_eq!(wave[31], 15);
+
+        // 制御ビットが0ならリロードは1回だけで、その後は数え下がって止まる
+        triangle.clock_linear_counter();
+        triangle.clock_linear_counter();
+        assert_eq!(triangle.linear_counter, 0);
+        let before = triangle.output();
+        for _ in 0..30 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.output(), before);
+
+        // 超音波の周期では波形を止める
+        triangle.write(0x400b, 0b0000_1000);
+        triangle.write(0x400a, 1);
+        triangle.clock_linear_counter();
+        let before = triangle.output();
+        for _ in 0..30 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.output(), before);
+    }
+
+    #[test]
+    fn test_noise_lfsr() {
+        // モード0は32767ステップ、モード1は93ステップで一周する
+        for (mode, expected) in [(0x00, 32767), (0x80, 93)] {
+            let mut noise = Noise::new(Region::Ntsc);
+            noise.write(0x400e, mode);
+            for _ in 0..4 * 200 {
+                noise.clock_timer();
+            }
+            let start = noise.shift;
+            let mut steps = 0;
+            loop {
+                for _ in 0..4 {
+                    noise.clock_timer();
+                }
+                steps += 1;
+                if noise.shift == start {
+                    break;
+                }
+            }
+            assert_eq!(steps, expected);
+        }
+    }
+
+    #[test]
+    fn test_noise_output() {
+        let mut noise = Noise::new(Region::Pal);
+        noise.length.set_enabled(true);
+        noise.write(0x400c, 0b0001_1010);
+        noise.write(0x400e, 0x02);
+        noise.write(0x400f, 0b0000_1000);
+        assert_eq!(noise.period(), 14);
+        noise.region = Region::Ntsc;
+        assert_eq!(noise.period(), 16);
+
+        let mut outputs = vec![];
+        for _ in 0..16 * 20 {
+            noise.clock_timer();
+            outputs.push(noise.output());
+        }
+        assert!(outputs.iter().all(|&v| v == 0 || v == 10));
+        assert!(outputs.contains(&0) && outputs.contains(&10));
+
+        noise.length.set_enabled(false);
+        assert_eq!(noise.output(), 0);
+    }
+
+    #[test]
+    fn test_dmc() {
+        let mut dmc = Dmc::new(Region::Ntsc);
+        // ループ, 周期54, $C040から17バイト
+        dmc.write(0x4010, 0x4f);
+        dmc.write(0x4011, 0x40);
+        dmc.write(0x4012, 0x01);
+        dmc.write(0x4013, 0x01);
+        assert_eq!(dmc.fetch_address(), None);
+        dmc.set_enabled(true);
+        assert_eq!(dmc.fetch_address(), Some(0xc040));
+        dmc.load_sample(0b0000_0011);
+        // バッファが埋まっている間は読まない
+        assert_eq!(dmc.fetch_address(), None);
+
+        // 最初の8ビットは無音のまま流れ、その後バッファのビットで±2
+        for _ in 0..54 * 8 {
+            dmc.clock_timer();
+        }
+        assert_eq!(dmc.output(), 0x40);
+        let mut levels = vec![];
+        for _ in 0..8 {
+            for _ in 0..54 {
+                dmc.clock_timer();
+            }
+            levels.push(dmc.output());
+        }
+        assert_eq!(levels, vec![0x42, 0x44, 0x42, 0x40, 0x3e, 0x3c, 0x3a, 0x38]);
+        assert_eq!(dmc.fetch_address(), Some(0xc041));
+
+        // 最後のバイトを読むとループして先頭に戻る
+        for _ in 0..16 {
+            dmc.load_sample(0);
+            dmc.buffer = None;
+        }
+        assert_eq!(dmc.fetch_address(), Some(0xc040));
+        assert!(!dmc.irq);
+
+        // $FFFFの次は$8000
+        dmc.current_address = 0xffff;
+        dmc.load_sample(0);
+        assert_eq!(dmc.current_address, 0x8000);
+    }
+
+    #[test]
+    fn test_take_samples() {
+        let mut apu = Apu::new(Region::Ntsc);
+        // 出力の値をそのまま比べるのでフィルタは外す
+        apu.set_filters(FilterConfig::NONE);
+        apu.write_register(0x4015, 0x01);
+        // 定音量15, 50%, 周期253 (約440Hz)
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 253);
+        apu.write_register(0x4003, 0);
+        tick_cycles(&mut apu, 29830);
+        let mut samples = vec![];
+        apu.take_samples(&mut samples);
+        assert!((samples.len() as i32 - 735).abs() <= 1);
+        // 最初の数サンプルは電源投入時の0からの立ち上がり
+        let max = samples[20..].iter().cloned().fold(0.0, f32::max);
+        // 止まっている三角波も15を出し続けている
+        assert!((max - apu_mixer::mix(15, 0, 15, 0, 0)).abs() < 0.02);
+        let min = samples[20..].iter().cloned().fold(1.0, f32::min);
+        assert!((min - apu_mixer::mix(0, 0, 15, 0, 0)).abs() < 0.02);
+
+        samples.clear();
+        apu.set_sample_rate(48000);
+        tick_cycles(&mut apu, 29830);
+        apu.take_samples(&mut samples);
+        assert!((samples.len() as i32 - 800).abs() <= 1);
+    }
+
+    #[test]
+    fn test_mute_and_solo() {
+        let mut apu = Apu::new(Region::Ntsc);
+        // 止まっている三角波は15を出し続ける
+        let triangle = apu_mixer::mix(0, 0, 15, 0, 0);
+        assert_eq!(apu.output(), triangle);
+
+        apu.toggle_mute(Channel::Triangle);
+        assert!(apu.is_muted(Channel::Triangle));
+        assert_eq!(apu.output(), 0.0);
+
+        // ソロはミュートより優先
+        apu.set_solo(Some(Channel::Triangle));
+        assert_eq!(apu.output(), triangle);
+        apu.set_solo(Some(Channel::Noise));
+        assert!(!apu.is_audible(Channel::Triangle));
+        assert_eq!(apu.output(), 0.0);
+        apu.set_solo(None);
+
+        apu.set_muted(Channel::Triangle, false);
+        apu.master_volume = 0.5;
+        assert_eq!(apu.output(), triangle * 0.5);
+
+        assert_eq!(Channel::parse("dmc"), Ok(Channel::Dmc));
+        assert!(Channel::parse("square").is_err());
+    }
+
+    #[test]
+    fn test_recording() {
+        let path = std::env::temp_dir().join(format!("nes-rs-apu-{}.wav", std::process::id()));
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.set_filters(FilterConfig::NONE);
+        apu.write_register(0x4015, 0x01);
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 253);
+        apu.write_register(0x4003, 0);
+        apu.set_muted(Channel::Pulse1, true);
+        apu.start_recording_stems(&path).unwrap();
+        assert!(apu.is_recording());
+        let mut samples = vec![];
+        for _ in 0..3 {
+            tick_cycles(&mut apu, 10000);
+            apu.take_samples(&mut samples);
+        }
+        apu.stop_recording().unwrap();
+        assert!(!apu.is_recording());
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(u16::from_le_bytes([data[22], data[23]]), 7);
+        let frames: Vec<Vec<i16>> = data[44..]
+            .chunks(14)
+            .map(|frame| {
+                frame
+                    .chunks(2)
+                    .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(frames.len(), samples.len());
+        // ミュートした矩形波1はミックスには入らないがステムには残る
+        let peak = |channel: usize| frames[20..].iter().map(|f| f[channel]).max().unwrap();
+        // 帯域制限のオーバーシュート分は許す
+        let near = |a: i16, b: i16| (a - b).abs() < 700;
+        let triangle = (apu_mixer::mix(0, 0, 15, 0, 0) * 32767.0) as i16;
+        assert!(near(peak(0), triangle));
+        let pulse = (apu_mixer::mix(15, 0, 0, 0, 0) * 32767.0) as i16;
+        assert!(near(peak(1), pulse));
+        assert_eq!(peak(2), 0);
+        assert!(near(peak(3), triangle));
+    }
+}
@@ -0,0 +1,190 @@
+use crate::apu::{Apu, Channel, FrameMode, Pulse};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApuWrite {
+    // 電源投入からのCPUサイクル
+    pub cycle: u64,
+    pub addr: u16,
+    pub data: u8,
+}
+
+// $4000-$4017への書き込みの記録。NSFの切り出しや音の確認に使う。
+// 記録はデフォルトで無効
+pub struct ApuWriteLog {
+    pub enabled: bool,
+    entries: Vec<ApuWrite>,
+}
+
+impl ApuWriteLog {
+    pub fn new() -> Self {
+        ApuWriteLog {
+            enabled: false,
+            entries: vec![],
+        }
+    }
+
+    pub fn record(&mut self, cycle: u64, addr: u16, data: u8) {
+        if self.enabled {
+            self.entries.push(ApuWrite { cycle, addr, data });
+        }
+    }
+
+    pub fn entries(&self) -> &[ApuWrite] {
+        &self.entries
+    }
+
+    // 溜まった分を取り出して空にする
+    pub fn take(&mut self) -> Vec<ApuWrite> {
+        std::mem::take(&mut self.entries)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl Default for ApuWriteLog {
+    fn default() -> Self {
+        ApuWriteLog::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelState {
+    pub channel: Channel,
+    // $4015のビット (DMCは残りのバイトがあるか)
+    pub enabled: bool,
+    // タイマーの周期。ノイズとDMCは表から引いたCPUサイクル数
+    pub period: u16,
+    // 今の音量 (0-15)。DMCは出力レベル (0-127)
+    pub volume: u8,
+    // 長さカウンタ。DMCは残りのバイト数
+    pub length: u16,
+    // 音の高さ (Hz)。ノイズはシフトレジスタ、DMCは1ビットを進める頻度
+    pub frequency: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApuSnapshot {
+    pub cycle: u64,
+    pub frame_mode: FrameMode,
+    pub status: u8,
+    // 矩形波1, 矩形波2, 三角波, ノイズ, DMCの順
+    pub channels: [ChannelState; 5],
+}
+
+impl Apu {
+    // デバッガの表示用に、各チャンネルの今の状態をまとめる
+    pub fn snapshot(&self) -> ApuSnapshot {
+        let clock = self.region.cpu_clock();
+        let pulse = |channel, pulse: &Pulse| ChannelState {
+            channel,
+            enabled: pulse.length.enabled,
+            period: pulse.period,
+            volume: pulse.envelope.output(),
+            length: pulse.length.counter as u16,
+            frequency: clock / (16.0 * (pulse.period as f64 + 1.0)),
+        };
+        let triangle = &self.triangle;
+        let noise = &self.noise;
+        let dmc = &self.dmc;
+        ApuSnapshot {
+            cycle: self.cycles(),
+            frame_mode: self.frame_mode,
+            status: self.peek_status(),
+            channels: [
+                pulse(Channel::Pulse1, &self.pulse1),
+                pulse(Channel::Pulse2, &self.pulse2),
+                ChannelState {
+                    channel: Channel::Triangle,
+                    enabled: triangle.length.enabled,
+                    period: triangle.period,
+                    volume: if triangle.length.is_active() && triangle.linear_counter > 0 {
+                        15
+                    } else {
+                        0
+                    },
+                    length: triangle.length.counter as u16,
+                    frequency: clock / (32.0 * (triangle.period as f64 + 1.0)),
+                },
+                ChannelState {
+                    channel: Channel::Noise,
+                    enabled: noise.length.enabled,
+                    period: noise.period(),
+                    volume: noise.envelope.output(),
+                    length: noise.length.counter as u16,
+                    frequency: clock / noise.period() as f64,
+                },
+                ChannelState {
+                    channel: Channel::Dmc,
+                    enabled: dmc.is_active(),
+                    period: dmc.rate(),
+                    volume: dmc.level,
+                    length: dmc.bytes_remaining(),
+                    frequency: clock / dmc.rate() as f64,
+                },
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::console::Region;
+
+    #[test]
+    fn test_write_log() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write_register(0x4015, 0x01);
+        assert!(apu.write_log.entries().is_empty());
+
+        apu.write_log.enabled = true;
+        apu.tick(10);
+        apu.write_register(0x4000, 0x3f);
+        apu.tick(2);
+        apu.write_register(0x4003, 0x08);
+        assert_eq!(
+            apu.write_log.take(),
+            vec![
+                ApuWrite {
+                    cycle: 10,
+                    addr: 0x4000,
+                    data: 0x3f
+                },
+                ApuWrite {
+                    cycle: 12,
+                    addr: 0x4003,
+                    data: 0x08
+                },
+            ]
+        );
+        assert!(apu.write_log.entries().is_empty());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write_register(0x4015, 0x05);
+        // 定音量9, 周期253
+        apu.write_register(0x4000, 0b0011_1001);
+        apu.write_register(0x4002, 253);
+        apu.write_register(0x4003, 0x08);
+        apu.write_register(0x4008, 0x7f);
+        apu.write_register(0x400a, 100);
+        apu.write_register(0x400b, 0x08);
+
+        let snapshot = apu.snapshot();
+        assert_eq!(snapshot.status, 0x05);
+        let pulse = snapshot.channels[0];
+        assert!(pulse.enabled);
+        assert_eq!((pulse.period, pulse.volume, pulse.length), (253, 9, 254));
+        assert!((pulse.frequency - 440.4).abs() < 0.1);
+        assert!(!snapshot.channels[1].enabled);
+
+        let triangle = snapshot.channels[2];
+        assert_eq!(triangle.channel, Channel::Triangle);
+        assert_eq!((triangle.period, triangle.length), (100, 254));
+        assert!(!snapshot.channels[4].enabled);
+    }
+}
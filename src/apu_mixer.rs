@@ -0,0 +1,352 @@
+use once_cell::sync::Lazy;
+
+use crate::savestate::State;
+
+// nesdev wikiの非線形ミキサーの近似式。出力は0.0-1.0
+pub fn mix(pulse1: u8, pulse2: u8, triangle: u8, noise: u8, dmc: u8) -> f32 {
+    let pulse = (pulse1 + pulse2) as f32;
+    let pulse_out = if pulse == 0.0 {
+        0.0
+    } else {
+        95.88 / (8128.0 / pulse + 100.0)
+    };
+    let tnd = triangle as f32 / 8227.0 + noise as f32 / 12241.0 + dmc as f32 / 22638.0;
+    let tnd_out = if tnd == 0.0 {
+        0.0
+    } else {
+        159.79 / (1.0 / tnd + 100.0)
+    };
+    pulse_out + tnd_out
+}
+
+// 本体とテレビの間にあるフィルタのカットオフ周波数 (Hz)。Noneなら使わない
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterConfig {
+    pub high_pass: [Option<f32>; 2],
+    pub low_pass: Option<f32>,
+}
+
+impl FilterConfig {
+    // 何もしない
+    pub const NONE: FilterConfig = FilterConfig {
+        high_pass: [None, None],
+        low_pass: None,
+    };
+}
+
+// nesdev wikiにあるNES本体の出力: 90Hzと440Hzのハイパス、14kHzのローパス
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            high_pass: [Some(90.0), Some(440.0)],
+            low_pass: Some(14000.0),
+        }
+    }
+}
+
+// 1次のハイパス
+struct HighPass {
+    alpha: f32,
+    prev_in: f32,
+    prev_out: f32,
+}
+
+impl HighPass {
+    fn new(cutoff: f32, sample_rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        HighPass {
+            alpha: rc / (rc + dt),
+            prev_in: 0.0,
+            prev_out: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        self.prev_out = self.alpha * (self.prev_out + input - self.prev_in);
+        self.prev_in = input;
+        self.prev_out
+    }
+}
+
+// 1次のローパス
+struct LowPass {
+    alpha: f32,
+    prev_out: f32,
+}
+
+impl LowPass {
+    fn new(cutoff: f32, sample_rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        LowPass {
+            alpha: dt / (rc + dt),
+            prev_out: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        self.prev_out += self.alpha * (input - self.prev_out);
+        self.prev_out
+    }
+}
+
+// リサンプル後の出力にかけるフィルタの列
+pub struct OutputFilter {
+    config: FilterConfig,
+    high_pass: Vec<HighPass>,
+    low_pass: Option<LowPass>,
+}
+
+impl OutputFilter {
+    pub fn new(config: FilterConfig, sample_rate: u32) -> Self {
+        OutputFilter {
+            config,
+            high_pass: config
+                .high_pass
+                .iter()
+                .flatten()
+                .map(|cutoff| HighPass::new(*cutoff, sample_rate))
+                .collect(),
+            low_pass: config
+                .low_pass
+                .map(|cutoff| LowPass::new(cutoff, sample_rate)),
+        }
+    }
+
+    pub fn config(&self) -> FilterConfig {
+        self.config
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples.iter_mut() {
+            let mut value = *sample;
+            for filter in self.high_pass.iter_mut() {
+                value = filter.process(value);
+            }
+            if let Some(filter) = self.low_pass.as_mut() {
+                value = filter.process(value);
+            }
+            *sample = value;
+        }
+    }
+
+    // フィルタの設定は含めず、直前の値だけ
+    pub fn state(&mut self, state: &mut State) {
+        for filter in self.high_pass.iter_mut() {
+            state.f32(&mut filter.prev_in);
+            state.f32(&mut filter.prev_out);
+        }
+        if let Some(filter) = self.low_pass.as_mut() {
+            state.f32(&mut filter.prev_out);
+        }
+    }
+}
+
+const KERNEL_WIDTH: usize = 16;
+const KERNEL_PHASES: usize = 32;
+
+// 出力サンプルの間の位置 (KERNEL_PHASES段階) ごとの帯域制限したインパルス。
+// 窓関数はBlackman、合計が1になるように正規化する
+static KERNEL: Lazy<Vec<[f32; KERNEL_WIDTH]>> = Lazy::new(|| {
+    let cutoff = 0.9;
+    (0..KERNEL_PHASES)
+        .map(|phase| {
+            let mut taps = [0.0; KERNEL_WIDTH];
+            let center = KERNEL_WIDTH as f64 / 2.0 + phase as f64 / KERNEL_PHASES as f64;
+            for (k, tap) in taps.iter_mut().enumerate() {
+                let x = k as f64 - center;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    let t = std::f64::consts::PI * x * cutoff;
+                    t.sin() / t
+                };
+                let w = (k as f64 + 1.0 - phase as f64 / KERNEL_PHASES as f64)
+                    / (KERNEL_WIDTH as f64 + 1.0);
+                let window = 0.42 - 0.5 * (2.0 * std::f64::consts::PI * w).cos()
+                    + 0.08 * (4.0 * std::f64::consts::PI * w).cos();
+                *tap = (sinc * window) as f32;
+            }
+            let sum: f32 = taps.iter().sum();
+            taps.iter_mut().for_each(|tap| *tap /= sum);
+            taps
+        })
+        .collect()
+});
+
+// CPUクロックで変化する出力を、変化量を帯域制限したステップとして足し込み
+// 任意のサンプルレートに落とす (blip_bufと同じ考え方)
+pub struct Resampler {
+    clock_rate: f64,
+    sample_rate: u32,
+    // 1クロックあたりの出力サンプル数
+    ratio: f64,
+    // 音声デバイスとの速度のずれを吸収するための倍率
+    adjustment: f64,
+    // buffer[0]から見た現在の位置 (出力サンプル単位)
+    offset: f64,
+    // 各サンプルでの変化量。読み出すときに積分する
+    buffer: Vec<f32>,
+    level: f32,
+}
+
+impl Resampler {
+    pub fn new(clock_rate: f64, sample_rate: u32) -> Self {
+        Resampler {
+            clock_rate,
+            sample_rate,
+            ratio: sample_rate as f64 / clock_rate,
+            adjustment: 1.0,
+            offset: 0.0,
+            buffer: vec![],
+            level: 0.0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_rates(&mut self, clock_rate: f64, sample_rate: u32) {
+        self.clock_rate = clock_rate;
+        self.sample_rate = sample_rate;
+        self.update_ratio();
+    }
+
+    // 1.0より大きければ同じ時間でサンプルを多めに作る
+    pub fn set_adjustment(&mut self, adjustment: f64) {
+        self.adjustment = adjustment;
+        self.update_ratio();
+    }
+
+    fn update_ratio(&mut self) {
+        self.ratio = self.sample_rate as f64 * self.adjustment / self.clock_rate;
+    }
+
+    // 位置と比率が同じで、出力が0から始まるもの。
+    // 同じように進めれば同じ数のサンプルが取り出せる
+    pub fn silent_copy(&self) -> Self {
+        Resampler {
+            clock_rate: self.clock_rate,
+            sample_rate: self.sample_rate,
+            ratio: self.ratio,
+            adjustment: self.adjustment,
+            offset: self.offset,
+            buffer: vec![0.0; self.buffer.len()],
+            level: 0.0,
+        }
+    }
+
+    pub fn advance(&mut self, clocks: u32) {
+        self.offset += clocks as f64 * self.ratio;
+    }
+
+    // 現在の位置で出力がdeltaだけ変わった
+    pub fn add_delta(&mut self, delta: f32) {
+        let position = self.offset.floor();
+        let index = position as usize;
+        let phase = ((self.offset - position) * KERNEL_PHASES as f64) as usize;
+        if self.buffer.len() < index + KERNEL_WIDTH {
+            self.buffer.resize(index + KERNEL_WIDTH, 0.0);
+        }
+        for (b, tap) in self.buffer[index..].iter_mut().zip(KERNEL[phase].iter()) {
+            *b += delta * tap;
+        }
+    }
+
+    // これ以上変化が足されないサンプルを取り出す
+    pub fn take_samples(&mut self, out: &mut Vec<f32>) {
+        let count = self.offset.floor() as usize;
+        if self.buffer.len() < count {
+            self.buffer.resize(count, 0.0);
+        }
+        for delta in self.buffer.drain(..count) {
+            self.level += delta;
+            out.push(self.level);
+        }
+        self.offset -= count as f64;
+    }
+
+    // サンプルレートなどの設定は含めず、位置とまだ取り出していない分だけ
+    pub fn state(&mut self, state: &mut State) {
+        state.f64(&mut self.offset);
+        state.vec(&mut self.buffer, |state, delta| state.f32(delta));
+        state.f32(&mut self.level);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mix() {
+        assert_eq!(mix(0, 0, 0, 0, 0), 0.0);
+        let pulse = mix(15, 15, 0, 0, 0);
+        assert!((pulse - 0.2586).abs() < 0.001);
+        let tnd = mix(0, 0, 15, 15, 127);
+        assert!((tnd - 0.7412).abs() < 0.001);
+        // 非線形なので2チャンネル分は1チャンネルの2倍より小さい
+        assert!(mix(15, 15, 0, 0, 0) < mix(15, 0, 0, 0, 0) * 2.0);
+    }
+
+    #[test]
+    fn test_resampler() {
+        let mut resampler = Resampler::new(1_789_773.0, 44100);
+        let mut samples = vec![];
+        resampler.advance(1000);
+        resampler.add_delta(0.5);
+        resampler.advance(1_789_773 - 1000);
+        resampler.take_samples(&mut samples);
+        assert!((samples.len() as i32 - 44100).abs() <= 1);
+
+        // 変化の前は0、後は滑らかに0.5に落ち着く
+        let step = (1000.0 * 44100.0 / 1_789_773.0) as usize;
+        assert!(samples[..step].iter().all(|&s| s.abs() < 1e-6));
+        assert!(samples[step + KERNEL_WIDTH..]
+            .iter()
+            .all(|&s| (s - 0.5).abs() < 1e-4));
+        assert!(samples.iter().all(|&s| s < 0.6));
+
+        // サンプルレートを変えると取り出せる数も変わる
+        resampler.set_rates(1_789_773.0, 48000);
+        resampler.advance(1_789_773);
+        samples.clear();
+        resampler.take_samples(&mut samples);
+        assert!((samples.len() as i32 - 48000).abs() <= 1);
+        assert!((samples[100] - 0.5).abs() < 1e-4);
+
+        resampler.set_adjustment(1.005);
+        resampler.advance(1_789_773);
+        samples.clear();
+        resampler.take_samples(&mut samples);
+        assert!((samples.len() as i32 - 48240).abs() <= 1);
+    }
+
+    #[test]
+    fn test_output_filter() {
+        // ハイパスで直流は0へ落ちていく
+        let mut filter = OutputFilter::new(FilterConfig::default(), 44100);
+        let mut samples = vec![0.5; 44100];
+        filter.process(&mut samples);
+        assert!(samples[0] > 0.25);
+        assert!(samples[44099].abs() < 1e-3);
+
+        // ローパスでナイキスト周波数の振動は小さくなる
+        let config = FilterConfig {
+            high_pass: [None, None],
+            low_pass: Some(14000.0),
+        };
+        let mut filter = OutputFilter::new(config, 44100);
+        let mut samples: Vec<f32> = (0..100).map(|i| (i % 2) as f32).collect();
+        filter.process(&mut samples);
+        let swing = samples[99] - samples[98];
+        assert!(swing > 0.0 && swing < 0.5);
+
+        let mut filter = OutputFilter::new(FilterConfig::NONE, 44100);
+        let mut samples = vec![0.5, 0.25];
+        filter.process(&mut samples);
+        assert_eq!(samples, vec![0.5, 0.25]);
+    }
+}
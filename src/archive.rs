@@ -0,0 +1,164 @@
+use std::path::Path;
+
+// ROMの拡張子。ZIPの中からはこれに当たる最初のファイルを使う
+const ROM_EXTENSIONS: [&str; 2] = ["nes", "fds"];
+
+pub fn is_zip(data: &[u8]) -> bool {
+    data.starts_with(b"PK\x03\x04")
+}
+
+// ROMのファイルを読む。ZIPなら中のROMを取り出す
+pub fn read_rom<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, String> {
+    let path = path.as_ref();
+    let data = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if is_zip(&data) {
+        extract_rom(&data).map_err(|e| format!("{}: {}", path.display(), e))
+    } else {
+        Ok(data)
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| "zip is truncated".to_string())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| "zip is truncated".to_string())
+}
+
+fn is_rom_name(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .is_some_and(|ext| ROM_EXTENSIONS.contains(&ext.as_str()))
+}
+
+// ZIPの中の最初のROMを取り出す。無圧縮 (stored) とdeflateだけ読める。
+// ローカルヘッダーのサイズは0のことがあるので、末尾の中央ディレクトリを使う
+pub fn extract_rom(data: &[u8]) -> Result<Vec<u8>, String> {
+    // 中央ディレクトリの終端 (EOCD)。後ろにコメントが付いていることがある
+    let eocd = (0..=data.len().saturating_sub(22))
+        .rev()
+        .find(|&i| data[i..].starts_with(b"PK\x05\x06"))
+        .ok_or("not a zip file")?;
+    let entries = u16_at(data, eocd + 10)?;
+    let mut offset = u32_at(data, eocd + 16)? as usize;
+    for _ in 0..entries {
+        if u32_at(data, offset)? != 0x0201_4b50 {
+            return Err("broken zip central directory".to_string());
+        }
+        let flags = u16_at(data, offset + 8)?;
+        let method = u16_at(data, offset + 10)?;
+        let compressed_size = u32_at(data, offset + 20)? as usize;
+        let size = u32_at(data, offset + 24)? as usize;
+        let name_len = u16_at(data, offset + 28)? as usize;
+        let extra_len = u16_at(data, offset + 30)? as usize;
+        let comment_len = u16_at(data, offset + 32)? as usize;
+        let local = u32_at(data, offset + 42)? as usize;
+        let name = data
+            .get(offset + 46..offset + 46 + name_len)
+            .ok_or("zip is truncated")?;
+        let name = String::from_utf8_lossy(name);
+        offset += 46 + name_len + extra_len + comment_len;
+        if !is_rom_name(&name) {
+            continue;
+        }
+        if flags & 1 != 0 {
+            return Err(format!("{} is encrypted", name));
+        }
+
+        if u32_at(data, local)? != 0x0403_4b50 {
+            return Err("broken zip local header".to_string());
+        }
+        let start =
+            local + 30 + u16_at(data, local + 26)? as usize + u16_at(data, local + 28)? as usize;
+        let body = data
+            .get(start..start + compressed_size)
+            .ok_or("zip is truncated")?;
+        let rom = match method {
+            0 => body.to_vec(),
+            8 => miniz_oxide::inflate::decompress_to_vec_with_limit(body, size)
+                .map_err(|e| format!("{}: {:?}", name, e.status))?,
+            _ => {
+                return Err(format!(
+                    "{}: unsupported compression method {}",
+                    name, method
+                ))
+            }
+        };
+        if rom.len() != size {
+            return Err(format!("{}: size mismatch", name));
+        }
+        return Ok(rom);
+    }
+    Err("no .nes or .fds file in zip".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // (名前, 中身, deflateするか) からZIPを作る。CRCは読まないので0のまま
+    fn zip(files: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut out = vec![];
+        let mut central = vec![];
+        for &(name, content, deflate) in files {
+            let body = if deflate {
+                miniz_oxide::deflate::compress_to_vec(content, 6)
+            } else {
+                content.to_vec()
+            };
+            let mut header = vec![];
+            header.extend_from_slice(&20u16.to_le_bytes());
+            header.extend_from_slice(&0u16.to_le_bytes());
+            header.extend_from_slice(&(if deflate { 8u16 } else { 0 }).to_le_bytes());
+            header.extend_from_slice(&[0; 8]); // 時刻とCRC
+            header.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(&0u16.to_le_bytes());
+
+            central.extend_from_slice(b"PK\x01\x02");
+            central.extend_from_slice(&20u16.to_le_bytes());
+            central.extend_from_slice(&header);
+            central.extend_from_slice(&[0; 10]); // コメント長、ディスク、属性
+            central.extend_from_slice(&(out.len() as u32).to_le_bytes());
+            central.extend_from_slice(name.as_bytes());
+
+            out.extend_from_slice(b"PK\x03\x04");
+            out.extend_from_slice(&header);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&body);
+        }
+        let central_offset = out.len() as u32;
+        out.extend_from_slice(&central);
+        out.extend_from_slice(b"PK\x05\x06");
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        out.extend_from_slice(&central_offset.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn test_extract_rom() {
+        let rom = b"NES\x1a0123456789abcdef".repeat(20);
+        let stored = zip(&[("readme.txt", b"hello", false), ("Game.NES", &rom, false)]);
+        assert!(is_zip(&stored));
+        assert_eq!(extract_rom(&stored).unwrap(), rom);
+
+        let deflated = zip(&[("game.fds", &rom, true)]);
+        assert!(deflated.len() < rom.len());
+        assert_eq!(extract_rom(&deflated).unwrap(), rom);
+
+        assert!(extract_rom(&zip(&[("readme.txt", b"hello", false)])).is_err());
+        assert!(extract_rom(&stored[..stored.len() - 4]).is_err());
+        assert!(!is_zip(&rom));
+    }
+}
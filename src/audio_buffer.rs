@@ -0,0 +1,120 @@
+use std::collections::VecDeque;
+
+// 音声デバイスとエミュレータの速さの差を吸収するために変える割合の上限
+pub const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+
+// エミュレータが作ったサンプルを音声デバイスのコールバックへ渡すリングバッファ
+pub struct AudioBuffer {
+    samples: VecDeque<f32>,
+    capacity: usize,
+    // 0.0-1.0
+    pub volume: f32,
+    // 足りなくなったときに続ける値
+    last: f32,
+    pub underruns: u64,
+    pub overruns: u64,
+}
+
+impl AudioBuffer {
+    pub fn new(capacity: usize) -> Self {
+        AudioBuffer {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+            volume: 1.0,
+            last: 0.0,
+            underruns: 0,
+            overruns: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // バッファが半分より空いていればサンプルを多めに、埋まっていれば少なめに作らせる。
+    // 固定の比率だとクロックのずれで少しずつ溢れるか枯れてしまう
+    pub fn rate_adjustment(&self) -> f64 {
+        let fill = self.samples.len() as f64 / self.capacity as f64;
+        1.0 + (0.5 - fill) * 2.0 * MAX_RATE_ADJUSTMENT
+    }
+
+    // 溢れた分は古いサンプルから捨てる
+    pub fn push(&mut self, samples: &[f32]) {
+        for sample in samples {
+            if self.samples.len() == self.capacity {
+                self.samples.pop_front();
+                self.overruns += 1;
+            }
+            self.samples.push_back(*sample);
+        }
+    }
+
+    // デバイスのバッファを埋める。足りないときは最後の値から0へ少しずつ下げて
+    // プチノイズを抑える
+    pub fn fill(&mut self, out: &mut [f32]) {
+        let mut underrun = false;
+        for sample in out.iter_mut() {
+            match self.samples.pop_front() {
+                Some(value) => self.last = value,
+                None => {
+                    underrun = true;
+                    self.last *= 0.99;
+                }
+            }
+            *sample = self.last * self.volume;
+        }
+        if underrun {
+            self.underruns += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fill_and_underrun() {
+        let mut buffer = AudioBuffer::new(4);
+        buffer.volume = 0.5;
+        buffer.push(&[0.2, 0.4]);
+        let mut out = [0.0; 2];
+        buffer.fill(&mut out);
+        assert_eq!(out, [0.1, 0.2]);
+        assert_eq!(buffer.underruns, 0);
+
+        buffer.fill(&mut out);
+        assert_eq!(buffer.underruns, 1);
+        assert!(out[0] < 0.2 && out[0] > out[1] && out[1] > 0.0);
+    }
+
+    #[test]
+    fn test_overrun() {
+        let mut buffer = AudioBuffer::new(3);
+        buffer.push(&[1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.overruns, 2);
+        let mut out = [0.0; 3];
+        buffer.fill(&mut out);
+        assert_eq!(out, [3.0, 4.0, 5.0]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_rate_adjustment() {
+        let mut buffer = AudioBuffer::new(100);
+        assert_eq!(buffer.rate_adjustment(), 1.0 + MAX_RATE_ADJUSTMENT);
+        buffer.push(&[0.0; 50]);
+        assert_eq!(buffer.rate_adjustment(), 1.0);
+        buffer.push(&[0.0; 50]);
+        assert_eq!(buffer.rate_adjustment(), 1.0 - MAX_RATE_ADJUSTMENT);
+    }
+}
@@ -0,0 +1,321 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIF_ISINTERLEAVED: u32 = 0x100;
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+// ヘッダーの中で、書き終わってから値を書き戻す場所
+const RIFF_SIZE: u64 = 4;
+const TOTAL_FRAMES: u64 = 48;
+const VIDEO_LENGTH: u64 = 140;
+const AUDIO_LENGTH: u64 = 264;
+const MOVI_SIZE: u64 = 318;
+const HEADER_SIZE: u64 = 326;
+
+// 無圧縮 (24bit RGB) の映像と16bit PCMのモノラル音声を交互に並べたAVIを書く。
+// インデックスは最後にidx1としてまとめて書く。OpenDMLの拡張は使わないので4GiBまで
+pub struct AviWriter {
+    writer: BufWriter<File>,
+    width: usize,
+    height: usize,
+    // 書いた位置。RIFFの大きさを超えないか確かめる
+    position: u64,
+    // (fourcc, 'movi'からの位置, 大きさ)
+    index: Vec<([u8; 4], u32, u32)>,
+    frames: u32,
+    samples: u32,
+}
+
+fn fourcc(header: &mut Vec<u8>, name: &[u8; 4]) {
+    header.extend_from_slice(name);
+}
+
+fn u32le(header: &mut Vec<u8>, value: u32) {
+    header.extend_from_slice(&value.to_le_bytes());
+}
+
+fn u16le(header: &mut Vec<u8>, value: u16) {
+    header.extend_from_slice(&value.to_le_bytes());
+}
+
+impl AviWriter {
+    // frame_rateはフレーム/秒。NTSCの60.0988のような端数も1万分の1まで持つ
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        width: usize,
+        height: usize,
+        frame_rate: f64,
+        sample_rate: u32,
+    ) -> Result<Self, String> {
+        let path = path.as_ref();
+        let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut avi = AviWriter {
+            writer: BufWriter::new(file),
+            width,
+            height,
+            position: 0,
+            index: vec![],
+            frames: 0,
+            samples: 0,
+        };
+        let header = avi.header(frame_rate, sample_rate);
+        avi.write(&header)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(avi)
+    }
+
+    // DIBの1行は4バイト単位
+    fn stride(&self) -> usize {
+        (self.width * 3 + 3) & !3
+    }
+
+    fn frame_size(&self) -> u32 {
+        (self.stride() * self.height) as u32
+    }
+
+    fn header(&self, frame_rate: f64, sample_rate: u32) -> Vec<u8> {
+        let rate = (frame_rate * 10000.0).round() as u32;
+        let (width, height) = (self.width as u32, self.height as u32);
+        let mut h = vec![];
+        fourcc(&mut h, b"RIFF");
+        u32le(&mut h, 0);
+        fourcc(&mut h, b"AVI ");
+        fourcc(&mut h, b"LIST");
+        u32le(&mut h, 4 + 64 + 124 + 102);
+        fourcc(&mut h, b"hdrl");
+
+        fourcc(&mut h, b"avih");
+        u32le(&mut h, 56);
+        u32le(&mut h, (1_000_000.0 / frame_rate).round() as u32);
+        u32le(
+            &mut h,
+            (self.frame_size() as f64 * frame_rate) as u32 + sample_rate * 2,
+        );
+        u32le(&mut h, 0);
+        u32le(&mut h, AVIF_HASINDEX | AVIF_ISINTERLEAVED);
+        u32le(&mut h, 0); // 総フレーム数
+        u32le(&mut h, 0);
+        u32le(&mut h, 2);
+        u32le(&mut h, self.frame_size());
+        u32le(&mut h, width);
+        u32le(&mut h, height);
+        h.extend_from_slice(&[0; 16]);
+
+        // 映像
+        fourcc(&mut h, b"LIST");
+        u32le(&mut h, 116);
+        fourcc(&mut h, b"strl");
+        fourcc(&mut h, b"strh");
+        u32le(&mut h, 56);
+        fourcc(&mut h, b"vids");
+        fourcc(&mut h, b"DIB ");
+        u32le(&mut h, 0);
+        u32le(&mut h, 0);
+        u32le(&mut h, 0);
+        u32le(&mut h, 10000);
+        u32le(&mut h, rate);
+        u32le(&mut h, 0);
+        u32le(&mut h, 0); // フレーム数
+        u32le(&mut h, self.frame_size());
+        u32le(&mut h, u32::MAX);
+        u32le(&mut h, 0);
+        u16le(&mut h, 0);
+        u16le(&mut h, 0);
+        u16le(&mut h, width as u16);
+        u16le(&mut h, height as u16);
+        fourcc(&mut h, b"strf");
+        u32le(&mut h, 40);
+        u32le(&mut h, 40);
+        u32le(&mut h, width);
+        // 正の高さは下の行から並べる
+        u32le(&mut h, height);
+        u16le(&mut h, 1);
+        u16le(&mut h, 24);
+        u32le(&mut h, 0);
+        u32le(&mut h, self.frame_size());
+        h.extend_from_slice(&[0; 16]);
+
+        // 音声
+        fourcc(&mut h, b"LIST");
+        u32le(&mut h, 94);
+        fourcc(&mut h, b"strl");
+        fourcc(&mut h, b"strh");
+        u32le(&mut h, 56);
+        fourcc(&mut h, b"auds");
+        u32le(&mut h, 0);
+        u32le(&mut h, 0);
+        u32le(&mut h, 0);
+        u32le(&mut h, 0);
+        u32le(&mut h, 2);
+        u32le(&mut h, sample_rate * 2);
+        u32le(&mut h, 0);
+        u32le(&mut h, 0); // サンプル数
+        u32le(&mut h, sample_rate / 10 * 2);
+        u32le(&mut h, u32::MAX);
+        u32le(&mut h, 2);
+        h.extend_from_slice(&[0; 8]);
+        fourcc(&mut h, b"strf");
+        u32le(&mut h, 18);
+        u16le(&mut h, 1);
+        u16le(&mut h, 1);
+        u32le(&mut h, sample_rate);
+        u32le(&mut h, sample_rate * 2);
+        u16le(&mut h, 2);
+        u16le(&mut h, 16);
+        u16le(&mut h, 0);
+
+        fourcc(&mut h, b"LIST");
+        u32le(&mut h, 0);
+        fourcc(&mut h, b"movi");
+        debug_assert_eq!(h.len() as u64, HEADER_SIZE);
+        h
+    }
+
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(data)?;
+        self.position += data.len() as u64;
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, id: &[u8; 4], data: &[u8]) -> Result<(), String> {
+        // 最後に書くidx1の分も残しておく
+        let end = self.position + 8 + data.len() as u64 + 16 * (self.index.len() as u64 + 1) + 8;
+        if end > u32::MAX as u64 {
+            return Err("AVI file would exceed 4 GiB".to_string());
+        }
+        let offset = (self.position - (MOVI_SIZE + 4)) as u32;
+        self.index.push((*id, offset, data.len() as u32));
+        let result = (|| {
+            self.write(id)?;
+            self.write(&(data.len() as u32).to_le_bytes())?;
+            self.write(data)
+        })();
+        result.map_err(|e| e.to_string())
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    // width x height x 3 (RGB, 上の行から) の画素
+    pub fn write_video_frame(&mut self, rgb: &[u8]) -> Result<(), String> {
+        assert_eq!(rgb.len(), self.width * self.height * 3);
+        let mut data = Vec::with_capacity(self.frame_size() as usize);
+        for row in rgb.chunks(self.width * 3).rev() {
+            for pixel in row.chunks(3) {
+                data.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            }
+            data.resize(data.len() + self.stride() - self.width * 3, 0);
+        }
+        self.write_chunk(b"00db", &data)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    // -1.0-1.0の外は切り詰める
+    pub fn write_audio(&mut self, samples: &[f32]) -> Result<(), String> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        let data: Vec<u8> = samples
+            .iter()
+            .flat_map(|sample| ((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes())
+            .collect();
+        self.write_chunk(b"01wb", &data)?;
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        let result = (|| {
+            let movi_size = self.position - (MOVI_SIZE + 4);
+            let mut index = vec![];
+            fourcc(&mut index, b"idx1");
+            u32le(&mut index, self.index.len() as u32 * 16);
+            for (id, offset, size) in self.index.iter() {
+                fourcc(&mut index, id);
+                u32le(&mut index, AVIIF_KEYFRAME);
+                u32le(&mut index, *offset);
+                u32le(&mut index, *size);
+            }
+            self.write(&index)?;
+
+            let riff_size = self.position - 8;
+            let patches = [
+                (RIFF_SIZE, riff_size as u32),
+                (TOTAL_FRAMES, self.frames),
+                (VIDEO_LENGTH, self.frames),
+                (AUDIO_LENGTH, self.samples),
+                (MOVI_SIZE, movi_size as u32),
+            ];
+            let w = &mut self.writer;
+            for (position, value) in patches {
+                w.seek(SeekFrom::Start(position))?;
+                w.write_all(&value.to_le_bytes())?;
+            }
+            w.flush()
+        })();
+        result.map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn u32_at(data: &[u8], position: usize) -> u32 {
+        u32::from_le_bytes(data[position..position + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_write_avi() {
+        let path = std::env::temp_dir().join(format!("nes-rs-avi-{}.avi", std::process::id()));
+        let mut avi = AviWriter::create(&path, 2, 2, 60.0988, 44100).unwrap();
+        // 左上だけ赤
+        let mut rgb = vec![0; 12];
+        rgb[0] = 255;
+        avi.write_video_frame(&rgb).unwrap();
+        avi.write_audio(&[0.5, -0.5]).unwrap();
+        avi.write_video_frame(&rgb).unwrap();
+        avi.write_audio(&[]).unwrap();
+        assert_eq!((avi.frames(), avi.samples()), (2, 2));
+        avi.finish().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32_at(&data, 4) as usize, data.len() - 8);
+        assert_eq!(&data[8..12], b"AVI ");
+        assert_eq!(u32_at(&data, TOTAL_FRAMES as usize), 2);
+        assert_eq!(u32_at(&data, VIDEO_LENGTH as usize), 2);
+        assert_eq!(u32_at(&data, AUDIO_LENGTH as usize), 2);
+        assert_eq!(u32_at(&data, 132), 600988);
+
+        // movi: 00db, 01wb, 00db
+        let movi = HEADER_SIZE as usize;
+        assert_eq!(&data[movi..movi + 4], b"00db");
+        assert_eq!(u32_at(&data, movi + 4), 16);
+        // 下の行から、BGRの順。1行は4バイト単位に揃える
+        assert_eq!(
+            &data[movi + 8..movi + 24],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(&data[movi + 24..movi + 28], b"01wb");
+        assert_eq!(&data[movi + 32..movi + 36], &[0xff, 0x3f, 0x01, 0xc0]);
+        let movi_size = u32_at(&data, MOVI_SIZE as usize) as usize;
+        assert_eq!(movi_size, 4 + 24 + 12 + 24);
+
+        let idx1 = MOVI_SIZE as usize + 4 + movi_size;
+        assert_eq!(&data[idx1..idx1 + 4], b"idx1");
+        assert_eq!(u32_at(&data, idx1 + 4), 3 * 16);
+        assert_eq!(&data[idx1 + 8 + 16..idx1 + 12 + 16], b"01wb");
+        // 'movi'の位置から数える
+        assert_eq!(u32_at(&data, idx1 + 8 + 16 + 8), 28);
+    }
+}
@@ -0,0 +1,89 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+// 約10秒ごとに保存する
+pub const DEFAULT_INTERVAL_FRAMES: u64 = 600;
+
+// 電池付きPRG RAMをROMの隣の .sav に保存する
+pub struct BatterySave {
+    pub path: PathBuf,
+    pub interval_frames: u64,
+    last_saved: Option<Vec<u8>>,
+}
+
+impl BatterySave {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        BatterySave {
+            path: path.as_ref().to_path_buf(),
+            interval_frames: DEFAULT_INTERVAL_FRAMES,
+            last_saved: None,
+        }
+    }
+
+    // game.nes -> game.sav
+    pub fn for_rom<P: AsRef<Path>>(rom_path: P) -> Self {
+        BatterySave::new(rom_path.as_ref().with_extension("sav"))
+    }
+
+    // ファイルが無ければNone
+    pub fn load(&mut self) -> Result<Option<Vec<u8>>, String> {
+        match fs::read(&self.path) {
+            Ok(data) => {
+                self.last_saved = Some(data.clone());
+                Ok(Some(data))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("{}: {}", self.path.display(), e)),
+        }
+    }
+
+    // 前回から変わっていれば書き込む。書き込んだらtrue
+    pub fn save(&mut self, ram: &[u8]) -> Result<bool, String> {
+        if self.last_saved.as_deref() == Some(ram) {
+            return Ok(false);
+        }
+        fs::write(&self.path, ram).map_err(|e| format!("{}: {}", self.path.display(), e))?;
+        self.last_saved = Some(ram.to_vec());
+        Ok(true)
+    }
+
+    // フレームごとに呼ぶ。interval_framesごとに保存する
+    pub fn tick(&mut self, frame: u64, ram: &[u8]) -> Result<bool, String> {
+        if self.interval_frames == 0 || !frame.is_multiple_of(self.interval_frames) {
+            return Ok(false);
+        }
+        self.save(ram)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sav_path() {
+        let save = BatterySave::for_rom("roms/zelda.nes");
+        assert_eq!(save.path, PathBuf::from("roms/zelda.sav"));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("nes-rs-battery-{}.sav", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut save = BatterySave::new(&path);
+        assert_eq!(save.load().unwrap(), None);
+        assert!(save.save(&[1, 2, 3]).unwrap());
+        // 変化が無ければ書き込まない
+        assert!(!save.save(&[1, 2, 3]).unwrap());
+
+        save.interval_frames = 60;
+        assert!(!save.tick(59, &[4, 5, 6]).unwrap());
+        assert!(save.tick(60, &[4, 5, 6]).unwrap());
+
+        let mut other = BatterySave::new(&path);
+        assert_eq!(other.load().unwrap(), Some(vec![4, 5, 6]));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,39 +1,364 @@
-use crate::{cartridge::Rom, cpu::Mem, joypad::Joypad, ppu::NesPPU};
+use crate::{
+    apu::{Apu, DMC_STALL_CYCLES},
+    cartridge::{Rom, RomError},
+    console::{FrameCounter, Region},
+    controller::ControllerPort,
+    cpu::Mem,
+    interrupts::interrupts::InterruptType,
+    joypad::Joypad,
+    mapper::{self, SharedMapper},
+    ppu::{Accuracy, NesPPU},
+    savestate::State,
+    timeline::{Timeline, TimelineEvent},
+};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+const PRG_RAM_SIZE: usize = 0x2000;
 
 pub struct Bus<'call> {
     cpu_wram: [u8; 2048], // 11bit
-    prg_rom: Vec<u8>,
+    mapper: SharedMapper,
     ppu: NesPPU,
+    apu: Apu,
     cycles: usize,
-    gameloop_callback: Box<dyn FnMut(&NesPPU, &mut Joypad) + 'call>,
-    joypad1: Joypad,
+    // PALでCPU 5サイクルに16ドット進めるための端数
+    ppu_phase: u32,
+    gameloop_callback: Box<dyn FnMut(&NesPPU, &mut Joypad, &mut Joypad) + 'call>,
+    // $4016と$4017。標準ではどちらもJoypad
+    ports: [Box<dyn ControllerPort>; 2],
+    region: Region,
+    frames: FrameCounter,
+    frame_stats: BusStats,
+    last_frame_stats: BusStats,
+    timeline: Timeline,
+    last_input: u8,
+    // マッパーがPRG RAMを持たないときの$6000-$7FFF
+    prg_ram: Option<Vec<u8>>,
+    has_battery: bool,
+}
+
+// 未定義動作になりがちなアクセスの回数 (1フレーム分)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BusStats {
+    pub write_only_reads: u32,
+    // $2002 (PPUSTATUS) への書き込み。何も起きない
+    pub read_only_writes: u32,
+    pub rom_writes: u32,
+    pub unmapped_reads: u32,
+    pub unmapped_writes: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BusState {
+    pub cpu_wram: Vec<u8>,
+    pub cycles: usize,
 }
 
 impl<'a> Bus<'a> {
-    pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Bus<'call>
+    pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Result<Bus<'call>, RomError>
+    where
+        F: FnMut(&NesPPU, &mut Joypad, &mut Joypad) + 'call,
+    {
+        let region = rom.region;
+        let has_battery = rom.header.has_battery;
+        let trainer = rom.trainer.clone();
+        let mapper = mapper::create(rom)?;
+        let mut bus = Bus::with_mapper(mapper, region, gameloop_callback);
+        bus.has_battery = has_battery;
+        // トレーナーは$7000-$71FFのPRG RAMに読み込んでおく
+        if let Some(trainer) = trainer {
+            for (i, &data) in trainer.iter().enumerate() {
+                bus.mem_write(0x7000 + i as u16, data);
+            }
+        }
+        Ok(bus)
+    }
+
+    // iNES以外 (NSFなど) から作ったマッパーをそのまま繋ぐ
+    pub fn with_mapper<'call, F>(
+        mapper: SharedMapper,
+        region: Region,
+        gameloop_callback: F,
+    ) -> Bus<'call>
     where
-        F: FnMut(&NesPPU, &mut Joypad) + 'call,
+        F: FnMut(&NesPPU, &mut Joypad, &mut Joypad) + 'call,
     {
-        let ppu = NesPPU::new(rom.chr_rom, rom.screen_mirroring);
+        let prg_ram = match mapper.borrow_mut().prg_ram() {
+            Some(_) => None,
+            None => Some(vec![0; PRG_RAM_SIZE]),
+        };
+        let mut ppu = NesPPU::with_mapper(mapper.clone());
+        ppu.set_warmup(true);
+        ppu.region = region;
         Bus {
             cpu_wram: [0; 2048],
-            prg_rom: rom.prg_rom,
+            mapper,
             ppu: ppu,
+            apu: Apu::new(region),
             cycles: 0,
+            ppu_phase: 0,
             gameloop_callback: Box::from(gameloop_callback),
-            joypad1: Joypad::new(),
+            ports: [Box::new(Joypad::new()), Box::new(Joypad::new())],
+            region,
+            frames: FrameCounter::new(),
+            frame_stats: BusStats::default(),
+            last_frame_stats: BusStats::default(),
+            timeline: Timeline::new(),
+            last_input: 0,
+            prg_ram,
+            has_battery: false,
         }
     }
 
+    fn with_prg_ram<R>(&mut self, f: impl FnOnce(&mut [u8]) -> R) -> R {
+        if let Some(ram) = self.prg_ram.as_mut() {
+            return f(ram);
+        }
+        let mut mapper = self.mapper.borrow_mut();
+        f(mapper.prg_ram().unwrap())
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.has_battery
+    }
+
+    // 電池付きのカートリッジならPRG RAMをfに渡す。毎フレーム呼んでもコピーはしない
+    pub fn with_battery_ram<R>(&mut self, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        if self.has_battery {
+            Some(self.with_prg_ram(|ram| f(ram)))
+        } else {
+            None
+        }
+    }
+
+    // .savの内容を書き戻す。サイズが違えば先頭から入る分だけ
+    pub fn load_battery_ram(&mut self, data: &[u8]) {
+        self.with_prg_ram(|ram| {
+            let len = ram.len().min(data.len());
+            ram[..len].copy_from_slice(&data[..len]);
+        });
+    }
+
     pub fn tick(&mut self, cycles: u8) {
         self.cycles += cycles as usize;
-        let new_frame = self.ppu.tick(cycles * 3);
+        self.mapper.borrow_mut().cpu_tick(cycles);
+        self.apu.expansion = self.mapper.borrow().audio_output();
+        self.apu.tick(cycles);
+        self.fetch_dmc_sample();
+        let phase = cycles as u32 * self.region.dots_per_5_cpu_cycles() + self.ppu_phase;
+        self.ppu_phase = phase % 5;
+        let new_frame = self.ppu.tick((phase / 5) as u8);
         if new_frame {
-            (self.gameloop_callback)(&self.ppu, &mut self.joypad1);
+            self.frames.end_frame();
+            self.last_frame_stats = std::mem::take(&mut self.frame_stats);
+            for port in self.ports.iter_mut() {
+                port.end_frame();
+            }
+            // Joypad以外が挿さっているポートには、入力を捨てる代わりのものを渡す
+            let [port1, port2] = &mut self.ports;
+            let mut unused = (Joypad::new(), Joypad::new());
+            (self.gameloop_callback)(
+                &self.ppu,
+                port1.joypad_mut().unwrap_or(&mut unused.0),
+                port2.joypad_mut().unwrap_or(&mut unused.1),
+            );
+
+            // コールバック内で更新された入力を次のフレームの先頭で記録する
+            let input = self
+                .joypad1()
+                .map(|joypad| joypad.buttons().bits())
+                .unwrap_or(0);
+            if input != self.last_input {
+                self.last_input = input;
+                self.record(TimelineEvent::InputChange(input));
+            }
+        }
+    }
+
+    // DMCのサンプルを読む間はCPUが止まり、その分だけ他が進む
+    fn fetch_dmc_sample(&mut self) {
+        if let Some(addr) = self.apu.dmc.fetch_address() {
+            let value = self.mem_read(addr);
+            self.apu.dmc.load_sample(value);
+            self.tick(DMC_STALL_CYCLES);
+        }
+    }
+
+    pub fn note_interrupt(&mut self, itype: &InterruptType) {
+        self.frames.interrupts_this_frame += 1;
+        match itype {
+            InterruptType::NMI => self.record(TimelineEvent::Nmi),
+            InterruptType::IRQ => self.record(TimelineEvent::Irq),
+        }
+    }
+
+    fn mapped_prg_banks(&self) -> [Option<usize>; 4] {
+        let mapper = self.mapper.borrow();
+        [0, 1, 2, 3].map(|slot| mapper.mapped_prg_bank(slot))
+    }
+
+    // マッパーへの書き込みの前後で、見えているPRGのバンクが変わった枠を記録する
+    fn note_bank_switches(&mut self, before: [Option<usize>; 4]) {
+        let after = self.mapped_prg_banks();
+        for (slot, (before, after)) in before.into_iter().zip(after).enumerate() {
+            if let Some(bank) = after.filter(|bank| Some(*bank) != before) {
+                self.record(TimelineEvent::BankSwitch {
+                    slot: slot as u8,
+                    bank: bank as u8,
+                });
+            }
+        }
+    }
+
+    fn record(&mut self, event: TimelineEvent) {
+        self.timeline.record(
+            self.frames.frame,
+            self.ppu.scanline(),
+            self.ppu.cycle(),
+            event,
+        );
+    }
+
+    pub fn timeline(&self) -> &Timeline {
+        &self.timeline
+    }
+
+    pub fn timeline_mut(&mut self) -> &mut Timeline {
+        &mut self.timeline
+    }
+
+    pub fn frame_counter(&self) -> &FrameCounter {
+        &self.frames
+    }
+
+    // 直前に完了したフレームの統計
+    pub fn stats(&self) -> BusStats {
+        self.last_frame_stats
+    }
+
+    // 現在のフレームでここまでに数えた統計
+    pub fn current_stats(&self) -> BusStats {
+        self.frame_stats
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    // ヘッダーの指定を上書きする
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.ppu.region = region;
+        self.apu.set_region(region);
+    }
+
+    pub fn ppu(&self) -> &NesPPU {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut NesPPU {
+        &mut self.ppu
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    // port: 0なら$4016、1なら$4017
+    pub fn connect(&mut self, port: usize, device: Box<dyn ControllerPort>) {
+        self.ports[port] = device;
+    }
+
+    pub fn port(&self, port: usize) -> &dyn ControllerPort {
+        self.ports[port].as_ref()
+    }
+
+    pub fn port_mut(&mut self, port: usize) -> &mut dyn ControllerPort {
+        self.ports[port].as_mut()
+    }
+
+    // 標準のコントローラー以外が挿さっていればNone
+    pub fn joypad1(&self) -> Option<&Joypad> {
+        self.ports[0].joypad()
+    }
+
+    pub fn joypad1_mut(&mut self) -> Option<&mut Joypad> {
+        self.ports[0].joypad_mut()
+    }
+
+    pub fn joypad2(&self) -> Option<&Joypad> {
+        self.ports[1].joypad()
+    }
+
+    pub fn joypad2_mut(&mut self) -> Option<&mut Joypad> {
+        self.ports[1].joypad_mut()
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    pub fn snapshot(&self) -> BusState {
+        BusState {
+            cpu_wram: self.cpu_wram.to_vec(),
+            cycles: self.cycles,
+        }
+    }
+
+    // 外から読み込んだBusStateはWRAMの長さが違うことがある
+    pub fn restore(&mut self, state: &BusState) -> Result<(), String> {
+        if state.cpu_wram.len() != self.cpu_wram.len() {
+            return Err(format!(
+                "cpu_wram must be {} bytes, got {}",
+                self.cpu_wram.len(),
+                state.cpu_wram.len()
+            ));
+        }
+        self.cpu_wram.copy_from_slice(&state.cpu_wram);
+        self.cycles = state.cycles;
+        Ok(())
+    }
+
+    // snapshotと違い、PPUやAPU、マッパー、コントローラーも含めたセーブステート
+    pub fn state(&mut self, state: &mut State) {
+        self.machine_state(state, true);
+    }
+
+    // 音の出力の状態を除いたstate。出力の設定に関係なく、同じ入力なら同じになる
+    pub fn core_state(&mut self, state: &mut State) {
+        self.machine_state(state, false);
+    }
+
+    fn machine_state(&mut self, state: &mut State, audio_output: bool) {
+        state.bytes(&mut self.cpu_wram);
+        state.usize(&mut self.cycles);
+        state.u32(&mut self.ppu_phase);
+        state.u64(&mut self.frames.frame);
+        state.u32(&mut self.frames.interrupts_this_frame);
+        state.u32(&mut self.frames.interrupts_last_frame);
+        state.u8(&mut self.last_input);
+        if let Some(ram) = self.prg_ram.as_mut() {
+            state.bytes(ram);
+        }
+        self.ppu.state(state);
+        if audio_output {
+            self.apu.state(state);
+        } else {
+            self.apu.core_state(state);
+        }
+        self.mapper.borrow_mut().state(state);
+        for port in self.ports.iter_mut() {
+            port.state(state);
         }
     }
 
@@ -41,12 +366,26 @@ impl<'a> Bus<'a> {
         self.ppu.poll_nmi_interrupt()
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            addr = addr % 0x4000;
+    // 状態を変えずに読む (トレースやデバッガー用)。読むとフラグやアドレスが変わる
+    // PPU、APU、コントローラーのレジスタは読まずに0xFFを返し、統計にも数えない
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_wram[(addr & 0b0000_0111_1111_1111) as usize],
+            0x2000..=0x401f => 0xff,
+            0x6000..=0x7fff if self.prg_ram.is_some() => {
+                self.prg_ram.as_ref().unwrap()[(addr - 0x6000) as usize]
+            }
+            _ => self.mapper.borrow_mut().prg_peek(addr).unwrap_or(0),
         }
-        self.prg_rom[addr as usize]
+    }
+
+    pub fn peek_u16(&self, addr: u16) -> u16 {
+        u16::from_le_bytes([self.peek(addr), self.peek(addr.wrapping_add(1))])
+    }
+
+    // カートリッジとAPUのIRQはワイヤードORで繋がっている
+    pub fn poll_irq(&self) -> bool {
+        self.mapper.borrow().irq() || self.apu.irq()
     }
 }
 
@@ -57,8 +396,9 @@ impl Mem for Bus<'_> {
                 let mirror_down_addr = addr & 0b0000_0111_1111_1111;
                 self.cpu_wram[mirror_down_addr as usize]
             }
-            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 | 0x4014 => {
-                panic!("Attempt to read from write-only PPU address {:x}", addr);
+            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 | 0x4000..=0x4014 => {
+                self.frame_stats.write_only_reads += 1;
+                0
             }
             0x2002 => self.ppu.read_status(),
             0x2004 => self.ppu.read_oam_data(),
@@ -67,12 +407,21 @@ impl Mem for Bus<'_> {
                 let mirror_down_addr = addr & 0b00100000_00000111;
                 self.mem_read(mirror_down_addr)
             }
-            0x4000..=0x4015 => 0,
-            0x4016 => self.joypad1.read(),
-            0x4017 => 0,
-            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            0x4015 => self.apu.read_status(),
+            0x4016 => self.ports[0].read(),
+            0x4017 => self.ports[1].read(),
+            0x6000..=0x7fff if self.prg_ram.is_some() => {
+                self.prg_ram.as_ref().unwrap()[(addr - 0x6000) as usize]
+            }
+            0x4020..=0xFFFF => match self.mapper.borrow_mut().prg_read(addr) {
+                Some(data) => data,
+                None => {
+                    self.frame_stats.unmapped_reads += 1;
+                    0
+                }
+            },
             _ => {
-                // Ignoring mem access to other addresses
+                self.frame_stats.unmapped_reads += 1;
                 0
             }
         }
@@ -84,21 +433,49 @@ impl Mem for Bus<'_> {
                 let mirror_down_addr = addr & 0b111_1111_1111;
                 self.cpu_wram[mirror_down_addr as usize] = data;
             }
-            0x2000 => self.ppu.write_to_ctrl(data),
-            0x2001 => self.ppu.write_to_mask(data),
-            0x2002 => panic!("Attempt to write to read-only PPU address {:x}", addr),
+            0x2000 => {
+                self.mapper.borrow_mut().ppu_register_write(addr, data);
+                self.ppu.write_to_ctrl(data);
+            }
+            0x2001 => {
+                self.mapper.borrow_mut().ppu_register_write(addr, data);
+                self.ppu.write_to_mask(data);
+            }
+            0x2002 => self.frame_stats.read_only_writes += 1,
             0x2003 => self.ppu.write_to_oam_addr(data),
             0x2004 => self.ppu.write_to_oam_data(data),
-            0x2005 => self.ppu.write_to_scroll(data),
+            0x2005 => {
+                self.record(TimelineEvent::ScrollWrite(data));
+                self.ppu.write_to_scroll(data);
+            }
             0x2006 => self.ppu.write_to_ppu_addr(data),
             0x2007 => self.ppu.write_to_data(data),
             0x2008..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00100000_00000111;
                 self.mem_write(mirror_down_addr, data);
             }
-            0x4000..=0x4013 | 0x4015 => {}
-            0x4016 => self.joypad1.write(data),
-            0x4017 => {}
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.write_register(addr, data),
+            // ストローブは両方のコントローラーに繋がっている
+            0x4016 => {
+                for port in self.ports.iter_mut() {
+                    port.write(data);
+                }
+            }
+            // Accurateでは1バイトずつ読み書きし、その間もPPUを進める (513か514サイクル)
+            0x4014 if self.ppu.accuracy == Accuracy::Accurate => {
+                let hi: u16 = (data as u16) << 8;
+                let odd = self.cycles % 2 == 1;
+                self.tick(1);
+                if odd {
+                    self.tick(1);
+                }
+                for i in 0..256u16 {
+                    let value = self.mem_read(hi + i);
+                    self.tick(1);
+                    self.ppu.write_oam_dma_byte(value);
+                    self.tick(1);
+                }
+            }
             0x4014 => {
                 let mut buffer: [u8; 256] = [0; 256];
                 let hi: u16 = (data as u16) << 8;
@@ -107,12 +484,331 @@ impl Mem for Bus<'_> {
                 }
                 self.ppu.write_oam_dma(&buffer);
             }
-            0x8000..=0xFFFF => {
-                panic!("Attempted to write to Cartridge ROM space");
+            0x6000..=0x7fff if self.prg_ram.is_some() => {
+                self.prg_ram.as_mut().unwrap()[(addr - 0x6000) as usize] = data;
+            }
+            0x4020..=0xFFFF => {
+                // タイムラインを取っていないときは比べない
+                let banks = self.timeline.enabled.then(|| self.mapped_prg_banks());
+                if !self.mapper.borrow_mut().prg_write(addr, data) {
+                    if addr >= 0x8000 {
+                        self.frame_stats.rom_writes += 1;
+                    } else {
+                        self.frame_stats.unmapped_writes += 1;
+                    }
+                }
+                if let Some(banks) = banks {
+                    self.note_bank_switches(banks);
+                }
             }
             _ => {
-                // Ignoring mem access to other addresses
+                self.frame_stats.unmapped_writes += 1;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::joypad::JoypadButton;
+
+    #[test]
+    fn test_stats() {
+        let mut bus =
+            Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        bus.mem_read(0x2000);
+        bus.mem_read(0x200e);
+        bus.mem_read(0x4000);
+        bus.mem_write(0x8000, 1);
+        bus.mem_read(0x5000);
+        bus.mem_write(0x5000, 1);
+        bus.mem_write(0x5001, 1);
+        bus.mem_read(0x0000);
+        bus.mem_read(0x4016);
+
+        let expected = BusStats {
+            write_only_reads: 3,
+            read_only_writes: 0,
+            rom_writes: 1,
+            unmapped_reads: 1,
+            unmapped_writes: 2,
+        };
+        assert_eq!(bus.current_stats(), expected);
+        assert_eq!(bus.stats(), BusStats::default());
+
+        // 1フレーム進めると直前フレームの統計として見える
+        for _ in 0..(262 * 341 / 21 + 1) {
+            bus.tick(7);
+        }
+        assert_eq!(bus.stats(), expected);
+        assert_eq!(bus.current_stats(), BusStats::default());
+
+        // 読み込み専用のPPUSTATUSとそのミラーへの書き込みは無視する
+        let status = bus.ppu().status.bits();
+        bus.mem_write(0x2002, 0xff);
+        bus.mem_write(0x3ffa, 0xff);
+        assert_eq!(bus.current_stats().read_only_writes, 2);
+        assert_eq!(bus.ppu().status.bits(), status);
+    }
+
+    #[test]
+    fn test_bank_switch_timeline() {
+        let mut rom = test_rom();
+        rom.mapper = 66;
+        rom.prg_rom = crate::mapper::test::prg_rom(2);
+        let mut bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        bus.timeline_mut().enabled = true;
+        // GxROMの2つ目の32KB (8KB単位で4-7)
+        bus.mem_write(0x8001, 0x10);
+        bus.mem_write(0x8001, 0x10);
+        let json = bus.timeline().to_json();
+        assert_eq!(json.matches("bank_switch").count(), 4);
+        assert!(json.contains(r#""type":"bank_switch","slot":0,"bank":4"#));
+        assert!(json.contains(r#""type":"bank_switch","slot":3,"bank":7"#));
+    }
+
+    #[test]
+    fn test_restore_wram_length() {
+        let mut bus =
+            Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        bus.mem_write(0x10, 0x44);
+        let mut state = bus.snapshot();
+        state.cpu_wram.truncate(100);
+        assert!(bus.restore(&state).is_err());
+        assert_eq!(bus.mem_read(0x10), 0x44);
+    }
+
+    #[test]
+    fn test_trainer() {
+        let trainer: Vec<u8> = (0..512).map(|i| (i % 251) as u8).collect();
+
+        // NROMにはPRG RAMが無いのでバス側のRAMに置く
+        let mut rom = test_rom();
+        rom.trainer = Some(trainer.clone());
+        let mut bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        assert_eq!(bus.mem_read(0x7000), 0);
+        assert_eq!(bus.mem_read(0x71ff), trainer[511]);
+        bus.mem_write(0x7001, 0x42);
+        assert_eq!(bus.mem_read(0x7001), 0x42);
+        assert_eq!(bus.current_stats(), BusStats::default());
+
+        // PRG RAMのあるマッパーではそちらに書き込む
+        let mut rom = test_rom();
+        rom.mapper = 23;
+        rom.trainer = Some(trainer.clone());
+        let mut bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        assert!(bus.prg_ram.is_none());
+        assert_eq!(bus.mem_read(0x71ff), trainer[511]);
+    }
+
+    #[test]
+    fn test_battery_ram() {
+        let mut bus =
+            Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        bus.mem_write(0x6000, 0x42);
+        assert_eq!(bus.mem_read(0x6000), 0x42);
+        assert_eq!(bus.with_battery_ram(|ram| ram.len()), None);
+
+        let mut rom = test_rom();
+        rom.header.has_battery = true;
+        let mut bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        bus.mem_write(0x7fff, 0x42);
+        let ram = bus.with_battery_ram(|ram| ram.to_vec()).unwrap();
+        assert_eq!(ram.len(), PRG_RAM_SIZE);
+        assert_eq!(ram[0x1fff], 0x42);
+
+        let mut rom = test_rom();
+        rom.mapper = 23;
+        rom.header.has_battery = true;
+        let mut bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        bus.load_battery_ram(&ram);
+        assert_eq!(bus.mem_read(0x7fff), 0x42);
+    }
+
+    #[test]
+    fn test_mapper_irq() {
+        let mut rom = test_rom();
+        rom.mapper = 5;
+        let mut bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        bus.mem_write(0x5203, 2);
+        bus.mem_write(0x5204, 0x80);
+        bus.ppu_mut().set_warmup(false);
+        bus.mem_write(0x2001, 0b0000_1000);
+
+        let mut ticks = 0;
+        while !bus.poll_irq() {
+            bus.tick(7);
+            ticks += 1;
+            assert!(ticks < 1000);
+        }
+        assert_eq!(bus.ppu().scanline(), 2);
+        assert_eq!(bus.mem_read(0x5204) & 0x80, 0x80);
+        assert!(!bus.poll_irq());
+    }
+
+    #[test]
+    fn test_timeline() {
+        let mut bus = Bus::new(
+            test_rom(),
+            |_: &NesPPU, joypad: &mut Joypad, _: &mut Joypad| {
+                joypad.set_button_pressed_status(crate::joypad::JoypadButton::START, true);
+            },
+        )
+        .unwrap();
+        bus.timeline_mut().enabled = true;
+        bus.mem_write(0x2005, 0x10);
+        for _ in 0..(262 * 341 / 21 + 1) {
+            bus.tick(7);
+        }
+        bus.note_interrupt(&InterruptType::NMI);
+
+        let events: Vec<(u64, TimelineEvent)> = bus
+            .timeline()
+            .entries()
+            .iter()
+            .map(|e| (e.frame, e.event))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (0, TimelineEvent::ScrollWrite(0x10)),
+                (1, TimelineEvent::InputChange(0b0000_1000)),
+                (1, TimelineEvent::Nmi),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pal_clock_ratio() {
+        let mut bus =
+            Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        bus.set_region(Region::Pal);
+        assert_eq!(bus.ppu().region, Region::Pal);
+        // CPU 5サイクルでPPUは16ドット進む
+        for _ in 0..5 {
+            bus.tick(1);
+        }
+        assert_eq!(bus.ppu().cycle(), 16);
+        bus.tick(2);
+        assert_eq!(bus.ppu().cycle(), 22);
+
+        // 1フレームは312ライン
+        for _ in 0..(312 * 341 * 5 / 16 / 7 + 1) {
+            bus.tick(7);
+        }
+        assert_eq!(bus.frame_counter().frame, 1);
+    }
+
+    #[test]
+    fn test_accurate_oam_dma() {
+        let mut bus =
+            Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        for i in 0..256u16 {
+            bus.mem_write(0x200 + i, i as u8);
+        }
+        bus.mem_write(0x4014, 0x02);
+        assert_eq!(bus.cycles(), 0);
+        assert_eq!(bus.ppu().oam_data[5], 5);
+
+        bus.ppu_mut().accuracy = Accuracy::Accurate;
+        bus.ppu_mut().oam_data = [0; 256];
+        bus.mem_write(0x4014, 0x02);
+        // 偶数サイクルから始めると513サイクル
+        assert_eq!(bus.cycles(), 513);
+        assert_eq!(bus.ppu().cycle(), 513 * 3 % 341);
+        assert_eq!(bus.ppu().oam_data[5], 5);
+        // 奇数サイクルからは1サイクル多い
+        bus.mem_write(0x4014, 0x02);
+        assert_eq!(bus.cycles(), 513 + 514);
+    }
+
+    #[test]
+    fn test_apu_frame_irq() {
+        let mut bus =
+            Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        assert_eq!(bus.mem_read(0x4015), 0);
+        for _ in 0..(29830 / 7 + 1) {
+            bus.tick(7);
+        }
+        assert!(bus.poll_irq());
+        assert_eq!(bus.mem_read(0x4015), 0x40);
+        assert!(!bus.poll_irq());
+    }
+
+    #[test]
+    fn test_dmc_dma() {
+        let mut bus =
+            Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        // IRQ有効, 1バイトのサンプル
+        bus.mem_write(0x4010, 0x8f);
+        bus.mem_write(0x4013, 0x00);
+        bus.mem_write(0x4015, 0x10);
+        assert_eq!(bus.mem_read(0x4015) & 0x10, 0x10);
+
+        // 読み込みの分だけCPUが止まる
+        bus.tick(1);
+        assert_eq!(bus.cycles(), 1 + DMC_STALL_CYCLES as usize);
+        assert!(bus.poll_irq());
+        assert_eq!(bus.mem_read(0x4015) & 0x90, 0x80);
+        // $4015への書き込みでIRQが消える
+        bus.mem_write(0x4015, 0x00);
+        assert!(!bus.poll_irq());
+    }
+
+    #[test]
+    fn test_joypad2() {
+        let mut bus = Bus::new(
+            test_rom(),
+            |_: &NesPPU, _: &mut Joypad, joypad2: &mut Joypad| {
+                joypad2.set_button_pressed_status(JoypadButton::BUTTON_B, true);
+            },
+        )
+        .unwrap();
+        bus.joypad1_mut()
+            .unwrap()
+            .set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        bus.joypad2_mut()
+            .unwrap()
+            .set_button_pressed_status(JoypadButton::START, true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        let read =
+            |bus: &mut Bus, addr: u16| (0..8).map(|_| bus.mem_read(addr)).collect::<Vec<u8>>();
+        assert_eq!(read(&mut bus, 0x4016), vec![1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(read(&mut bus, 0x4017), vec![0, 0, 0, 1, 0, 0, 0, 0]);
+
+        // コールバックで2P側の入力も更新できる
+        while bus.frame_counter().frame == 0 {
+            bus.tick(1);
+        }
+        assert!(bus
+            .joypad2()
+            .unwrap()
+            .button_status()
+            .contains(JoypadButton::BUTTON_B));
+    }
+
+    #[test]
+    fn test_controller_port() {
+        let mut bus = Bus::new(
+            test_rom(),
+            |_: &NesPPU, joypad: &mut Joypad, _: &mut Joypad| {
+                joypad.set_button_pressed_status(JoypadButton::START, true);
+            },
+        )
+        .unwrap();
+        bus.connect(0, Box::new(crate::controller::Unplugged));
+        assert!(bus.joypad1().is_none());
+        bus.mem_write(0x4016, 1);
+        assert_eq!(bus.mem_read(0x4016), 0);
+
+        // 挿さっていないポートへの入力は捨てられる
+        while bus.frame_counter().frame == 0 {
+            bus.tick(1);
+        }
+        assert_eq!(bus.port(0).peek(), 0);
+        assert_eq!(bus.port(1).peek(), 0);
+    }
+}
@@ -1,8 +1,21 @@
+use std::fmt;
+
+use crate::console::Region;
+use crate::mapper;
+use crate::patch;
+
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const UNIF_TAG: [u8; 4] = *b"UNIF";
+const UNIF_HEADER_SIZE: usize = 32;
+const NSF_TAG: [u8; 5] = [0x4E, 0x45, 0x53, 0x4D, 0x1A];
+const NSF_HEADER_SIZE: usize = 0x80;
+const FDS_TAG: [u8; 4] = [0x46, 0x44, 0x53, 0x1A];
+const FDS_HEADER_SIZE: usize = 16;
+pub const FDS_SIDE_SIZE: usize = 65500;
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
@@ -10,46 +23,589 @@ pub enum Mirroring {
     FOUR_SCREEN,
 }
 
-pub struct Rom {
-    pub prg_rom: Vec<u8>,
-    pub chr_rom: Vec<u8>,
-    pub mapper: u8,
-    pub screen_mirroring: Mirroring,
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RomError {
+    BadMagic,
+    TruncatedHeader,
+    UnknownVersion(u8),
+    TruncatedTrainer,
+    TruncatedPrg { expected: usize, actual: usize },
+    TruncatedChr { expected: usize, actual: usize },
+    BadPrgSize(usize),
+    BadChrSize(usize),
+    UnsupportedMapper(u16),
+    TruncatedChunk(String),
+    MissingBoard,
+    UnsupportedBoard(String),
+    TruncatedDisk { expected: usize, actual: usize },
+    BadPatch(String),
 }
 
-impl Rom {
-    pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        if &raw[0..4] != NES_TAG {
-            return Err("File is not in iNES file format".to_string());
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::BadMagic => write!(f, "File is not in iNES file format"),
+            RomError::TruncatedHeader => write!(f, "File is too short for an iNES header"),
+            RomError::UnknownVersion(version) => {
+                write!(f, "Unknown iNES header version {}", version)
+            }
+            RomError::TruncatedTrainer => write!(f, "Trainer is truncated"),
+            RomError::TruncatedPrg { expected, actual } => write!(
+                f,
+                "PRG ROM is truncated: expected {} bytes, found {}",
+                expected, actual
+            ),
+            RomError::TruncatedChr { expected, actual } => write!(
+                f,
+                "CHR ROM is truncated: expected {} bytes, found {}",
+                expected, actual
+            ),
+            RomError::BadPrgSize(size) => write!(f, "invalid PRG ROM size: {} bytes", size),
+            RomError::BadChrSize(size) => write!(f, "invalid CHR ROM size: {} bytes", size),
+            RomError::UnsupportedMapper(mapper) => write!(f, "mapper {} is not supported", mapper),
+            RomError::TruncatedChunk(id) => write!(f, "UNIF chunk {} is truncated", id),
+            RomError::MissingBoard => write!(f, "UNIF file has no MAPR chunk"),
+            RomError::UnsupportedBoard(board) => write!(f, "board {} is not supported", board),
+            RomError::BadPatch(reason) => write!(f, "failed to apply patch: {}", reason),
+            RomError::TruncatedDisk { expected, actual } => write!(
+                f,
+                "disk image is truncated: expected {} bytes, got {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+// Result<_, String> を返す関数から ? で使えるようにする
+impl From<RomError> for String {
+    fn from(error: RomError) -> String {
+        error.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderFormat {
+    INes,
+    Nes2,
+    Unif,
+}
+
+// NES 2.0 の12バイト目 (CPU/PPUのタイミング)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+impl Timing {
+    // 複数地域対応のROMはNTSCで動かす
+    pub fn region(&self) -> Region {
+        match self {
+            Timing::Ntsc | Timing::MultiRegion => Region::Ntsc,
+            Timing::Pal => Region::Pal,
+            Timing::Dendy => Region::Dendy,
         }
+    }
+}
+
+// ヘッダ16バイトの内容。サイズはバイト単位
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomHeader {
+    pub format: HeaderFormat,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub mirroring: Mirroring,
+    pub has_battery: bool,
+    pub has_trainer: bool,
+    pub timing: Timing,
+}
+
+// NES 2.0のROMサイズ。上位が$Fのときは指数表記 (2^E * (MM * 2 + 1))
+fn nes2_rom_size(lsb: u8, msb: u8, page_size: usize) -> usize {
+    if msb == 0x0f {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        2usize.saturating_pow(exponent).saturating_mul(multiplier)
+    } else {
+        ((msb as usize) << 8 | lsb as usize) * page_size
+    }
+}
+
+// マッパーはPRGとCHRがバンクの倍数であることを前提にしている。
+// PRGの単位はiNESなら16KB、NES 2.0とUNIFなら8KB。CHRは0ならCHR RAM
+fn check_rom_sizes(
+    prg_rom_size: usize,
+    chr_rom_size: usize,
+    prg_unit: usize,
+) -> Result<(), RomError> {
+    if prg_rom_size == 0 || !prg_rom_size.is_multiple_of(prg_unit) {
+        return Err(RomError::BadPrgSize(prg_rom_size));
+    }
+    if !chr_rom_size.is_multiple_of(CHR_ROM_PAGE_SIZE) {
+        return Err(RomError::BadChrSize(chr_rom_size));
+    }
+    Ok(())
+}
 
-        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
-        let ines_ver = (raw[7] >> 2) & 0b11;
-        if ines_ver != 0 {
-            return Err("NES2.0 format is not supported".to_string());
+// RAMサイズは 64 << shift バイト。0なら無し
+fn nes2_ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+impl RomHeader {
+    pub fn parse(raw: &[u8]) -> Result<RomHeader, RomError> {
+        if raw.len() < 4 || raw[0..4] != NES_TAG {
+            return Err(RomError::BadMagic);
         }
+        if raw.len() < 16 {
+            return Err(RomError::TruncatedHeader);
+        }
+
+        let format = match (raw[7] >> 2) & 0b11 {
+            0 => HeaderFormat::INes,
+            2 => HeaderFormat::Nes2,
+            version => return Err(RomError::UnknownVersion(version)),
+        };
 
         let four_screen = raw[6] & 0b1000 != 0;
         let vertical_mirroring = raw[6] & 0b01 != 0;
-        let screen_mirroring = match (four_screen, vertical_mirroring) {
+        let mirroring = match (four_screen, vertical_mirroring) {
             (true, _) => Mirroring::FOUR_SCREEN,
             (false, true) => Mirroring::VERTICAL,
             (false, false) => Mirroring::HORIZONTAL,
         };
+        let has_battery = raw[6] & 0b10 != 0;
+        let has_trainer = raw[6] & 0b100 != 0;
+        let mapper = ((raw[7] & 0b1111_0000) | (raw[6] >> 4)) as u16;
 
-        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        let header = match format {
+            HeaderFormat::INes => {
+                // 8バイト目は8KB単位のPRG RAM。0は互換のため8KBとみなす
+                let prg_ram_size = raw[8].max(1) as usize * 0x2000;
+                let timing = if raw[9] & 1 != 0 {
+                    Timing::Pal
+                } else {
+                    Timing::Ntsc
+                };
+                let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+                RomHeader {
+                    format,
+                    mapper,
+                    submapper: 0,
+                    prg_rom_size: raw[4] as usize * PRG_ROM_PAGE_SIZE,
+                    chr_rom_size,
+                    prg_ram_size: if has_battery { 0 } else { prg_ram_size },
+                    prg_nvram_size: if has_battery { prg_ram_size } else { 0 },
+                    chr_ram_size: if chr_rom_size == 0 { 0x2000 } else { 0 },
+                    chr_nvram_size: 0,
+                    mirroring,
+                    has_battery,
+                    has_trainer,
+                    timing,
+                }
+            }
+            _ => {
+                let timing = match raw[12] & 0b11 {
+                    0 => Timing::Ntsc,
+                    1 => Timing::Pal,
+                    2 => Timing::MultiRegion,
+                    _ => Timing::Dendy,
+                };
+                RomHeader {
+                    format,
+                    mapper: mapper | ((raw[8] & 0x0f) as u16) << 8,
+                    submapper: raw[8] >> 4,
+                    prg_rom_size: nes2_rom_size(raw[4], raw[9] & 0x0f, PRG_ROM_PAGE_SIZE),
+                    chr_rom_size: nes2_rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE),
+                    prg_ram_size: nes2_ram_size(raw[10] & 0x0f),
+                    prg_nvram_size: nes2_ram_size(raw[10] >> 4),
+                    chr_ram_size: nes2_ram_size(raw[11] & 0x0f),
+                    chr_nvram_size: nes2_ram_size(raw[11] >> 4),
+                    mirroring,
+                    has_battery,
+                    has_trainer,
+                    timing,
+                }
+            }
+        };
+        let prg_unit = match format {
+            HeaderFormat::INes => PRG_ROM_PAGE_SIZE,
+            _ => 0x2000,
+        };
+        check_rom_sizes(header.prg_rom_size, header.chr_rom_size, prg_unit)?;
+        Ok(header)
+    }
+}
+
+pub struct Rom {
+    pub header: RomHeader,
+    // $7000-$71FFに置かれる512バイト
+    pub trainer: Option<Vec<u8>>,
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub mapper: u16,
+    pub screen_mirroring: Mirroring,
+    pub region: Region,
+}
+
+impl Rom {
+    // 壊れたファイルでもパニックせずにエラーを返す
+    pub fn new(raw: &Vec<u8>) -> Result<Rom, RomError> {
+        if raw.starts_with(&UNIF_TAG) {
+            return Rom::from_unif(raw);
+        }
+        let header = RomHeader::parse(raw)?;
 
-        let skip_trainer = raw[6] & 0b100 != 0;
+        let prg_rom_size = header.prg_rom_size;
+        let chr_rom_size = header.chr_rom_size;
 
-        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
+        let prg_rom_start = 16 + if header.has_trainer { 512 } else { 0 };
+        if raw.len() < prg_rom_start {
+            return Err(RomError::TruncatedTrainer);
+        }
+        let trainer = if header.has_trainer {
+            Some(raw[16..prg_rom_start].to_vec())
+        } else {
+            None
+        };
+        let prg_rom = raw[prg_rom_start..]
+            .get(..prg_rom_size)
+            .ok_or(RomError::TruncatedPrg {
+                expected: prg_rom_size,
+                actual: raw.len() - prg_rom_start,
+            })?;
         let chr_rom_start = prg_rom_start + prg_rom_size;
+        let chr_rom = raw[chr_rom_start..]
+            .get(..chr_rom_size)
+            .ok_or(RomError::TruncatedChr {
+                expected: chr_rom_size,
+                actual: raw.len() - chr_rom_start,
+            })?;
+
+        #[allow(unused_mut)]
+        let mut rom = Rom {
+            trainer,
+            prg_rom: prg_rom.to_vec(),
+            chr_rom: chr_rom.to_vec(),
+            mapper: header.mapper,
+            screen_mirroring: header.mirroring,
+            region: header.timing.region(),
+            header,
+        };
+        // ヘッダの間違いはマッパー番号の確認より先に直す
+        #[cfg(feature = "rom-db")]
+        crate::rom_db::apply(&mut rom);
+        if !mapper::is_supported(rom.mapper) {
+            return Err(RomError::UnsupportedMapper(rom.mapper));
+        }
+        Ok(rom)
+    }
+
+    // IPS/BPSパッチを当ててから読み込む
+    pub fn load_with_patch(rom: &[u8], patch: &[u8]) -> Result<Rom, RomError> {
+        let patched = patch::apply(rom, patch).map_err(RomError::BadPatch)?;
+        Rom::new(&patched)
+    }
+
+    pub fn info(&self) -> RomInfo {
+        // チェックサムはヘッダを除いたPRG+CHR
+        let mut crc = crc32fast::Hasher::new();
+        let mut sha1 = sha1_smol::Sha1::new();
+        for data in [&self.prg_rom, &self.chr_rom] {
+            crc.update(data);
+            sha1.update(data);
+        }
+        RomInfo {
+            mapper: self.mapper,
+            submapper: self.header.submapper,
+            prg_rom_size: self.prg_rom.len(),
+            chr_rom_size: self.chr_rom.len(),
+            chr_ram_size: self.header.chr_ram_size,
+            mirroring: self.screen_mirroring,
+            has_battery: self.header.has_battery,
+            region: self.region,
+            crc32: crc.finalize(),
+            sha1: sha1.digest().to_string(),
+        }
+    }
+}
+
+// フロントエンドの表示やテスト結果のキーに使う
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomInfo {
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub chr_ram_size: usize,
+    pub mirroring: Mirroring,
+    pub has_battery: bool,
+    pub region: Region,
+    pub crc32: u32,
+    // 小文字の16進40桁
+    pub sha1: String,
+}
+
+impl fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "mapper:    {}.{}", self.mapper, self.submapper)?;
+        writeln!(f, "PRG ROM:   {}KB", self.prg_rom_size / 1024)?;
+        if self.chr_rom_size > 0 {
+            writeln!(f, "CHR ROM:   {}KB", self.chr_rom_size / 1024)?;
+        } else {
+            writeln!(f, "CHR RAM:   {}KB", self.chr_ram_size / 1024)?;
+        }
+        writeln!(f, "mirroring: {:?}", self.mirroring)?;
+        writeln!(
+            f,
+            "battery:   {}",
+            if self.has_battery { "yes" } else { "no" }
+        )?;
+        writeln!(f, "region:    {:?}", self.region)?;
+        writeln!(f, "CRC32:     {:08X}", self.crc32)?;
+        write!(f, "SHA-1:     {}", self.sha1)
+    }
+}
+
+// UNIFのボード名 (NES-/UNL-などの接頭辞を除く) に対応するマッパー番号
+pub fn board_to_mapper(board: &str) -> Option<u16> {
+    let name = ["NES-", "UNL-", "HVC-", "BTL-", "BMC-", "IREM-", "KONAMI-"]
+        .iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(board);
+    match name {
+        "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" => Some(0),
+        "ELROM" | "EKROM" | "ETROM" | "EWROM" => Some(5),
+        "11-IN-1" | "COLORDREAMS" => Some(11),
+        "GNROM" | "MHROM" => Some(66),
+        "BTR" | "JLROM" | "JSROM" => Some(69),
+        "CAMERICA" | "BF9093" | "BF9096" | "BF9097" => Some(71),
+        "DEROM" | "DE1ROM" | "DRROM" | "TEROM-108" => Some(206),
+        _ => None,
+    }
+}
+
+impl Rom {
+    // UNIF: 32バイトのヘッダの後に (ID 4バイト, 長さ 4バイト, データ) のチャンクが続く
+    pub fn from_unif(raw: &[u8]) -> Result<Rom, RomError> {
+        if !raw.starts_with(&UNIF_TAG) {
+            return Err(RomError::BadMagic);
+        }
+        if raw.len() < UNIF_HEADER_SIZE {
+            return Err(RomError::TruncatedHeader);
+        }
+
+        let mut board = None;
+        let mut prg_chunks: Vec<(u8, Vec<u8>)> = vec![];
+        let mut chr_chunks: Vec<(u8, Vec<u8>)> = vec![];
+        let mut mirroring = Mirroring::HORIZONTAL;
+        let mut has_battery = false;
+        let mut timing = Timing::Ntsc;
+
+        let mut pos = UNIF_HEADER_SIZE;
+        while pos < raw.len() {
+            let chunk_header = raw
+                .get(pos..pos + 8)
+                .ok_or_else(|| RomError::TruncatedChunk("header".to_string()))?;
+            // IDはASCIIとは限らないので、バイト列のまま比べる
+            let id: [u8; 4] = chunk_header[0..4].try_into().unwrap();
+            let len = u32::from_le_bytes(chunk_header[4..8].try_into().unwrap()) as usize;
+            let data = (pos + 8)
+                .checked_add(len)
+                .and_then(|end| raw.get(pos + 8..end))
+                .ok_or_else(|| {
+                    RomError::TruncatedChunk(String::from_utf8_lossy(&id).to_string())
+                })?;
+            pos += 8 + len;
+
+            // PRG0-PRGF、CHR0-CHRFの最後の1文字が番号
+            let index = (id[3] as char).to_digit(16).unwrap_or(0) as u8;
+            match &id {
+                b"MAPR" => {
+                    let name = data.split(|b| *b == 0).next().unwrap_or(&[]);
+                    board = Some(String::from_utf8_lossy(name).to_string());
+                }
+                [b'P', b'R', b'G', _] => prg_chunks.push((index, data.to_vec())),
+                [b'C', b'H', b'R', _] => chr_chunks.push((index, data.to_vec())),
+                b"MIRR" => {
+                    mirroring = match data.first() {
+                        Some(1) => Mirroring::VERTICAL,
+                        Some(4) => Mirroring::FOUR_SCREEN,
+                        // 1画面やマッパー制御はマッパー側で扱う
+                        _ => Mirroring::HORIZONTAL,
+                    }
+                }
+                b"BATR" => has_battery = true,
+                b"TVCI" => {
+                    timing = match data.first() {
+                        Some(1) => Timing::Pal,
+                        Some(2) => Timing::MultiRegion,
+                        _ => Timing::Ntsc,
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let board = board.ok_or(RomError::MissingBoard)?;
+        let mapper =
+            board_to_mapper(&board).ok_or_else(|| RomError::UnsupportedBoard(board.clone()))?;
+        if !mapper::is_supported(mapper) {
+            return Err(RomError::UnsupportedMapper(mapper));
+        }
 
+        // PRG0, PRG1, ... の順に連結する
+        prg_chunks.sort_by_key(|(index, _)| *index);
+        chr_chunks.sort_by_key(|(index, _)| *index);
+        let prg_rom: Vec<u8> = prg_chunks.into_iter().flat_map(|(_, data)| data).collect();
+        let chr_rom: Vec<u8> = chr_chunks.into_iter().flat_map(|(_, data)| data).collect();
+        check_rom_sizes(prg_rom.len(), chr_rom.len(), 0x2000)?;
+
+        let header = RomHeader {
+            format: HeaderFormat::Unif,
+            mapper,
+            submapper: 0,
+            prg_rom_size: prg_rom.len(),
+            chr_rom_size: chr_rom.len(),
+            prg_ram_size: if has_battery { 0 } else { 0x2000 },
+            prg_nvram_size: if has_battery { 0x2000 } else { 0 },
+            chr_ram_size: if chr_rom.is_empty() { 0x2000 } else { 0 },
+            chr_nvram_size: 0,
+            mirroring,
+            has_battery,
+            has_trainer: false,
+            timing,
+        };
         Ok(Rom {
-            prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
-            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
-            mapper: mapper,
-            screen_mirroring: screen_mirroring,
+            trainer: None,
+            prg_rom,
+            chr_rom,
+            mapper,
+            screen_mirroring: mirroring,
+            region: timing.region(),
+            header,
+        })
+    }
+}
+
+// NSF: 128バイトのヘッダの後にload_addrから配置するデータが続く
+#[derive(Debug, Clone, PartialEq)]
+pub struct Nsf {
+    pub version: u8,
+    pub total_songs: u8,
+    // 1始まり
+    pub starting_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub name: String,
+    pub artist: String,
+    pub copyright: String,
+    // play を呼ぶ間隔 (マイクロ秒)
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    // すべて0ならバンク切り替えなし
+    pub bankswitch_init: [u8; 8],
+    pub timing: Timing,
+    pub extra_sound_chips: u8,
+    pub data: Vec<u8>,
+}
+
+impl Nsf {
+    pub fn new(raw: &[u8]) -> Result<Nsf, RomError> {
+        if !raw.starts_with(&NSF_TAG) {
+            return Err(RomError::BadMagic);
+        }
+        if raw.len() < NSF_HEADER_SIZE {
+            return Err(RomError::TruncatedHeader);
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([raw[i], raw[i + 1]]);
+        let text = |range: std::ops::Range<usize>| {
+            let bytes = raw[range].split(|b| *b == 0).next().unwrap_or(&[]);
+            String::from_utf8_lossy(bytes).to_string()
+        };
+        let timing = match raw[0x7a] & 0b11 {
+            0 => Timing::Ntsc,
+            1 => Timing::Pal,
+            _ => Timing::MultiRegion,
+        };
+        Ok(Nsf {
+            version: raw[5],
+            total_songs: raw[6],
+            starting_song: raw[7].max(1),
+            load_addr: u16_at(0x08),
+            init_addr: u16_at(0x0a),
+            play_addr: u16_at(0x0c),
+            name: text(0x0e..0x2e),
+            artist: text(0x2e..0x4e),
+            copyright: text(0x4e..0x6e),
+            ntsc_speed: u16_at(0x6e),
+            bankswitch_init: raw[0x70..0x78].try_into().unwrap(),
+            pal_speed: u16_at(0x78),
+            timing,
+            extra_sound_chips: raw[0x7b],
+            data: raw[NSF_HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    pub fn is_bank_switched(&self) -> bool {
+        self.bankswitch_init.iter().any(|bank| *bank != 0)
+    }
+}
+
+// .fds: 16バイトのヘッダ (省略可) の後に65500バイトのディスク面が並ぶ
+#[derive(Debug, Clone, PartialEq)]
+pub struct FdsImage {
+    pub sides: Vec<Vec<u8>>,
+}
+
+impl FdsImage {
+    pub fn new(raw: &[u8]) -> Result<FdsImage, RomError> {
+        let data = if raw.starts_with(&FDS_TAG) {
+            let header = raw
+                .get(..FDS_HEADER_SIZE)
+                .ok_or(RomError::TruncatedHeader)?;
+            let expected = FDS_HEADER_SIZE + header[4] as usize * FDS_SIDE_SIZE;
+            if raw.len() < expected {
+                return Err(RomError::TruncatedDisk {
+                    expected,
+                    actual: raw.len(),
+                });
+            }
+            &raw[FDS_HEADER_SIZE..expected]
+        } else {
+            raw
+        };
+
+        // 各面はディスク情報ブロック (01 *NINTENDO-HVC*) で始まる
+        if !data.starts_with(b"\x01*NINTENDO-HVC*") {
+            return Err(RomError::BadMagic);
+        }
+        if data.len() % FDS_SIDE_SIZE != 0 {
+            return Err(RomError::TruncatedDisk {
+                expected: data.len().next_multiple_of(FDS_SIDE_SIZE),
+                actual: data.len(),
+            });
+        }
+        Ok(FdsImage {
+            sides: data
+                .chunks(FDS_SIDE_SIZE)
+                .map(|side| side.to_vec())
+                .collect(),
         })
     }
 }
@@ -86,7 +642,7 @@ pub mod test {
     pub fn test_rom() -> Rom {
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
             ],
             trainer: None,
             pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
@@ -100,7 +656,7 @@ pub mod test {
     fn test() {
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x51, 00, 00, 00, 00, 00, 00, 00, 00, 00,
             ],
             trainer: None,
             pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
@@ -111,8 +667,9 @@ pub mod test {
 
         assert_eq!(rom.chr_rom, vec!(2; 1 * CHR_ROM_PAGE_SIZE));
         assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
-        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.mapper, 5);
         assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+        assert_eq!(rom.region, Region::Ntsc);
     }
 
     #[test]
@@ -125,7 +682,7 @@ pub mod test {
                 0x1A,
                 0x02,
                 0x01,
-                0x31 | 0b100,
+                0x51 | 0b100,
                 00,
                 00,
                 00,
@@ -136,33 +693,379 @@ pub mod test {
                 00,
                 00,
             ],
-            trainer: Some(vec![0; 512]),
+            trainer: Some((0..512).map(|i| i as u8).collect()),
             pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
             chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
         });
 
         let rom: Rom = Rom::new(&test_rom).unwrap();
 
+        let trainer = rom.trainer.as_ref().unwrap();
+        assert_eq!(trainer.len(), 512);
+        assert_eq!(trainer[511], 0xff);
+
         assert_eq!(rom.chr_rom, vec!(2; 1 * CHR_ROM_PAGE_SIZE));
         assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
-        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.mapper, 5);
         assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
     }
 
     #[test]
-    fn test_nes2_is_not_supported() {
+    fn test_nes2_header() {
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x12, 0x48, 0x31, 00, 0x70, 0x07, 0x03, 00, 00,
+                00,
             ],
             trainer: None,
             pgp_rom: vec![1; 1 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+        assert_eq!(
+            Rom::new(&test_rom).err(),
+            Some(RomError::UnsupportedMapper(0x141))
+        );
+
+        // 拡張マッパー番号を0にして読み込む
+        let mut test_rom = test_rom;
+        test_rom[6] &= 0x0f;
+        test_rom[7] &= 0x0f;
+        test_rom[8] &= 0xf0;
+        let rom = Rom::new(&test_rom).unwrap();
+        let header = &rom.header;
+        assert_eq!(header.format, HeaderFormat::Nes2);
+        assert_eq!(header.mapper, 0);
+        assert_eq!(header.submapper, 3);
+        assert_eq!(header.prg_rom_size, PRG_ROM_PAGE_SIZE);
+        assert_eq!(header.chr_rom_size, 0);
+        assert_eq!(header.prg_ram_size, 0);
+        assert_eq!(header.prg_nvram_size, 8192);
+        assert_eq!(header.chr_ram_size, 8192);
+        assert_eq!(header.chr_nvram_size, 0);
+        assert!(header.has_battery);
+        assert_eq!(header.timing, Timing::Dendy);
+        assert_eq!(rom.region, Region::Dendy);
+        assert_eq!(rom.screen_mirroring, Mirroring::HORIZONTAL);
+    }
+
+    #[test]
+    fn test_nes2_rom_size() {
+        assert_eq!(
+            nes2_rom_size(0x02, 0x01, PRG_ROM_PAGE_SIZE),
+            0x102 * PRG_ROM_PAGE_SIZE
+        );
+        // 2^5 * 3
+        assert_eq!(nes2_rom_size(0b0001_0101, 0x0f, PRG_ROM_PAGE_SIZE), 96);
+
+        // 指数表記では8KBより小さいサイズも書けるが、マッパーが扱えないので断る
+        let mut header = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0, 0x08];
+        header.resize(16, 0);
+        let parse = |header: &[u8]| RomHeader::parse(header).map(|h| h.chr_rom_size);
+        assert_eq!(parse(&header), Ok(CHR_ROM_PAGE_SIZE));
+        header[9] = 0xf0;
+        header[5] = 0b0010_1000; // 2^10
+        assert_eq!(parse(&header), Err(RomError::BadChrSize(0x400)));
+        header[5] = 0b0011_0101; // 2^13 * 3
+        assert_eq!(parse(&header), Ok(3 * CHR_ROM_PAGE_SIZE));
+        header[9] = 0xff;
+        header[4] = 0b0000_0001; // 2^0 * 3
+        assert_eq!(parse(&header), Err(RomError::BadPrgSize(3)));
+    }
+
+    #[test]
+    fn test_ines_header() {
+        let header = test_rom().header;
+        assert_eq!(header.format, HeaderFormat::INes);
+        assert_eq!(header.prg_ram_size, 8192);
+        assert_eq!(header.chr_ram_size, 0);
+        assert!(!header.has_battery);
+        assert_eq!(header.timing, Timing::Ntsc);
+    }
+
+    #[test]
+    fn test_errors() {
+        let bytes = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x04, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: Some(vec![0; 512]),
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
             chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
         });
-        let rom = Rom::new(&test_rom);
-        match rom {
-            Result::Ok(_) => assert!(false, "should not load rom"),
-            Result::Err(str) => assert_eq!(str, "NES2.0 format is not supported"),
+        assert!(Rom::new(&bytes).is_ok());
+
+        assert_eq!(Rom::new(&vec![0x4E, 0x45]).err(), Some(RomError::BadMagic));
+        assert_eq!(
+            Rom::new(&bytes[..10].to_vec()).err(),
+            Some(RomError::TruncatedHeader)
+        );
+        assert_eq!(
+            Rom::new(&bytes[..100].to_vec()).err(),
+            Some(RomError::TruncatedTrainer)
+        );
+        assert_eq!(
+            Rom::new(&bytes[..528 + 100].to_vec()).err(),
+            Some(RomError::TruncatedPrg {
+                expected: 2 * PRG_ROM_PAGE_SIZE,
+                actual: 100
+            })
+        );
+        assert_eq!(
+            Rom::new(&bytes[..bytes.len() - 1].to_vec()).err(),
+            Some(RomError::TruncatedChr {
+                expected: CHR_ROM_PAGE_SIZE,
+                actual: CHR_ROM_PAGE_SIZE - 1
+            })
+        );
+
+        let mut bytes = bytes;
+        bytes[7] = 0x04;
+        assert_eq!(Rom::new(&bytes).err(), Some(RomError::UnknownVersion(1)));
+        bytes[7] = 0xf0;
+        assert_eq!(
+            Rom::new(&bytes).err().map(|e| e.to_string()),
+            Some("mapper 240 is not supported".to_string())
+        );
+
+        // PRGが無いと、ファイルの長さが足りていてもマッパーが動かない
+        bytes[7] = 0;
+        bytes[4] = 0;
+        assert_eq!(Rom::new(&bytes).err(), Some(RomError::BadPrgSize(0)));
+        // NES 2.0は8KB単位
+        bytes[7] = 0x08;
+        assert_eq!(Rom::new(&bytes).err(), Some(RomError::BadPrgSize(0)));
+        bytes[9] = 0x0f;
+        bytes[4] = 0b0000_1101; // 2^3 * 3
+        assert_eq!(Rom::new(&bytes).err(), Some(RomError::BadPrgSize(24)));
+        bytes[4] = 0b0011_0101; // 2^13 * 3
+        assert!(Rom::new(&bytes).is_ok());
+    }
+
+    #[test]
+    fn test_truncated_at_every_length_does_not_panic() {
+        let bytes = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x04, 0x08, 00, 00, 0x77, 0x77, 00, 00, 00, 00,
+            ],
+            trainer: Some(vec![0; 512]),
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        for len in 0..bytes.len() {
+            assert!(Rom::new(&bytes[..len].to_vec()).is_err());
         }
     }
+
+    pub fn unif_chunk(id: &str, data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.as_bytes().to_vec();
+        chunk.extend((data.len() as u32).to_le_bytes());
+        chunk.extend(data);
+        chunk
+    }
+
+    pub fn unif(board: &str) -> Vec<u8> {
+        let mut raw = b"UNIF".to_vec();
+        raw.extend(7u32.to_le_bytes());
+        raw.extend([0; 24]);
+        raw.extend(unif_chunk("MAPR", format!("{}\0", board).as_bytes()));
+        raw.extend(unif_chunk("PRG1", &[2; 0x4000]));
+        raw.extend(unif_chunk("PRG0", &[1; 0x4000]));
+        raw.extend(unif_chunk("CHR0", &[3; 0x2000]));
+        raw.extend(unif_chunk("MIRR", &[1]));
+        raw.extend(unif_chunk("BATR", &[0]));
+        raw
+    }
+
+    #[test]
+    fn test_unif() {
+        let rom = Rom::new(&unif("NES-GNROM")).unwrap();
+        assert_eq!(rom.header.format, HeaderFormat::Unif);
+        assert_eq!(rom.mapper, 66);
+        assert_eq!(rom.prg_rom.len(), 0x8000);
+        assert_eq!(rom.prg_rom[0], 1);
+        assert_eq!(rom.prg_rom[0x4000], 2);
+        assert_eq!(rom.chr_rom, vec![3; 0x2000]);
+        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+        assert!(rom.header.has_battery);
+    }
+
+    #[test]
+    fn test_unif_errors() {
+        assert_eq!(
+            Rom::new(&unif("UNL-SOMETHING")).err(),
+            Some(RomError::UnsupportedBoard("UNL-SOMETHING".to_string()))
+        );
+        assert_eq!(
+            Rom::new(&unif("NES-TLROM")).err(),
+            Some(RomError::UnsupportedBoard("NES-TLROM".to_string()))
+        );
+        let raw = unif("NES-NROM-256");
+        assert_eq!(
+            Rom::new(&raw[..100].to_vec()).err(),
+            Some(RomError::TruncatedChunk("PRG1".to_string()))
+        );
+        for len in 0..raw.len() {
+            let _ = Rom::new(&raw[..len].to_vec());
+        }
+
+        // ASCIIでないIDのチャンクは読み飛ばす
+        let mut raw = unif("NES-NROM-256");
+        raw.extend([0x41, 0xff, 0x42, 0x43, 1, 0, 0, 0, 0]);
+        assert!(Rom::new(&raw).is_ok());
+        // PRGが無いか、8KBの倍数でない
+        let mut raw = b"UNIF".to_vec();
+        raw.extend([0; 28]);
+        raw.extend(unif_chunk("MAPR", b"NES-NROM\0"));
+        assert_eq!(Rom::new(&raw).err(), Some(RomError::BadPrgSize(0)));
+        raw.extend(unif_chunk("PRG0", &[1; 0x3000]));
+        assert_eq!(Rom::new(&raw).err(), Some(RomError::BadPrgSize(0x3000)));
+        // 長さが大きすぎるチャンク
+        let mut raw = unif("NES-NROM-256");
+        raw.extend(b"PRG2");
+        raw.extend(u32::MAX.to_le_bytes());
+        assert_eq!(
+            Rom::new(&raw).err(),
+            Some(RomError::TruncatedChunk("PRG2".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_board_to_mapper() {
+        assert_eq!(board_to_mapper("NES-NROM-128"), Some(0));
+        assert_eq!(board_to_mapper("UNL-BF9097"), Some(71));
+        assert_eq!(board_to_mapper("NES-DEROM"), Some(206));
+        assert_eq!(board_to_mapper("NES-SNROM"), None);
+    }
+
+    pub fn nsf(songs: u8, program: &[u8]) -> Vec<u8> {
+        let mut raw = b"NESM\x1a\x01".to_vec();
+        raw.extend([songs, 1]);
+        raw.extend(0x8000u16.to_le_bytes());
+        raw.extend(0x8000u16.to_le_bytes());
+        raw.extend(0x8010u16.to_le_bytes());
+        for text in ["Song", "Artist", "2024"] {
+            let mut field = [0; 32];
+            field[..text.len()].copy_from_slice(text.as_bytes());
+            raw.extend(field);
+        }
+        raw.extend(16639u16.to_le_bytes());
+        raw.extend([0; 8]);
+        raw.extend(19997u16.to_le_bytes());
+        raw.extend([0; 6]);
+        raw.extend(program);
+        raw
+    }
+
+    #[test]
+    fn test_nsf() {
+        let parsed = Nsf::new(&nsf(3, &[0x60])).unwrap();
+        assert_eq!(parsed.total_songs, 3);
+        assert_eq!(parsed.starting_song, 1);
+        assert_eq!(parsed.init_addr, 0x8000);
+        assert_eq!(parsed.play_addr, 0x8010);
+        assert_eq!(parsed.name, "Song");
+        assert_eq!(parsed.artist, "Artist");
+        assert_eq!(parsed.ntsc_speed, 16639);
+        assert_eq!(parsed.timing, Timing::Ntsc);
+        assert!(!parsed.is_bank_switched());
+        assert_eq!(parsed.data, vec![0x60]);
+
+        assert_eq!(Nsf::new(&unif("NES-NROM")).err(), Some(RomError::BadMagic));
+        assert_eq!(
+            Nsf::new(&nsf(1, &[])[..0x40]).err(),
+            Some(RomError::TruncatedHeader)
+        );
+    }
+
+    // ディスク情報, ファイル数1, ファイルヘッダ, 4バイトのファイル
+    pub fn fds_side() -> Vec<u8> {
+        let mut side = vec![0x01];
+        side.extend(b"*NINTENDO-HVC*");
+        side.resize(56, 0);
+        side.extend([0x02, 0x01]);
+        side.extend([0x03, 0x00, 0x00, b'F', b'I', b'L', b'E', b'0', b'0', b'0']);
+        side.extend([0x00, 0x60, 0x04, 0x00, 0x00]);
+        side.extend([0x04, 0xde, 0xad, 0xbe, 0xef]);
+        side.resize(FDS_SIDE_SIZE, 0);
+        side
+    }
+
+    #[test]
+    fn test_fds_image() {
+        let mut raw = FDS_TAG.to_vec();
+        raw.extend([2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        raw.extend(fds_side());
+        raw.extend(fds_side());
+        let image = FdsImage::new(&raw).unwrap();
+        assert_eq!(image.sides.len(), 2);
+        assert_eq!(image.sides[1], fds_side());
+
+        // ヘッダなし
+        let image = FdsImage::new(&raw[FDS_HEADER_SIZE..]).unwrap();
+        assert_eq!(image.sides.len(), 2);
+
+        assert_eq!(
+            FdsImage::new(&raw[..FDS_HEADER_SIZE + 100]).err(),
+            Some(RomError::TruncatedDisk {
+                expected: FDS_HEADER_SIZE + 2 * FDS_SIDE_SIZE,
+                actual: FDS_HEADER_SIZE + 100
+            })
+        );
+        assert_eq!(FdsImage::new(&[0; 100]).err(), Some(RomError::BadMagic));
+    }
+
+    #[test]
+    fn test_info() {
+        let info = test_rom().info();
+        assert_eq!(info.mapper, 0);
+        assert_eq!(info.prg_rom_size, 2 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(info.chr_rom_size, CHR_ROM_PAGE_SIZE);
+        assert_eq!(info.mirroring, Mirroring::VERTICAL);
+        assert!(!info.has_battery);
+        assert_eq!(info.region, Region::Ntsc);
+
+        let mut data = vec![1; 2 * PRG_ROM_PAGE_SIZE];
+        data.extend(vec![2; CHR_ROM_PAGE_SIZE]);
+        assert_eq!(info.crc32, crc32fast::hash(&data));
+        assert_eq!(info.sha1, sha1_smol::Sha1::from(&data).digest().to_string());
+        assert_eq!(info.sha1.len(), 40);
+        assert!(info
+            .to_string()
+            .contains(&format!("CRC32:     {:08X}", info.crc32)));
+
+        // 既知の値: "The quick brown fox jumps over the lazy dog"
+        let mut rom = test_rom();
+        rom.prg_rom = b"The quick brown fox jumps over the lazy dog".to_vec();
+        rom.chr_rom = vec![];
+        let info = rom.info();
+        assert_eq!(info.crc32, 0x414fa339);
+        assert_eq!(info.sha1, "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12");
+    }
+
+    #[test]
+    fn test_load_with_patch() {
+        let mut raw = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        // PRGの先頭2バイトを書き換えるIPS
+        let mut patch = b"PATCH".to_vec();
+        patch.extend([0x00, 0x00, 0x10, 0x00, 0x02, 0xea, 0xea]);
+        patch.extend(b"EOF");
+        let rom = Rom::load_with_patch(&raw, &patch).unwrap();
+        assert_eq!(rom.prg_rom[..3], [0xea, 0xea, 1]);
+        assert_eq!(raw[16], 1);
+
+        assert!(matches!(
+            Rom::load_with_patch(&raw, b"junk"),
+            Err(RomError::BadPatch(_))
+        ));
+        // パッチ後のイメージも検査される
+        raw.truncate(100);
+        assert!(Rom::load_with_patch(&raw, &patch).is_err());
+    }
 }
@@ -0,0 +1,200 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use crate::renderer_frame::Image;
+
+// GIFは1/100秒単位で、2/100秒より短い間隔はブラウザが遅くしてしまうので1フレームおきに残す
+const FRAME_STEP: u64 = 2;
+
+// 256色までのパレットと、その番号で表した画面
+struct ClipFrame {
+    width: usize,
+    height: usize,
+    palette: Vec<[u8; 3]>,
+    pixels: Vec<u8>,
+}
+
+impl ClipFrame {
+    // NESの画面は1枚でせいぜい数十色なので、そのままの色でパレットを作る。
+    // 256色を超えた分は一番近い色にする
+    fn new(image: &Image) -> Self {
+        let mut palette: Vec<[u8; 3]> = vec![];
+        let mut colors: HashMap<[u8; 3], u8> = HashMap::new();
+        let pixels = image
+            .data
+            .chunks(3)
+            .map(|rgb| {
+                let rgb = [rgb[0], rgb[1], rgb[2]];
+                if let Some(index) = colors.get(&rgb) {
+                    return *index;
+                }
+                let index = if palette.len() < 256 {
+                    palette.push(rgb);
+                    (palette.len() - 1) as u8
+                } else {
+                    nearest(&palette, rgb)
+                };
+                colors.insert(rgb, index);
+                index
+            })
+            .collect();
+        ClipFrame {
+            width: image.width,
+            height: image.height,
+            palette,
+            pixels,
+        }
+    }
+}
+
+fn nearest(palette: &[[u8; 3]], rgb: [u8; 3]) -> u8 {
+    let distance = |color: &[u8; 3]| -> i32 {
+        (0..3)
+            .map(|i| (color[i] as i32 - rgb[i] as i32).pow(2))
+            .sum()
+    };
+    palette
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, color)| distance(color))
+        .map(|(index, _)| index as u8)
+        .unwrap_or(0)
+}
+
+// 直近の数秒の画面を持っておき、ループするGIFにして書き出す
+pub struct ClipBuffer {
+    frames: VecDeque<ClipFrame>,
+    capacity: usize,
+    // 残すフレームの秒あたりの数
+    frame_rate: f64,
+    pushed: u64,
+}
+
+impl ClipBuffer {
+    // frame_rateはエミュレータのフレームレート
+    pub fn new(seconds: f64, frame_rate: f64) -> Self {
+        let frame_rate = frame_rate / FRAME_STEP as f64;
+        let capacity = ((seconds * frame_rate).ceil() as usize).max(1);
+        ClipBuffer {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+            frame_rate,
+            pushed: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    // 毎フレーム呼ぶ
+    pub fn push(&mut self, image: &Image) {
+        self.pushed += 1;
+        if !(self.pushed - 1).is_multiple_of(FRAME_STEP) {
+            return;
+        }
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(ClipFrame::new(image));
+    }
+
+    pub fn save_gif<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let first = self.frames.front().ok_or("no frames to save")?;
+        let path = path.as_ref();
+        let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut encoder = gif::Encoder::new(
+            BufWriter::new(file),
+            first.width as u16,
+            first.height as u16,
+            &[],
+        )
+        .map_err(|e| e.to_string())?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(|e| e.to_string())?;
+        // 端数が溜まらないように、経過時間を丸めた差を間隔にする
+        let centis = |i: usize| (i as f64 * 100.0 / self.frame_rate).round() as u16;
+        for (i, frame) in self.frames.iter().enumerate() {
+            let gif_frame = gif::Frame {
+                width: frame.width as u16,
+                height: frame.height as u16,
+                delay: centis(i + 1) - centis(i),
+                palette: Some(frame.palette.concat()),
+                buffer: Cow::Borrowed(&frame.pixels),
+                ..gif::Frame::default()
+            };
+            encoder.write_frame(&gif_frame).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn image(color: u8) -> Image {
+        Image {
+            width: 4,
+            height: 2,
+            data: vec![color; 4 * 2 * 3],
+        }
+    }
+
+    #[test]
+    fn test_clip_buffer() {
+        // 30フレーム/秒で残すので0.1秒は3フレーム
+        let mut clip = ClipBuffer::new(0.1, 60.0);
+        for color in 0..10 {
+            clip.push(&image(color));
+        }
+        assert_eq!(clip.len(), 3);
+
+        let path = std::env::temp_dir().join(format!("nes-rs-clip-{}.gif", std::process::id()));
+        clip.save_gif(&path).unwrap();
+        let mut decoder = gif::DecodeOptions::new();
+        decoder.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = decoder.read_info(File::open(&path).unwrap()).unwrap();
+        let mut colors = vec![];
+        let mut delays = vec![];
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            assert_eq!((frame.width, frame.height), (4, 2));
+            colors.push(frame.buffer[0]);
+            delays.push(frame.delay);
+        }
+        std::fs::remove_file(&path).unwrap();
+        // 古いものから捨て、1フレームおきに残っている
+        assert_eq!(colors, vec![4, 6, 8]);
+        assert_eq!(delays, vec![3, 4, 3]);
+
+        clip.clear();
+        assert!(clip.save_gif(&path).is_err());
+    }
+
+    #[test]
+    fn test_many_colors() {
+        let data: Vec<u8> = (0..300u32)
+            .flat_map(|i| [(i % 256) as u8, (i / 256) as u8, 0])
+            .collect();
+        let frame = ClipFrame::new(&Image {
+            width: 300,
+            height: 1,
+            data,
+        });
+        assert_eq!(frame.palette.len(), 256);
+        // 257色目からは一番近い色
+        assert_eq!(frame.pixels[256], 0);
+    }
+}
@@ -0,0 +1,161 @@
+use std::time::Instant;
+
+use crate::bus::BusStats;
+
+// 1フレームにこれ以上割り込みが入ったら異常とみなす
+pub const IRQ_STORM_THRESHOLD: u32 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    // 1フレームのライン数 (プリレンダーラインを含む)
+    pub fn scanlines(self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    pub fn pre_render_line(self) -> u16 {
+        self.scanlines() - 1
+    }
+
+    // DendyはVBlankの前に50ライン待つ
+    pub fn vblank_line(self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    // CPU 5サイクルあたりのPPUのドット数 (PALは3.2倍)
+    pub fn dots_per_5_cpu_cycles(self) -> u32 {
+        match self {
+            Region::Ntsc | Region::Dendy => 15,
+            Region::Pal => 16,
+        }
+    }
+
+    pub fn cpu_clock(self) -> f64 {
+        match self {
+            Region::Ntsc => 1_789_773.0,
+            Region::Pal => 1_662_607.0,
+            Region::Dendy => 1_773_448.0,
+        }
+    }
+
+    pub fn frame_rate(self) -> f64 {
+        match self {
+            Region::Ntsc => 60.0988,
+            Region::Pal | Region::Dendy => 50.0070,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunState {
+    Running,
+    // BRKで実行ループを抜けた
+    Halted,
+    // KIL命令でCPUが停止した
+    Jammed,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsoleStatus {
+    pub run_state: RunState,
+    pub region: Region,
+    pub frame: u64,
+    pub fps: f64,
+    pub interrupts_last_frame: u32,
+    pub irq_storm: bool,
+    pub bus_stats: BusStats,
+}
+
+// Busがフレームの区切りごとに更新する統計
+pub struct FrameCounter {
+    pub frame: u64,
+    pub fps: f64,
+    pub interrupts_this_frame: u32,
+    pub interrupts_last_frame: u32,
+    window_start: Option<Instant>,
+    window_frames: u32,
+}
+
+impl FrameCounter {
+    pub fn new() -> Self {
+        FrameCounter {
+            frame: 0,
+            fps: 0.0,
+            interrupts_this_frame: 0,
+            interrupts_last_frame: 0,
+            window_start: None,
+            window_frames: 0,
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        self.frame += 1;
+        self.interrupts_last_frame = self.interrupts_this_frame;
+        self.interrupts_this_frame = 0;
+
+        // wasm32-unknown-unknownには時計がなく、Instant::nowはパニックする
+        if cfg!(target_arch = "wasm32") {
+            return;
+        }
+        let now = Instant::now();
+        let start = *self.window_start.get_or_insert(now);
+        self.window_frames += 1;
+        let elapsed = now.duration_since(start).as_secs_f64();
+        if elapsed >= 1.0 {
+            self.fps = self.window_frames as f64 / elapsed;
+            self.window_start = Some(now);
+            self.window_frames = 0;
+        }
+    }
+
+    pub fn irq_storm(&self) -> bool {
+        self.interrupts_this_frame.max(self.interrupts_last_frame) >= IRQ_STORM_THRESHOLD
+    }
+}
+
+impl Default for FrameCounter {
+    fn default() -> Self {
+        FrameCounter::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_region_timing() {
+        for region in [Region::Ntsc, Region::Pal, Region::Dendy] {
+            // 1秒分のドット数をフレームのドット数で割るとフレームレートになる
+            let dots = region.cpu_clock() * region.dots_per_5_cpu_cycles() as f64 / 5.0;
+            let frame_dots = region.scanlines() as f64 * 341.0;
+            assert!((dots / frame_dots - region.frame_rate()).abs() < 0.05);
+        }
+        assert_eq!(Region::Pal.pre_render_line(), 311);
+        assert_eq!(Region::Dendy.vblank_line(), 291);
+    }
+
+    #[test]
+    fn test_irq_storm() {
+        let mut frames = FrameCounter::new();
+        frames.interrupts_this_frame = IRQ_STORM_THRESHOLD;
+        assert!(frames.irq_storm());
+        frames.end_frame();
+        assert_eq!(frames.interrupts_last_frame, IRQ_STORM_THRESHOLD);
+        assert!(frames.irq_storm());
+        frames.end_frame();
+        assert!(!frames.irq_storm());
+        assert_eq!(frames.frame, 2);
+    }
+}
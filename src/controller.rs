@@ -0,0 +1,115 @@
+use crate::joypad::Joypad;
+use crate::savestate::State;
+
+// $4016/$4017に繋ぐ機器。標準のコントローラー以外 (ザッパーやパドル、
+// フォースコアなど) もこれを実装すればどちらのポートにも挿せる
+pub trait ControllerPort {
+    // $4016への書き込み。bit0がストローブで、両方のポートに届く
+    fn write(&mut self, data: u8);
+
+    // $4016/$4017の読み込み。下位5ビットだけが意味を持つ
+    fn read(&mut self) -> u8;
+
+    // readと同じ値を、シフトなどの副作用なしで返す
+    fn peek(&self) -> u8;
+
+    // フレームの区切りごとに呼ばれる。連射のように毎フレーム変わる状態を進める
+    fn end_frame(&mut self) {}
+
+    // セーブステート。挿さっている機器ごとに中身が変わる
+    fn state(&mut self, state: &mut State);
+
+    // 巻き戻しのチェックポイント用
+    fn clone_box(&self) -> Box<dyn ControllerPort>;
+
+    // 標準のコントローラーならそのJoypad。ゲームループのコールバックに渡す
+    fn joypad(&self) -> Option<&Joypad> {
+        None
+    }
+
+    fn joypad_mut(&mut self) -> Option<&mut Joypad> {
+        None
+    }
+}
+
+// 何も挿さっていないポート
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Unplugged;
+
+impl ControllerPort for Unplugged {
+    fn write(&mut self, _data: u8) {}
+
+    fn read(&mut self) -> u8 {
+        0
+    }
+
+    fn peek(&self) -> u8 {
+        0
+    }
+
+    fn state(&mut self, _state: &mut State) {}
+
+    fn clone_box(&self) -> Box<dyn ControllerPort> {
+        Box::new(*self)
+    }
+}
+
+impl ControllerPort for Joypad {
+    fn write(&mut self, data: u8) {
+        Joypad::write(self, data)
+    }
+
+    fn read(&mut self) -> u8 {
+        Joypad::read(self)
+    }
+
+    fn peek(&self) -> u8 {
+        Joypad::peek(self)
+    }
+
+    fn end_frame(&mut self) {
+        Joypad::end_frame(self)
+    }
+
+    fn state(&mut self, state: &mut State) {
+        Joypad::state(self, state)
+    }
+
+    fn clone_box(&self) -> Box<dyn ControllerPort> {
+        Box::new(self.clone())
+    }
+
+    fn joypad(&self) -> Option<&Joypad> {
+        Some(self)
+    }
+
+    fn joypad_mut(&mut self) -> Option<&mut Joypad> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::joypad::JoypadButton;
+
+    #[test]
+    fn test_joypad_port() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_B, true);
+        let mut port: Box<dyn ControllerPort> = Box::new(joypad);
+        port.write(1);
+        port.write(0);
+        assert_eq!(port.read(), 0);
+        assert_eq!(port.peek(), 1);
+        assert_eq!(port.read(), 1);
+
+        let copy = port.clone_box();
+        assert_eq!(copy.peek(), 0);
+        assert!(copy.joypad().is_some());
+
+        let mut unplugged: Box<dyn ControllerPort> = Box::new(Unplugged);
+        assert_eq!(unplugged.read(), 0);
+        assert!(unplugged.joypad_mut().is_none());
+    }
+}
@@ -1,7 +1,15 @@
 use crate::interrupts::*;
-use crate::{bus::Bus, opcodes::OPCODES_MAP};
+use crate::{
+    bus::{Bus, BusState},
+    console::{ConsoleStatus, RunState},
+    opcodes::OPCODES_MAP,
+    savestate::State,
+};
 
-#[derive(Debug)]
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize, Serializer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 pub enum AddressingMode {
     Immediate,
@@ -63,9 +71,34 @@ pub struct CPU<'a> {
     pub stack_pointer: u8,
     pub status: u8,
     pub program_counter: u16,
+    pub run_state: RunState,
     pub bus: Bus<'a>,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CpuState {
+    pub register_a: u8,
+    pub register_x: u8,
+    pub register_y: u8,
+    pub stack_pointer: u8,
+    pub status: u8,
+    pub program_counter: u16,
+    pub bus: BusState,
+}
+
+// Bus owns the gameloop callback and the cartridge, so a CPU can't be rebuilt
+// from serialized data alone. Deserialize a CpuState and apply it with restore().
+#[cfg(feature = "serde")]
+impl Serialize for CPU<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.snapshot().serialize(serializer)
+    }
+}
+
 impl<'a> CPU<'a> {
     pub fn new<'b>(bus: Bus<'b>) -> CPU<'b> {
         CPU {
@@ -75,10 +108,68 @@ impl<'a> CPU<'a> {
             stack_pointer: 0xfd,
             status: 0b0010_0100,
             program_counter: 0,
+            run_state: RunState::Running,
             bus: bus,
         }
     }
 
+    pub fn snapshot(&self) -> CpuState {
+        CpuState {
+            register_a: self.register_a,
+            register_x: self.register_x,
+            register_y: self.register_y,
+            stack_pointer: self.stack_pointer,
+            status: self.status,
+            program_counter: self.program_counter,
+            bus: self.bus.snapshot(),
+        }
+    }
+
+    pub fn restore(&mut self, state: &CpuState) -> Result<(), String> {
+        self.bus.restore(&state.bus)?;
+        self.register_a = state.register_a;
+        self.register_x = state.register_x;
+        self.register_y = state.register_y;
+        self.stack_pointer = state.stack_pointer;
+        self.status = state.status;
+        self.program_counter = state.program_counter;
+        Ok(())
+    }
+
+    // マシン全体のセーブステート
+    pub fn state(&mut self, state: &mut State) {
+        self.register_state(state);
+        self.bus.state(state);
+    }
+
+    // 音の出力の状態を除いたstate。ムービーの同期の確認に使う
+    pub fn core_state(&mut self, state: &mut State) {
+        self.register_state(state);
+        self.bus.core_state(state);
+    }
+
+    fn register_state(&mut self, state: &mut State) {
+        state.u8(&mut self.register_a);
+        state.u8(&mut self.register_x);
+        state.u8(&mut self.register_y);
+        state.u8(&mut self.stack_pointer);
+        state.u8(&mut self.status);
+        state.u16(&mut self.program_counter);
+        state.map_u8(
+            &mut self.run_state,
+            |run_state| match run_state {
+                RunState::Running => 0,
+                RunState::Halted => 1,
+                RunState::Jammed => 2,
+            },
+            |byte| match byte {
+                1 => RunState::Halted,
+                2 => RunState::Jammed,
+                _ => RunState::Running,
+            },
+        );
+    }
+
     fn pop_stack(&mut self) -> u8 {
         self.stack_pointer = self.stack_pointer.wrapping_add(1);
         self.mem_read(0x0100 as u16 + self.stack_pointer as u16)
@@ -103,7 +194,7 @@ impl<'a> CPU<'a> {
     }
 
     fn adc(&mut self, mode: &AddressingMode) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let mem_value = self.mem_read(addr);
 
         let a = self.register_a.clone();
@@ -128,21 +219,13 @@ impl<'a> CPU<'a> {
         // set accumulator
         self.register_a = result;
         self.update_zero_and_negative_flags(self.register_a);
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn and(&mut self, mode: &AddressingMode) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let value = self.mem_read(addr);
         self.register_a &= value;
         self.update_zero_and_negative_flags(self.register_a);
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn asl_a(&mut self) {
@@ -250,7 +333,7 @@ impl<'a> CPU<'a> {
     }
 
     fn cmp(&mut self, mode: &AddressingMode, reg_value: u8) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let mem_value = self.mem_read(addr);
 
         if reg_value >= mem_value {
@@ -260,10 +343,6 @@ impl<'a> CPU<'a> {
         }
 
         self.update_zero_and_negative_flags(reg_value.wrapping_sub(mem_value));
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn dec(&mut self, mode: &AddressingMode) {
@@ -293,14 +372,10 @@ impl<'a> CPU<'a> {
     }
 
     fn eor(&mut self, mode: &AddressingMode) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let value = self.mem_read(addr);
         self.register_a ^= value;
         self.update_zero_and_negative_flags(self.register_a);
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn inc(&mut self, mode: &AddressingMode) -> u8 {
@@ -356,36 +431,24 @@ impl<'a> CPU<'a> {
     }
 
     fn lda(&mut self, mode: &AddressingMode) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let value = self.mem_read(addr);
         self.register_a = value;
         self.update_zero_and_negative_flags(self.register_a);
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn ldx(&mut self, mode: &AddressingMode) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let value = self.mem_read(addr);
         self.register_x = value;
         self.update_zero_and_negative_flags(self.register_x);
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn ldy(&mut self, mode: &AddressingMode) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let value = self.mem_read(addr);
         self.register_y = value;
         self.update_zero_and_negative_flags(self.register_y);
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn lsr_a(&mut self) {
@@ -415,14 +478,10 @@ impl<'a> CPU<'a> {
     }
 
     fn ora(&mut self, mode: &AddressingMode) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let value = self.mem_read(addr);
         self.register_a |= value;
         self.update_zero_and_negative_flags(self.register_a);
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn pha(&mut self) {
@@ -526,7 +585,7 @@ impl<'a> CPU<'a> {
     }
 
     fn sbc(&mut self, mode: &AddressingMode) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let mem_value = self.mem_read(addr);
 
         let a = self.register_a.clone();
@@ -557,10 +616,6 @@ impl<'a> CPU<'a> {
         // set accumulator
         self.register_a = result;
         self.update_zero_and_negative_flags(self.register_a);
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn sec(&mut self) {
@@ -623,10 +678,13 @@ impl<'a> CPU<'a> {
         if condition {
             // -128~127
             let jump = self.mem_read(self.program_counter) as i8;
-            let jump_addr = self
-                .program_counter
-                .wrapping_add(1)
-                .wrapping_add(jump as u16);
+            let next_addr = self.program_counter.wrapping_add(1);
+            let jump_addr = next_addr.wrapping_add(jump as u16);
+            // 分岐成立で+1、ページをまたぐとさらに+1サイクル
+            self.bus.tick(1);
+            if next_addr & 0xFF00 != jump_addr & 0xFF00 {
+                self.bus.tick(1);
+            }
             self.program_counter = jump_addr;
         }
     }
@@ -768,47 +826,48 @@ impl<'a> CPU<'a> {
         }
     }
 
-    pub fn get_absolute_address(&mut self, mode: &AddressingMode, addr: u16) -> u16 {
+    // トレース用。Bus::peekで読むので状態を変えない
+    pub fn get_absolute_address(&self, mode: &AddressingMode, addr: u16) -> u16 {
         match mode {
-            AddressingMode::ZeroPage => self.mem_read(addr) as u16,
+            AddressingMode::ZeroPage => self.bus.peek(addr) as u16,
 
-            AddressingMode::Absolute => self.mem_read_u16(addr),
+            AddressingMode::Absolute => self.bus.peek_u16(addr),
 
             AddressingMode::ZeroPage_X => {
-                let pos = self.mem_read(addr);
+                let pos = self.bus.peek(addr);
                 let addr = pos.wrapping_add(self.register_x) as u16;
                 addr
             }
             AddressingMode::ZeroPage_Y => {
-                let pos = self.mem_read(addr);
+                let pos = self.bus.peek(addr);
                 let addr = pos.wrapping_add(self.register_y) as u16;
                 addr
             }
 
             AddressingMode::Absolute_X => {
-                let base = self.mem_read_u16(addr);
+                let base = self.bus.peek_u16(addr);
                 let addr = base.wrapping_add(self.register_x as u16);
                 addr
             }
             AddressingMode::Absolute_Y => {
-                let base = self.mem_read_u16(addr);
+                let base = self.bus.peek_u16(addr);
                 let addr = base.wrapping_add(self.register_y as u16);
                 addr
             }
 
             AddressingMode::Indirect_X => {
-                let base = self.mem_read(addr);
+                let base = self.bus.peek(addr);
 
                 let ptr: u8 = (base as u8).wrapping_add(self.register_x);
-                let lo = self.mem_read(ptr as u16);
-                let hi = self.mem_read(ptr.wrapping_add(1) as u16);
+                let lo = self.bus.peek(ptr as u16);
+                let hi = self.bus.peek(ptr.wrapping_add(1) as u16);
                 (hi as u16) << 8 | (lo as u16)
             }
             AddressingMode::Indirect_Y => {
-                let base = self.mem_read(addr);
+                let base = self.bus.peek(addr);
 
-                let lo = self.mem_read(base as u16);
-                let hi = self.mem_read((base as u8).wrapping_add(1) as u16);
+                let lo = self.bus.peek(base as u16);
+                let hi = self.bus.peek((base as u8).wrapping_add(1) as u16);
                 let deref_base = (hi as u16) << 8 | (lo as u16);
                 let deref = deref_base.wrapping_add(self.register_y as u16);
                 deref
@@ -820,6 +879,7 @@ impl<'a> CPU<'a> {
     }
 
     fn interrupt(&mut self, interrupt: interrupts::Interrupt) {
+        self.bus.note_interrupt(&interrupt.itype);
         self.push_stack_u16(self.program_counter);
         let mut flag = self.status.clone();
         if interrupt.b_flag_mask & 0b010000 == 1 {
@@ -852,7 +912,23 @@ impl<'a> CPU<'a> {
         self.register_y = 0;
         self.stack_pointer = 0xFD;
         self.status = 0b0010_0100;
+        self.run_state = RunState::Running;
         self.program_counter = self.mem_read_u16(0xFFFC);
+        // リセットシーケンスは7サイクルかかる
+        self.bus.tick(7);
+    }
+
+    pub fn console_status(&self) -> ConsoleStatus {
+        let frames = self.bus.frame_counter();
+        ConsoleStatus {
+            run_state: self.run_state,
+            region: self.bus.region(),
+            frame: frames.frame,
+            fps: frames.fps,
+            interrupts_last_frame: frames.interrupts_last_frame,
+            irq_storm: frames.irq_storm(),
+            bus_stats: self.bus.stats(),
+        }
     }
 
     pub fn load(&mut self, program: Vec<u8>) {
@@ -876,291 +952,315 @@ impl<'a> CPU<'a> {
     where
         F: FnMut(&mut CPU),
     {
-        let ref opcodes = OPCODES_MAP;
+        while self.step_with_callback(&mut callback) {}
+    }
 
-        loop {
-            if let Some(_nmi) = self.bus.poll_nmi_status() {
-                self.interrupt(interrupts::NMI);
-            }
+    // 割り込みを処理した後、命令を実行する直前にcallbackを呼ぶ
+    pub fn step_with_callback<F>(&mut self, mut callback: F) -> bool
+    where
+        F: FnMut(&mut CPU),
+    {
+        self.poll_interrupts();
+        callback(self);
+        self.execute_instruction()
+    }
 
-            callback(self);
+    // 1命令だけ実行する。BRKに到達したらfalseを返す
+    pub fn step(&mut self) -> bool {
+        self.step_with_callback(|_| {})
+    }
 
-            let code = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            let program_counter_state = self.program_counter;
-            let opcode = opcodes.get(&code).unwrap();
+    fn poll_interrupts(&mut self) {
+        if let Some(_nmi) = self.bus.poll_nmi_status() {
+            self.interrupt(interrupts::NMI);
+        } else if self.bus.poll_irq() && self.status & 0b0000_0100 == 0 {
+            // IRQはIフラグが立っている間はマスクされる
+            self.interrupt(interrupts::IRQ);
+        }
+    }
 
-            match code {
-                0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode),
-                0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode),
-                0x0a => self.asl_a(),
-                0x06 | 0x16 | 0x0e | 0x1e => {
-                    self.asl_m(&opcode.mode);
-                }
-                0x90 => self.bcc(),
-                0xB0 => self.bcs(),
-                0xF0 => self.beq(),
-                0x24 | 0x2C => self.bit(&opcode.mode),
-                0x30 => self.bmi(),
-                0xD0 => self.bne(),
-                0x10 => self.bpl(),
-                0x00 => return self.brk(),
-                0x50 => self.bvc(),
-                0x70 => self.bvs(),
-                0x18 => self.clc(),
-                0xD8 => self.cld(),
-                0x58 => self.cli(),
-                0xB8 => self.clv(),
-                0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
-                    self.cmp(&opcode.mode, self.register_a)
-                }
-                0xE0 | 0xE4 | 0xEC => self.cmp(&opcode.mode, self.register_x),
-                0xC0 | 0xC4 | 0xCc => self.cmp(&opcode.mode, self.register_y),
-                0xC6 | 0xD6 | 0xCE | 0xDE => self.dec(&opcode.mode),
-                0xCA => self.dex(),
-                0x88 => self.dey(),
-                0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => self.eor(&opcode.mode),
-                0xE6 | 0xF6 | 0xEE | 0xFE => {
-                    self.inc(&opcode.mode);
-                }
-                0xE8 => self.inx(),
-                0xc8 => self.iny(),
-                0x20 => self.jsr(),
-                0x4c => self.jmp_absolute(),
-                0x6c => self.jmp_indirect(),
-                0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => self.lda(&opcode.mode),
-                0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => self.ldx(&opcode.mode),
-                0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => self.ldy(&opcode.mode),
-                0x4a => self.lsr_a(),
-                0x46 | 0x56 | 0x4e | 0x5e => {
-                    self.lsr_m(&opcode.mode);
-                }
-                0xEA => {}
-                0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => self.ora(&opcode.mode),
-                0x48 => self.pha(),
-                0x08 => self.php(),
-                0x68 => self.pla(),
-                0x28 => self.plp(),
-                0x2a => self.rol_a(),
-                0x26 | 0x36 | 0x2e | 0x3e => {
-                    self.rol_m(&opcode.mode);
-                }
-                0x6a => self.ror_a(),
-                0x66 | 0x76 | 0x6e | 0x7e => {
-                    self.ror_m(&opcode.mode);
-                }
-                0x40 => self.rti(),
-                0x60 => self.rts(),
-                0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => self.sbc(&opcode.mode),
-                0x38 => self.sec(),
-                0xf8 => self.sed(),
-                0x78 => self.sei(),
-                0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => self.sta(&opcode.mode),
-                0x86 | 0x96 | 0x8E => self.stx(&opcode.mode),
-                0x84 | 0x94 | 0x8C => self.sty(&opcode.mode),
-                0xAA => self.tax(),
-                0xA8 => self.tay(),
-                0xBA => self.tsx(),
-                0x8A => self.txa(),
-                0x9A => self.txs(),
-                0x98 => self.tya(),
-                // unofficial opcodes
-                // https://www.nesdev.org/wiki/Programming_with_unofficial_opcodes
-                /* NOPs */
-                // IGN a/ IGN a,X/ IGN d / IGN d,X
-                0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c
-                | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
-                    let (addr, page_crossed) = self.get_operand_address(&opcode.mode);
-                    self.mem_read(addr);
-
-                    if page_crossed {
-                        self.bus.tick(1);
-                    }
-                }
-                0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2
-                | 0xf2 => {}
-                // NOP
-                0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => {}
-                // SKB
-                0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => {}
-                /* LAX */
-                0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.register_a = data;
-                    self.update_zero_and_negative_flags(self.register_a);
-                    self.register_x = self.register_a;
-                }
-                /* SAX */
-                0x87 | 0x97 | 0x8f | 0x83 => {
-                    let data = self.register_a & self.register_x;
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    self.mem_write(addr, data);
-                }
-                /* SBC */
-                0xeb => self.sbc(&opcode.mode),
-                /* DCP */
-                0xc7 | 0xd7 | 0xCF | 0xdF | 0xdb | 0xd3 | 0xc3 => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let mut data = self.mem_read(addr);
-                    data = data.wrapping_sub(1);
-                    self.mem_write(addr, data);
-
-                    if data <= self.register_a {
-                        self.status = self.status | 0x0000_0001;
-                    }
-
-                    self.update_zero_and_negative_flags(self.register_a.wrapping_sub(data));
-                }
-                /* ISB */
-                0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => self.unofficial_isb(&opcode.mode),
-                /* SLO */
-                0x07 | 0x17 | 0x0F | 0x1f | 0x1b | 0x03 | 0x13 => self.unofficial_slo(&opcode.mode),
-                /* RLA */
-                0x27 | 0x37 | 0x2F | 0x3F | 0x3b | 0x33 | 0x23 => self.unofficial_rla(&opcode.mode),
-                /* SRE */
-                0x47 | 0x57 | 0x4F | 0x5f | 0x5b | 0x43 | 0x53 => self.unofficial_sre(&opcode.mode),
-                /* RRA */
-                0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => self.unofficial_rra(&opcode.mode),
-                /* AXS */
-                0xCB => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    let x_and_a = self.register_x & self.register_a;
-                    let result = x_and_a.wrapping_sub(data);
-
-                    if data <= x_and_a {
-                        self.status = self.status | 0b0000_0001;
-                    }
-                    self.update_zero_and_negative_flags(result);
-
-                    self.register_x = result;
-                }
-                /* ARR */
-                0x6B => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.register_a = data & self.register_a;
-                    self.update_zero_and_negative_flags(self.register_a);
-                    self.ror_a();
-
-                    let result = self.register_a;
-                    let bit_5 = (result >> 5) & 1;
-                    let bit_6 = (result >> 6) & 1;
-
-                    if bit_6 == 1 {
-                        self.status = self.status | 0b0000_0001;
-                    } else {
-                        self.status = self.status & 0b1111_1110;
-                    }
-
-                    if bit_5 ^ bit_6 == 1 {
-                        self.status = self.status | 0b0100_0000;
-                    } else {
-                        self.status = self.status & 0b1011_1111;
-                    }
-
-                    self.update_zero_and_negative_flags(result);
-                }
-                /* ANC */
-                0x0b | 0x2b => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.register_a = data & self.register_a;
-                    self.update_zero_and_negative_flags(self.register_a);
-                    if self.status == 0b1000_0000 {
-                        self.status = self.status | 0b0000_0001;
-                    } else {
-                        self.status = self.status & 0b1111_1110;
-                    }
-                }
-                /* ALR */
-                0x4b => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.register_a = data & self.register_a;
-                    self.update_zero_and_negative_flags(self.register_a);
-                    self.lsr_a();
-                }
-                /* LXA */
-                0xab => {
-                    self.lda(&opcode.mode);
-                    self.tax();
-                }
-                /* XAA */
-                0x8b => {
-                    self.register_a = self.register_x;
-                    self.update_zero_and_negative_flags(self.register_a);
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let data = self.mem_read(addr);
-                    self.register_a = data & self.register_a;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
-                /* LAS */
-                0xbb => {
-                    let (addr, _) = self.get_operand_address(&opcode.mode);
-                    let mut data = self.mem_read(addr);
-                    data = data & self.stack_pointer;
-                    self.register_a = data;
-                    self.register_x = data;
-                    self.stack_pointer = data;
-                    self.update_zero_and_negative_flags(data);
-                }
-                /* TAS */
-                0x9b => {
-                    let data = self.register_a & self.register_x;
-                    self.stack_pointer = data;
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_y as u16;
-
-                    let data = ((mem_address >> 8) as u8 + 1) & self.stack_pointer;
-                    self.mem_write(mem_address, data)
+    fn execute_instruction(&mut self) -> bool {
+        let ref opcodes = OPCODES_MAP;
+
+        let code = self.mem_read(self.program_counter);
+        self.program_counter += 1;
+        let program_counter_state = self.program_counter;
+        let opcode = opcodes.get(&code).unwrap();
+        let page_crossed = opcode.page_cross_penalty && self.get_operand_address(&opcode.mode).1;
+
+        match code {
+            0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode),
+            0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode),
+            0x0a => self.asl_a(),
+            0x06 | 0x16 | 0x0e | 0x1e => {
+                self.asl_m(&opcode.mode);
+            }
+            0x90 => self.bcc(),
+            0xB0 => self.bcs(),
+            0xF0 => self.beq(),
+            0x24 | 0x2C => self.bit(&opcode.mode),
+            0x30 => self.bmi(),
+            0xD0 => self.bne(),
+            0x10 => self.bpl(),
+            0x00 => {
+                self.brk();
+                self.run_state = RunState::Halted;
+                return false;
+            }
+            0x50 => self.bvc(),
+            0x70 => self.bvs(),
+            0x18 => self.clc(),
+            0xD8 => self.cld(),
+            0x58 => self.cli(),
+            0xB8 => self.clv(),
+            0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => {
+                self.cmp(&opcode.mode, self.register_a)
+            }
+            0xE0 | 0xE4 | 0xEC => self.cmp(&opcode.mode, self.register_x),
+            0xC0 | 0xC4 | 0xCc => self.cmp(&opcode.mode, self.register_y),
+            0xC6 | 0xD6 | 0xCE | 0xDE => self.dec(&opcode.mode),
+            0xCA => self.dex(),
+            0x88 => self.dey(),
+            0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => self.eor(&opcode.mode),
+            0xE6 | 0xF6 | 0xEE | 0xFE => {
+                self.inc(&opcode.mode);
+            }
+            0xE8 => self.inx(),
+            0xc8 => self.iny(),
+            0x20 => self.jsr(),
+            0x4c => self.jmp_absolute(),
+            0x6c => self.jmp_indirect(),
+            0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => self.lda(&opcode.mode),
+            0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => self.ldx(&opcode.mode),
+            0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => self.ldy(&opcode.mode),
+            0x4a => self.lsr_a(),
+            0x46 | 0x56 | 0x4e | 0x5e => {
+                self.lsr_m(&opcode.mode);
+            }
+            0xEA => {}
+            0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => self.ora(&opcode.mode),
+            0x48 => self.pha(),
+            0x08 => self.php(),
+            0x68 => self.pla(),
+            0x28 => self.plp(),
+            0x2a => self.rol_a(),
+            0x26 | 0x36 | 0x2e | 0x3e => {
+                self.rol_m(&opcode.mode);
+            }
+            0x6a => self.ror_a(),
+            0x66 | 0x76 | 0x6e | 0x7e => {
+                self.ror_m(&opcode.mode);
+            }
+            0x40 => self.rti(),
+            0x60 => self.rts(),
+            0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => self.sbc(&opcode.mode),
+            0x38 => self.sec(),
+            0xf8 => self.sed(),
+            0x78 => self.sei(),
+            0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => self.sta(&opcode.mode),
+            0x86 | 0x96 | 0x8E => self.stx(&opcode.mode),
+            0x84 | 0x94 | 0x8C => self.sty(&opcode.mode),
+            0xAA => self.tax(),
+            0xA8 => self.tay(),
+            0xBA => self.tsx(),
+            0x8A => self.txa(),
+            0x9A => self.txs(),
+            0x98 => self.tya(),
+            // unofficial opcodes
+            // https://www.nesdev.org/wiki/Programming_with_unofficial_opcodes
+            /* NOPs */
+            // IGN a/ IGN a,X/ IGN d / IGN d,X
+            0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c | 0x3c
+            | 0x5c | 0x7c | 0xdc | 0xfc => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                self.mem_read(addr);
+            }
+            /* KIL */
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2 | 0xf2 => {
+                self.program_counter -= 1;
+                self.run_state = RunState::Jammed;
+                return false;
+            }
+            // NOP
+            0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => {}
+            // SKB
+            0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => {}
+            /* LAX */
+            0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.register_a = data;
+                self.update_zero_and_negative_flags(self.register_a);
+                self.register_x = self.register_a;
+            }
+            /* SAX */
+            0x87 | 0x97 | 0x8f | 0x83 => {
+                let data = self.register_a & self.register_x;
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                self.mem_write(addr, data);
+            }
+            /* SBC */
+            0xeb => self.sbc(&opcode.mode),
+            /* DCP */
+            0xc7 | 0xd7 | 0xCF | 0xdF | 0xdb | 0xd3 | 0xc3 => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let mut data = self.mem_read(addr);
+                data = data.wrapping_sub(1);
+                self.mem_write(addr, data);
+
+                if data <= self.register_a {
+                    self.status = self.status | 0x0000_0001;
                 }
-                /* AHX  Indirect Y */
-                0x93 => {
-                    let pos: u8 = self.mem_read(self.program_counter);
-                    let mem_address = self.mem_read_u16(pos as u16) + self.register_y as u16;
-                    let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                    self.mem_write(mem_address, data)
+
+                self.update_zero_and_negative_flags(self.register_a.wrapping_sub(data));
+            }
+            /* ISB */
+            0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => self.unofficial_isb(&opcode.mode),
+            /* SLO */
+            0x07 | 0x17 | 0x0F | 0x1f | 0x1b | 0x03 | 0x13 => self.unofficial_slo(&opcode.mode),
+            /* RLA */
+            0x27 | 0x37 | 0x2F | 0x3F | 0x3b | 0x33 | 0x23 => self.unofficial_rla(&opcode.mode),
+            /* SRE */
+            0x47 | 0x57 | 0x4F | 0x5f | 0x5b | 0x43 | 0x53 => self.unofficial_sre(&opcode.mode),
+            /* RRA */
+            0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => self.unofficial_rra(&opcode.mode),
+            /* AXS */
+            0xCB => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                let x_and_a = self.register_x & self.register_a;
+                let result = x_and_a.wrapping_sub(data);
+
+                if data <= x_and_a {
+                    self.status = self.status | 0b0000_0001;
                 }
-                /* AHX Absolute Y*/
-                0x9f => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_y as u16;
+                self.update_zero_and_negative_flags(result);
 
-                    let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
-                    self.mem_write(mem_address, data)
+                self.register_x = result;
+            }
+            /* ARR */
+            0x6B => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.register_a = data & self.register_a;
+                self.update_zero_and_negative_flags(self.register_a);
+                self.ror_a();
+
+                let result = self.register_a;
+                let bit_5 = (result >> 5) & 1;
+                let bit_6 = (result >> 6) & 1;
+
+                if bit_6 == 1 {
+                    self.status = self.status | 0b0000_0001;
+                } else {
+                    self.status = self.status & 0b1111_1110;
                 }
-                /* SHX */
-                0x9e => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_y as u16;
 
-                    let data = self.register_x & ((mem_address >> 8) as u8 + 1);
-                    self.mem_write(mem_address, data)
+                if bit_5 ^ bit_6 == 1 {
+                    self.status = self.status | 0b0100_0000;
+                } else {
+                    self.status = self.status & 0b1011_1111;
                 }
-                /* SHY */
-                0x9c => {
-                    let mem_address =
-                        self.mem_read_u16(self.program_counter) + self.register_x as u16;
-                    let data = self.register_y & ((mem_address >> 8) as u8 + 1);
-                    self.mem_write(mem_address, data)
+
+                self.update_zero_and_negative_flags(result);
+            }
+            /* ANC */
+            0x0b | 0x2b => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.register_a = data & self.register_a;
+                self.update_zero_and_negative_flags(self.register_a);
+                if self.status == 0b1000_0000 {
+                    self.status = self.status | 0b0000_0001;
+                } else {
+                    self.status = self.status & 0b1111_1110;
                 }
             }
+            /* ALR */
+            0x4b => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.register_a = data & self.register_a;
+                self.update_zero_and_negative_flags(self.register_a);
+                self.lsr_a();
+            }
+            /* LXA */
+            0xab => {
+                self.lda(&opcode.mode);
+                self.tax();
+            }
+            /* XAA */
+            0x8b => {
+                self.register_a = self.register_x;
+                self.update_zero_and_negative_flags(self.register_a);
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let data = self.mem_read(addr);
+                self.register_a = data & self.register_a;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
+            /* LAS */
+            0xbb => {
+                let (addr, _) = self.get_operand_address(&opcode.mode);
+                let mut data = self.mem_read(addr);
+                data = data & self.stack_pointer;
+                self.register_a = data;
+                self.register_x = data;
+                self.stack_pointer = data;
+                self.update_zero_and_negative_flags(data);
+            }
+            /* TAS */
+            0x9b => {
+                let data = self.register_a & self.register_x;
+                self.stack_pointer = data;
+                let mem_address = self.mem_read_u16(self.program_counter) + self.register_y as u16;
+
+                let data = ((mem_address >> 8) as u8 + 1) & self.stack_pointer;
+                self.mem_write(mem_address, data)
+            }
+            /* AHX  Indirect Y */
+            0x93 => {
+                let pos: u8 = self.mem_read(self.program_counter);
+                let mem_address = self.mem_read_u16(pos as u16) + self.register_y as u16;
+                let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
+                self.mem_write(mem_address, data)
+            }
+            /* AHX Absolute Y*/
+            0x9f => {
+                let mem_address = self.mem_read_u16(self.program_counter) + self.register_y as u16;
 
-            self.bus.tick(opcode.cycles);
+                let data = self.register_a & self.register_x & (mem_address >> 8) as u8;
+                self.mem_write(mem_address, data)
+            }
+            /* SHX */
+            0x9e => {
+                let mem_address = self.mem_read_u16(self.program_counter) + self.register_y as u16;
 
-            if program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+                let data = self.register_x & ((mem_address >> 8) as u8 + 1);
+                self.mem_write(mem_address, data)
+            }
+            /* SHY */
+            0x9c => {
+                let mem_address = self.mem_read_u16(self.program_counter) + self.register_x as u16;
+                let data = self.register_y & ((mem_address >> 8) as u8 + 1);
+                self.mem_write(mem_address, data)
             }
         }
+
+        self.bus.tick(opcode.cycles);
+        if page_crossed {
+            self.bus.tick(1);
+        }
+
+        if program_counter_state == self.program_counter {
+            self.program_counter += (opcode.len - 1) as u16;
+        }
+
+        true
     }
 }
 
 #[cfg(test)]
 mod test {
-    // TODO: AND/EOR/ORA
-    // TODO: ASL/LSR/ROL/ROR
     // TODO: PHP/PLA/PLP
     // TODO: RTI/RTS
     // TODO: JSR/JMP
@@ -1168,4 +1268,159 @@ mod test {
     // TODO: CMP/CPX/CPY
     // TODO: BCC/BCS/BEQ/BMI/BNE/BPL/BVC/BVS/BIT
     // TODO: ADC
+
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::joypad::Joypad;
+    use crate::ppu::NesPPU;
+
+    fn test_cpu<'a>() -> CPU<'a> {
+        let bus = Bus::new(
+            test_rom(),
+            |_ppu: &NesPPU, _joypad: &mut Joypad, _: &mut Joypad| {},
+        )
+        .unwrap();
+        CPU::new(bus)
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut cpu = test_cpu();
+        cpu.register_a = 0x11;
+        cpu.register_x = 0x22;
+        cpu.register_y = 0x33;
+        cpu.program_counter = 0x8123;
+        cpu.mem_write(0x10, 0x44);
+        cpu.bus.tick(7);
+        let state = cpu.snapshot();
+
+        let mut other = test_cpu();
+        other.restore(&state).unwrap();
+
+        assert_eq!(other.register_a, 0x11);
+        assert_eq!(other.register_x, 0x22);
+        assert_eq!(other.register_y, 0x33);
+        assert_eq!(other.program_counter, 0x8123);
+        assert_eq!(other.mem_read(0x10), 0x44);
+        assert_eq!(other.bus.cycles(), 7);
+        assert_eq!(other.snapshot(), state);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let mut cpu = test_cpu();
+        cpu.register_a = 0x7f;
+        cpu.status = 0b1010_0101;
+        cpu.stack_pointer = 0xf0;
+        cpu.mem_write(0x07ff, 0x99);
+        for _ in 0..10 {
+            cpu.bus.tick(7);
+        }
+
+        let json = serde_json::to_string(&cpu).unwrap();
+        let state: CpuState = serde_json::from_str(&json).unwrap();
+        assert_eq!(state, cpu.snapshot());
+
+        let mut other = test_cpu();
+        other.restore(&state).unwrap();
+        assert_eq!(other.snapshot(), cpu.snapshot());
+    }
+
+    #[test]
+    fn test_console_status() {
+        let mut cpu = test_cpu();
+        cpu.load(vec![0xe8, 0x00]);
+        cpu.program_counter = 0x0600;
+        assert_eq!(cpu.console_status().run_state, RunState::Running);
+        cpu.run();
+        let status = cpu.console_status();
+        assert_eq!(status.run_state, RunState::Halted);
+        assert_eq!(status.region, crate::console::Region::Ntsc);
+        assert!(!status.irq_storm);
+
+        cpu.load(vec![0xe8, 0x02, 0xe8]);
+        cpu.program_counter = 0x0600;
+        cpu.run();
+        assert_eq!(cpu.console_status().run_state, RunState::Jammed);
+        assert_eq!(cpu.program_counter, 0x0601);
+        assert_eq!(cpu.register_x, 2);
+    }
+
+    #[test]
+    fn test_console_status_counts_frames_and_interrupts() {
+        let mut cpu = test_cpu();
+        // JMP $0600 の無限ループにNMIを入れ続ける
+        cpu.load(vec![0x4c, 0x00, 0x06]);
+        cpu.program_counter = 0x0600;
+        cpu.bus.ppu_mut().set_warmup(false);
+        cpu.mem_write(0x2000, 0b1000_0000);
+        for _ in 0..(3 * 262 * 341 / 9) {
+            if cpu.console_status().frame == 2 {
+                break;
+            }
+            cpu.step();
+        }
+        let status = cpu.console_status();
+        assert_eq!(status.frame, 2);
+        assert_eq!(status.interrupts_last_frame, 1);
+    }
+
+    #[cfg(feature = "assembler")]
+    fn run_asm(source: &str) -> CPU<'static> {
+        let mut cpu = test_cpu();
+        cpu.load(crate::opcodes::assemble(source));
+        cpu.program_counter = 0x0600;
+        cpu.run();
+        cpu
+    }
+
+    #[cfg(feature = "assembler")]
+    #[test]
+    fn test_and_eor_ora() {
+        let cpu = run_asm("LDA #$f0; AND #$3c; BRK");
+        assert_eq!(cpu.register_a, 0x30);
+
+        let cpu = run_asm("LDA #$0f; STA $10; LDA #$ff; EOR $10; BRK");
+        assert_eq!(cpu.register_a, 0xf0);
+        assert_eq!(cpu.status & 0b1000_0000, 0b1000_0000);
+
+        let cpu = run_asm("LDA #$00; LDX #$02; STA $10,X; ORA $12; BRK");
+        assert_eq!(cpu.register_a, 0x00);
+        assert_eq!(cpu.status & 0b0000_0010, 0b0000_0010);
+    }
+
+    #[cfg(feature = "assembler")]
+    #[test]
+    fn test_asl_lsr_rol_ror() {
+        let cpu = run_asm("LDA #$81; ASL A; BRK");
+        assert_eq!(cpu.register_a, 0x02);
+        assert_eq!(cpu.status & 0b0000_0001, 1);
+
+        let cpu = run_asm("LDA #$01; LSR A; BRK");
+        assert_eq!(cpu.register_a, 0x00);
+        assert_eq!(cpu.status & 0b0000_0011, 0b0000_0011);
+
+        let mut cpu = run_asm("SEC; LDA #$40; STA $20; ROL $20; BRK");
+        assert_eq!(cpu.mem_read(0x20), 0x81);
+        assert_eq!(cpu.status & 0b0000_0001, 0);
+
+        let cpu = run_asm("SEC; LDA #$02; ROR A; BRK");
+        assert_eq!(cpu.register_a, 0x81);
+        assert_eq!(cpu.status & 0b1000_0000, 0b1000_0000);
+    }
+
+    #[cfg(feature = "assembler")]
+    #[test]
+    fn test_page_cross_penalty() {
+        let cpu = run_asm("LDX #$01; LDA $0010,X; BRK");
+        assert_eq!(cpu.bus.cycles(), 2 + 4);
+
+        let cpu = run_asm("LDX #$01; LDA $00ff,X; BRK");
+        assert_eq!(cpu.bus.cycles(), 2 + 4 + 1);
+
+        // stores always take the fixed cycle count
+        let cpu = run_asm("LDX #$01; STA $00ff,X; BRK");
+        assert_eq!(cpu.bus.cycles(), 2 + 5);
+    }
 }
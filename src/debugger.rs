@@ -0,0 +1,251 @@
+use std::collections::VecDeque;
+
+use crate::controller::ControllerPort;
+use crate::cpu::CPU;
+use crate::savestate::State;
+
+// stateはCPU::stateのセーブステートで、マッパーやAPU、PRG RAMも含む。
+// 挿さっていた機器は種類ごと戻せるように別に持っておく
+struct Checkpoint {
+    instruction: u64,
+    state: Vec<u8>,
+    ports: [Box<dyn ControllerPort>; 2],
+}
+
+// 一定命令ごとにマシン全体のスナップショットを取り、巻き戻しは
+// 直前のチェックポイントに戻してから目的の命令まで再実行することで実現する。
+// 再実行中もgameloopコールバックは呼ばれるので、入力はその間変化しない前提。
+pub struct Debugger {
+    checkpoints: VecDeque<Checkpoint>,
+    history: VecDeque<u16>,
+    instruction_count: u64,
+    interval: u64,
+    max_checkpoints: usize,
+    history_size: usize,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger::with_capacity(1000, 100, 4096)
+    }
+
+    pub fn with_capacity(interval: u64, max_checkpoints: usize, history_size: usize) -> Self {
+        Debugger {
+            checkpoints: VecDeque::new(),
+            history: VecDeque::new(),
+            instruction_count: 0,
+            interval: interval.max(1),
+            max_checkpoints: max_checkpoints.max(1),
+            history_size,
+        }
+    }
+
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    // 実行した命令のPCを古い順に返す
+    pub fn history(&self) -> impl Iterator<Item = &u16> {
+        self.history.iter()
+    }
+
+    pub fn step(&mut self, cpu: &mut CPU) -> bool {
+        if self.instruction_count.is_multiple_of(self.interval) {
+            self.save_checkpoint(cpu);
+        }
+
+        if self.history_size > 0 {
+            if self.history.len() == self.history_size {
+                self.history.pop_front();
+            }
+            self.history.push_back(cpu.program_counter);
+        }
+
+        self.instruction_count += 1;
+        cpu.step()
+    }
+
+    // 1命令巻き戻す。履歴が残っていなければfalse
+    pub fn step_back(&mut self, cpu: &mut CPU) -> bool {
+        if self.instruction_count == 0 {
+            return false;
+        }
+        let target = self.instruction_count - 1;
+        match self.checkpoints.front() {
+            Some(oldest) if oldest.instruction <= target => {}
+            _ => return false,
+        }
+
+        while let Some(checkpoint) = self.checkpoints.back() {
+            if checkpoint.instruction <= target {
+                break;
+            }
+            self.checkpoints.pop_back();
+        }
+        let checkpoint = self.checkpoints.back().unwrap();
+
+        for (port, device) in checkpoint.ports.iter().enumerate() {
+            cpu.bus.connect(port, device.clone_box());
+        }
+        let mut state = State::load(&checkpoint.state);
+        cpu.state(&mut state);
+        state.finish().unwrap();
+        for _ in checkpoint.instruction..target {
+            cpu.step();
+        }
+
+        self.instruction_count = target;
+        self.history.pop_back();
+        true
+    }
+
+    fn save_checkpoint(&mut self, cpu: &mut CPU) {
+        if let Some(last) = self.checkpoints.back() {
+            if last.instruction == self.instruction_count {
+                return;
+            }
+        }
+        // 一番古いチェックポイントのバッファを使い回す
+        let mut buf = if self.checkpoints.len() == self.max_checkpoints {
+            self.checkpoints.pop_front().unwrap().state
+        } else {
+            vec![]
+        };
+        cpu.state(&mut State::save(&mut buf));
+        self.checkpoints.push_back(Checkpoint {
+            instruction: self.instruction_count,
+            state: buf,
+            ports: [cpu.bus.port(0).clone_box(), cpu.bus.port(1).clone_box()],
+        });
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::Mem;
+    use crate::joypad::Joypad;
+    use crate::ppu::NesPPU;
+
+    fn test_cpu<'a>() -> CPU<'a> {
+        let bus = Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        // LDX #$00; INX; STX $10; JMP $0602
+        cpu.load(vec![0xa2, 0x00, 0xe8, 0x86, 0x10, 0x4c, 0x02, 0x06]);
+        cpu.program_counter = 0x0600;
+        cpu
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::with_capacity(4, 10, 16);
+
+        let mut states = vec![];
+        for _ in 0..20 {
+            states.push(cpu.snapshot());
+            debugger.step(&mut cpu);
+        }
+        assert_eq!(cpu.register_x, 7);
+
+        for expected in states.iter().rev() {
+            assert!(debugger.step_back(&mut cpu));
+            assert_eq!(&cpu.snapshot(), expected);
+        }
+        assert!(!debugger.step_back(&mut cpu));
+        assert_eq!(cpu.program_counter, 0x0600);
+    }
+
+    fn machine_state(cpu: &mut CPU) -> Vec<u8> {
+        let mut buf = vec![];
+        cpu.state(&mut State::save(&mut buf));
+        buf
+    }
+
+    // バンク切り替えとPRG RAMへの書き込みも巻き戻る
+    #[test]
+    fn test_step_back_mapper() {
+        let mut rom = test_rom();
+        rom.mapper = 69;
+        let bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.load(vec![
+            0xa9, 0x08, 0x8d, 0x00, 0x80, // LDA #$08; STA $8000 (コマンド8)
+            0xa9, 0xc0, 0x8d, 0x00, 0xa0, // LDA #$C0; STA $A000 ($6000にPRG RAM)
+            0xa9, 0x09, 0x8d, 0x00, 0x80, // LDA #$09; STA $8000 (コマンド9)
+            0xa2, 0x00, // LDX #$00
+            0xe8, // INX
+            0x8e, 0x00, 0x60, // STX $6000
+            0x8e, 0x00, 0xa0, // STX $A000 ($8000のバンク)
+            0x4c, 0x11, 0x06, // JMP $0611
+        ]);
+        cpu.program_counter = 0x0600;
+        let mut debugger = Debugger::with_capacity(5, 10, 16);
+
+        let mut states = vec![];
+        for _ in 0..30 {
+            states.push(machine_state(&mut cpu));
+            debugger.step(&mut cpu);
+        }
+        assert_eq!(cpu.mem_read(0x6000), cpu.register_x);
+
+        for expected in states.iter().rev() {
+            assert!(debugger.step_back(&mut cpu));
+            assert!(&machine_state(&mut cpu) == expected);
+        }
+    }
+
+    #[test]
+    fn test_step_back_then_forward() {
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::with_capacity(3, 10, 16);
+
+        for _ in 0..10 {
+            debugger.step(&mut cpu);
+        }
+        let after_ten = cpu.snapshot();
+
+        debugger.step_back(&mut cpu);
+        debugger.step_back(&mut cpu);
+        assert_eq!(debugger.instruction_count(), 8);
+
+        debugger.step(&mut cpu);
+        debugger.step(&mut cpu);
+        assert_eq!(cpu.snapshot(), after_ten);
+        assert_eq!(cpu.mem_read(0x10), cpu.register_x);
+    }
+
+    #[test]
+    fn test_history() {
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::with_capacity(100, 1, 3);
+        for _ in 0..5 {
+            debugger.step(&mut cpu);
+        }
+        let history: Vec<u16> = debugger.history().copied().collect();
+        assert_eq!(history, vec![0x0603, 0x0605, 0x0602]);
+    }
+
+    #[test]
+    fn test_step_back_past_oldest_checkpoint() {
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::with_capacity(2, 2, 16);
+        for _ in 0..6 {
+            debugger.step(&mut cpu);
+        }
+        // checkpoints at 2 and 4 remain, so we can go back to instruction 2
+        for _ in 0..4 {
+            assert!(debugger.step_back(&mut cpu));
+        }
+        assert!(!debugger.step_back(&mut cpu));
+        assert_eq!(debugger.instruction_count(), 2);
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+
+use crate::cpu::CPU;
+use crate::savestate::State;
+
+// FNV-1a。ムービーファイルに保存するのでRustのバージョンで値が変わらないハッシュを使う
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    let mut hash = hash;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+// マッパーやAPU、PRG RAMも含めたマシンの状態のハッシュ。音の出力の状態は
+// サンプリングレートなどで変わるので含めない
+pub fn state_hash(cpu: &mut CPU) -> u64 {
+    let mut buf = vec![];
+    cpu.core_state(&mut State::save(&mut buf));
+    fnv1a(0xcbf2_9ce4_8422_2325, &buf)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Desync {
+    pub frame: u64,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+pub struct DesyncDetector {
+    expected: BTreeMap<u64, u64>,
+    last_good_frame: Option<u64>,
+    first_desync: Option<Desync>,
+}
+
+impl DesyncDetector {
+    pub fn new<I>(expected: I) -> Self
+    where
+        I: IntoIterator<Item = (u64, u64)>,
+    {
+        DesyncDetector {
+            expected: expected.into_iter().collect(),
+            last_good_frame: None,
+            first_desync: None,
+        }
+    }
+
+    // 記録されたハッシュがあるフレームだけ比較する。最初のずれだけを覚えておく
+    pub fn check(&mut self, frame: u64, cpu: &mut CPU) -> Option<&Desync> {
+        if self.first_desync.is_some() {
+            return self.first_desync.as_ref();
+        }
+        let expected = *self.expected.get(&frame)?;
+        let actual = state_hash(cpu);
+        if actual == expected {
+            self.last_good_frame = Some(frame);
+            return None;
+        }
+
+        self.first_desync = Some(Desync {
+            frame,
+            expected,
+            actual,
+        });
+        self.first_desync.as_ref()
+    }
+
+    pub fn first_desync(&self) -> Option<&Desync> {
+        self.first_desync.as_ref()
+    }
+
+    pub fn report(&self) -> String {
+        match (&self.first_desync, self.last_good_frame) {
+            (None, _) => format!("in sync ({} checkpoints)", self.expected.len()),
+            (Some(desync), Some(good)) => format!(
+                "desync at frame {} (expected {:016x}, got {:016x}); last matching frame {}",
+                desync.frame, desync.expected, desync.actual, good
+            ),
+            (Some(desync), None) => format!(
+                "desync at frame {} (expected {:016x}, got {:016x}); no earlier frame matched",
+                desync.frame, desync.expected, desync.actual
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::Mem;
+    use crate::joypad::Joypad;
+    use crate::ppu::NesPPU;
+
+    fn test_cpu<'a>() -> CPU<'a> {
+        let bus = Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        // INC $10; JMP $0600
+        cpu.load(vec![0xe6, 0x10, 0x4c, 0x00, 0x06]);
+        cpu.program_counter = 0x0600;
+        cpu
+    }
+
+    fn record(frames: u64) -> Vec<(u64, u64)> {
+        let mut cpu = test_cpu();
+        (0..frames)
+            .map(|frame| {
+                cpu.step();
+                cpu.step();
+                (frame, state_hash(&mut cpu))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_hash_covers_whole_machine() {
+        let mut cpu = test_cpu();
+        let mut hash = state_hash(&mut cpu);
+        // PRG RAM、APU
+        for (addr, data) in [(0x6000, 1), (0x4000, 0x3f)] {
+            cpu.mem_write(addr, data);
+            let next = state_hash(&mut cpu);
+            assert_ne!(next, hash, "${:04x}", addr);
+            hash = next;
+        }
+        // 音の出力の設定では変わらない
+        cpu.bus.apu_mut().set_sample_rate(22050);
+        assert_eq!(state_hash(&mut cpu), hash);
+
+        // マッパーが持っているPRG RAMとバンクのレジスタ
+        let mut rom = test_rom();
+        rom.mapper = 23;
+        let bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        let mut hash = state_hash(&mut cpu);
+        for (addr, data) in [(0x6000, 1), (0x8000, 3)] {
+            cpu.mem_write(addr, data);
+            let next = state_hash(&mut cpu);
+            assert_ne!(next, hash, "${:04x}", addr);
+            hash = next;
+        }
+    }
+
+    #[test]
+    fn test_in_sync() {
+        let mut detector = DesyncDetector::new(record(10));
+        let mut cpu = test_cpu();
+        for frame in 0..10 {
+            cpu.step();
+            cpu.step();
+            assert_eq!(detector.check(frame, &mut cpu), None);
+        }
+        assert_eq!(detector.report(), "in sync (10 checkpoints)");
+    }
+
+    #[test]
+    fn test_detects_first_divergent_frame() {
+        let mut detector = DesyncDetector::new(record(10).into_iter().step_by(2));
+        let mut cpu = test_cpu();
+        for frame in 0..10 {
+            if frame == 5 {
+                cpu.mem_write(0x20, 1);
+            }
+            cpu.step();
+            cpu.step();
+            detector.check(frame, &mut cpu);
+        }
+        assert_eq!(detector.first_desync().unwrap().frame, 6);
+        assert!(detector.report().contains("last matching frame 4"));
+    }
+}
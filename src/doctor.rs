@@ -0,0 +1,231 @@
+use crate::apu::LengthCounter;
+use crate::bus::Bus;
+use crate::cartridge::Rom;
+use crate::cpu::{Mem, CPU};
+use crate::joypad::Joypad;
+use crate::ppu::NesPPU;
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    Skip(String),
+}
+
+impl From<Result<(), String>> for Outcome {
+    fn from(result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => Outcome::Pass,
+            Err(msg) => Outcome::Fail(msg),
+        }
+    }
+}
+
+type Check = (&'static str, fn() -> Outcome);
+
+pub struct CheckResult {
+    pub name: &'static str,
+    pub outcome: Outcome,
+}
+
+// 外部ROMなしで動かすための最小のNROMイメージ
+fn synthetic_rom() -> Rom {
+    let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 0x00];
+    raw.extend([0; 8]);
+    raw.extend(vec![0xea; 0x4000]);
+    raw.extend(vec![0; 0x2000]);
+    Rom::new(&raw).unwrap()
+}
+
+fn run_program(program: Vec<u8>) -> CPU<'static> {
+    let bus = Bus::new(
+        synthetic_rom(),
+        |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {},
+    )
+    .unwrap();
+    let mut cpu = CPU::new(bus);
+    cpu.load(program);
+    cpu.program_counter = 0x0600;
+    cpu.run();
+    cpu
+}
+
+fn expect(name: &str, actual: u8, expected: u8) -> Result<(), String> {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "{}: expected {:02X}, got {:02X}",
+            name, expected, actual
+        ))
+    }
+}
+
+fn check_cpu_vectors() -> Result<(), String> {
+    // LDA #$C0; TAX; INX
+    let cpu = run_program(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
+    expect("LDA/TAX/INX X", cpu.register_x, 0xc1)?;
+
+    // LDA #$50; ADC #$50 -> overflow and negative set
+    let cpu = run_program(vec![0xa9, 0x50, 0x69, 0x50, 0x00]);
+    expect("ADC A", cpu.register_a, 0xa0)?;
+    expect("ADC P", cpu.status & 0b1100_0001, 0b1100_0000)?;
+
+    // SEC; LDA #$50; SBC #$F0 -> borrow clears carry
+    let cpu = run_program(vec![0x38, 0xa9, 0x50, 0xe9, 0xf0, 0x00]);
+    expect("SBC A", cpu.register_a, 0x60)?;
+    expect("SBC C", cpu.status & 0b0000_0001, 0)?;
+
+    // LDA #$42; PHA; LDA #$00; PLA; STA $10
+    let mut cpu = run_program(vec![0xa9, 0x42, 0x48, 0xa9, 0x00, 0x68, 0x85, 0x10, 0x00]);
+    expect("PHA/PLA", cpu.mem_read(0x10), 0x42)?;
+    expect("SP", cpu.stack_pointer, 0xfd)?;
+
+    // LDX #$03; DEX; BNE -3 -> loops until X is zero
+    let cpu = run_program(vec![0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x00]);
+    expect("DEX/BNE X", cpu.register_x, 0)?;
+
+    Ok(())
+}
+
+fn check_ppu_timing() -> Result<(), String> {
+    let mut ppu = NesPPU::new_empty_rom();
+    ppu.write_to_ctrl(0b1000_0000);
+
+    let mut dots: usize = 0;
+    let mut vblank_at = None;
+    let mut nmi_at = None;
+    let mut frame_at = None;
+    while frame_at.is_none() && dots < 400 * 341 {
+        let new_frame = ppu.tick(3);
+        dots += 3;
+        if vblank_at.is_none() && ppu.status.is_in_vblank() {
+            vblank_at = Some(dots);
+        }
+        if nmi_at.is_none() && ppu.poll_nmi_interrupt().is_some() {
+            nmi_at = Some(dots);
+        }
+        if new_frame {
+            frame_at = Some(dots);
+        }
+    }
+
+    let vblank_at = vblank_at.ok_or("VBlank flag never set")?;
+    let nmi_at = nmi_at.ok_or("NMI never raised")?;
+    let frame_at = frame_at.ok_or("frame never completed")?;
+
+    let within = |dots: usize, scanline: usize| dots.abs_diff(scanline * 341) <= 2 * 341;
+    if !within(vblank_at, 241) {
+        return Err(format!("VBlank started after {} dots", vblank_at));
+    }
+    if nmi_at != vblank_at {
+        return Err(format!("NMI at {} dots, VBlank at {}", nmi_at, vblank_at));
+    }
+    if !within(frame_at, 262) {
+        return Err(format!("frame completed after {} dots", frame_at));
+    }
+    if ppu.status.is_in_vblank() {
+        return Err("VBlank flag not cleared at end of frame".to_string());
+    }
+    Ok(())
+}
+
+fn check_apu_length_table() -> Result<(), String> {
+    // nesdev wikiの長さカウンタ表
+    let hardware = [
+        10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96,
+        22, 192, 24, 72, 26, 16, 28, 32, 30,
+    ];
+    for (index, expected) in hardware.iter().enumerate() {
+        let mut length = LengthCounter::new();
+        length.set_enabled(true);
+        length.load(index as u8);
+        let mut clocks: u8 = 0;
+        while length.is_active() {
+            length.clock();
+            clocks += 1;
+        }
+        expect(&format!("length[{}]", index), clocks, *expected)?;
+    }
+    Ok(())
+}
+
+fn check_save_load() -> Result<(), String> {
+    let mut cpu = run_program(vec![0xa9, 0x12, 0x85, 0x20, 0xa2, 0x34, 0xa0, 0x56, 0x00]);
+    let state = cpu.snapshot();
+
+    let mut other = run_program(vec![0x00]);
+    other.restore(&state)?;
+    if other.snapshot() != state {
+        return Err("restored state differs from snapshot".to_string());
+    }
+    expect("RAM $20", other.mem_read(0x20), cpu.mem_read(0x20))?;
+
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_string(&cpu).map_err(|e| e.to_string())?;
+        let decoded: crate::cpu::CpuState =
+            serde_json::from_str(&json).map_err(|e| e.to_string())?;
+        if decoded != state {
+            return Err("serde round trip differs from snapshot".to_string());
+        }
+    }
+    Ok(())
+}
+
+pub fn run() -> Vec<CheckResult> {
+    let checks: [Check; 4] = [
+        ("cpu vectors", || check_cpu_vectors().into()),
+        ("ppu timing", || check_ppu_timing().into()),
+        ("apu length table", || check_apu_length_table().into()),
+        ("save/load round trip", || check_save_load().into()),
+    ];
+
+    checks
+        .iter()
+        .map(|(name, check)| CheckResult {
+            name,
+            outcome: check(),
+        })
+        .collect()
+}
+
+pub fn report(results: &[CheckResult]) -> String {
+    let mut lines = vec![format!(
+        "nes-rs {} ({} {})",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    )];
+    for result in results {
+        let line = match &result.outcome {
+            Outcome::Pass => format!("[PASS] {}", result.name),
+            Outcome::Fail(msg) => format!("[FAIL] {}: {}", result.name, msg),
+            Outcome::Skip(msg) => format!("[SKIP] {}: {}", result.name, msg),
+        };
+        lines.push(line);
+    }
+    let failed = results
+        .iter()
+        .filter(|r| matches!(r.outcome, Outcome::Fail(_)))
+        .count();
+    lines.push(format!("{} checks, {} failed", results.len(), failed));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_doctor_passes() {
+        let results = run();
+        for result in results.iter() {
+            assert!(
+                !matches!(result.outcome, Outcome::Fail(_)),
+                "{}",
+                report(&results)
+            );
+        }
+    }
+}
@@ -0,0 +1,879 @@
+use std::path::Path;
+
+use crate::bus::Bus;
+use crate::cartridge::{Rom, RomError};
+use crate::clip::ClipBuffer;
+use crate::console::Region;
+use crate::cpu::CPU;
+use crate::desync::Desync;
+use crate::joypad::{Joypad, JoypadButton};
+use crate::movie::{Movie, MoviePlayer, MovieRecorder, MovieStart};
+pub use crate::ppu::Accuracy;
+use crate::ppu::NesPPU;
+use crate::renderer::{self, Layers};
+use crate::renderer_frame::{Frame, Image, IndexedFrame, Overscan};
+use crate::renderer_input;
+use crate::renderer_palette::Palette;
+use crate::savestate::State;
+use crate::video::{self, VideoWriter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmulatorConfig {
+    pub accuracy: Accuracy,
+    pub layers: Layers,
+    // 電源投入直後にPPUへの書き込みを無視する期間
+    pub warmup: bool,
+    // Noneならヘッダーの指定に従う
+    pub region: Option<Region>,
+    // スクリーンショットで切り落とす端
+    pub overscan: Overscan,
+}
+
+impl Default for EmulatorConfig {
+    fn default() -> Self {
+        EmulatorConfig {
+            accuracy: Accuracy::Fast,
+            layers: Layers::default(),
+            warmup: true,
+            region: None,
+            overscan: Overscan::default(),
+        }
+    }
+}
+
+impl EmulatorConfig {
+    // "accurate,no-sprites" のようなカンマ区切りの指定を読む
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut config = EmulatorConfig::default();
+        for key in spec.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            match key {
+                "default" => {}
+                "fast" => config.accuracy = Accuracy::Fast,
+                "accurate" => config.accuracy = Accuracy::Accurate,
+                "no-bg" => config.layers.background = false,
+                "no-sprites" => config.layers.sprites = false,
+                "no-sprite-limit" => config.layers.sprite_limit = false,
+                "nametable-0" | "nametable-1" | "nametable-2" | "nametable-3" => {
+                    config.layers.nametable = key[10..].parse().ok();
+                }
+                "no-warmup" => config.warmup = false,
+                "ntsc" => config.region = Some(Region::Ntsc),
+                "pal" => config.region = Some(Region::Pal),
+                "dendy" => config.region = Some(Region::Dendy),
+                "crop-overscan" => config.overscan = Overscan::NTSC,
+                _ => return Err(format!("unknown config option: {}", key)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+// 録画中の動画。書き込みに失敗したらstop_video_recordingで返す
+struct VideoRecording {
+    writer: Box<dyn VideoWriter>,
+    error: Option<String>,
+}
+
+// ウィンドウなしで1フレームずつ進めるためのラッパー
+pub struct Emulator {
+    pub config: EmulatorConfig,
+    cpu: CPU<'static>,
+    frame: Frame,
+    indexed: IndexedFrame,
+    palette: Palette,
+    rom_sha1: String,
+    // 押されているボタンを画面に重ねて描く
+    pub input_overlay: bool,
+    recorder: Option<MovieRecorder>,
+    player: Option<MoviePlayer>,
+    video: Option<VideoRecording>,
+    clip: Option<ClipBuffer>,
+    // 録画中やラン・アヘッド中はrun_frameでAPUから取り出しておき、take_samplesで渡す
+    samples: Vec<f32>,
+    // 一時停止中はstep_frameで進めない。advanceはコマ送りで残っているフレーム数
+    paused: bool,
+    advance: u32,
+    // 何フレーム先の画面を見せるか。0なら使わない。
+    // run_ahead_stateは先読みの前に戻すためのセーブステートで、使い回す
+    run_ahead: u32,
+    run_ahead_state: Vec<u8>,
+}
+
+impl Emulator {
+    pub fn new(rom: Rom, config: EmulatorConfig) -> Result<Self, RomError> {
+        let rom_sha1 = rom.info().sha1;
+        Ok(Emulator {
+            cpu: Self::boot(rom, &config)?,
+            config,
+            frame: Frame::new(),
+            indexed: IndexedFrame::new(),
+            palette: Palette::default(),
+            rom_sha1,
+            input_overlay: false,
+            recorder: None,
+            player: None,
+            video: None,
+            clip: None,
+            samples: vec![],
+            paused: false,
+            advance: 0,
+            run_ahead: 0,
+            run_ahead_state: vec![],
+        })
+    }
+
+    fn boot(rom: Rom, config: &EmulatorConfig) -> Result<CPU<'static>, RomError> {
+        let bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {})?;
+        let mut cpu = CPU::new(bus);
+        if let Some(region) = config.region {
+            cpu.bus.set_region(region);
+        }
+        cpu.bus.ppu_mut().accuracy = config.accuracy;
+        cpu.bus.ppu_mut().sprite_limit = config.layers.sprite_limit;
+        cpu.bus.ppu_mut().set_warmup(config.warmup);
+        cpu.reset();
+        Ok(cpu)
+    }
+
+    // 電源を入れ直して別のROMに差し替える。設定や録画、ラン・アヘッドはそのまま、
+    // ROMに結びつくムービーの記録と再生は止める。コントローラーは標準のものに戻る。
+    // 録音中のWAVはAPUごと消えるので、先にApu::stop_recordingしておく
+    pub fn swap_rom(&mut self, rom: Rom) -> Result<(), RomError> {
+        let rom_sha1 = rom.info().sha1;
+        let mut cpu = Self::boot(rom, &self.config)?;
+        self.rom_sha1 = rom_sha1;
+        cpu.bus.apu_mut().copy_settings(self.cpu.bus.apu());
+        self.cpu = cpu;
+        self.recorder = None;
+        self.player = None;
+        self.samples.clear();
+        Ok(())
+    }
+
+    pub fn cpu(&self) -> &CPU<'static> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU<'static> {
+        &mut self.cpu
+    }
+
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    // 今の画面からconfig.overscanの分を切り落としたもの
+    pub fn screenshot(&self) -> Image {
+        self.frame.crop(self.config.overscan)
+    }
+
+    // パレットを通す前の画面。フロントエンドが自分でRGBにするとき用
+    pub fn indexed_frame(&self) -> &IndexedFrame {
+        &self.indexed
+    }
+
+    // 次に描くフレームから使われる
+    pub fn set_layers(&mut self, layers: Layers) {
+        self.config.layers = layers;
+        self.cpu.bus.ppu_mut().sprite_limit = layers.sprite_limit;
+    }
+
+    // 次に描くフレームから使われる
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    // フレームを出す間隔 (秒あたり)
+    pub fn frame_rate(&self) -> f64 {
+        self.cpu.bus.region().frame_rate()
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.cpu.bus.frame_counter().frame
+    }
+
+    // 前回から作られた音声のサンプルをoutの後ろに足す
+    pub fn take_samples(&mut self, out: &mut Vec<f32>) {
+        out.append(&mut self.samples);
+        self.cpu.bus.apu_mut().take_samples(out);
+    }
+
+    // 以降のフレームの画面と音声を書き出す。.aviなら無圧縮のAVI、
+    // それ以外の拡張子はffmpegに渡してエンコードする
+    pub fn start_video_recording<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+        if self.video.is_some() {
+            return Err("already recording video".to_string());
+        }
+        let sample_rate = self.cpu.bus.apu().sample_rate();
+        let writer = video::create(path, self.frame_rate(), sample_rate)?;
+        self.video = Some(VideoRecording {
+            writer,
+            error: None,
+        });
+        Ok(())
+    }
+
+    pub fn stop_video_recording(&mut self) -> Result<(), String> {
+        match self.video.take() {
+            Some(video) => {
+                let result = video.writer.finish();
+                match video.error {
+                    Some(e) => Err(e),
+                    None => result,
+                }
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording_video(&self) -> bool {
+        self.video.is_some()
+    }
+
+    // 直近seconds秒の画面を残しておき、save_clipでGIFにできるようにする。Noneなら残さない
+    pub fn set_clip_length(&mut self, seconds: Option<f64>) {
+        let frame_rate = self.frame_rate();
+        self.clip = seconds.map(|seconds| ClipBuffer::new(seconds, frame_rate));
+    }
+
+    pub fn save_clip<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        match &self.clip {
+            Some(clip) => clip.save_gif(path),
+            None => Err("clip buffer is disabled".to_string()),
+        }
+    }
+
+    // 1Pのボタン
+    pub fn set_buttons(&mut self, buttons: JoypadButton) {
+        let _ = self.set_controller_state(0, buttons);
+    }
+
+    // フレームの間に呼ぶ。次のフレームではゲームからこのボタンだけが押されて見える。
+    // portは0 (1P) か1 (2P)
+    pub fn set_controller_state(
+        &mut self,
+        port: usize,
+        buttons: JoypadButton,
+    ) -> Result<(), String> {
+        if port > 1 {
+            return Err(format!("no controller port {}", port));
+        }
+        match self.cpu.bus.port_mut(port).joypad_mut() {
+            Some(joypad) => {
+                joypad.set_turbo_pressed_status(JoypadButton::all(), false);
+                joypad.set_button_pressed_status(JoypadButton::all(), false);
+                joypad.set_button_pressed_status(buttons, true);
+                Ok(())
+            }
+            None => Err(format!("port {} has no standard controller", port)),
+        }
+    }
+
+    pub fn controller_state(&self, port: usize) -> Option<JoypadButton> {
+        self.buttons().get(port).copied()
+    }
+
+    fn buttons(&self) -> [JoypadButton; 2] {
+        let bus = &self.cpu.bus;
+        [bus.joypad1(), bus.joypad2()]
+            .map(|joypad| joypad.map(|j| j.buttons()).unwrap_or(JoypadButton::empty()))
+    }
+
+    // 以降のフレームの入力を記録する。最初のフレームの前なら電源投入から、
+    // そうでなければ今の状態のスナップショットから始まるムービーになる
+    pub fn start_movie_recording(&mut self) {
+        let start = if self.frame_count() == 0 {
+            MovieStart::PowerOn
+        } else {
+            let mut state = vec![];
+            self.save_state(&mut state);
+            MovieStart::Snapshot { state }
+        };
+        self.recorder = Some(MovieRecorder::new(start, Some(self.rom_sha1.clone())));
+    }
+
+    pub fn stop_movie_recording(&mut self) -> Option<Movie> {
+        self.recorder.take().map(|recorder| recorder.finish())
+    }
+
+    // 以降のrun_frameではムービーの入力を使う。電源投入から始まるムービーは
+    // まだフレームを進めていないエミュレータでしか再生できない
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), String> {
+        if let Some(sha1) = &movie.rom_sha1 {
+            if *sha1 != self.rom_sha1 {
+                return Err(format!(
+                    "movie was recorded with a different ROM (SHA-1 {})",
+                    sha1
+                ));
+            }
+        }
+        match &movie.start {
+            MovieStart::PowerOn if self.frame_count() != 0 => {
+                return Err(
+                    "movie starts at power-on but the emulator is already running".to_string(),
+                );
+            }
+            MovieStart::PowerOn => {}
+            MovieStart::Snapshot { state } => self.load_state(state)?,
+        }
+        self.player = Some(MoviePlayer::new(movie));
+        Ok(())
+    }
+
+    pub fn is_playing_movie(&self) -> bool {
+        self.player
+            .as_ref()
+            .is_some_and(|player| !player.is_finished())
+    }
+
+    // 再生中のムービーが記録した時と違う状態になった最初のフレーム。
+    // 再生し終わった後も次のplay_movieまで残る
+    pub fn movie_desync(&self) -> Option<&Desync> {
+        self.player.as_ref().and_then(|player| player.desync())
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.advance = 0;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // 一時停止して、次のstep_frameで1フレームだけ進める。呼んだ回数だけ進む
+    pub fn advance_frame(&mut self) {
+        self.paused = true;
+        self.advance += 1;
+    }
+
+    // 一時停止を考えたrun_frame。止まっている間は何もしない。CPUが止まったらfalse
+    pub fn step_frame(&mut self) -> bool {
+        if self.paused {
+            if self.advance == 0 {
+                return true;
+            }
+            self.advance -= 1;
+        }
+        self.run_frame()
+    }
+
+    // マシン全体の状態をbufに書き出す。同じbufを渡せば2回目からはメモリを確保しない
+    pub fn save_state(&mut self, buf: &mut Vec<u8>) {
+        self.cpu.state(&mut State::save(buf));
+    }
+
+    // 同じROMのsave_stateで作ったものを読む。壊れていたらErrだが、途中まで読んだ分は戻らない
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = State::load(data);
+        self.cpu.state(&mut state);
+        state.finish()
+    }
+
+    // 毎フレーム、今の入力が続くとしてframesフレーム先まで進めた画面を見せ、
+    // 状態は元に戻す。入力の変化が画面に出るまでの遅れがその分だけ縮む
+    pub fn set_run_ahead(&mut self, frames: u32) {
+        self.run_ahead = frames;
+    }
+
+    pub fn run_ahead(&self) -> u32 {
+        self.run_ahead
+    }
+
+    // 次のフレームの終わりまで実行して描画する。CPUが止まったらfalse
+    pub fn run_frame(&mut self) -> bool {
+        if let Some(buttons) = self.player.as_mut().and_then(|player| player.next_frame()) {
+            // Joypad以外が挿さったポートの分は捨てる
+            for (port, buttons) in buttons.into_iter().enumerate() {
+                let _ = self.set_controller_state(port, buttons);
+            }
+            if let Some(player) = self.player.as_mut() {
+                player.check(&mut self.cpu);
+            }
+        }
+        let buttons = self.buttons();
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record(buttons, &mut self.cpu);
+        }
+
+        if !self.emulate_frame() {
+            return false;
+        }
+        self.render();
+        if let Some(clip) = self.clip.as_mut() {
+            clip.push(&self.frame.crop(self.config.overscan));
+        }
+        if let Some(video) = self.video.as_mut() {
+            let start = self.samples.len();
+            self.cpu.bus.apu_mut().take_samples(&mut self.samples);
+            if video.error.is_none() {
+                if let Err(e) = video
+                    .writer
+                    .write_frame(&self.frame.data, &self.samples[start..])
+                {
+                    video.error = Some(e);
+                }
+            }
+        }
+        // 録音やAPUの書き込みの記録に先読みの分が混ざらないようにする
+        let apu = self.cpu.bus.apu();
+        if self.run_ahead > 0 && !apu.is_recording() && !apu.write_log.enabled {
+            self.show_ahead();
+        }
+        true
+    }
+
+    fn emulate_frame(&mut self) -> bool {
+        let start = self.frame_count();
+        while self.frame_count() == start {
+            if !self.cpu.step() {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn render(&mut self) {
+        renderer::render_indexed(self.cpu.bus.ppu(), &mut self.indexed, &self.config.layers);
+        self.indexed.to_rgb(&self.palette, &mut self.frame);
+        if self.input_overlay {
+            for (port, buttons) in self.buttons().into_iter().enumerate() {
+                renderer_input::draw_input(&mut self.frame, port, buttons);
+            }
+        }
+    }
+
+    // 今のフレームの後をrun_aheadフレーム進めて描き、進める前に戻す。
+    // 毎フレーム必ず戻すので、次のフレームで入力が変わっても予想が外れたままにはならない。
+    // 先読みした分の音声は戻すときに捨てる
+    fn show_ahead(&mut self) {
+        self.cpu.bus.apu_mut().take_samples(&mut self.samples);
+        let mut buf = std::mem::take(&mut self.run_ahead_state);
+        self.save_state(&mut buf);
+        let mut ran = true;
+        for _ in 0..self.run_ahead {
+            if !self.emulate_frame() {
+                ran = false;
+                break;
+            }
+        }
+        if ran {
+            self.render();
+        }
+        self.load_state(&buf)
+            .expect("run-ahead state was saved just now");
+        self.run_ahead_state = buf;
+    }
+}
+
+// 1行1フレームで、押されているボタンを16進で書いたもの
+pub fn parse_inputs(text: &str) -> Result<Vec<JoypadButton>, String> {
+    text.lines()
+        .map(|line| {
+            let line = line.trim();
+            if line.is_empty() {
+                return Ok(JoypadButton::empty());
+            }
+            u8::from_str_radix(line.trim_start_matches("0x"), 16)
+                .map(JoypadButton::from_bits_truncate)
+                .map_err(|e| format!("invalid input line {:?}: {}", line, e))
+        })
+        .collect()
+}
+
+pub struct FrameDiff {
+    pub frame: u64,
+    pub a: Frame,
+    pub b: Frame,
+}
+
+// 同じROMと入力を2つの設定で動かし、最初に画面が食い違ったフレームを返す
+pub fn compare(
+    rom: &[u8],
+    a: EmulatorConfig,
+    b: EmulatorConfig,
+    inputs: &[JoypadButton],
+    frames: u64,
+) -> Result<Option<FrameDiff>, String> {
+    let mut emu_a = Emulator::new(Rom::new(&rom.to_vec())?, a)?;
+    let mut emu_b = Emulator::new(Rom::new(&rom.to_vec())?, b)?;
+
+    for frame in 0..frames {
+        let buttons = inputs
+            .get(frame as usize)
+            .copied()
+            .unwrap_or(JoypadButton::empty());
+        emu_a.set_buttons(buttons);
+        emu_b.set_buttons(buttons);
+
+        let running_a = emu_a.run_frame();
+        let running_b = emu_b.run_frame();
+        if emu_a.frame().data != emu_b.frame().data {
+            return Ok(Some(FrameDiff {
+                frame,
+                a: emu_a.frame().clone(),
+                b: emu_b.frame().clone(),
+            }));
+        }
+        if !running_a || !running_b {
+            break;
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::Mem;
+
+    fn nestest() -> Vec<u8> {
+        std::fs::read(format!("{}/nestest.nes", env!("CARGO_MANIFEST_DIR"))).unwrap()
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config = EmulatorConfig::parse("accurate, no-sprites").unwrap();
+        assert_eq!(config.accuracy, Accuracy::Accurate);
+        assert!(config.layers.background);
+        assert!(!config.layers.sprites);
+        assert!(config.layers.sprite_limit);
+        assert!(
+            !EmulatorConfig::parse("no-sprite-limit")
+                .unwrap()
+                .layers
+                .sprite_limit
+        );
+        assert!(!EmulatorConfig::parse("no-warmup").unwrap().warmup);
+        assert_eq!(
+            EmulatorConfig::parse("nametable-2")
+                .unwrap()
+                .layers
+                .nametable,
+            Some(2)
+        );
+        assert_eq!(
+            EmulatorConfig::parse("pal").unwrap().region,
+            Some(Region::Pal)
+        );
+        assert_eq!(EmulatorConfig::default().region, None);
+        assert!(EmulatorConfig::parse("turbo").is_err());
+    }
+
+    #[test]
+    fn test_parse_inputs() {
+        let inputs = parse_inputs("08\n\n0x81\n").unwrap();
+        assert_eq!(
+            inputs,
+            vec![
+                JoypadButton::START,
+                JoypadButton::empty(),
+                JoypadButton::RIGHT | JoypadButton::BUTTON_A
+            ]
+        );
+        assert!(parse_inputs("zz").is_err());
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut emu =
+            Emulator::new(Rom::new(&nestest()).unwrap(), EmulatorConfig::default()).unwrap();
+        for _ in 0..3 {
+            assert!(emu.run_frame());
+        }
+        assert_eq!(emu.frame_count(), 3);
+    }
+
+    #[test]
+    fn test_compare() {
+        let rom = nestest();
+        let same = compare(
+            &rom,
+            EmulatorConfig::default(),
+            EmulatorConfig::parse("no-sprite-limit").unwrap(),
+            &[],
+            10,
+        )
+        .unwrap();
+        assert!(same.is_none());
+
+        let diff = compare(
+            &rom,
+            EmulatorConfig::default(),
+            EmulatorConfig::parse("no-bg").unwrap(),
+            &[],
+            10,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(diff.a.data != diff.b.data);
+    }
+
+    #[test]
+    fn test_accurate_matches_fast() {
+        let mut fast =
+            Emulator::new(Rom::new(&nestest()).unwrap(), EmulatorConfig::default()).unwrap();
+        let accurate_config = EmulatorConfig::parse("accurate").unwrap();
+        let mut accurate = Emulator::new(Rom::new(&nestest()).unwrap(), accurate_config).unwrap();
+        for frame in 0..10 {
+            assert!(fast.run_frame());
+            assert!(accurate.run_frame());
+            // フレーム3は描画の途中でパレットが書かれるので、ドット単位だと上の方が違う色になる
+            if frame == 3 {
+                assert!(fast.frame().data != accurate.frame().data);
+            } else {
+                assert!(fast.frame().data == accurate.frame().data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_movie_playback() {
+        let mut emu =
+            Emulator::new(Rom::new(&nestest()).unwrap(), EmulatorConfig::default()).unwrap();
+        emu.start_movie_recording();
+        for frame in 0..40 {
+            // START で最初のテストを走らせる
+            let buttons = if (5..8).contains(&frame) {
+                JoypadButton::START
+            } else {
+                JoypadButton::empty()
+            };
+            emu.set_buttons(buttons);
+            assert!(emu.run_frame());
+        }
+        let movie = emu.stop_movie_recording().unwrap();
+        assert_eq!(movie.len(), 40);
+        assert_eq!(movie.frames[5][0], JoypadButton::START);
+
+        let mut replay =
+            Emulator::new(Rom::new(&nestest()).unwrap(), EmulatorConfig::default()).unwrap();
+        let text = movie.to_text().unwrap();
+        replay.play_movie(Movie::parse(&text).unwrap()).unwrap();
+        for _ in 0..40 {
+            assert!(replay.is_playing_movie());
+            assert!(replay.run_frame());
+        }
+        assert!(replay.frame().data == emu.frame().data);
+        assert_eq!(replay.movie_desync(), None);
+        assert!(replay.run_frame());
+        assert!(!replay.is_playing_movie());
+
+        // STARTを1フレーム遅らせると、その後の最初のハッシュでずれる
+        let mut edited = Movie::parse(&text).unwrap();
+        assert_eq!(edited.hashes.len(), 4);
+        edited.frames[5][0] = JoypadButton::empty();
+        let mut desynced =
+            Emulator::new(Rom::new(&nestest()).unwrap(), EmulatorConfig::default()).unwrap();
+        desynced.play_movie(edited).unwrap();
+        for _ in 0..40 {
+            assert!(desynced.run_frame());
+        }
+        assert_eq!(desynced.movie_desync().unwrap().frame, 10);
+
+        // 走り出したあとに電源投入からのムービーは再生できない
+        assert!(replay.play_movie(movie.clone()).is_err());
+        let mut other = movie;
+        other.rom_sha1 = Some("0".repeat(40));
+        let mut fresh =
+            Emulator::new(Rom::new(&nestest()).unwrap(), EmulatorConfig::default()).unwrap();
+        assert!(fresh.play_movie(other).is_err());
+    }
+
+    #[test]
+    fn test_set_controller_state() {
+        let mut emu =
+            Emulator::new(Rom::new(&nestest()).unwrap(), EmulatorConfig::default()).unwrap();
+        emu.set_controller_state(1, JoypadButton::UP | JoypadButton::BUTTON_B)
+            .unwrap();
+        assert_eq!(
+            emu.controller_state(1),
+            Some(JoypadButton::UP | JoypadButton::BUTTON_B)
+        );
+        assert_eq!(emu.controller_state(0), Some(JoypadButton::empty()));
+
+        // ゲームからは$4017で読める
+        let cpu = emu.cpu_mut();
+        cpu.mem_write(0x4016, 1);
+        cpu.mem_write(0x4016, 0);
+        let bits: Vec<u8> = (0..8).map(|_| cpu.mem_read(0x4017)).collect();
+        assert_eq!(bits, vec![0, 1, 0, 0, 1, 0, 0, 0]);
+
+        assert!(emu.set_controller_state(2, JoypadButton::START).is_err());
+        emu.cpu_mut()
+            .bus
+            .connect(0, Box::new(crate::controller::Unplugged));
+        assert!(emu.set_controller_state(0, JoypadButton::START).is_err());
+    }
+
+    #[test]
+    fn test_input_overlay() {
+        let mut plain =
+            Emulator::new(Rom::new(&nestest()).unwrap(), EmulatorConfig::default()).unwrap();
+        let mut overlay =
+            Emulator::new(Rom::new(&nestest()).unwrap(), EmulatorConfig::default()).unwrap();
+        overlay.input_overlay = true;
+        assert!(plain.run_frame());
+        assert!(overlay.run_frame());
+        assert!(plain.frame().data != overlay.frame().data);
+    }
+
+    #[test]
+    fn test_screenshot() {
+        let config = EmulatorConfig::parse("crop-overscan").unwrap();
+        let mut emulator = Emulator::new(Rom::new(&nestest()).unwrap(), config).unwrap();
+        assert!(emulator.run_frame());
+        let image = emulator.screenshot();
+        assert_eq!((image.width, image.height), (256, 224));
+        assert_eq!(
+            image.data[..],
+            emulator.frame().data[256 * 8 * 3..256 * 232 * 3]
+        );
+    }
+
+    #[test]
+    fn test_video_recording() {
+        let path = std::env::temp_dir().join(format!("nes-rs-video-{}.avi", std::process::id()));
+        let mut emulator =
+            Emulator::new(Rom::new(&nestest()).unwrap(), EmulatorConfig::default()).unwrap();
+        emulator.start_video_recording(&path).unwrap();
+        assert!(emulator.start_video_recording(&path).is_err());
+        for _ in 0..3 {
+            assert!(emulator.run_frame());
+        }
+        // 録画しても音声はいつも通り取り出せる
+        let mut samples = vec![];
+        emulator.take_samples(&mut samples);
+        assert!(samples.len() > 700 * 2);
+        emulator.stop_video_recording().unwrap();
+        assert!(!emulator.is_recording_video());
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&data[8..12], b"AVI ");
+        // 総フレーム数と音声のサンプル数
+        assert_eq!(u32::from_le_bytes(data[48..52].try_into().unwrap()), 3);
+        assert_eq!(
+            u32::from_le_bytes(data[264..268].try_into().unwrap()) as usize,
+            samples.len()
+        );
+    }
+
+    #[test]
+    fn test_save_clip() {
+        let path = std::env::temp_dir().join(format!("nes-rs-emulator-{}.gif", std::process::id()));
+        let mut emulator =
+            Emulator::new(Rom::new(&nestest()).unwrap(), EmulatorConfig::default()).unwrap();
+        assert!(emulator.save_clip(&path).is_err());
+        emulator.set_clip_length(Some(1.0));
+        for _ in 0..4 {
+            assert!(emulator.run_frame());
+        }
+        emulator.save_clip(&path).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&data[..6], b"GIF89a");
+    }
+
+    #[test]
+    fn test_pause() {
+        let mut emulator =
+            Emulator::new(Rom::new(&nestest()).unwrap(), EmulatorConfig::default()).unwrap();
+        assert!(emulator.step_frame());
+        emulator.set_paused(true);
+        for _ in 0..3 {
+            assert!(emulator.step_frame());
+        }
+        assert_eq!(emulator.frame_count(), 1);
+
+        // 押した回数だけ1フレームずつ進み、止まったまま
+        emulator.advance_frame();
+        emulator.advance_frame();
+        for _ in 0..3 {
+            emulator.step_frame();
+        }
+        assert_eq!(emulator.frame_count(), 3);
+        assert!(emulator.is_paused());
+
+        emulator.set_paused(false);
+        emulator.step_frame();
+        assert_eq!(emulator.frame_count(), 4);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut emulator =
+            Emulator::new(Rom::new(&nestest()).unwrap(), EmulatorConfig::default()).unwrap();
+        for _ in 0..30 {
+            emulator.run_frame();
+        }
+        let mut saved = vec![];
+        emulator.save_state(&mut saved);
+        let run = |emulator: &mut Emulator| {
+            emulator.set_buttons(JoypadButton::DOWN);
+            for _ in 0..20 {
+                emulator.run_frame();
+            }
+            let mut state = vec![];
+            emulator.save_state(&mut state);
+            (emulator.frame().data.clone(), state)
+        };
+        let first = run(&mut emulator);
+        emulator.set_buttons(JoypadButton::empty());
+        emulator.load_state(&saved).unwrap();
+        assert_eq!(emulator.frame_count(), 30);
+        assert!(run(&mut emulator) == first);
+        assert!(emulator.load_state(&saved[1..]).is_err());
+    }
+
+    #[test]
+    fn test_run_ahead() {
+        let rom = nestest();
+        let mut normal = Emulator::new(Rom::new(&rom).unwrap(), EmulatorConfig::default()).unwrap();
+        let mut ahead = Emulator::new(Rom::new(&rom).unwrap(), EmulatorConfig::default()).unwrap();
+        ahead.set_run_ahead(2);
+        let (mut normal_samples, mut ahead_samples) = (vec![], vec![]);
+        for _ in 0..40 {
+            normal.run_frame();
+            normal.take_samples(&mut normal_samples);
+            ahead.run_frame();
+            ahead.take_samples(&mut ahead_samples);
+        }
+        assert_eq!(ahead.frame_count(), 40);
+        // 音声は本当に進んだフレームの分だけ
+        assert!(normal_samples == ahead_samples);
+        // 画面は2フレーム先
+        normal.run_frame();
+        normal.run_frame();
+        assert!(normal.frame().data == ahead.frame().data);
+    }
+
+    #[test]
+    fn test_swap_rom() {
+        let mut emulator =
+            Emulator::new(Rom::new(&nestest()).unwrap(), EmulatorConfig::default()).unwrap();
+        emulator.cpu_mut().bus.apu_mut().master_volume = 0.5;
+        emulator.start_movie_recording();
+        for _ in 0..10 {
+            emulator.run_frame();
+        }
+        // 差し替えられないROMなら今のゲームが続く
+        let mut unsupported = Rom::new(&nestest()).unwrap();
+        unsupported.mapper = 255;
+        assert!(emulator.swap_rom(unsupported).is_err());
+        assert_eq!(emulator.frame_count(), 10);
+        emulator.swap_rom(Rom::new(&nestest()).unwrap()).unwrap();
+        assert_eq!(emulator.frame_count(), 0);
+        assert_eq!(emulator.cpu().bus.apu().master_volume, 0.5);
+        assert!(emulator.stop_movie_recording().is_none());
+
+        // 電源を入れたばかりのものと同じに動く
+        let mut fresh =
+            Emulator::new(Rom::new(&nestest()).unwrap(), EmulatorConfig::default()).unwrap();
+        for _ in 0..10 {
+            emulator.run_frame();
+            fresh.run_frame();
+        }
+        assert!(emulator.frame().data == fresh.frame().data);
+    }
+}
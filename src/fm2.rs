@@ -0,0 +1,291 @@
+use crate::joypad::JoypadButton;
+use crate::movie::{Movie, MovieStart};
+
+// 入力ログのボタンの並び。左がbit7 (RIGHT) で右がbit0 (A)
+const BUTTON_CHARS: &[u8; 8] = b"RLDUTSBA";
+
+// FM2のportN。1がゲームパッドで、それ以外の機器には対応していない
+const PORT_NONE: u8 = 0;
+const PORT_GAMEPAD: u8 = 1;
+
+// 入力ログの先頭のコマンド
+const COMMAND_HARD_RESET: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subtitle {
+    pub frame: u64,
+    pub text: String,
+}
+
+// FCEUXのテキスト形式のムービー (.fm2)
+#[derive(Clone)]
+pub struct Fm2 {
+    pub version: u32,
+    pub emu_version: u32,
+    pub rerecord_count: u32,
+    pub pal: bool,
+    pub rom_filename: String,
+    // "base64:" で始まるROMのMD5。計算はしないので読んだものをそのまま書き戻す
+    pub rom_checksum: String,
+    pub guid: String,
+    pub comments: Vec<String>,
+    pub subtitles: Vec<Subtitle>,
+    // 知らないヘッダー行。書き出すときにそのまま残す
+    pub extra: Vec<(String, String)>,
+    // 2Pのゲームパッドを繋ぐか
+    pub port1: bool,
+    pub movie: Movie,
+}
+
+impl Fm2 {
+    // 電源投入から始まるムービーのみ書き出せる
+    pub fn from_movie(movie: Movie, rom_filename: &str) -> Result<Self, String> {
+        if let MovieStart::Snapshot { .. } = movie.start {
+            return Err("FM2 export needs a movie starting at power-on".to_string());
+        }
+        Ok(Fm2 {
+            version: 3,
+            emu_version: 0,
+            rerecord_count: 0,
+            pal: false,
+            rom_filename: rom_filename.to_string(),
+            rom_checksum: String::new(),
+            guid: "00000000-0000-0000-0000-000000000000".to_string(),
+            comments: vec![],
+            subtitles: vec![],
+            extra: vec![],
+            port1: true,
+            movie,
+        })
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut fm2 = Fm2::from_movie(Movie::new(MovieStart::PowerOn, None), "")?;
+        fm2.port1 = false;
+        let mut ports = [PORT_GAMEPAD, PORT_NONE, PORT_NONE];
+        for line in text.lines() {
+            let line = line.trim_end_matches('\r');
+            if line.starts_with('|') {
+                let frame = fm2.movie.frames.len();
+                fm2.movie.frames.push(parse_input(line, frame, &ports)?);
+                continue;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            let number = || {
+                value
+                    .trim()
+                    .parse::<u32>()
+                    .map_err(|e| format!("invalid {} {:?}: {}", key, value, e))
+            };
+            match key {
+                "version" => fm2.version = number()?,
+                "emuVersion" => fm2.emu_version = number()?,
+                "rerecordCount" => fm2.rerecord_count = number()?,
+                "palFlag" => fm2.pal = number()? != 0,
+                "romFilename" => fm2.rom_filename = value.to_string(),
+                "romChecksum" => fm2.rom_checksum = value.to_string(),
+                "guid" => fm2.guid = value.to_string(),
+                "comment" => fm2.comments.push(value.to_string()),
+                "subtitle" => {
+                    let (frame, text) = value.split_once(' ').unwrap_or((value, ""));
+                    let frame = frame
+                        .parse()
+                        .map_err(|e| format!("invalid subtitle {:?}: {}", value, e))?;
+                    fm2.subtitles.push(Subtitle {
+                        frame,
+                        text: text.to_string(),
+                    });
+                }
+                "port0" | "port1" | "port2" => {
+                    let index = (key.as_bytes()[4] - b'0') as usize;
+                    ports[index] = number()? as u8;
+                    let supported = match index {
+                        2 => ports[index] == PORT_NONE,
+                        _ => ports[index] == PORT_NONE || ports[index] == PORT_GAMEPAD,
+                    };
+                    if !supported {
+                        return Err(format!("unsupported input device: {} {}", key, value));
+                    }
+                }
+                "fourscore" | "binary" if number()? != 0 => {
+                    return Err(format!("unsupported FM2 option: {}", key));
+                }
+                "fourscore" | "binary" => {}
+                "savestate" => {
+                    return Err("FM2 movies starting from a savestate are not supported".to_string())
+                }
+                _ => fm2.extra.push((key.to_string(), value.to_string())),
+            }
+        }
+        fm2.port1 = ports[1] == PORT_GAMEPAD;
+        Ok(fm2)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        let mut field = |key: &str, value: &dyn std::fmt::Display| {
+            text.push_str(&format!("{} {}\n", key, value));
+        };
+        field("version", &self.version);
+        field("emuVersion", &self.emu_version);
+        field("rerecordCount", &self.rerecord_count);
+        field("palFlag", &(self.pal as u8));
+        field("romFilename", &self.rom_filename);
+        field("romChecksum", &self.rom_checksum);
+        field("guid", &self.guid);
+        field("fourscore", &0);
+        field("port0", &PORT_GAMEPAD);
+        field("port1", &if self.port1 { PORT_GAMEPAD } else { PORT_NONE });
+        field("port2", &PORT_NONE);
+        for (key, value) in self.extra.iter() {
+            field(key, value);
+        }
+        for comment in self.comments.iter() {
+            field("comment", comment);
+        }
+        for subtitle in self.subtitles.iter() {
+            field("subtitle", &format!("{} {}", subtitle.frame, subtitle.text));
+        }
+        for [p1, p2] in self.movie.frames.iter() {
+            let p2 = if self.port1 {
+                format_buttons(*p2)
+            } else {
+                String::new()
+            };
+            text.push_str(&format!("|0|{}|{}||\n", format_buttons(*p1), p2));
+        }
+        text
+    }
+}
+
+// |コマンド|1P|2P|拡張ポート|
+fn parse_input(line: &str, frame: usize, ports: &[u8; 3]) -> Result<[JoypadButton; 2], String> {
+    let fields: Vec<&str> = line.split('|').collect();
+    if fields.len() < 4 {
+        return Err(format!("invalid input line {:?}", line));
+    }
+    let command: u8 = fields[1]
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid input line {:?}: {}", line, e))?;
+    // 最初のフレームのハードリセットは電源投入と同じ
+    if command != 0 && !(frame == 0 && command == COMMAND_HARD_RESET) {
+        return Err(format!(
+            "unsupported command {} at frame {}",
+            command, frame
+        ));
+    }
+    let mut buttons = [JoypadButton::empty(); 2];
+    for (port, pad) in buttons.iter_mut().enumerate() {
+        if ports[port] == PORT_GAMEPAD {
+            *pad = parse_buttons(fields[2 + port])
+                .ok_or_else(|| format!("invalid input line {:?}", line))?;
+        }
+    }
+    Ok(buttons)
+}
+
+// 押されていないボタンは '.' か ' '
+fn parse_buttons(field: &str) -> Option<JoypadButton> {
+    if field.len() != BUTTON_CHARS.len() {
+        return None;
+    }
+    let mut bits = 0;
+    for (i, c) in field.bytes().enumerate() {
+        if c != b'.' && c != b' ' {
+            bits |= 0x80 >> i;
+        }
+    }
+    Some(JoypadButton::from_bits_truncate(bits))
+}
+
+fn format_buttons(buttons: JoypadButton) -> String {
+    BUTTON_CHARS
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            if buttons.bits() & (0x80 >> i) != 0 {
+                *c as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MOVIE: &str = "version 3
+emuVersion 22020
+rerecordCount 12
+palFlag 0
+romFilename Super Mario Bros.
+romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==
+guid 6C6D0DA9-8C9B-BE83-2C4B-5F23C2E7B2B6
+fourscore 0
+microphone 0
+port0 1
+port1 1
+port2 0
+FDS 0
+NewPPU 0
+comment author someone
+subtitle 2 World 1-1
+|2|........|........||
+|0|....T...|........||
+|0|R......A|.L....B.||
+";
+
+    #[test]
+    fn test_parse_fm2() {
+        let fm2 = Fm2::parse(MOVIE).unwrap();
+        assert_eq!(fm2.rerecord_count, 12);
+        assert!(!fm2.pal);
+        assert_eq!(fm2.rom_filename, "Super Mario Bros.");
+        assert_eq!(fm2.comments, vec!["author someone".to_string()]);
+        assert_eq!(
+            fm2.subtitles,
+            vec![Subtitle {
+                frame: 2,
+                text: "World 1-1".to_string()
+            }]
+        );
+        assert!(fm2.port1);
+        assert_eq!(fm2.movie.len(), 3);
+        assert_eq!(fm2.movie.frames[1][0], JoypadButton::START);
+        assert_eq!(
+            fm2.movie.frames[2],
+            [
+                JoypadButton::RIGHT | JoypadButton::BUTTON_A,
+                JoypadButton::LEFT | JoypadButton::BUTTON_B
+            ]
+        );
+
+        // 読んだものを書き戻すと入力もヘッダーも変わらない
+        let again = Fm2::parse(&fm2.to_text()).unwrap();
+        assert_eq!(again.movie.frames, fm2.movie.frames);
+        assert_eq!(again.rom_checksum, fm2.rom_checksum);
+        assert_eq!(again.guid, fm2.guid);
+        assert_eq!(again.subtitles, fm2.subtitles);
+        assert_eq!(again.extra, fm2.extra);
+        assert!(fm2.to_text().contains("|0|R......A|.L....B.||\n"));
+    }
+
+    #[test]
+    fn test_unsupported_fm2() {
+        assert!(Fm2::parse("port0 2\n").is_err());
+        assert!(Fm2::parse("fourscore 1\n").is_err());
+        assert!(Fm2::parse("|0|........||||\n|1|........||||\n").is_err());
+        assert!(Fm2::parse("|0|...|||\n").is_err());
+
+        let mut movie = Movie::new(MovieStart::PowerOn, None);
+        movie.frames.push([JoypadButton::UP, JoypadButton::empty()]);
+        let fm2 = Fm2::from_movie(movie, "game.nes").unwrap();
+        assert!(fm2.to_text().ends_with("|0|...U....|........||\n"));
+    }
+}
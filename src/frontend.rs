@@ -0,0 +1,246 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::emulator::Emulator;
+use crate::renderer_frame::Frame;
+
+// 画面をウィンドウに合わせて拡大するやり方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScaleMode {
+    // 整数倍で拡大する。ドットの大きさが揃う
+    Integer,
+    // 画素の縦横比を8:7としてウィンドウいっぱいに広げる (ブラウン管での見た目)
+    AspectCorrect,
+}
+
+impl ScaleMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScaleMode::Integer => "integer",
+            ScaleMode::AspectCorrect => "aspect",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "integer" => Ok(ScaleMode::Integer),
+            "aspect" => Ok(ScaleMode::AspectCorrect),
+            _ => Err(format!("unknown scale mode: {}", name)),
+        }
+    }
+}
+
+// ウィンドウが裏に回っている (フォーカスがない) ときのふるまい
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackgroundMode {
+    // そのまま動かす
+    Run,
+    // 一時停止し、戻ってきたら再開する。自分で止めていたなら止めたまま
+    Pause,
+    // 音を消し、pacing::BACKGROUND_SPEEDまで遅くして動かす
+    Throttle,
+}
+
+impl BackgroundMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BackgroundMode::Run => "run",
+            BackgroundMode::Pause => "pause",
+            BackgroundMode::Throttle => "throttle",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "run" => Ok(BackgroundMode::Run),
+            "pause" => Ok(BackgroundMode::Pause),
+            "throttle" => Ok(BackgroundMode::Throttle),
+            _ => Err(format!("unknown background mode: {}", name)),
+        }
+    }
+}
+
+// width x heightのウィンドウの中で画面を描く位置と大きさ (x, y, w, h)。
+// 中央に置き、余りは黒のままにする
+pub fn viewport(mode: ScaleMode, width: u32, height: u32) -> (i32, i32, u32, u32) {
+    let (w, h) = match mode {
+        ScaleMode::Integer => {
+            let scale = (width / 256).min(height / 240).max(1);
+            (256 * scale, 240 * scale)
+        }
+        ScaleMode::AspectCorrect => {
+            let display_width = 256.0 * 8.0 / 7.0;
+            let scale = (width as f64 / display_width).min(height as f64 / 240.0);
+            (
+                (display_width * scale).round() as u32,
+                (240.0 * scale).round() as u32,
+            )
+        }
+    };
+    (
+        (width as i32 - w as i32) / 2,
+        (height as i32 - h as i32) / 2,
+        w,
+        h,
+    )
+}
+
+// ROMの隣に置くスクリーンショットの名前。game.nes -> game-20240102-030405-678.png (UTC)
+pub fn screenshot_path<P: AsRef<Path>>(rom_path: P, time: SystemTime) -> PathBuf {
+    let rom_path = rom_path.as_ref();
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = elapsed.as_secs() as i64;
+    // 1970-01-01からの日数を年月日にする (Howard Hinnantのcivil_from_days)
+    let z = secs.div_euclid(86400) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let time_of_day = secs.rem_euclid(86400);
+    let stem = rom_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "screenshot".to_string());
+    rom_path.with_file_name(format!(
+        "{}-{:04}{:02}{:02}-{:02}{:02}{:02}-{:03}.png",
+        stem,
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60,
+        elapsed.subsec_millis()
+    ))
+}
+
+// 出来上がったフレームを表示する先
+pub trait VideoSink {
+    fn present(&mut self, frame: &Frame);
+}
+
+// 画面と入力、音声を受け持つもの。SDL以外 (WASMやminifbなど) も
+// これを実装すればrunで動かせる
+pub trait Frontend: VideoSink {
+    // フレームの間に呼ばれる。入力をエミュレータへ渡し、終了するならfalseを返す
+    fn update(&mut self, emulator: &mut Emulator) -> bool;
+
+    // 直前のフレームで作られた音声。音を出さないなら何もしない
+    fn queue_audio(&mut self, _samples: &[f32]) {}
+}
+
+// フロントエンドが止めるかCPUが止まるまで1フレームずつ進める。
+// 一時停止中も画面は出し続ける (OSDの表示を更新するため)
+pub fn run<F: Frontend + ?Sized>(emulator: &mut Emulator, frontend: &mut F) {
+    let mut samples = vec![];
+    while frontend.update(emulator) {
+        if !emulator.step_frame() {
+            break;
+        }
+        frontend.present(emulator.frame());
+        samples.clear();
+        emulator.take_samples(&mut samples);
+        frontend.queue_audio(&samples);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Rom;
+    use crate::emulator::EmulatorConfig;
+    use crate::joypad::JoypadButton;
+
+    // 決まったフレーム数だけ動かすフロントエンド
+    struct Headless {
+        frames: usize,
+        presented: usize,
+        samples: usize,
+    }
+
+    impl VideoSink for Headless {
+        fn present(&mut self, frame: &Frame) {
+            assert_eq!(frame.data.len(), 256 * 240 * 3);
+            self.presented += 1;
+        }
+    }
+
+    impl Frontend for Headless {
+        fn update(&mut self, emulator: &mut Emulator) -> bool {
+            emulator.set_buttons(JoypadButton::empty());
+            self.presented < self.frames
+        }
+
+        fn queue_audio(&mut self, samples: &[f32]) {
+            self.samples += samples.len();
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let rom = std::fs::read(format!("{}/nestest.nes", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let mut emulator =
+            Emulator::new(Rom::new(&rom).unwrap(), EmulatorConfig::default()).unwrap();
+        let mut frontend = Headless {
+            frames: 5,
+            presented: 0,
+            samples: 0,
+        };
+        run(&mut emulator, &mut frontend);
+        assert_eq!(frontend.presented, 5);
+        assert_eq!(emulator.frame_count(), 5);
+        // 1フレームは約735サンプル
+        assert!(frontend.samples > 700 * 4);
+    }
+
+    #[test]
+    fn test_viewport() {
+        assert_eq!(viewport(ScaleMode::Integer, 768, 720), (0, 0, 768, 720));
+        assert_eq!(
+            viewport(ScaleMode::Integer, 1920, 1080),
+            (448, 60, 1024, 960)
+        );
+        // ウィンドウが小さくても1倍より縮めない
+        assert_eq!(viewport(ScaleMode::Integer, 200, 200), (-28, -20, 256, 240));
+
+        assert_eq!(
+            viewport(ScaleMode::AspectCorrect, 1920, 1080),
+            (301, 0, 1317, 1080)
+        );
+        assert_eq!(
+            viewport(ScaleMode::AspectCorrect, 878, 1000),
+            (0, 140, 878, 720)
+        );
+        assert_eq!(ScaleMode::parse("aspect"), Ok(ScaleMode::AspectCorrect));
+        assert!(ScaleMode::parse("stretch").is_err());
+    }
+
+    #[test]
+    fn test_background_mode() {
+        for mode in [
+            BackgroundMode::Run,
+            BackgroundMode::Pause,
+            BackgroundMode::Throttle,
+        ] {
+            assert_eq!(BackgroundMode::parse(mode.name()), Ok(mode));
+        }
+        assert!(BackgroundMode::parse("mute").is_err());
+    }
+
+    #[test]
+    fn test_screenshot_path() {
+        let time = UNIX_EPOCH + std::time::Duration::from_millis(1_709_251_199_250);
+        assert_eq!(
+            screenshot_path("roms/zelda.nes", time),
+            PathBuf::from("roms/zelda-20240229-235959-250.png")
+        );
+        assert_eq!(
+            screenshot_path("smb.nes", UNIX_EPOCH),
+            PathBuf::from("smb-19700101-000000-000.png")
+        );
+    }
+}
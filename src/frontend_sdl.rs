@@ -0,0 +1,756 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
+
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::controller::{Button, GameController};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::mouse::MouseUtil;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::video::{FullscreenType, Window, WindowContext};
+use sdl2::{EventPump, GameControllerSubsystem};
+
+use crate::apu;
+use crate::archive;
+use crate::audio_buffer::AudioBuffer;
+use crate::battery::BatterySave;
+use crate::cartridge::Rom;
+use crate::console::Region;
+use crate::emulator::Emulator;
+use crate::frontend::{self, BackgroundMode, Frontend, ScaleMode, VideoSink};
+use crate::input_config::{Action, Hotkey, InputConfig};
+use crate::joypad::Joypad;
+use crate::pacing::{FramePacer, BACKGROUND_SPEED, FAST_FORWARD_SPEEDS, SLOW_MOTION_SPEEDS};
+use crate::renderer::Layers;
+use crate::renderer_crt::{self, CrtFilter, CrtOptions};
+use crate::renderer_filter::{self, Filter};
+use crate::renderer_frame::{Frame, Image, Overscan};
+use crate::renderer_osd::Osd;
+
+// SDLの音声スレッドから呼ばれる
+struct AudioOutput(AudioBuffer);
+
+impl AudioCallback for AudioOutput {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        self.0.fill(out);
+    }
+}
+
+// 設定ファイルの名前から作った、キーとゲームパッドのボタンの対応
+struct Bindings {
+    keys: [HashMap<Keycode, Action>; 2],
+    // つないだ順に1P, 2P
+    buttons: [HashMap<Button, Action>; 2],
+    hotkeys: HashMap<Keycode, Hotkey>,
+}
+
+impl Bindings {
+    fn new(config: &InputConfig) -> Result<Self, String> {
+        let key = |name: &str| Keycode::from_name(name).ok_or(format!("unknown key: {}", name));
+        let button = |name: &str| {
+            Button::from_string(name).ok_or(format!("unknown gamepad button: {}", name))
+        };
+        let mut bindings = Bindings {
+            keys: [HashMap::new(), HashMap::new()],
+            buttons: [HashMap::new(), HashMap::new()],
+            hotkeys: HashMap::new(),
+        };
+        for port in 0..2 {
+            for (action, name) in config.keyboard.actions(port) {
+                bindings.keys[port].insert(key(name)?, action);
+            }
+            for (action, name) in config.gamepad.actions(port) {
+                bindings.buttons[port].insert(button(name)?, action);
+            }
+        }
+        for (hotkey, name) in config.hotkeys() {
+            bindings.hotkeys.insert(key(name)?, hotkey);
+        }
+        Ok(bindings)
+    }
+}
+
+fn window_title(rom_path: &Path) -> String {
+    rom_path
+        .file_stem()
+        .map(|stem| format!("NES-RS - {}", stem.to_string_lossy()))
+        .unwrap_or_else(|| "NES-RS".to_string())
+}
+
+fn apply(emulator: &mut Emulator, port: usize, action: Action, pressed: bool) {
+    if let Some(joypad) = emulator.cpu_mut().bus.port_mut(port).joypad_mut() {
+        match action {
+            Action::Button(button) => joypad.set_button_pressed_status(button, pressed),
+            Action::Turbo(button) => joypad.set_turbo_pressed_status(button, pressed),
+        }
+    }
+}
+
+// SDL2のウィンドウと音声デバイスを使うフロントエンド
+pub struct SdlFrontend {
+    canvas: Canvas<Window>,
+    texture: Texture,
+    // 後処理をかけるときはSCALE倍に拡大した画像をこちらに描く
+    crt_texture: Texture,
+    crt: CrtFilter,
+    // 拡大フィルタ。ブラウン管風の後処理が有効ならそちらを優先する
+    filter: Option<Box<dyn Filter>>,
+    filter_image: Image,
+    // フィルタの倍率ごとに作り直す
+    filter_texture: Option<(usize, Texture)>,
+    creator: TextureCreator<WindowContext>,
+    // FPSや知らせを重ねる。描くのはフレームのコピー
+    osd: Osd,
+    osd_frame: Option<Frame>,
+    // ウィンドウのタイトル。後ろにFPSを付ける。title_fpsは今出しているFPS
+    title: String,
+    title_fps: Option<f64>,
+    event_pump: EventPump,
+    audio_device: Option<AudioDevice<AudioOutput>>,
+    controller_subsystem: GameControllerSubsystem,
+    controllers: Vec<GameController>,
+    bindings: Bindings,
+    // F1: 背景, F2: スプライト, F3: ネームテーブルを1枚ずつ表示
+    layers: Layers,
+    battery: Option<BatterySave>,
+    // 録音したWAVの置き場所 (ROMのパス)
+    rom_path: PathBuf,
+    pacer: FramePacer,
+    scale_mode: ScaleMode,
+    // 全画面ではマウスカーソルを隠す
+    mouse: MouseUtil,
+    // 押している間は早送り/スロー。速さはHotkey::CycleSpeedで切り替える
+    fast_forward: bool,
+    slow_motion: bool,
+    fast_forward_speed: usize,
+    slow_motion_speed: usize,
+    // ウィンドウにフォーカスがないときどうするか。paused_by_focusは裏に回ったので止めたとき
+    background: BackgroundMode,
+    focused: bool,
+    paused_by_focus: bool,
+}
+
+impl SdlFrontend {
+    pub fn new(rom_path: &str, scale: u32, config: &InputConfig) -> Result<Self, String> {
+        let bindings = Bindings::new(config)?;
+        let rom_path = PathBuf::from(rom_path);
+        let title = window_title(&rom_path);
+
+        let sdl_context = sdl2::init()?;
+        let video_subsystem = sdl_context.video()?;
+        let window = video_subsystem
+            .window(&title, 256 * scale, 240 * scale)
+            .position_centered()
+            .resizable()
+            .build()
+            .map_err(|e| e.to_string())?;
+        // 速さはFramePacerで合わせるので、垂直同期は待たない
+        let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        let creator = canvas.texture_creator();
+        let texture = creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, 256, 240)
+            .map_err(|e| e.to_string())?;
+        let crt_texture = creator
+            .create_texture_streaming(
+                PixelFormatEnum::RGB24,
+                (256 * renderer_crt::SCALE) as u32,
+                (240 * renderer_crt::SCALE) as u32,
+            )
+            .map_err(|e| e.to_string())?;
+        let event_pump = sdl_context.event_pump()?;
+        let mouse = sdl_context.mouse();
+        // つながっているゲームパッドはControllerDeviceAddedで開く
+        let controller_subsystem = sdl_context.game_controller()?;
+
+        // 音声デバイスが開けなくても音なしで動かす
+        let desired = AudioSpecDesired {
+            freq: Some(apu::DEFAULT_SAMPLE_RATE as i32),
+            channels: Some(1),
+            samples: Some(1024),
+        };
+        let audio_device = sdl_context
+            .audio()
+            .and_then(|audio| {
+                audio.open_playback(None, &desired, |spec| {
+                    // 0.25秒分まで溜める
+                    AudioOutput(AudioBuffer::new(spec.freq as usize / 4))
+                })
+            })
+            .map_err(|e| eprintln!("failed to open audio device: {}", e))
+            .ok();
+        if let Some(device) = audio_device.as_ref() {
+            device.resume();
+        }
+
+        Ok(SdlFrontend {
+            canvas,
+            texture,
+            crt_texture,
+            crt: CrtFilter::new(CrtOptions::default()),
+            filter: None,
+            filter_image: Image {
+                width: 0,
+                height: 0,
+                data: vec![],
+            },
+            filter_texture: None,
+            creator,
+            osd: Osd::new(),
+            osd_frame: None,
+            title,
+            title_fps: None,
+            event_pump,
+            audio_device,
+            controller_subsystem,
+            controllers: vec![],
+            bindings,
+            layers: Layers::default(),
+            battery: Some(BatterySave::for_rom(&rom_path)),
+            rom_path,
+            pacer: FramePacer::new(Region::Ntsc.frame_rate()),
+            scale_mode: ScaleMode::Integer,
+            mouse,
+            fast_forward: false,
+            slow_motion: false,
+            fast_forward_speed: 0,
+            slow_motion_speed: 0,
+            background: BackgroundMode::Run,
+            focused: true,
+            paused_by_focus: false,
+        })
+    }
+
+    pub fn is_fullscreen(&self) -> bool {
+        self.canvas.window().fullscreen_state() != FullscreenType::Off
+    }
+
+    // 画面モードを切り替えないデスクトップ解像度の全画面にする。
+    // 大きさが変わっても描くときにウィンドウの大きさから位置を計算し直す
+    pub fn set_fullscreen(&mut self, fullscreen: bool) -> Result<(), String> {
+        let state = if fullscreen {
+            FullscreenType::Desktop
+        } else {
+            FullscreenType::Off
+        };
+        self.canvas.window_mut().set_fullscreen(state)?;
+        self.mouse.show_cursor(!fullscreen);
+        Ok(())
+    }
+
+    pub fn set_scale_mode(&mut self, mode: ScaleMode) {
+        self.scale_mode = mode;
+    }
+
+    pub fn set_background_mode(&mut self, mode: BackgroundMode) {
+        self.background = mode;
+    }
+
+    // 裏にいる間、音を消して遅くしているか
+    fn is_throttled(&self) -> bool {
+        !self.focused && self.background == BackgroundMode::Throttle
+    }
+
+    fn set_focused(&mut self, emulator: &mut Emulator, focused: bool) {
+        if self.focused == focused {
+            return;
+        }
+        self.focused = focused;
+        if self.background != BackgroundMode::Pause {
+            return;
+        }
+        if !focused && !emulator.is_paused() {
+            emulator.set_paused(true);
+            self.paused_by_focus = true;
+        } else if focused && self.paused_by_focus {
+            emulator.set_paused(false);
+            self.paused_by_focus = false;
+        }
+    }
+
+    pub fn set_show_fps(&mut self, show: bool) {
+        self.osd.show_fps = show;
+    }
+
+    pub fn set_filter(&mut self, filter: Option<Box<dyn Filter>>) {
+        self.filter = filter;
+    }
+
+    // なし -> renderer_filter::all() の順 -> なし
+    fn cycle_filter(&mut self) {
+        let filters = renderer_filter::all();
+        let next = match &self.filter {
+            None => 0,
+            Some(current) => filters
+                .iter()
+                .position(|filter| filter.name() == current.name())
+                .map_or(0, |i| i + 1),
+        };
+        self.filter = filters.into_iter().nth(next);
+        let name = self.filter.as_ref().map_or("none", |filter| filter.name());
+        self.notify(format!("filter: {}", name));
+    }
+
+    // 標準出力と画面の両方に出す
+    fn notify(&mut self, text: String) {
+        println!("{}", text);
+        self.osd.message(text, Instant::now());
+    }
+
+    fn warn(&mut self, text: String) {
+        eprintln!("{}", text);
+        self.osd.message(text, Instant::now());
+    }
+
+    // 最初のフレームの前に呼ぶ。セーブデータを読み込み、サンプルレートを合わせる
+    pub fn prepare(&mut self, emulator: &mut Emulator) {
+        self.pacer.set_frame_rate(emulator.frame_rate());
+        let bus = &mut emulator.cpu_mut().bus;
+        if bus.has_battery() {
+            if let Some(battery) = self.battery.as_mut() {
+                match battery.load() {
+                    Ok(Some(data)) => bus.load_battery_ram(&data),
+                    Ok(None) => {}
+                    Err(e) => eprintln!("failed to load save data: {}", e),
+                }
+            }
+        } else {
+            self.battery = None;
+        }
+        if let Some(device) = self.audio_device.as_ref() {
+            bus.apu_mut().set_sample_rate(device.spec().freq as u32);
+        }
+    }
+
+    // ウィンドウに落とされた.nes/.zipに差し替える。読めなければ今のゲームを続ける
+    fn load_rom(&mut self, emulator: &mut Emulator, path: PathBuf) {
+        let rom = match archive::read_rom(&path)
+            .and_then(|data| Rom::new(&data).map_err(|e| e.to_string()))
+        {
+            Ok(rom) => rom,
+            Err(e) => {
+                self.warn(format!("failed to load {}: {}", path.display(), e));
+                return;
+            }
+        };
+        // 前のゲームのセーブデータと録音は先に書き出す
+        let bus = &mut emulator.cpu_mut().bus;
+        if let Some(battery) = self.battery.as_mut() {
+            if let Some(Err(e)) = bus.with_battery_ram(|ram| battery.save(ram)) {
+                eprintln!("failed to write save data: {}", e);
+            }
+        }
+        if let Err(e) = bus.apu_mut().stop_recording() {
+            eprintln!("failed to record audio: {}", e);
+        }
+
+        if let Err(e) = emulator.swap_rom(rom) {
+            self.warn(format!("failed to load {}: {}", path.display(), e));
+            return;
+        }
+        self.battery = Some(BatterySave::for_rom(&path));
+        self.title = window_title(&path);
+        self.title_fps = None;
+        let _ = self.canvas.window_mut().set_title(&self.title);
+        self.prepare(emulator);
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        self.notify(format!("loaded {}", name));
+        self.rom_path = path;
+    }
+
+    // 終了の前にセーブデータと録音を書き出す
+    fn shutdown(&mut self, emulator: &mut Emulator) {
+        let bus = &mut emulator.cpu_mut().bus;
+        if let Some(battery) = self.battery.as_mut() {
+            if let Some(Err(e)) = bus.with_battery_ram(|ram| battery.save(ram)) {
+                eprintln!("failed to write save data: {}", e);
+            }
+        }
+        if let Err(e) = bus.apu_mut().stop_recording() {
+            eprintln!("failed to record audio: {}", e);
+        }
+        if let Err(e) = emulator.stop_video_recording() {
+            eprintln!("failed to record video: {}", e);
+        }
+    }
+
+    // 録音を開始/停止する。Shiftを押しながらならチャンネルごとのステムも書く
+    fn toggle_recording(&mut self, emulator: &mut Emulator, stems: bool) {
+        let frame = emulator.frame_count();
+        let apu = emulator.cpu_mut().bus.apu_mut();
+        let result = if apu.is_recording() {
+            apu.stop_recording()
+        } else {
+            let path = self.rom_path.with_extension(format!("{}.wav", frame));
+            self.notify(format!("recording to {}", path.display()));
+            if stems {
+                apu.start_recording_stems(&path)
+            } else {
+                apu.start_recording(&path)
+            }
+        };
+        if let Err(e) = result {
+            self.warn(format!("failed to record audio: {}", e));
+        }
+    }
+
+    // 録画を開始/停止する。Shiftを押しながらならffmpegでMP4に、そうでなければ無圧縮のAVIにする
+    fn toggle_video_recording(&mut self, emulator: &mut Emulator, ffmpeg: bool) {
+        let result = if emulator.is_recording_video() {
+            emulator.stop_video_recording()
+        } else {
+            let ext = if ffmpeg { "mp4" } else { "avi" };
+            let path = self
+                .rom_path
+                .with_extension(format!("{}.{}", emulator.frame_count(), ext));
+            self.notify(format!("recording video to {}", path.display()));
+            emulator.start_video_recording(&path)
+        };
+        if let Err(e) = result {
+            self.warn(format!("failed to record video: {}", e));
+        }
+    }
+
+    fn speed(&self) -> Option<f64> {
+        if self.is_throttled() {
+            Some(BACKGROUND_SPEED)
+        } else if self.fast_forward {
+            FAST_FORWARD_SPEEDS[self.fast_forward_speed]
+        } else if self.slow_motion {
+            Some(SLOW_MOTION_SPEEDS[self.slow_motion_speed])
+        } else {
+            Some(1.0)
+        }
+    }
+
+    fn cycle_speed(&mut self, slow_motion: bool) {
+        if slow_motion {
+            self.slow_motion_speed = (self.slow_motion_speed + 1) % SLOW_MOTION_SPEEDS.len();
+            self.notify(format!(
+                "slow motion: {}%",
+                SLOW_MOTION_SPEEDS[self.slow_motion_speed] * 100.0
+            ));
+        } else {
+            self.fast_forward_speed = (self.fast_forward_speed + 1) % FAST_FORWARD_SPEEDS.len();
+            let text = match FAST_FORWARD_SPEEDS[self.fast_forward_speed] {
+                Some(speed) => format!("fast forward: {}x", speed),
+                None => "fast forward: uncapped".to_string(),
+            };
+            self.notify(text);
+        }
+    }
+
+    // 終了するならfalse。pressedがfalseなのは押している間だけのものを離したとき
+    fn hotkey(
+        &mut self,
+        emulator: &mut Emulator,
+        hotkey: Hotkey,
+        shift: bool,
+        pressed: bool,
+    ) -> bool {
+        match hotkey {
+            Hotkey::FastForward => self.fast_forward = pressed,
+            Hotkey::SlowMotion => self.slow_motion = pressed,
+            _ if !pressed => {}
+            Hotkey::Quit => return false,
+            Hotkey::VolumeDown => {
+                let apu = emulator.cpu_mut().bus.apu_mut();
+                apu.master_volume = (apu.master_volume - 0.1).max(0.0);
+            }
+            Hotkey::VolumeUp => {
+                let apu = emulator.cpu_mut().bus.apu_mut();
+                apu.master_volume = (apu.master_volume + 0.1).min(1.0);
+            }
+            Hotkey::ToggleBackground => {
+                self.layers.background = !self.layers.background;
+                emulator.set_layers(self.layers);
+            }
+            Hotkey::ToggleSprites => {
+                self.layers.sprites = !self.layers.sprites;
+                emulator.set_layers(self.layers);
+            }
+            Hotkey::CycleNametable => {
+                self.layers.nametable = match self.layers.nametable {
+                    None => Some(0),
+                    Some(3) => None,
+                    Some(table) => Some(table + 1),
+                };
+                emulator.set_layers(self.layers);
+            }
+            Hotkey::InputOverlay => emulator.input_overlay = !emulator.input_overlay,
+            Hotkey::Mute(channel) => {
+                let apu = emulator.cpu_mut().bus.apu_mut();
+                if shift {
+                    let solo = if apu.solo() == Some(channel) {
+                        None
+                    } else {
+                        Some(channel)
+                    };
+                    apu.set_solo(solo);
+                } else {
+                    apu.toggle_mute(channel);
+                }
+            }
+            Hotkey::RecordAudio => self.toggle_recording(emulator, shift),
+            Hotkey::TurboFaster => self.change_turbo_rate(emulator, true),
+            Hotkey::TurboSlower => self.change_turbo_rate(emulator, false),
+            Hotkey::CycleSpeed => self.cycle_speed(shift),
+            Hotkey::Fullscreen => {
+                if let Err(e) = self.set_fullscreen(!self.is_fullscreen()) {
+                    self.warn(format!("failed to change fullscreen: {}", e));
+                }
+            }
+            Hotkey::RecordVideo => self.toggle_video_recording(emulator, shift),
+            Hotkey::CycleCrtFilter => {
+                let options = self.crt.options().next();
+                self.crt.set_options(options);
+                self.notify(format!("crt filter: {}", options.name()));
+            }
+            Hotkey::CycleFilter => self.cycle_filter(),
+            Hotkey::ToggleFps => self.osd.show_fps = !self.osd.show_fps,
+            Hotkey::Pause => {
+                let paused = !emulator.is_paused();
+                emulator.set_paused(paused);
+                self.notify(if paused { "paused" } else { "resumed" }.to_string());
+            }
+            Hotkey::FrameAdvance => emulator.advance_frame(),
+            Hotkey::SaveClip => {
+                let path = frontend::screenshot_path(&self.rom_path, SystemTime::now())
+                    .with_extension("gif");
+                match emulator.save_clip(&path) {
+                    Ok(()) => self.notify(format!("saved clip to {}", path.display())),
+                    Err(e) => self.warn(format!("failed to save clip: {}", e)),
+                }
+            }
+            Hotkey::Screenshot => {
+                let path = frontend::screenshot_path(&self.rom_path, SystemTime::now());
+                match emulator.screenshot().save_png(&path) {
+                    Ok(()) => self.notify(format!("saved screenshot to {}", path.display())),
+                    Err(e) => self.warn(format!("failed to save screenshot: {}", e)),
+                }
+            }
+            Hotkey::CycleScaleMode => {
+                self.scale_mode = match self.scale_mode {
+                    ScaleMode::Integer => ScaleMode::AspectCorrect,
+                    ScaleMode::AspectCorrect => ScaleMode::Integer,
+                };
+                self.notify(format!("scale mode: {}", self.scale_mode.name()));
+            }
+        }
+        true
+    }
+
+    fn controller_port(&self, which: u32) -> Option<usize> {
+        self.controllers
+            .iter()
+            .position(|controller| controller.instance_id() == which)
+            .filter(|port| *port < 2)
+    }
+
+    fn change_turbo_rate(&mut self, emulator: &mut Emulator, faster: bool) {
+        let bus = &mut emulator.cpu_mut().bus;
+        let current = bus.joypad1().map(Joypad::turbo_rate).unwrap_or(1);
+        let rate = if faster {
+            current.saturating_sub(1)
+        } else {
+            current.saturating_add(1)
+        };
+        for port in 0..2 {
+            if let Some(joypad) = bus.port_mut(port).joypad_mut() {
+                joypad.set_turbo_rate(rate);
+            }
+        }
+    }
+}
+
+impl SdlFrontend {
+    fn draw(&mut self, frame: &Frame) {
+        let texture = if self.crt.options().is_enabled() {
+            let image = self.crt.apply(frame);
+            self.crt_texture
+                .update(None, &image.data, image.width * 3)
+                .unwrap();
+            &self.crt_texture
+        } else if let Some(filter) = &self.filter {
+            filter.apply(&frame.crop(Overscan::default()), &mut self.filter_image);
+            let scale = filter.scale();
+            if self.filter_texture.as_ref().map(|(s, _)| *s) != Some(scale) {
+                let texture = self
+                    .creator
+                    .create_texture_streaming(
+                        PixelFormatEnum::RGB24,
+                        self.filter_image.width as u32,
+                        self.filter_image.height as u32,
+                    )
+                    .unwrap();
+                self.filter_texture = Some((scale, texture));
+            }
+            let (_, texture) = self.filter_texture.as_mut().unwrap();
+            texture
+                .update(None, &self.filter_image.data, self.filter_image.width * 3)
+                .unwrap();
+            texture
+        } else {
+            self.texture.update(None, &frame.data, 256 * 3).unwrap();
+            &self.texture
+        };
+        self.canvas.clear();
+        let (width, height) = self.canvas.output_size().unwrap();
+        let (x, y, w, h) = frontend::viewport(self.scale_mode, width, height);
+        self.canvas
+            .copy(texture, None, Rect::new(x, y, w, h))
+            .unwrap();
+        self.canvas.present();
+    }
+}
+
+impl VideoSink for SdlFrontend {
+    fn present(&mut self, frame: &Frame) {
+        self.osd.tick(Instant::now());
+        // FPSは1秒ごとにしか変わらない
+        if self.osd.fps() != self.title_fps {
+            self.title_fps = self.osd.fps();
+            if let Some(fps) = self.title_fps {
+                let title = format!("{} - {:.1} FPS", self.title, fps);
+                let _ = self.canvas.window_mut().set_title(&title);
+            }
+        }
+        if !self.osd.is_visible() {
+            self.draw(frame);
+            return;
+        }
+        let mut overlay = self.osd_frame.take().unwrap_or_else(|| frame.clone());
+        overlay.data.copy_from_slice(&frame.data);
+        self.osd.draw(&mut overlay);
+        self.draw(&overlay);
+        self.osd_frame = Some(overlay);
+    }
+}
+
+impl Frontend for SdlFrontend {
+    fn update(&mut self, emulator: &mut Emulator) -> bool {
+        self.pacer.wait();
+        let events: Vec<Event> = self.event_pump.poll_iter().collect();
+        for event in events {
+            let running = match event {
+                Event::Quit { .. } => false,
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    keymod,
+                    repeat,
+                    ..
+                } => match self.bindings.hotkeys.get(&keycode) {
+                    Some(_) if repeat => true,
+                    Some(&hotkey) => self.hotkey(
+                        emulator,
+                        hotkey,
+                        keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD),
+                        true,
+                    ),
+                    None => {
+                        for port in 0..2 {
+                            if let Some(&action) = self.bindings.keys[port].get(&keycode) {
+                                apply(emulator, port, action, true);
+                            }
+                        }
+                        true
+                    }
+                },
+                Event::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => match self.bindings.hotkeys.get(&keycode) {
+                    Some(&hotkey) => self.hotkey(emulator, hotkey, false, false),
+                    None => {
+                        for port in 0..2 {
+                            if let Some(&action) = self.bindings.keys[port].get(&keycode) {
+                                apply(emulator, port, action, false);
+                            }
+                        }
+                        true
+                    }
+                },
+                Event::ControllerDeviceAdded { which, .. } => {
+                    match self.controller_subsystem.open(which) {
+                        Ok(controller) => {
+                            self.notify(format!("gamepad connected: {}", controller.name()));
+                            self.controllers.push(controller);
+                        }
+                        Err(e) => eprintln!("failed to open gamepad: {}", e),
+                    }
+                    true
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    self.controllers
+                        .retain(|controller| controller.instance_id() != which);
+                    true
+                }
+                Event::ControllerButtonDown { which, button, .. }
+                | Event::ControllerButtonUp { which, button, .. } => {
+                    let pressed = matches!(event, Event::ControllerButtonDown { .. });
+                    if let Some(port) = self.controller_port(which) {
+                        if let Some(&action) = self.bindings.buttons[port].get(&button) {
+                            apply(emulator, port, action, pressed);
+                        }
+                    }
+                    true
+                }
+                Event::Window {
+                    win_event: WindowEvent::FocusGained,
+                    ..
+                } => {
+                    self.set_focused(emulator, true);
+                    true
+                }
+                Event::Window {
+                    win_event: WindowEvent::FocusLost,
+                    ..
+                } => {
+                    self.set_focused(emulator, false);
+                    true
+                }
+                Event::DropFile { filename, .. } => {
+                    self.load_rom(emulator, PathBuf::from(filename));
+                    true
+                }
+                _ => true,
+            };
+            if !running {
+                self.shutdown(emulator);
+                return false;
+            }
+        }
+
+        self.pacer.set_speed(self.speed());
+
+        let frame = emulator.frame_count();
+        let bus = &mut emulator.cpu_mut().bus;
+        if let Some(device) = self.audio_device.as_mut() {
+            let adjustment = device.lock().0.rate_adjustment();
+            bus.apu_mut().set_rate_adjustment(adjustment);
+        }
+        if let Some(battery) = self.battery.as_mut() {
+            if let Some(Err(e)) = bus.with_battery_ram(|ram| battery.tick(frame, ram)) {
+                eprintln!("failed to write save data: {}", e);
+            }
+        }
+        true
+    }
+
+    fn queue_audio(&mut self, samples: &[f32]) {
+        if self.is_throttled() {
+            return;
+        }
+        if let Some(device) = self.audio_device.as_mut() {
+            device.lock().0.push(samples);
+        }
+    }
+}
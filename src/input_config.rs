@@ -0,0 +1,439 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::apu::Channel;
+use crate::joypad::JoypadButton;
+
+// コントローラーのボタン名。turbo_ を付けると連射
+const BUTTONS: [(&str, JoypadButton); 8] = [
+    ("up", JoypadButton::UP),
+    ("down", JoypadButton::DOWN),
+    ("left", JoypadButton::LEFT),
+    ("right", JoypadButton::RIGHT),
+    ("select", JoypadButton::SELECT),
+    ("start", JoypadButton::START),
+    ("a", JoypadButton::BUTTON_A),
+    ("b", JoypadButton::BUTTON_B),
+];
+
+// キーやゲームパッドのボタンを押したときにコントローラーで押されるもの
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Button(JoypadButton),
+    Turbo(JoypadButton),
+}
+
+impl Action {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        let (turbo, button) = match name.strip_prefix("turbo_") {
+            Some(button) => (true, button),
+            None => (false, name),
+        };
+        let button = BUTTONS
+            .iter()
+            .find(|(n, _)| *n == button)
+            .map(|(_, b)| *b)
+            .ok_or_else(|| format!("unknown button: {}", name))?;
+        Ok(if turbo {
+            Action::Turbo(button)
+        } else {
+            Action::Button(button)
+        })
+    }
+}
+
+// エミュレータ自体の操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hotkey {
+    Quit,
+    VolumeDown,
+    VolumeUp,
+    ToggleBackground,
+    ToggleSprites,
+    CycleNametable,
+    InputOverlay,
+    // Shiftを押しながらならソロ
+    Mute(Channel),
+    // Shiftを押しながらならチャンネルごとのステムも書く
+    RecordAudio,
+    TurboFaster,
+    TurboSlower,
+    // 押している間だけ
+    FastForward,
+    SlowMotion,
+    // Shiftを押しながらならスローの速さ
+    CycleSpeed,
+    Fullscreen,
+    CycleScaleMode,
+    Screenshot,
+    // Shiftを押しながらならffmpegでMP4にする
+    RecordVideo,
+    // 直近の数秒をGIFにする
+    SaveClip,
+    // 走査線などのブラウン管風の後処理を切り替える
+    CycleCrtFilter,
+    // Scale2xなどの拡大フィルタを切り替える
+    CycleFilter,
+    // 画面の右上にFPSを出す
+    ToggleFps,
+    Pause,
+    // 一時停止して1フレームだけ進める
+    FrameAdvance,
+}
+
+impl Hotkey {
+    pub const ALL: [Hotkey; 29] = [
+        Hotkey::Quit,
+        Hotkey::VolumeDown,
+        Hotkey::VolumeUp,
+        Hotkey::ToggleBackground,
+        Hotkey::ToggleSprites,
+        Hotkey::CycleNametable,
+        Hotkey::InputOverlay,
+        Hotkey::Mute(Channel::Pulse1),
+        Hotkey::Mute(Channel::Pulse2),
+        Hotkey::Mute(Channel::Triangle),
+        Hotkey::Mute(Channel::Noise),
+        Hotkey::Mute(Channel::Dmc),
+        Hotkey::Mute(Channel::Expansion),
+        Hotkey::RecordAudio,
+        Hotkey::TurboFaster,
+        Hotkey::TurboSlower,
+        Hotkey::FastForward,
+        Hotkey::SlowMotion,
+        Hotkey::CycleSpeed,
+        Hotkey::Fullscreen,
+        Hotkey::CycleScaleMode,
+        Hotkey::Screenshot,
+        Hotkey::RecordVideo,
+        Hotkey::SaveClip,
+        Hotkey::CycleCrtFilter,
+        Hotkey::CycleFilter,
+        Hotkey::ToggleFps,
+        Hotkey::Pause,
+        Hotkey::FrameAdvance,
+    ];
+
+    pub fn name(&self) -> String {
+        match self {
+            Hotkey::Quit => "quit".to_string(),
+            Hotkey::VolumeDown => "volume_down".to_string(),
+            Hotkey::VolumeUp => "volume_up".to_string(),
+            Hotkey::ToggleBackground => "toggle_background".to_string(),
+            Hotkey::ToggleSprites => "toggle_sprites".to_string(),
+            Hotkey::CycleNametable => "cycle_nametable".to_string(),
+            Hotkey::InputOverlay => "input_overlay".to_string(),
+            Hotkey::Mute(channel) => format!("mute_{}", channel.name()),
+            Hotkey::RecordAudio => "record_audio".to_string(),
+            Hotkey::TurboFaster => "turbo_faster".to_string(),
+            Hotkey::TurboSlower => "turbo_slower".to_string(),
+            Hotkey::FastForward => "fast_forward".to_string(),
+            Hotkey::SlowMotion => "slow_motion".to_string(),
+            Hotkey::CycleSpeed => "cycle_speed".to_string(),
+            Hotkey::Fullscreen => "fullscreen".to_string(),
+            Hotkey::CycleScaleMode => "cycle_scale_mode".to_string(),
+            Hotkey::Screenshot => "screenshot".to_string(),
+            Hotkey::RecordVideo => "record_video".to_string(),
+            Hotkey::SaveClip => "save_clip".to_string(),
+            Hotkey::CycleCrtFilter => "cycle_crt_filter".to_string(),
+            Hotkey::CycleFilter => "cycle_filter".to_string(),
+            Hotkey::ToggleFps => "toggle_fps".to_string(),
+            Hotkey::Pause => "pause".to_string(),
+            Hotkey::FrameAdvance => "frame_advance".to_string(),
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        Hotkey::ALL
+            .iter()
+            .find(|hotkey| hotkey.name() == name)
+            .copied()
+            .ok_or_else(|| format!("unknown hotkey: {}", name))
+    }
+
+    // SDLのキー名
+    fn default_key(&self) -> &'static str {
+        match self {
+            Hotkey::Quit => "Escape",
+            Hotkey::VolumeDown => "-",
+            Hotkey::VolumeUp => "=",
+            Hotkey::ToggleBackground => "F1",
+            Hotkey::ToggleSprites => "F2",
+            Hotkey::CycleNametable => "F3",
+            Hotkey::InputOverlay => "F4",
+            Hotkey::Mute(Channel::Pulse1) => "F5",
+            Hotkey::Mute(Channel::Pulse2) => "F6",
+            Hotkey::Mute(Channel::Triangle) => "F7",
+            Hotkey::Mute(Channel::Noise) => "F8",
+            Hotkey::Mute(Channel::Dmc) => "F9",
+            Hotkey::Mute(Channel::Expansion) => "F10",
+            Hotkey::RecordAudio => "F11",
+            Hotkey::TurboFaster => "[",
+            Hotkey::TurboSlower => "]",
+            Hotkey::FastForward => "Tab",
+            Hotkey::SlowMotion => "`",
+            Hotkey::CycleSpeed => "F12",
+            Hotkey::Fullscreen => "F",
+            Hotkey::CycleScaleMode => "G",
+            Hotkey::Screenshot => "P",
+            Hotkey::RecordVideo => "V",
+            Hotkey::SaveClip => "C",
+            Hotkey::CycleCrtFilter => "H",
+            Hotkey::CycleFilter => "Y",
+            Hotkey::ToggleFps => "T",
+            Hotkey::Pause => "Backspace",
+            Hotkey::FrameAdvance => "\\",
+        }
+    }
+}
+
+// ボタン名からキー (ゲームパッドのボタン) 名への対応。空文字なら割り当てない
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PortBindings {
+    pub port1: BTreeMap<String, String>,
+    pub port2: BTreeMap<String, String>,
+}
+
+impl PortBindings {
+    fn from_pairs(port1: &[(&str, &str)], port2: &[(&str, &str)]) -> Self {
+        let map = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(button, key)| (button.to_string(), key.to_string()))
+                .collect()
+        };
+        PortBindings {
+            port1: map(port1),
+            port2: map(port2),
+        }
+    }
+
+    pub fn port(&self, port: usize) -> &BTreeMap<String, String> {
+        if port == 0 {
+            &self.port1
+        } else {
+            &self.port2
+        }
+    }
+
+    // 割り当てられているものだけ
+    pub fn actions(&self, port: usize) -> Vec<(Action, &str)> {
+        self.port(port)
+            .iter()
+            .filter(|(_, key)| !key.is_empty())
+            .filter_map(|(name, key)| Some((Action::parse(name).ok()?, key.as_str())))
+            .collect()
+    }
+
+    // 書かれていないボタンはdefaultsのまま
+    fn merge(&mut self, other: PortBindings) -> Result<(), String> {
+        for (name, _) in other.port1.iter().chain(other.port2.iter()) {
+            Action::parse(name)?;
+        }
+        self.port1.extend(other.port1);
+        self.port2.extend(other.port2);
+        Ok(())
+    }
+}
+
+// キーボードとゲームパッドの割り当て、ホットキー
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputConfig {
+    pub keyboard: PortBindings,
+    // SDLのゲームコントローラーのボタン名 (a, b, x, y, back, start, dpup など)。
+    // つないだ順に1P, 2P
+    pub gamepad: PortBindings,
+    pub hotkeys: BTreeMap<String, String>,
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        let gamepad = [
+            ("up", "dpup"),
+            ("down", "dpdown"),
+            ("left", "dpleft"),
+            ("right", "dpright"),
+            ("select", "back"),
+            ("start", "start"),
+            // NESのBとAは左右に並ぶので、パッドの下と右のボタンに割り当てる
+            ("a", "b"),
+            ("b", "a"),
+            ("turbo_a", "y"),
+            ("turbo_b", "x"),
+        ];
+        InputConfig {
+            keyboard: PortBindings::from_pairs(
+                &[
+                    ("up", "Up"),
+                    ("down", "Down"),
+                    ("left", "Left"),
+                    ("right", "Right"),
+                    ("select", "Space"),
+                    ("start", "Return"),
+                    ("a", "A"),
+                    ("b", "S"),
+                    ("turbo_a", "X"),
+                    ("turbo_b", "Z"),
+                ],
+                &[
+                    ("up", "I"),
+                    ("down", "K"),
+                    ("left", "J"),
+                    ("right", "L"),
+                    ("select", "U"),
+                    ("start", "O"),
+                    ("a", "M"),
+                    ("b", "N"),
+                    ("turbo_a", "."),
+                    ("turbo_b", ","),
+                ],
+            ),
+            gamepad: PortBindings::from_pairs(&gamepad, &gamepad),
+            hotkeys: Hotkey::ALL
+                .iter()
+                .map(|hotkey| (hotkey.name(), hotkey.default_key().to_string()))
+                .collect(),
+        }
+    }
+}
+
+const HEADER: &str = "# nes-rs の入力設定。キー名はSDLのもの (A, Return, Left, F1, [ など)、
+# ゲームパッドのボタン名はSDLのゲームコントローラーのもの (a, b, x, y, back, start, dpup など)。
+# 書かなかった項目は初期値のまま、空文字にすると割り当てなし
+";
+
+impl InputConfig {
+    // 書かれている項目だけ初期値を上書きする
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let file: InputConfig = toml::from_str(text).map_err(|e| e.to_string())?;
+        let mut config = InputConfig::default();
+        config.keyboard.merge(file.keyboard)?;
+        config.gamepad.merge(file.gamepad)?;
+        for name in file.hotkeys.keys() {
+            Hotkey::parse(name)?;
+        }
+        config.hotkeys.extend(file.hotkeys);
+        Ok(config)
+    }
+
+    pub fn to_text(&self) -> String {
+        format!("{}\n{}", HEADER, toml::to_string(self).unwrap())
+    }
+
+    // 割り当てられているものだけ
+    pub fn hotkeys(&self) -> Vec<(Hotkey, &str)> {
+        self.hotkeys
+            .iter()
+            .filter(|(_, key)| !key.is_empty())
+            .filter_map(|(name, key)| Some((Hotkey::parse(name).ok()?, key.as_str())))
+            .collect()
+    }
+
+    // $XDG_CONFIG_HOME/nes-rs/config.toml か ~/.config/nes-rs/config.toml
+    pub fn default_path() -> PathBuf {
+        let dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+            .unwrap_or_default();
+        dir.join("nes-rs").join("config.toml")
+    }
+
+    // ファイルが無ければ初期値を書き出して使う。書き出せなくても初期値で続ける
+    pub fn load_or_create<P: AsRef<Path>>(path: P) -> Result<LoadedConfig, String> {
+        let path = path.as_ref();
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(LoadedConfig {
+                config: InputConfig::parse(&text)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?,
+                created: None,
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let config = InputConfig::default();
+                let created = path
+                    .parent()
+                    .map_or(Ok(()), fs::create_dir_all)
+                    .and_then(|_| fs::write(path, config.to_text()))
+                    .map(|_| path.to_path_buf())
+                    .map_err(|e| format!("{}: {}", path.display(), e));
+                Ok(LoadedConfig {
+                    config,
+                    created: Some(created),
+                })
+            }
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+}
+
+// load_or_createの結果。初期値を書き出そうとしたときは、そのパスか書き出せなかった理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadedConfig {
+    pub config: InputConfig,
+    pub created: Option<Result<PathBuf, String>>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_input_config() {
+        let config = InputConfig::parse(
+            r#"
+[keyboard.port1]
+a = "K"
+turbo_b = ""
+
+[gamepad.port2]
+start = "guide"
+
+[hotkeys]
+quit = "Q"
+mute_dmc = "F1"
+"#,
+        )
+        .unwrap();
+        let actions = config.keyboard.actions(0);
+        assert!(actions.contains(&(Action::Button(JoypadButton::BUTTON_A), "K")));
+        assert!(actions.contains(&(Action::Button(JoypadButton::UP), "Up")));
+        assert!(actions.contains(&(Action::Turbo(JoypadButton::BUTTON_A), "X")));
+        assert!(!actions
+            .iter()
+            .any(|(action, _)| *action == Action::Turbo(JoypadButton::BUTTON_B)));
+        assert_eq!(config.keyboard.port(1)["a"], "M");
+        assert_eq!(config.gamepad.port(1)["start"], "guide");
+        assert_eq!(config.gamepad.port(0)["start"], "start");
+
+        let hotkeys = config.hotkeys();
+        assert!(hotkeys.contains(&(Hotkey::Quit, "Q")));
+        assert!(hotkeys.contains(&(Hotkey::Mute(Channel::Dmc), "F1")));
+        assert_eq!(hotkeys.len(), Hotkey::ALL.len());
+
+        assert!(InputConfig::parse("[keyboard.port1]\njump = \"A\"\n").is_err());
+        assert!(InputConfig::parse("[hotkeys]\nrewind = \"R\"\n").is_err());
+        assert!(InputConfig::parse("[mouse]\n").is_err());
+    }
+
+    #[test]
+    fn test_load_or_create() {
+        let path = std::env::temp_dir()
+            .join(format!("nes-rs-config-{}", std::process::id()))
+            .join("config.toml");
+        let _ = fs::remove_file(&path);
+
+        let loaded = InputConfig::load_or_create(&path).unwrap();
+        assert_eq!(loaded.config, InputConfig::default());
+        assert_eq!(loaded.created, Some(Ok(path.clone())));
+        // 書き出したものを読み直しても同じ
+        let reloaded = InputConfig::load_or_create(&path).unwrap();
+        assert_eq!(reloaded.config, loaded.config);
+        assert_eq!(reloaded.created, None);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
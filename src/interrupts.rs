@@ -2,6 +2,7 @@ pub mod interrupts {
     #[derive(PartialEq, Eq)]
     pub enum InterruptType {
         NMI,
+        IRQ,
     }
 
     #[derive(PartialEq, Eq)]
@@ -18,4 +19,11 @@ pub mod interrupts {
         b_flag_mask: 0b0010_0000,
         cpu_cycles: 2,
     };
+
+    pub const IRQ: Interrupt = Interrupt {
+        itype: InterruptType::IRQ,
+        vector_addr: 0xfffe,
+        b_flag_mask: 0b0010_0000,
+        cpu_cycles: 2,
+    };
 }
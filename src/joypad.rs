@@ -1,5 +1,7 @@
 use bitflags::bitflags;
 
+use crate::savestate::State;
+
 bitflags! {
   pub struct JoypadButton: u8 {
     const RIGHT    = 0b10000000;
@@ -13,10 +15,20 @@ bitflags! {
   }
 }
 
+// 連射で押している/離しているフレーム数の初期値 (60fpsで15回/秒)
+pub const DEFAULT_TURBO_RATE: u8 = 2;
+// 押して離す1周期 (rateの2倍) がu8に収まる最大
+pub const MAX_TURBO_RATE: u8 = 127;
+
+#[derive(Clone)]
 pub struct Joypad {
     strobe: bool,
     button_index: u8,
     button_status: JoypadButton,
+    // 連射キーで押されているボタン
+    turbo_status: JoypadButton,
+    turbo_rate: u8,
+    turbo_frame: u8,
 }
 
 impl Joypad {
@@ -25,6 +37,9 @@ impl Joypad {
             strobe: false,
             button_index: 0,
             button_status: JoypadButton::from_bits_truncate(0),
+            turbo_status: JoypadButton::empty(),
+            turbo_rate: DEFAULT_TURBO_RATE,
+            turbo_frame: 0,
         }
     }
 
@@ -41,14 +56,123 @@ impl Joypad {
         }
 
         // 当該button_indexのbitが立っているがどうかを調べてるだけ
-        let response = (self.button_status.bits & (1 << self.button_index)) >> self.button_index;
+        let response = (self.buttons().bits & (1 << self.button_index)) >> self.button_index;
         if !self.strobe && self.button_index <= 7 {
             self.button_index += 1;
         }
         response
     }
 
+    // 次にreadで返す値
+    pub fn peek(&self) -> u8 {
+        if self.button_index > 7 {
+            return 1;
+        }
+        (self.buttons().bits >> self.button_index) & 1
+    }
+
+    // ゲームから見える今のボタン。連射中のボタンは一定フレームごとに押したり離したりする
+    pub fn buttons(&self) -> JoypadButton {
+        if self.turbo_frame < self.turbo_rate {
+            self.button_status | self.turbo_status
+        } else {
+            self.button_status
+        }
+    }
+
+    pub fn button_status(&self) -> JoypadButton {
+        self.button_status
+    }
+
     pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
         self.button_status.set(button, pressed);
     }
+
+    pub fn turbo_status(&self) -> JoypadButton {
+        self.turbo_status
+    }
+
+    // 連射キーの押下。通常のボタンとは別に持つ
+    pub fn set_turbo_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
+        self.turbo_status.set(button, pressed);
+    }
+
+    pub fn turbo_rate(&self) -> u8 {
+        self.turbo_rate
+    }
+
+    // 押している/離しているフレーム数。1..=MAX_TURBO_RATEに収める
+    pub fn set_turbo_rate(&mut self, frames: u8) {
+        self.turbo_rate = frames.clamp(1, MAX_TURBO_RATE);
+        self.turbo_frame = 0;
+    }
+
+    // フレームの区切りごとに呼ぶ
+    pub fn end_frame(&mut self) {
+        // 読み込んだセーブステートのturbo_frameは範囲外のことがある
+        let period = self.turbo_rate as u16 * 2;
+        self.turbo_frame = ((self.turbo_frame as u16 + 1) % period) as u8;
+    }
+
+    // 連射の速さは設定なので含めない
+    pub fn state(&mut self, state: &mut State) {
+        state.bool(&mut self.strobe);
+        state.u8(&mut self.button_index);
+        state.map_u8(
+            &mut self.button_status,
+            |b| b.bits(),
+            JoypadButton::from_bits_truncate,
+        );
+        state.map_u8(
+            &mut self.turbo_status,
+            |b| b.bits(),
+            JoypadButton::from_bits_truncate,
+        );
+        state.u8(&mut self.turbo_frame);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_turbo() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_B, true);
+        joypad.set_turbo_pressed_status(JoypadButton::BUTTON_A, true);
+        let mut pressed = vec![];
+        for _ in 0..8 {
+            pressed.push(joypad.buttons().contains(JoypadButton::BUTTON_A));
+            assert!(joypad.buttons().contains(JoypadButton::BUTTON_B));
+            joypad.end_frame();
+        }
+        assert_eq!(
+            pressed,
+            vec![true, true, false, false, true, true, false, false]
+        );
+
+        joypad.set_turbo_rate(1);
+        joypad.write(1);
+        assert_eq!(joypad.read(), 1);
+        joypad.end_frame();
+        assert_eq!(joypad.read(), 0);
+
+        joypad.set_turbo_pressed_status(JoypadButton::BUTTON_A, false);
+        joypad.end_frame();
+        assert_eq!(joypad.read(), 0);
+
+        // 大きすぎる値はMAX_TURBO_RATEにする
+        joypad.set_turbo_pressed_status(JoypadButton::BUTTON_A, true);
+        joypad.set_turbo_rate(255);
+        assert_eq!(joypad.turbo_rate(), MAX_TURBO_RATE);
+        let pressed = (0..4 * MAX_TURBO_RATE as usize)
+            .filter(|_| {
+                let pressed = joypad.buttons().contains(JoypadButton::BUTTON_A);
+                joypad.end_frame();
+                pressed
+            })
+            .count();
+        assert_eq!(pressed, 2 * MAX_TURBO_RATE as usize);
+    }
 }
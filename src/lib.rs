@@ -1,16 +1,64 @@
+pub mod apu;
+pub mod apu_inspect;
+pub mod apu_mixer;
+pub mod archive;
+pub mod audio_buffer;
+pub mod avi;
+pub mod battery;
 pub mod bus;
 pub mod cartridge;
+pub mod clip;
+pub mod console;
+pub mod controller;
 pub mod cpu;
+pub mod debugger;
+pub mod desync;
+pub mod doctor;
+pub mod emulator;
+pub mod fm2;
+pub mod frontend;
+#[cfg(feature = "sdl")]
+pub mod frontend_sdl;
+#[cfg(feature = "config")]
+pub mod input_config;
 pub mod interrupts;
 pub mod joypad;
+pub mod mapper;
+pub mod mapper_camerica;
+pub mod mapper_fds;
+pub mod mapper_fme7;
+pub mod mapper_latch;
+pub mod mapper_mmc5;
+pub mod mapper_namco108;
+pub mod mapper_namco163;
+pub mod mapper_nrom;
+pub mod mapper_nsf;
+pub mod mapper_vrc4;
+pub mod mapper_vrc6;
+pub mod movie;
+pub mod nsf;
 pub mod opcodes;
+pub mod pacing;
+pub mod patch;
 pub mod ppu;
-pub mod ppu_addr_register;
 pub mod ppu_control_register;
+pub mod ppu_loopy_register;
 pub mod ppu_mask_register;
-pub mod ppu_scroll_register;
 pub mod ppu_status_register;
 pub mod renderer;
+pub mod renderer_crt;
+pub mod renderer_filter;
 pub mod renderer_frame;
+pub mod renderer_input;
+pub mod renderer_osd;
 pub mod renderer_palette;
+#[cfg(feature = "rom-db")]
+pub mod rom_db;
+pub mod savestate;
+pub mod timeline;
 pub mod trace;
+pub mod trace_binary;
+pub mod video;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod wav;
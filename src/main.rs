@@ -1,150 +1,272 @@
-use std::collections::HashMap;
-
-use nes_rs::bus::Bus;
+use nes_rs::archive;
 use nes_rs::cartridge::Rom;
-use nes_rs::cpu::{Mem, CPU};
-use nes_rs::ppu::NesPPU;
-use nes_rs::renderer_frame::Frame;
-use nes_rs::{joypad, renderer, trace::*};
-use rand::Rng;
-use sdl2::event::Event;
-use sdl2::keyboard::{self, Keycode};
-use sdl2::pixels::{Color, PixelFormatEnum};
-use sdl2::EventPump;
+use nes_rs::console::Region;
+use nes_rs::doctor;
+use nes_rs::emulator::{self, Accuracy, Emulator, EmulatorConfig};
+use nes_rs::frontend::{self, BackgroundMode, ScaleMode};
+use nes_rs::frontend_sdl::SdlFrontend;
+use nes_rs::input_config::InputConfig;
+use nes_rs::renderer_filter::{self, Filter};
+use nes_rs::renderer_frame::Overscan;
 
-fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
-    for event in event_pump.poll_iter() {
-        match event {
-            Event::Quit { .. }
-            | Event::KeyDown {
-                keycode: Some(Keycode::Escape),
-                ..
-            } => std::process::exit(0),
-            Event::KeyDown {
-                keycode: Some(Keycode::W),
-                ..
-            } => {
-                cpu.mem_write(0xff, 0x77);
-            }
-            Event::KeyDown {
-                keycode: Some(Keycode::S),
-                ..
-            } => {
-                cpu.mem_write(0xff, 0x73);
-            }
-            Event::KeyDown {
-                keycode: Some(Keycode::A),
-                ..
-            } => {
-                cpu.mem_write(0xff, 0x61);
-            }
-            Event::KeyDown {
-                keycode: Some(Keycode::D),
-                ..
-            } => {
-                cpu.mem_write(0xff, 0x64);
-            }
-            _ => {}
+// nes-rs compare <rom> <config-a> <config-b> [frames] [inputs]
+fn run_compare(args: &[String]) -> Result<bool, String> {
+    if args.len() < 3 {
+        return Err(
+            "usage: nes-rs compare <rom> <config-a> <config-b> [frames] [inputs]".to_string(),
+        );
+    }
+    let rom = std::fs::read(&args[0]).map_err(|e| e.to_string())?;
+    let config_a = EmulatorConfig::parse(&args[1])?;
+    let config_b = EmulatorConfig::parse(&args[2])?;
+    let frames = match args.get(3) {
+        Some(n) => n.parse::<u64>().map_err(|e| e.to_string())?,
+        None => 600,
+    };
+    let inputs = match args.get(4) {
+        Some(path) => {
+            emulator::parse_inputs(&std::fs::read_to_string(path).map_err(|e| e.to_string())?)?
+        }
+        None => vec![],
+    };
+
+    match emulator::compare(&rom, config_a, config_b, &inputs, frames)? {
+        Some(diff) => {
+            diff.a.save_png("compare-a.png")?;
+            diff.b.save_png("compare-b.png")?;
+            println!(
+                "outputs differ at frame {} (see compare-a.png, compare-b.png)",
+                diff.frame
+            );
+            Ok(false)
+        }
+        None => {
+            println!("outputs identical for {} frames", frames);
+            Ok(true)
         }
     }
 }
 
-fn color(byte: u8) -> Color {
-    match byte {
-        0 => sdl2::pixels::Color::BLACK,
-        1 => sdl2::pixels::Color::WHITE,
-        2 | 9 => sdl2::pixels::Color::GREY,
-        3 | 10 => sdl2::pixels::Color::RED,
-        4 | 11 => sdl2::pixels::Color::GREEN,
-        5 | 12 => sdl2::pixels::Color::BLUE,
-        6 | 13 => sdl2::pixels::Color::MAGENTA,
-        7 | 14 => sdl2::pixels::Color::YELLOW,
-        _ => sdl2::pixels::Color::CYAN,
-    }
+const DEFAULT_SCALE: u32 = 3;
+
+const DEFAULT_CLIP_SECONDS: f64 = 10.0;
+
+const USAGE: &str =
+    "usage: nes-rs [--scale N] [--fullscreen] [--scale-mode integer|aspect] [--crop-overscan] [--clip-seconds N] [--filter NAME] [--show-fps] [--run-ahead N] [--region ntsc|pal|dendy] [--accuracy fast|accurate] [--background run|pause|throttle] [--config PATH] <rom>";
+
+struct RunArgs {
+    rom_path: String,
+    scale: u32,
+    fullscreen: bool,
+    scale_mode: ScaleMode,
+    // スクリーンショットの上下8ラインを切り落とす
+    crop_overscan: bool,
+    // GIFにするために残しておく秒数。0なら残さない
+    clip_seconds: f64,
+    // scale2x, scale3x, scale4x, xbr2x
+    filter: Option<Box<dyn Filter>>,
+    show_fps: bool,
+    // 何フレーム先の画面を見せるか。0なら使わない
+    run_ahead: u32,
+    // Noneならヘッダーの指定に従う
+    region: Option<Region>,
+    accuracy: Accuracy,
+    // ウィンドウが裏に回ったときに止めるか、音を消して遅くするか
+    background: BackgroundMode,
+    // Noneなら InputConfig::default_path()
+    config_path: Option<String>,
 }
 
-fn read_screen_state(cpu: &mut CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
-    let mut frame_idx = 0;
-    let mut update = false;
-    for i in 0x0200..0x0600 {
-        let color_idx = cpu.mem_read(i as u16);
-        let (b1, b2, b3) = color(color_idx).rgb();
-        if frame[frame_idx] != b1 || frame[frame_idx + 1] != b2 || frame[frame_idx + 2] != b3 {
-            frame[frame_idx] = b1;
-            frame[frame_idx + 1] = b2;
-            frame[frame_idx + 2] = b3;
-            update = true;
+// nes-rs [--scale N] [--fullscreen] [--scale-mode integer|aspect] [--crop-overscan]
+//        [--clip-seconds N] [--filter NAME] [--show-fps] [--run-ahead N]
+//        [--region ntsc|pal|dendy] [--accuracy fast|accurate]
+//        [--background run|pause|throttle] [--config PATH] <rom>
+fn parse_run_args(args: &[String]) -> Result<RunArgs, String> {
+    let mut rom_path = None;
+    let mut scale = DEFAULT_SCALE;
+    let mut fullscreen = false;
+    let mut scale_mode = ScaleMode::Integer;
+    let mut crop_overscan = false;
+    let mut clip_seconds = DEFAULT_CLIP_SECONDS;
+    let mut filter = None;
+    let mut show_fps = false;
+    let mut run_ahead = 0;
+    let mut region = None;
+    let mut accuracy = Accuracy::Fast;
+    let mut background = BackgroundMode::Run;
+    let mut config_path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scale" => {
+                let value = args.next().ok_or(USAGE)?;
+                scale = value
+                    .parse()
+                    .ok()
+                    .filter(|scale| *scale > 0)
+                    .ok_or_else(|| format!("invalid scale: {}", value))?;
+            }
+            "--fullscreen" => fullscreen = true,
+            "--scale-mode" => scale_mode = ScaleMode::parse(args.next().ok_or(USAGE)?)?,
+            "--crop-overscan" => crop_overscan = true,
+            "--clip-seconds" => {
+                let value = args.next().ok_or(USAGE)?;
+                clip_seconds = value
+                    .parse()
+                    .ok()
+                    .filter(|seconds: &f64| *seconds >= 0.0)
+                    .ok_or_else(|| format!("invalid clip length: {}", value))?;
+            }
+            "--filter" => {
+                filter = Some(renderer_filter::by_name(args.next().ok_or(USAGE)?)?);
+            }
+            "--show-fps" => show_fps = true,
+            "--run-ahead" => {
+                let value = args.next().ok_or(USAGE)?;
+                run_ahead = value
+                    .parse()
+                    .map_err(|_| format!("invalid run-ahead frames: {}", value))?;
+            }
+            "--region" => {
+                region = Some(match args.next().ok_or(USAGE)?.as_str() {
+                    "ntsc" => Region::Ntsc,
+                    "pal" => Region::Pal,
+                    "dendy" => Region::Dendy,
+                    other => return Err(format!("unknown region: {}", other)),
+                });
+            }
+            "--accuracy" => {
+                accuracy = match args.next().ok_or(USAGE)?.as_str() {
+                    "fast" => Accuracy::Fast,
+                    "accurate" => Accuracy::Accurate,
+                    other => return Err(format!("unknown accuracy: {}", other)),
+                };
+            }
+            "--background" => background = BackgroundMode::parse(args.next().ok_or(USAGE)?)?,
+            "--config" => config_path = Some(args.next().ok_or(USAGE)?.clone()),
+            _ if arg.starts_with("--") => {
+                return Err(format!("unknown option: {}\n{}", arg, USAGE))
+            }
+            _ if rom_path.is_none() => rom_path = Some(arg.clone()),
+            _ => return Err(USAGE.to_string()),
         }
-        frame_idx += 3
     }
-    update
+    Ok(RunArgs {
+        rom_path: rom_path.ok_or(USAGE)?,
+        scale,
+        fullscreen,
+        scale_mode,
+        crop_overscan,
+        clip_seconds,
+        filter,
+        show_fps,
+        run_ahead,
+        region,
+        accuracy,
+        background,
+        config_path,
+    })
 }
 
 fn main() {
-    // init sdl
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
-    let window = video_subsystem
-        .window("NES-RS", (256.0 * 3.0) as u32, (240.0 * 3.0) as u32)
-        .position_centered()
-        .build()
-        .unwrap();
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
-    let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(3.0, 3.0).unwrap();
-
-    // create texture
-    let creator = canvas.texture_creator();
-    let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
-        .unwrap();
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|s| s.as_str()) == Some("compare") {
+        match run_compare(&args[2..]) {
+            Ok(same) => std::process::exit(if same { 0 } else { 1 }),
+            Err(msg) => {
+                eprintln!("{}", msg);
+                std::process::exit(2);
+            }
+        }
+    }
+    if args.get(1).map(|s| s.as_str()) == Some("doctor") {
+        let results = doctor::run();
+        println!("{}", doctor::report(&results));
+        let failed = results
+            .iter()
+            .any(|r| matches!(r.outcome, doctor::Outcome::Fail(_)));
+        std::process::exit(if failed { 1 } else { 0 });
+    }
 
+    let RunArgs {
+        rom_path,
+        scale,
+        fullscreen,
+        scale_mode,
+        crop_overscan,
+        clip_seconds,
+        filter,
+        show_fps,
+        run_ahead,
+        region,
+        accuracy,
+        background,
+        config_path,
+    } = parse_run_args(&args[1..]).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let config_path = config_path
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(InputConfig::default_path);
+    let loaded = InputConfig::load_or_create(&config_path).unwrap_or_else(|e| {
+        eprintln!("failed to load input config: {}", e);
+        std::process::exit(1);
+    });
+    match &loaded.created {
+        Some(Ok(path)) => println!("wrote default input config to {}", path.display()),
+        Some(Err(e)) => eprintln!("failed to write default input config: {}", e),
+        None => {}
+    }
+    let input_config = loaded.config;
     // load the game to rom
-    let bytes: Vec<u8> = std::fs::read("nestest.nes").unwrap();
-    let rom = Rom::new(&bytes).unwrap();
-    let mut frame = Frame::new();
-
-    let mut key_map = HashMap::new();
-    key_map.insert(Keycode::Down, joypad::JoypadButton::DOWN);
-    key_map.insert(Keycode::Up, joypad::JoypadButton::UP);
-    key_map.insert(Keycode::Right, joypad::JoypadButton::RIGHT);
-    key_map.insert(Keycode::Left, joypad::JoypadButton::LEFT);
-    key_map.insert(Keycode::Space, joypad::JoypadButton::SELECT);
-    key_map.insert(Keycode::Return, joypad::JoypadButton::START);
-    key_map.insert(Keycode::A, joypad::JoypadButton::BUTTON_A);
-    key_map.insert(Keycode::S, joypad::JoypadButton::BUTTON_B);
-
-    // init game
-    let bus = Bus::new(rom, move |ppu: &NesPPU, joypad: &mut joypad::Joypad| {
-        renderer::render(ppu, &mut frame);
-        texture.update(None, &frame.data, 256 * 3).unwrap();
+    let bytes = archive::read_rom(&rom_path).unwrap_or_else(|e| {
+        eprintln!("failed to read {}", e);
+        std::process::exit(1);
+    });
+    let rom = Rom::new(&bytes).unwrap_or_else(|e| {
+        eprintln!("failed to load {}: {}", rom_path, e);
+        std::process::exit(1);
+    });
 
-        canvas.copy(&texture, None, None).unwrap();
+    let name = std::path::Path::new(&rom_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| rom_path.clone());
+    println!("{}\n{}", name, rom.info());
+    if let Some(region) = region {
+        println!("running as {:?} (--region)", region);
+    }
 
-        canvas.present();
-        for event in event_pump.poll_iter() {
-            match event {
-                Event::Quit { .. }
-                | Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
-                    ..
-                } => std::process::exit(0),
-                Event::KeyDown { keycode, .. } => {
-                    if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, true);
-                    }
-                }
-                Event::KeyUp { keycode, .. } => {
-                    if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, false);
-                    }
-                }
-                _ => { /* do nothing */ }
-            }
-        }
+    let mut config = EmulatorConfig {
+        accuracy,
+        region,
+        ..EmulatorConfig::default()
+    };
+    if crop_overscan {
+        config.overscan = Overscan::NTSC;
+    }
+    let mut emulator = Emulator::new(rom, config).unwrap_or_else(|e| {
+        eprintln!("failed to load {}: {}", rom_path, e);
+        std::process::exit(1);
     });
-
-    let mut cpu = CPU::new(bus);
-    cpu.reset();
-    cpu.run();
+    emulator.set_run_ahead(run_ahead);
+    if clip_seconds > 0.0 {
+        emulator.set_clip_length(Some(clip_seconds));
+    }
+    let mut frontend = SdlFrontend::new(&rom_path, scale, &input_config).unwrap_or_else(|e| {
+        eprintln!("failed to start: {}", e);
+        std::process::exit(1);
+    });
+    frontend.set_scale_mode(scale_mode);
+    frontend.set_filter(filter);
+    frontend.set_show_fps(show_fps);
+    frontend.set_background_mode(background);
+    if fullscreen {
+        if let Err(e) = frontend.set_fullscreen(true) {
+            eprintln!("failed to enter fullscreen: {}", e);
+        }
+    }
+    frontend.prepare(&mut emulator);
+    frontend::run(&mut emulator, &mut frontend);
 }
@@ -0,0 +1,206 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::cartridge::{Mirroring, Rom, RomError};
+use crate::mapper_camerica::Camerica;
+use crate::mapper_fme7::Fme7;
+use crate::mapper_latch::{self, Latch};
+use crate::mapper_mmc5::Mmc5;
+use crate::mapper_namco108::Namco108;
+use crate::mapper_namco163::Namco163;
+use crate::mapper_nrom::Nrom;
+use crate::mapper_vrc4::{Vrc4, VrcWiring};
+use crate::mapper_vrc6::Vrc6;
+use crate::savestate::State;
+
+// どの用途でCHRを読んでいるか。MMC5はスプライトと背景でバンクを切り替える
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChrFetch {
+    Background,
+    Sprite,
+    Cpu,
+}
+
+// ネームテーブル0-3がどこに繋がっているか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Nametable {
+    // 本体のVRAM (CIRAM) の0/1ページ目。2/3ページ目は4画面用の追加VRAM
+    Ciram(u8),
+    // カートリッジ側 (nametable_read/nametable_write)
+    Mapper,
+}
+
+pub fn nametable_from_mirroring(mirroring: Mirroring, table: u8) -> Nametable {
+    match mirroring {
+        Mirroring::HORIZONTAL => Nametable::Ciram((table >> 1) & 1),
+        Mirroring::VERTICAL => Nametable::Ciram(table & 1),
+        Mirroring::FOUR_SCREEN => Nametable::Ciram(table & 3),
+    }
+}
+
+// カートリッジ側の回路。BusとNesPPUの両方から同じインスタンスを参照する
+pub trait Mapper {
+    // $4020-$FFFF の読み込み。何も繋がっていなければNone
+    fn prg_read(&mut self, addr: u16) -> Option<u8>;
+    // 状態を変えずにprg_readと同じ値を返す (トレースやデバッガー用)。
+    // 読むとフラグが落ちたりアドレスが進んだりするレジスタを持つマッパーは上書きする
+    fn prg_peek(&mut self, addr: u16) -> Option<u8> {
+        self.prg_read(addr)
+    }
+    // $8000-$FFFFの8KBの枠slot (0-3) に見えているPRG ROMのバンク (8KB単位)。
+    // タイムラインにバンク切り替えを記録するのに使う。切り替えの無いマッパーはNone
+    fn mapped_prg_bank(&self, _slot: u8) -> Option<usize> {
+        None
+    }
+    // $4020-$FFFF への書き込み。レジスタやRAMが受け取らなければfalse
+    fn prg_write(&mut self, addr: u16, data: u8) -> bool;
+    // PPUの $0000-$1FFF
+    fn chr_read(&mut self, addr: u16) -> u8;
+    fn chr_write(&mut self, addr: u16, data: u8);
+    fn mirroring(&self) -> Mirroring;
+    // セーブステート。ROMは含めず、バンクなどのレジスタとRAMだけを読み書きする
+    fn state(&mut self, state: &mut State);
+    // カートリッジ側のPRG RAM。無ければバスの8KBを$6000-$7FFFに置く
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // 拡張音源の出力 (0.0-1.0)。APUのミキサーで本体の音に足し合わせる
+    fn audio_output(&self) -> f32 {
+        0.0
+    }
+
+    fn chr_fetch(&mut self, addr: u16, _kind: ChrFetch) -> u8 {
+        self.chr_read(addr)
+    }
+
+    fn nametable(&self, table: u8) -> Nametable {
+        nametable_from_mirroring(self.mirroring(), table)
+    }
+
+    fn nametable_read(&mut self, _addr: u16) -> u8 {
+        0
+    }
+
+    fn nametable_write(&mut self, _addr: u16, _data: u8) {}
+
+    // $2000/$2001 への書き込みを覗き見る
+    fn ppu_register_write(&mut self, _addr: u16, _data: u8) {}
+
+    // CPUがcyclesサイクル進んだときに呼ばれる
+    fn cpu_tick(&mut self, _cycles: u8) {}
+
+    // PPUが次のスキャンラインに進んだときに呼ばれる
+    fn scanline(&mut self, _scanline: u16, _rendering: bool) {}
+
+    // PPUのCHRのアドレスのA12が、しばらく (約10ドット) 下がっていた後に上がったときに呼ばれる。
+    // MMC3のスキャンラインカウンタを入れるときのためのフックで、今はどのマッパーも使っていない
+    fn a12_rise(&mut self) {}
+
+    // 背景タイルのCHRとパレットを差し替える (MMC5の拡張アトリビュート)
+    fn extended_tile(&mut self, _offset: u16, _tile_idx: u8) -> Option<([u8; 16], u8)> {
+        None
+    }
+
+    // 画面の縦分割。タイル列columnの画面y行目を (下位, 上位, パレット) で返す
+    fn split_tile_row(&mut self, _column: u8, _y: u8) -> Option<(u8, u8, u8)> {
+        None
+    }
+}
+
+pub type SharedMapper = Rc<RefCell<dyn Mapper>>;
+
+// createで扱えるマッパー番号
+pub fn is_supported(mapper: u16) -> bool {
+    matches!(
+        mapper,
+        0 | 5 | 11 | 19 | 21 | 22 | 23 | 24 | 25 | 26 | 66 | 69 | 71 | 206
+    )
+}
+
+pub fn create(rom: Rom) -> Result<SharedMapper, RomError> {
+    match rom.mapper {
+        0 => Ok(Rc::new(RefCell::new(Nrom::new(
+            rom.prg_rom,
+            rom.chr_rom,
+            rom.screen_mirroring,
+        )))),
+        5 => Ok(Rc::new(RefCell::new(Mmc5::new(rom.prg_rom, rom.chr_rom)))),
+        11 => Ok(Rc::new(RefCell::new(Latch::new(
+            mapper_latch::color_dreams,
+            rom.prg_rom,
+            rom.chr_rom,
+            rom.screen_mirroring,
+        )))),
+        19 => Ok(Rc::new(RefCell::new(Namco163::new(
+            rom.prg_rom,
+            rom.chr_rom,
+        )))),
+        21 | 22 | 23 | 25 => {
+            let wiring = VrcWiring::for_mapper(rom.mapper).unwrap();
+            Ok(Rc::new(RefCell::new(Vrc4::new(
+                wiring,
+                rom.prg_rom,
+                rom.chr_rom,
+            ))))
+        }
+        24 | 26 => Ok(Rc::new(RefCell::new(Vrc6::new(
+            rom.mapper,
+            rom.prg_rom,
+            rom.chr_rom,
+        )))),
+        66 => Ok(Rc::new(RefCell::new(Latch::new(
+            mapper_latch::gxrom,
+            rom.prg_rom,
+            rom.chr_rom,
+            rom.screen_mirroring,
+        )))),
+        69 => Ok(Rc::new(RefCell::new(Fme7::new(rom.prg_rom, rom.chr_rom)))),
+        71 => Ok(Rc::new(RefCell::new(Camerica::new(
+            rom.prg_rom,
+            rom.chr_rom,
+            rom.screen_mirroring,
+        )))),
+        206 => Ok(Rc::new(RefCell::new(Namco108::new(
+            rom.prg_rom,
+            rom.chr_rom,
+            rom.screen_mirroring,
+        )))),
+        n => Err(RomError::UnsupportedMapper(n)),
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    // 32KBの各バンクは先頭がバンク番号、残りは$FF
+    pub fn prg_rom(banks: usize) -> Vec<u8> {
+        (0..banks)
+            .flat_map(|bank| {
+                let mut data = vec![0xff; 0x8000];
+                data[0] = bank as u8;
+                data
+            })
+            .collect()
+    }
+
+    // sizeバイトのバンクをbanks個並べる。中身はすべてバンク番号
+    pub fn rom_banks(banks: usize, size: usize) -> Vec<u8> {
+        (0..banks).flat_map(|bank| vec![bank as u8; size]).collect()
+    }
+
+    #[test]
+    fn test_create() {
+        let mapper = create(test_rom()).unwrap();
+        assert_eq!(mapper.borrow_mut().prg_read(0x8000), Some(1));
+
+        let mut rom = test_rom();
+        rom.mapper = 255;
+        assert!(create(rom).is_err());
+    }
+}
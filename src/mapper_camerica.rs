@@ -0,0 +1,143 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::{nametable_from_mirroring, Mapper, Nametable};
+use crate::savestate::State;
+
+// マッパー71 (Camerica / Codemasters BF909x)。$C000-$FFFF への書き込みで
+// $8000-の16KBを切り替え、$C000-は最後のバンクに固定
+pub struct Camerica {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+    prg_bank: usize,
+    // Fire Hawk (BF9097) は $9000-$9FFF の bit4 で1画面ミラーリングのページを選ぶ。
+    // 初めて書き込まれるまではヘッダのミラーリングを使う
+    single_screen: Option<u8>,
+}
+
+impl Camerica {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        Camerica {
+            prg_rom,
+            chr: if chr_is_ram { vec![0; 0x2000] } else { chr_rom },
+            chr_is_ram,
+            mirroring,
+            prg_bank: 0,
+            single_screen: None,
+        }
+    }
+
+    fn read_prg_rom(&self, bank: usize, addr: u16) -> u8 {
+        let index = bank * 0x4000 + (addr as usize & 0x3fff);
+        self.prg_rom[index % self.prg_rom.len()]
+    }
+}
+
+impl Mapper for Camerica {
+    fn prg_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xbfff => Some(self.read_prg_rom(self.prg_bank, addr)),
+            0xc000..=0xffff => {
+                // NES 2.0やUNIFでは8KBしかないことがある
+                let last = (self.prg_rom.len() / 0x4000).saturating_sub(1);
+                Some(self.read_prg_rom(last, addr))
+            }
+            _ => None,
+        }
+    }
+
+    fn mapped_prg_bank(&self, slot: u8) -> Option<usize> {
+        let bank_16k = match slot {
+            0 | 1 => self.prg_bank,
+            _ => (self.prg_rom.len() / 0x4000).saturating_sub(1),
+        };
+        Some((bank_16k * 2 + slot as usize % 2) % (self.prg_rom.len() / 0x2000))
+    }
+
+    fn prg_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x9000..=0x9fff => self.single_screen = Some((data >> 4) & 1),
+            // BF9093はこの範囲を無視する
+            0x8000..=0xbfff => {}
+            0xc000..=0xffff => self.prg_bank = (data & 0x0f) as usize,
+            _ => return false,
+        }
+        true
+    }
+
+    fn chr_read(&mut self, addr: u16) -> u8 {
+        self.chr[addr as usize % self.chr.len()]
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            self.chr[addr as usize] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn nametable(&self, table: u8) -> Nametable {
+        match self.single_screen {
+            Some(page) => Nametable::Ciram(page),
+            None => nametable_from_mirroring(self.mirroring, table),
+        }
+    }
+
+    fn state(&mut self, state: &mut State) {
+        if self.chr_is_ram {
+            state.bytes(&mut self.chr);
+        }
+        state.usize(&mut self.prg_bank);
+        state.option(&mut self.single_screen, |state, page| state.u8(page));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::rom_banks;
+
+    fn camerica() -> Camerica {
+        // 16KBごとにバンク番号を書いたPRG 256KB、CHR RAM
+        let prg_rom = rom_banks(16, 0x4000);
+        Camerica::new(prg_rom, vec![], Mirroring::VERTICAL)
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut mapper = camerica();
+        assert_eq!(mapper.prg_read(0x8000), Some(0));
+        assert_eq!(mapper.prg_read(0xc000), Some(15));
+
+        mapper.prg_write(0xc000, 5);
+        assert_eq!(mapper.prg_read(0xbfff), Some(5));
+        mapper.prg_write(0xffff, 0xf7);
+        assert_eq!(mapper.prg_read(0x8000), Some(7));
+        assert_eq!(mapper.prg_read(0xffff), Some(15));
+        assert!(!mapper.prg_write(0x6000, 0));
+    }
+
+    #[test]
+    fn test_fire_hawk_single_screen() {
+        let mut mapper = camerica();
+        assert_eq!(mapper.nametable(1), Nametable::Ciram(1));
+
+        mapper.prg_write(0x9000, 0x10);
+        for table in 0..4 {
+            assert_eq!(mapper.nametable(table), Nametable::Ciram(1));
+        }
+        mapper.prg_write(0x9000, 0x00);
+        assert_eq!(mapper.nametable(3), Nametable::Ciram(0));
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let mut mapper = camerica();
+        mapper.chr_write(0x1234, 0x56);
+        assert_eq!(mapper.chr_read(0x1234), 0x56);
+    }
+}
@@ -0,0 +1,819 @@
+use crate::cartridge::{FdsImage, Mirroring};
+use crate::mapper::Mapper;
+use crate::savestate::State;
+
+const BIOS_SIZE: usize = 0x2000;
+const RAM_SIZE: usize = 0x8000;
+// 1バイト読み書きするのにかかるCPUサイクル (96.4kHz)
+const BYTE_CYCLES: u32 = 150;
+// ヘッドが先頭に戻ってから読み始めるまで
+const REWIND_CYCLES: u32 = 50000;
+// ディスクを入れ替えてから認識されるまで (約0.5秒)
+const INSERT_CYCLES: u32 = 900_000;
+// 先頭のギャップ (28300bit) とブロック間のギャップ (976bit)
+const LEAD_IN_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+
+#[cfg(feature = "expansion-audio")]
+struct FdsEnvelope {
+    disabled: bool,
+    increase: bool,
+    speed: u8,
+    gain: u8,
+    counter: u32,
+}
+
+#[cfg(feature = "expansion-audio")]
+impl FdsEnvelope {
+    fn new() -> Self {
+        FdsEnvelope {
+            disabled: true,
+            increase: false,
+            speed: 0,
+            gain: 0,
+            counter: 0,
+        }
+    }
+
+    // $4080/$4084 (MDSS SSSS)
+    fn write(&mut self, data: u8) {
+        self.disabled = data & 0x80 != 0;
+        self.increase = data & 0x40 != 0;
+        self.speed = data & 0x3f;
+        if self.disabled {
+            self.gain = self.speed;
+        }
+        self.counter = 0;
+    }
+
+    fn clock(&mut self, master_speed: u8) {
+        if self.disabled {
+            return;
+        }
+        self.counter += 1;
+        if self.counter < 8 * (self.speed as u32 + 1) * master_speed as u32 {
+            return;
+        }
+        self.counter = 0;
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+
+    fn state(&mut self, state: &mut State) {
+        state.bool(&mut self.disabled);
+        state.bool(&mut self.increase);
+        state.u8(&mut self.speed);
+        state.u8(&mut self.gain);
+        state.u32(&mut self.counter);
+    }
+}
+
+// 64サンプルの波形メモリ音源と、周波数変調ユニット
+#[cfg(feature = "expansion-audio")]
+pub struct FdsAudio {
+    pub wave: [u8; 64],
+    wave_write: bool,
+    master_volume: u8,
+    wave_freq: u16,
+    wave_halt: bool,
+    envelope_halt: bool,
+    wave_acc: u32,
+    volume: FdsEnvelope,
+    modulation: FdsEnvelope,
+    mod_table: [u8; 64],
+    mod_pos: u8,
+    mod_freq: u16,
+    mod_halt: bool,
+    mod_acc: u32,
+    // 7bitの符号付き
+    mod_counter: i8,
+    envelope_speed: u8,
+}
+
+#[cfg(feature = "expansion-audio")]
+impl FdsAudio {
+    pub fn new() -> Self {
+        FdsAudio {
+            wave: [0; 64],
+            wave_write: false,
+            master_volume: 0,
+            wave_freq: 0,
+            wave_halt: true,
+            envelope_halt: false,
+            wave_acc: 0,
+            volume: FdsEnvelope::new(),
+            modulation: FdsEnvelope::new(),
+            mod_table: [0; 64],
+            mod_pos: 0,
+            mod_freq: 0,
+            mod_halt: true,
+            mod_acc: 0,
+            mod_counter: 0,
+            envelope_speed: 0xe8,
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4040..=0x407f => Some(self.wave[(addr - 0x4040) as usize] | 0x40),
+            0x4090 => Some(self.volume.gain | 0x40),
+            0x4092 => Some(self.modulation.gain | 0x40),
+            _ => None,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4040..=0x407f if self.wave_write => {
+                self.wave[(addr - 0x4040) as usize] = data & 0x3f;
+            }
+            0x4080 => self.volume.write(data),
+            0x4082 => self.wave_freq = (self.wave_freq & 0x0f00) | data as u16,
+            0x4083 => {
+                self.wave_freq = (self.wave_freq & 0x00ff) | ((data as u16 & 0x0f) << 8);
+                self.wave_halt = data & 0x80 != 0;
+                self.envelope_halt = data & 0x40 != 0;
+                if self.wave_halt {
+                    self.wave_acc = 0;
+                }
+            }
+            0x4084 => self.modulation.write(data),
+            0x4085 => self.mod_counter = ((data << 1) as i8) >> 1,
+            0x4086 => self.mod_freq = (self.mod_freq & 0x0f00) | data as u16,
+            0x4087 => {
+                self.mod_freq = (self.mod_freq & 0x00ff) | ((data as u16 & 0x0f) << 8);
+                self.mod_halt = data & 0x80 != 0;
+                if self.mod_halt {
+                    self.mod_acc = 0;
+                }
+            }
+            // 変調の停止中だけ書ける。1回の書き込みで2エントリ埋まる
+            0x4088 if self.mod_halt => {
+                for _ in 0..2 {
+                    self.mod_table[self.mod_pos as usize] = data & 0b111;
+                    self.mod_pos = (self.mod_pos + 1) & 0x3f;
+                }
+            }
+            0x4089 => {
+                self.wave_write = data & 0x80 != 0;
+                self.master_volume = data & 0b11;
+            }
+            0x408a => self.envelope_speed = data,
+            _ => {}
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.envelope_halt && !self.wave_halt && self.envelope_speed != 0 {
+            self.volume.clock(self.envelope_speed);
+            self.modulation.clock(self.envelope_speed);
+        }
+
+        if !self.mod_halt && self.mod_freq != 0 {
+            self.mod_acc += self.mod_freq as u32;
+            if self.mod_acc >= 0x10000 {
+                self.mod_acc -= 0x10000;
+                self.step_modulator();
+            }
+        }
+
+        if !self.wave_halt && !self.wave_write {
+            self.wave_acc = (self.wave_acc + self.pitch()) & 0x3f_ffff;
+        }
+    }
+
+    fn step_modulator(&mut self) {
+        let step = self.mod_table[self.mod_pos as usize];
+        self.mod_pos = (self.mod_pos + 1) & 0x3f;
+        let counter = match step {
+            0 => self.mod_counter,
+            1 => self.mod_counter + 1,
+            2 => self.mod_counter + 2,
+            3 => self.mod_counter + 4,
+            4 => 0,
+            5 => self.mod_counter - 4,
+            6 => self.mod_counter - 2,
+            _ => self.mod_counter - 1,
+        };
+        // -64..63 で折り返す
+        self.mod_counter = (counter << 1) >> 1;
+    }
+
+    // 変調をかけた後の周波数 (nesdev wikiの計算式)
+    fn pitch(&self) -> u32 {
+        let pitch = self.wave_freq as i32;
+        if self.mod_halt {
+            return pitch as u32;
+        }
+        let counter = self.mod_counter as i32;
+        let mut temp = counter * self.modulation.gain as i32;
+        let remainder = temp & 0x0f;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        temp *= pitch;
+        let remainder = temp & 0x3f;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        (pitch + temp).max(0) as u32
+    }
+
+    // 0.0..=1.0
+    pub fn output(&self) -> f32 {
+        let sample = self.wave[(self.wave_acc >> 16) as usize & 0x3f] as f32;
+        let gain = self.volume.gain.min(32) as f32;
+        let master = [1.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0][self.master_volume as usize];
+        sample * gain / (63.0 * 32.0) * master
+    }
+
+    pub fn state(&mut self, state: &mut State) {
+        state.bytes(&mut self.wave);
+        state.bool(&mut self.wave_write);
+        state.u8(&mut self.master_volume);
+        state.u16(&mut self.wave_freq);
+        state.bool(&mut self.wave_halt);
+        state.bool(&mut self.envelope_halt);
+        state.u32(&mut self.wave_acc);
+        self.volume.state(state);
+        self.modulation.state(state);
+        state.bytes(&mut self.mod_table);
+        state.u8(&mut self.mod_pos);
+        state.u16(&mut self.mod_freq);
+        state.bool(&mut self.mod_halt);
+        state.u32(&mut self.mod_acc);
+        state.map_u8(
+            &mut self.mod_counter,
+            |counter| counter as u8,
+            |byte| byte as i8,
+        );
+        state.u8(&mut self.envelope_speed);
+    }
+}
+
+#[cfg(feature = "expansion-audio")]
+impl Default for FdsAudio {
+    fn default() -> Self {
+        FdsAudio::new()
+    }
+}
+
+// .fdsにはギャップとCRCが含まれないので、ドライブから見えるデータに直す
+fn add_gaps(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; LEAD_IN_GAP];
+    let mut pos = 0;
+    let mut file_size = 0;
+    while pos < side.len() {
+        let len = match side[pos] {
+            1 => 56,
+            2 => 2,
+            3 => {
+                if let Some(size) = side.get(pos + 13..pos + 15) {
+                    file_size = u16::from_le_bytes([size[0], size[1]]) as usize;
+                }
+                16
+            }
+            4 => 1 + file_size,
+            _ => break,
+        };
+        let end = (pos + len).min(side.len());
+        raw.push(0x80);
+        raw.extend(&side[pos..end]);
+        // CRCは検査しないので適当な値
+        raw.extend([0x4d, 0x62]);
+        raw.extend([0; BLOCK_GAP]);
+        pos = end;
+    }
+    raw.resize(raw.len().max(side.len() + LEAD_IN_GAP), 0);
+    raw
+}
+
+// ディスクシステム。$6000-$DFFFは32KBのRAM、$E000-はBIOS
+pub struct Fds {
+    bios: Vec<u8>,
+    ram: Vec<u8>,
+    chr: Vec<u8>,
+    sides: Vec<Vec<u8>>,
+    side: Option<usize>,
+    insert_delay: u32,
+
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_enabled: bool,
+    timer_repeat: bool,
+    timer_irq: bool,
+    disk_io_enabled: bool,
+    sound_io_enabled: bool,
+
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    mirroring: Mirroring,
+    disk_ready: bool,
+    disk_irq_enabled: bool,
+    disk_irq: bool,
+    transfer_complete: bool,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    position: usize,
+    delay: u32,
+    read_data: u8,
+    write_data: u8,
+    external: u8,
+
+    #[cfg(feature = "expansion-audio")]
+    pub audio: FdsAudio,
+}
+
+impl Fds {
+    pub fn new(bios: Vec<u8>, image: FdsImage) -> Result<Self, String> {
+        if bios.len() != BIOS_SIZE {
+            return Err(format!(
+                "FDS BIOS must be {} bytes, got {}",
+                BIOS_SIZE,
+                bios.len()
+            ));
+        }
+        Ok(Fds {
+            bios,
+            ram: vec![0; RAM_SIZE],
+            chr: vec![0; 0x2000],
+            side: if image.sides.is_empty() {
+                None
+            } else {
+                Some(0)
+            },
+            sides: image.sides.iter().map(|side| add_gaps(side)).collect(),
+            insert_delay: 0,
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_enabled: false,
+            timer_repeat: false,
+            timer_irq: false,
+            disk_io_enabled: false,
+            sound_io_enabled: false,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            mirroring: Mirroring::VERTICAL,
+            disk_ready: false,
+            disk_irq_enabled: false,
+            disk_irq: false,
+            transfer_complete: false,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            position: 0,
+            delay: 0,
+            read_data: 0,
+            write_data: 0,
+            external: 0,
+            #[cfg(feature = "expansion-audio")]
+            audio: FdsAudio::new(),
+        })
+    }
+
+    pub fn side_count(&self) -> usize {
+        self.sides.len()
+    }
+
+    // 入っている面 (0始まり)。取り出している間はNone
+    pub fn current_side(&self) -> Option<usize> {
+        self.side
+    }
+
+    pub fn eject_disk(&mut self) {
+        self.side = None;
+    }
+
+    // 面を入れ替える。BIOSが入れ替えに気づけるよう、しばらくは取り出した状態に見せる
+    pub fn insert_disk(&mut self, side: usize) -> Result<(), String> {
+        if side >= self.sides.len() {
+            return Err(format!(
+                "disk side {} does not exist ({} sides)",
+                side,
+                self.sides.len()
+            ));
+        }
+        self.side = Some(side);
+        self.insert_delay = INSERT_CYCLES;
+        Ok(())
+    }
+
+    // $4030
+    fn disk_status(&self) -> u8 {
+        let mut status = 0x80;
+        status |= self.timer_irq as u8;
+        status |= (self.transfer_complete as u8) << 1;
+        status |= (self.end_of_head as u8) << 6;
+        status
+    }
+
+    fn disk_inserted(&self) -> bool {
+        self.side.is_some() && self.insert_delay == 0
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.timer_enabled || !self.disk_io_enabled {
+            return;
+        }
+        if self.timer_counter == 0 {
+            self.timer_irq = true;
+            self.timer_counter = self.timer_reload;
+            if !self.timer_repeat {
+                self.timer_enabled = false;
+            }
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    fn clock_drive(&mut self) {
+        if self.insert_delay > 0 {
+            self.insert_delay -= 1;
+            return;
+        }
+        let side = match self.side {
+            Some(side) if self.motor_on => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            }
+        };
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = REWIND_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+        self.scanning = true;
+
+        let mut need_irq = self.disk_irq_enabled;
+        let disk = &mut self.sides[side];
+        if self.read_mode {
+            let data = disk[self.position];
+            if !self.disk_ready {
+                self.gap_ended = false;
+            } else if data != 0 && !self.gap_ended {
+                // ギャップの後の開始マーク ($80) は割り込みを起こさない
+                self.gap_ended = true;
+                need_irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                self.disk_irq |= need_irq;
+            }
+        } else {
+            disk[self.position] = if self.disk_ready { self.write_data } else { 0 };
+            self.gap_ended = false;
+            self.transfer_complete = true;
+            self.disk_irq |= need_irq;
+        }
+
+        self.position += 1;
+        if self.position >= disk.len() {
+            self.motor_on = false;
+            self.end_of_head = true;
+        } else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+}
+
+impl Mapper for Fds {
+    fn prg_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4030 => {
+                let status = self.disk_status();
+                self.timer_irq = false;
+                self.disk_irq = false;
+                self.transfer_complete = false;
+                Some(status)
+            }
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+                Some(self.read_data)
+            }
+            0x4032 => {
+                let no_disk = !self.disk_inserted();
+                let mut status = 0x40;
+                status |= no_disk as u8;
+                status |= ((no_disk || !self.scanning) as u8) << 1;
+                status |= (no_disk as u8) << 2;
+                Some(status)
+            }
+            // bit7: バッテリー良好
+            0x4033 => Some(0x80 | (self.external & 0x7f)),
+            #[cfg(feature = "expansion-audio")]
+            0x4040..=0x4092 if self.sound_io_enabled => self.audio.read(addr),
+            0x6000..=0xdfff => Some(self.ram[(addr - 0x6000) as usize]),
+            0xe000..=0xffff => Some(self.bios[(addr - 0xe000) as usize]),
+            _ => None,
+        }
+    }
+
+    // $4030と$4031はIRQを解除しない
+    fn prg_peek(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4030 => Some(self.disk_status()),
+            0x4031 => Some(self.read_data),
+            _ => self.prg_read(addr),
+        }
+    }
+
+    fn prg_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x4020 => self.timer_reload = (self.timer_reload & 0xff00) | data as u16,
+            0x4021 => self.timer_reload = (self.timer_reload & 0x00ff) | (data as u16) << 8,
+            0x4022 => {
+                self.timer_repeat = data & 0b01 != 0;
+                self.timer_enabled = data & 0b10 != 0 && self.disk_io_enabled;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_io_enabled = data & 0b01 != 0;
+                self.sound_io_enabled = data & 0b10 != 0;
+                if !self.disk_io_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            0x4024..=0x4026 if !self.disk_io_enabled => {}
+            0x4024 => {
+                self.write_data = data;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4025 => {
+                self.disk_irq = false;
+                self.motor_on = data & 0x01 != 0;
+                self.reset_transfer = data & 0x02 != 0;
+                self.read_mode = data & 0x04 != 0;
+                self.mirroring = if data & 0x08 != 0 {
+                    Mirroring::HORIZONTAL
+                } else {
+                    Mirroring::VERTICAL
+                };
+                self.disk_ready = data & 0x40 != 0;
+                self.disk_irq_enabled = data & 0x80 != 0;
+            }
+            0x4026 => self.external = data,
+            0x4040..=0x408a =>
+            {
+                #[cfg(feature = "expansion-audio")]
+                if self.sound_io_enabled {
+                    self.audio.write(addr, data);
+                }
+            }
+            0x6000..=0xdfff => self.ram[(addr - 0x6000) as usize] = data,
+            _ => return false,
+        }
+        true
+    }
+
+    fn chr_read(&mut self, addr: u16) -> u8 {
+        self.chr[addr as usize]
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        self.chr[addr as usize] = data;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.ram)
+    }
+
+    fn irq(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    fn cpu_tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.clock_timer();
+            self.clock_drive();
+            #[cfg(feature = "expansion-audio")]
+            self.audio.clock();
+        }
+    }
+
+    #[cfg(feature = "expansion-audio")]
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    // 書き換えられたディスクの中身も含める
+    fn state(&mut self, state: &mut State) {
+        state.bytes(&mut self.ram);
+        state.bytes(&mut self.chr);
+        for side in self.sides.iter_mut() {
+            state.bytes(side);
+        }
+        state.option(&mut self.side, |state, side| state.usize(side));
+        state.u32(&mut self.insert_delay);
+        state.u16(&mut self.timer_reload);
+        state.u16(&mut self.timer_counter);
+        state.bool(&mut self.timer_enabled);
+        state.bool(&mut self.timer_repeat);
+        state.bool(&mut self.timer_irq);
+        state.bool(&mut self.disk_io_enabled);
+        state.bool(&mut self.sound_io_enabled);
+        state.bool(&mut self.motor_on);
+        state.bool(&mut self.reset_transfer);
+        state.bool(&mut self.read_mode);
+        state.map_u8(
+            &mut self.mirroring,
+            |mirroring| (mirroring == Mirroring::HORIZONTAL) as u8,
+            |byte| {
+                if byte != 0 {
+                    Mirroring::HORIZONTAL
+                } else {
+                    Mirroring::VERTICAL
+                }
+            },
+        );
+        state.bool(&mut self.disk_ready);
+        state.bool(&mut self.disk_irq_enabled);
+        state.bool(&mut self.disk_irq);
+        state.bool(&mut self.transfer_complete);
+        state.bool(&mut self.end_of_head);
+        state.bool(&mut self.scanning);
+        state.bool(&mut self.gap_ended);
+        state.usize(&mut self.position);
+        state.u32(&mut self.delay);
+        state.u8(&mut self.read_data);
+        state.u8(&mut self.write_data);
+        state.u8(&mut self.external);
+        #[cfg(feature = "expansion-audio")]
+        self.audio.state(state);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::fds_side;
+
+    fn fds() -> Fds {
+        let image = FdsImage {
+            sides: vec![fds_side(), fds_side()],
+        };
+        Fds::new(vec![0xea; BIOS_SIZE], image).unwrap()
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let mut fds = fds();
+        assert_eq!(fds.prg_read(0xfffc), Some(0xea));
+        assert!(fds.prg_write(0xdfff, 0x12));
+        assert_eq!(fds.prg_read(0xdfff), Some(0x12));
+        assert!(!fds.prg_write(0xe000, 0x12));
+        assert!(Fds::new(vec![0; 0x1000], FdsImage { sides: vec![] }).is_err());
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut fds = fds();
+        // ディスクI/Oが無効な間は動かない
+        fds.prg_write(0x4022, 0b10);
+        fds.cpu_tick(100);
+        assert!(!fds.irq());
+
+        fds.prg_write(0x4023, 0b01);
+        fds.prg_write(0x4020, 10);
+        fds.prg_write(0x4021, 0);
+        fds.prg_write(0x4022, 0b11);
+        fds.cpu_tick(10);
+        assert!(!fds.irq());
+        fds.cpu_tick(1);
+        assert!(fds.irq());
+        assert_eq!(fds.prg_read(0x4030).unwrap() & 1, 1);
+        assert!(!fds.irq());
+
+        // リピート
+        fds.cpu_tick(11);
+        assert!(fds.irq());
+    }
+
+    #[test]
+    fn test_disk_read() {
+        let mut fds = fds();
+        fds.prg_write(0x4023, 0b01);
+        // モーター, 読み込み, 転送開始, 割り込み有効
+        fds.prg_write(0x4025, 0b1100_0101);
+
+        let mut bytes = vec![];
+        for _ in 0..1_000_000 {
+            fds.cpu_tick(1);
+            if fds.irq() {
+                bytes.push(fds.prg_read(0x4031).unwrap());
+                if bytes.len() == 15 {
+                    break;
+                }
+            }
+        }
+        let mut expected = vec![0x01];
+        expected.extend(b"*NINTENDO-HVC*");
+        assert_eq!(bytes, expected);
+        assert_eq!(fds.prg_read(0x4032).unwrap() & 0b11, 0);
+    }
+
+    #[test]
+    fn test_disk_sides() {
+        let mut fds = fds();
+        assert_eq!(fds.side_count(), 2);
+        assert_eq!(fds.current_side(), Some(0));
+
+        fds.eject_disk();
+        assert_eq!(fds.prg_read(0x4032).unwrap() & 0b101, 0b101);
+
+        fds.insert_disk(1).unwrap();
+        assert_eq!(fds.current_side(), Some(1));
+        assert_eq!(fds.prg_read(0x4032).unwrap() & 1, 1);
+        for _ in 0..INSERT_CYCLES / 250 {
+            fds.cpu_tick(250);
+        }
+        assert_eq!(fds.prg_read(0x4032).unwrap() & 1, 0);
+        assert!(fds.insert_disk(2).is_err());
+    }
+
+    #[test]
+    fn test_mirroring() {
+        let mut fds = fds();
+        fds.prg_write(0x4023, 0b01);
+        fds.prg_write(0x4025, 0b0000_1000);
+        assert_eq!(fds.mirroring(), Mirroring::HORIZONTAL);
+        fds.prg_write(0x4025, 0);
+        assert_eq!(fds.mirroring(), Mirroring::VERTICAL);
+    }
+
+    #[test]
+    fn test_add_gaps() {
+        let raw = add_gaps(&fds_side());
+        assert_eq!(raw[LEAD_IN_GAP], 0x80);
+        assert_eq!(raw[LEAD_IN_GAP + 1], 0x01);
+        // ディスク情報 (56) + CRC (2) + ギャップの後に次のブロック
+        let next = LEAD_IN_GAP + 1 + 56 + 2 + BLOCK_GAP;
+        assert_eq!(raw[next..next + 3], [0x80, 0x02, 0x01]);
+    }
+
+    #[cfg(feature = "expansion-audio")]
+    #[test]
+    fn test_wavetable() {
+        let mut fds = fds();
+        fds.prg_write(0x4023, 0b11);
+        fds.prg_write(0x4089, 0x80);
+        for i in 0..64 {
+            fds.prg_write(0x4040 + i, if i < 32 { 63 } else { 0 });
+        }
+        assert_eq!(fds.prg_read(0x4040), Some(0x7f));
+        fds.prg_write(0x4089, 0x00);
+        // 音量32 (エンベロープ無効), 周波数$400
+        fds.prg_write(0x4080, 0x80 | 32);
+        fds.prg_write(0x4082, 0x00);
+        fds.prg_write(0x4083, 0x04);
+        assert_eq!(fds.audio_output(), 1.0);
+
+        // 1サンプル進むのに64サイクル
+        let mut samples = vec![];
+        for _ in 0..64 {
+            fds.cpu_tick(64);
+            samples.push(fds.audio_output());
+        }
+        assert_eq!(samples.iter().filter(|s| **s == 1.0).count(), 32);
+
+        fds.prg_write(0x4089, 0x03);
+        fds.prg_write(0x4083, 0x84);
+        assert_eq!(fds.audio_output(), 2.0 / 5.0);
+    }
+}
@@ -0,0 +1,293 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::{Mapper, Nametable};
+use crate::savestate::State;
+
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// マッパー69 (Sunsoft FME-7 / 5B)。$8000にコマンド番号、$A000にその値を書く
+pub struct Fme7 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+
+    command: u8,
+    chr_banks: [u8; 8],
+    // コマンド8: $6000-$7FFF (ERbb bbbb)
+    prg_bank_6000: u8,
+    // コマンド9-B: $8000/$A000/$C000
+    prg_banks: [u8; 3],
+    nametable_mode: u8,
+
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_counter: u16,
+    irq_pending: bool,
+
+    // 5B音源は未実装。$C000/$E000で書かれたレジスタだけ保持する
+    audio_register: u8,
+    pub audio_registers: [u8; 16],
+}
+
+impl Fme7 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        Fme7 {
+            prg_rom,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            chr: if chr_is_ram { vec![0; 0x2000] } else { chr_rom },
+            chr_is_ram,
+            command: 0,
+            chr_banks: [0; 8],
+            prg_bank_6000: 0,
+            prg_banks: [0; 3],
+            nametable_mode: 0,
+            irq_enabled: false,
+            irq_counter_enabled: false,
+            irq_counter: 0,
+            irq_pending: false,
+            audio_register: 0,
+            audio_registers: [0; 16],
+        }
+    }
+
+    fn read_prg_rom(&self, bank: usize, addr: u16) -> u8 {
+        let index = bank * 0x2000 + (addr as usize & 0x1fff);
+        self.prg_rom[index % self.prg_rom.len()]
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr / 0x400) as usize] as usize;
+        (bank * 0x400 + (addr as usize % 0x400)) % self.chr.len()
+    }
+
+    fn write_parameter(&mut self, data: u8) {
+        match self.command {
+            0..=7 => self.chr_banks[self.command as usize] = data,
+            8 => self.prg_bank_6000 = data,
+            9..=0xb => self.prg_banks[(self.command - 9) as usize] = data & 0x3f,
+            0xc => self.nametable_mode = data & 0b11,
+            0xd => {
+                // 書き込むと保留中のIRQも解除される
+                self.irq_enabled = data & 0x01 != 0;
+                self.irq_counter_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0xe => self.irq_counter = (self.irq_counter & 0xff00) | data as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00ff) | (data as u16) << 8,
+        }
+    }
+}
+
+impl Mapper for Fme7 {
+    fn prg_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff => {
+                let bank = self.prg_bank_6000;
+                match (bank & 0x40 != 0, bank & 0x80 != 0) {
+                    (false, _) => Some(self.read_prg_rom((bank & 0x3f) as usize, addr)),
+                    (true, true) => Some(self.prg_ram[addr as usize % PRG_RAM_SIZE]),
+                    // RAMが選ばれていても無効ならオープンバス
+                    (true, false) => None,
+                }
+            }
+            0x8000..=0xdfff => {
+                let slot = ((addr - 0x8000) / 0x2000) as usize;
+                Some(self.read_prg_rom(self.prg_banks[slot] as usize, addr))
+            }
+            0xe000..=0xffff => {
+                let last = self.prg_rom.len() / 0x2000 - 1;
+                Some(self.read_prg_rom(last, addr))
+            }
+            _ => None,
+        }
+    }
+
+    fn mapped_prg_bank(&self, slot: u8) -> Option<usize> {
+        let banks = self.prg_rom.len() / 0x2000;
+        match slot {
+            0..=2 => Some(self.prg_banks[slot as usize] as usize % banks),
+            _ => Some(banks - 1),
+        }
+    }
+
+    fn prg_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x6000..=0x7fff => {
+                if self.prg_bank_6000 & 0xc0 != 0xc0 {
+                    return false;
+                }
+                self.prg_ram[addr as usize % PRG_RAM_SIZE] = data;
+            }
+            0x8000..=0x9fff => self.command = data & 0x0f,
+            0xa000..=0xbfff => self.write_parameter(data),
+            0xc000..=0xdfff => self.audio_register = data & 0x0f,
+            0xe000..=0xffff => self.audio_registers[self.audio_register as usize] = data,
+            _ => return false,
+        }
+        true
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn chr_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_index(addr)]
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let index = self.chr_index(addr);
+            self.chr[index] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.nametable_mode {
+            0 => Mirroring::VERTICAL,
+            _ => Mirroring::HORIZONTAL,
+        }
+    }
+
+    fn nametable(&self, table: u8) -> Nametable {
+        match self.nametable_mode {
+            0 => Nametable::Ciram(table & 1),
+            1 => Nametable::Ciram((table >> 1) & 1),
+            // 1画面
+            2 => Nametable::Ciram(0),
+            _ => Nametable::Ciram(1),
+        }
+    }
+
+    // カウンタはCPUサイクルごとに減り、$0000から$FFFFに戻るときにIRQ
+    fn cpu_tick(&mut self, cycles: u8) {
+        if !self.irq_counter_enabled {
+            return;
+        }
+        for _ in 0..cycles {
+            let (counter, wrapped) = self.irq_counter.overflowing_sub(1);
+            self.irq_counter = counter;
+            if wrapped && self.irq_enabled {
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn state(&mut self, state: &mut State) {
+        state.bytes(&mut self.prg_ram);
+        if self.chr_is_ram {
+            state.bytes(&mut self.chr);
+        }
+        state.u8(&mut self.command);
+        state.bytes(&mut self.chr_banks);
+        state.u8(&mut self.prg_bank_6000);
+        state.bytes(&mut self.prg_banks);
+        state.u8(&mut self.nametable_mode);
+        state.bool(&mut self.irq_enabled);
+        state.bool(&mut self.irq_counter_enabled);
+        state.u16(&mut self.irq_counter);
+        state.bool(&mut self.irq_pending);
+        state.u8(&mut self.audio_register);
+        state.bytes(&mut self.audio_registers);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::rom_banks;
+
+    fn fme7() -> Fme7 {
+        // 8KBごとにバンク番号を書いたPRG 256KB、1KBごとのCHR 256KB
+        let prg_rom = rom_banks(32, 0x2000);
+        let chr_rom = rom_banks(256, 0x400);
+        Fme7::new(prg_rom, chr_rom)
+    }
+
+    fn command(mapper: &mut Fme7, command: u8, data: u8) {
+        mapper.prg_write(0x8000, command);
+        mapper.prg_write(0xa000, data);
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut mapper = fme7();
+        assert_eq!(mapper.prg_read(0xe000), Some(31));
+
+        command(&mut mapper, 9, 4);
+        command(&mut mapper, 0xa, 5);
+        command(&mut mapper, 0xb, 0x46);
+        assert_eq!(mapper.prg_read(0x8000), Some(4));
+        assert_eq!(mapper.prg_read(0xa000), Some(5));
+        assert_eq!(mapper.prg_read(0xdfff), Some(6));
+        assert_eq!(mapper.prg_read(0xffff), Some(31));
+
+        // $6000-にはROMも出せる
+        command(&mut mapper, 8, 7);
+        assert_eq!(mapper.prg_read(0x6000), Some(7));
+        assert!(!mapper.prg_write(0x6000, 0x42));
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut mapper = fme7();
+        command(&mut mapper, 8, 0x40);
+        assert_eq!(mapper.prg_read(0x6000), None);
+        assert!(!mapper.prg_write(0x6000, 0x42));
+
+        command(&mut mapper, 8, 0xc0);
+        assert!(mapper.prg_write(0x6000, 0x42));
+        assert_eq!(mapper.prg_read(0x6000), Some(0x42));
+    }
+
+    #[test]
+    fn test_chr_banks_and_mirroring() {
+        let mut mapper = fme7();
+        for i in 0..8 {
+            command(&mut mapper, i, 100 + i);
+        }
+        assert_eq!(mapper.chr_read(0x0000), 100);
+        assert_eq!(mapper.chr_read(0x1fff), 107);
+
+        command(&mut mapper, 0xc, 1);
+        assert_eq!(mapper.nametable(1), Nametable::Ciram(0));
+        assert_eq!(mapper.nametable(2), Nametable::Ciram(1));
+        command(&mut mapper, 0xc, 3);
+        assert_eq!(mapper.nametable(0), Nametable::Ciram(1));
+    }
+
+    #[test]
+    fn test_irq_counter() {
+        let mut mapper = fme7();
+        command(&mut mapper, 0xe, 0x10);
+        command(&mut mapper, 0xf, 0x00);
+        command(&mut mapper, 0xd, 0x81);
+
+        mapper.cpu_tick(16);
+        assert!(!mapper.irq());
+        mapper.cpu_tick(1);
+        assert!(mapper.irq());
+        assert_eq!(mapper.irq_counter, 0xffff);
+
+        // $0Dへの書き込みでIRQを解除
+        command(&mut mapper, 0xd, 0x80);
+        assert!(!mapper.irq());
+        mapper.cpu_tick(255);
+        mapper.cpu_tick(255);
+        assert!(!mapper.irq());
+        assert_eq!(mapper.irq_counter, 0xffff - 510);
+    }
+
+    #[test]
+    fn test_audio_registers() {
+        let mut mapper = fme7();
+        mapper.prg_write(0xc000, 0x07);
+        mapper.prg_write(0xe000, 0x38);
+        assert_eq!(mapper.audio_registers[7], 0x38);
+    }
+}
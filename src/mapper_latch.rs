@@ -0,0 +1,169 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::Mapper;
+use crate::savestate::State;
+
+// 書き込まれた値から (PRG 32KBのバンク, CHR 8KBのバンク) を取り出す
+pub type LatchDecode = fn(u8) -> (usize, usize);
+
+// マッパー11 (Color Dreams)。CCCC LLPP
+pub fn color_dreams(data: u8) -> (usize, usize) {
+    ((data & 0b11) as usize, (data >> 4) as usize)
+}
+
+// マッパー66 (GxROM)。--PP --CC
+pub fn gxrom(data: u8) -> (usize, usize) {
+    (((data >> 4) & 0b11) as usize, (data & 0b11) as usize)
+}
+
+// $8000-$FFFF への書き込みを1つのラッチに覚えて、PRG 32KB と CHR 8KB のバンクを選ぶ
+// マッパー。ビットの割り当てだけがマッパーごとに違う
+pub struct Latch {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+    decode: LatchDecode,
+    prg_bank: usize,
+    chr_bank: usize,
+}
+
+impl Latch {
+    pub fn new(
+        decode: LatchDecode,
+        prg_rom: Vec<u8>,
+        chr_rom: Vec<u8>,
+        mirroring: Mirroring,
+    ) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        Latch {
+            prg_rom,
+            chr: if chr_is_ram { vec![0; 0x2000] } else { chr_rom },
+            chr_is_ram,
+            mirroring,
+            decode,
+            prg_bank: 0,
+            chr_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Latch {
+    fn prg_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xffff => {
+                let index = self.prg_bank * 0x8000 + (addr - 0x8000) as usize;
+                Some(self.prg_rom[index % self.prg_rom.len()])
+            }
+            _ => None,
+        }
+    }
+
+    fn mapped_prg_bank(&self, slot: u8) -> Option<usize> {
+        Some((self.prg_bank * 4 + slot as usize) % (self.prg_rom.len() / 0x2000))
+    }
+
+    fn prg_write(&mut self, addr: u16, data: u8) -> bool {
+        if addr < 0x8000 {
+            return false;
+        }
+        // バスコンフリクト: ROMの値とANDされる
+        let data = data & self.prg_read(addr).unwrap_or(0xff);
+        (self.prg_bank, self.chr_bank) = (self.decode)(data);
+        true
+    }
+
+    fn chr_read(&mut self, addr: u16) -> u8 {
+        let index = self.chr_bank * 0x2000 + addr as usize;
+        self.chr[index % self.chr.len()]
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            self.chr[addr as usize] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn state(&mut self, state: &mut State) {
+        if self.chr_is_ram {
+            state.bytes(&mut self.chr);
+        }
+        state.usize(&mut self.prg_bank);
+        state.usize(&mut self.chr_bank);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::{prg_rom, rom_banks};
+
+    #[test]
+    fn test_color_dreams_banks() {
+        let chr_rom = rom_banks(16, 0x2000);
+        let mut mapper = Latch::new(color_dreams, prg_rom(4), chr_rom, Mirroring::VERTICAL);
+        assert_eq!(mapper.prg_read(0x8000), Some(0));
+
+        assert!(mapper.prg_write(0x8001, 0b1001_0010));
+        assert_eq!(mapper.prg_read(0x8000), Some(2));
+        assert_eq!(mapper.chr_read(0x0000), 9);
+        assert_eq!(mapper.chr_read(0x1fff), 9);
+
+        mapper.prg_write(0xffff, 0b1111_0111);
+        assert_eq!(mapper.prg_read(0x8000), Some(3));
+        assert_eq!(mapper.chr_read(0x1000), 15);
+        assert!(!mapper.prg_write(0x6000, 0));
+    }
+
+    #[test]
+    fn test_gxrom_banks() {
+        let chr_rom = rom_banks(4, 0x2000);
+        let mut mapper = Latch::new(gxrom, prg_rom(4), chr_rom, Mirroring::HORIZONTAL);
+        assert_eq!(mapper.prg_read(0x8000), Some(0));
+
+        assert!(mapper.prg_write(0x8001, 0b0010_0011));
+        assert_eq!(mapper.prg_read(0x8000), Some(2));
+        assert_eq!(mapper.chr_read(0x0000), 3);
+        assert_eq!(mapper.chr_read(0x1fff), 3);
+
+        // 未使用ビットは無視される
+        mapper.prg_write(0xffff, 0b1101_1101);
+        assert_eq!(mapper.prg_read(0x8000), Some(1));
+        assert_eq!(mapper.chr_read(0x1000), 1);
+    }
+
+    #[test]
+    fn test_bus_conflict() {
+        let chr_rom = rom_banks(16, 0x2000);
+        let mut mapper = Latch::new(color_dreams, prg_rom(4), chr_rom, Mirroring::VERTICAL);
+        mapper.prg_write(0x8001, 0b0001_0011);
+        // $8000の値 (3) とANDされる
+        mapper.prg_write(0x8000, 0b0011_0001);
+        assert_eq!(mapper.prg_read(0x8000), Some(1));
+        assert_eq!(mapper.chr_read(0), 0);
+    }
+
+    #[test]
+    fn test_small_rom_wraps() {
+        // PRG 64KB, CHR 16KBのカートリッジではバンク番号が折り返す
+        let chr_rom = rom_banks(2, 0x2000);
+        let mut mapper = Latch::new(gxrom, prg_rom(2), chr_rom, Mirroring::HORIZONTAL);
+        mapper.prg_write(0x8001, 0b0011_0011);
+        assert_eq!(mapper.prg_read(0x8000), Some(1));
+        assert_eq!(mapper.chr_read(0x0000), 1);
+        mapper.prg_write(0x8001, 0b0010_0010);
+        assert_eq!(mapper.prg_read(0x8000), Some(0));
+        assert_eq!(mapper.chr_read(0x0000), 0);
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        // CHR ROMがなければ8KBのCHR RAM
+        let mut mapper = Latch::new(color_dreams, prg_rom(1), vec![], Mirroring::VERTICAL);
+        mapper.chr_write(0x1234, 0x56);
+        assert_eq!(mapper.chr_read(0x1234), 0x56);
+    }
+}
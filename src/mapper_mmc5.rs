@@ -0,0 +1,561 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::{ChrFetch, Mapper, Nametable};
+use crate::savestate::State;
+
+const PRG_RAM_SIZE: usize = 64 * 1024;
+
+// マッパー5 (MMC5)
+pub struct Mmc5 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    exram: [u8; 0x400],
+
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2],
+    exram_mode: u8,
+    nametable_mapping: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    // $5113-$5117
+    prg_banks: [u8; 5],
+    // $5120-$5127 (Aセット) と $5128-$512B (Bセット)
+    chr_banks: [u16; 12],
+    chr_upper: u8,
+    last_chr_set_b: bool,
+    sprite_8x16: bool,
+
+    split_mode: u8,
+    split_scroll: u8,
+    split_bank: u8,
+
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    in_frame: bool,
+    scanline_counter: u8,
+
+    multiplicand: u8,
+    multiplier: u8,
+}
+
+impl Mmc5 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        Mmc5 {
+            prg_rom,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            chr: if chr_is_ram { vec![0; 0x2000] } else { chr_rom },
+            chr_is_ram,
+            exram: [0; 0x400],
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0; 2],
+            exram_mode: 0,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            prg_banks: [0, 0, 0, 0, 0xff],
+            chr_banks: [0; 12],
+            chr_upper: 0,
+            last_chr_set_b: false,
+            sprite_8x16: false,
+            split_mode: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            in_frame: false,
+            scanline_counter: 0,
+            multiplicand: 0xff,
+            multiplier: 0xff,
+        }
+    }
+
+    // $8000-$FFFF の8KBスロットごとに (ROMかどうか, 8KB単位のバンク)
+    fn prg_slot(&self, slot: u8) -> (bool, usize) {
+        let reg = |i: usize| self.prg_banks[i];
+        let (value, bank) = match (self.prg_mode, slot) {
+            (0, _) => (reg(4), (reg(4) & 0x7c) | slot),
+            (1, 0..=1) => (reg(2), (reg(2) & 0x7e) | slot),
+            (1, _) => (reg(4), (reg(4) & 0x7e) | (slot & 1)),
+            (2, 0..=1) => (reg(2), (reg(2) & 0x7e) | slot),
+            (2, 2) => (reg(3), reg(3) & 0x7f),
+            (2, _) => (reg(4), reg(4) & 0x7f),
+            (_, _) => (reg(1 + slot as usize), reg(1 + slot as usize) & 0x7f),
+        };
+        // $E000-は常にROM
+        let is_rom = slot == 3 || value & 0x80 != 0;
+        (is_rom, bank as usize)
+    }
+
+    // $5204
+    fn irq_status(&self) -> u8 {
+        (self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6
+    }
+
+    fn prg_ram_index(&self, bank: usize, addr: u16) -> usize {
+        ((bank & 0x07) * 0x2000 + (addr as usize & 0x1fff)) % PRG_RAM_SIZE
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect == [0b10, 0b01]
+    }
+
+    fn chr_register(&self, index: usize) -> usize {
+        self.chr_banks[index] as usize
+    }
+
+    // 1KB単位のバンク番号
+    fn chr_bank(&self, addr: u16, set_b: bool) -> usize {
+        let addr = addr as usize;
+        if set_b {
+            let b = |i: usize| self.chr_register(8 + i);
+            match self.chr_mode {
+                0 => b(3) * 8 + addr / 0x400,
+                1 => b(3) * 4 + (addr % 0x1000) / 0x400,
+                2 => b(1 + 2 * ((addr / 0x800) % 2)) * 2 + (addr % 0x800) / 0x400,
+                _ => b((addr / 0x400) % 4),
+            }
+        } else {
+            let a = |i: usize| self.chr_register(i);
+            match self.chr_mode {
+                0 => a(7) * 8 + addr / 0x400,
+                1 => a(3 + 4 * (addr / 0x1000)) * 4 + (addr % 0x1000) / 0x400,
+                2 => a(1 + 2 * (addr / 0x800)) * 2 + (addr % 0x800) / 0x400,
+                _ => a(addr / 0x400),
+            }
+        }
+    }
+
+    fn chr_at(&self, absolute: usize) -> u8 {
+        self.chr[absolute % self.chr.len()]
+    }
+
+    fn read_chr_with_set(&self, addr: u16, set_b: bool) -> u8 {
+        self.chr_at(self.chr_bank(addr, set_b) * 0x400 + (addr as usize % 0x400))
+    }
+
+    fn split_enabled(&self) -> bool {
+        self.split_mode & 0x80 != 0 && self.exram_mode <= 1
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn prg_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5204 => {
+                let status = self.irq_status();
+                self.irq_pending = false;
+                Some(status)
+            }
+            0x5205 => Some((self.multiplicand as u16 * self.multiplier as u16) as u8),
+            0x5206 => Some(((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8),
+            0x5c00..=0x5fff if self.exram_mode >= 2 => Some(self.exram[(addr - 0x5c00) as usize]),
+            0x6000..=0x7fff => {
+                let index = self.prg_ram_index(self.prg_banks[0] as usize, addr);
+                Some(self.prg_ram[index])
+            }
+            0x8000..=0xffff => {
+                let (is_rom, bank) = self.prg_slot(((addr - 0x8000) / 0x2000) as u8);
+                if is_rom {
+                    let index = (bank * 0x2000 + (addr as usize & 0x1fff)) % self.prg_rom.len();
+                    Some(self.prg_rom[index])
+                } else {
+                    Some(self.prg_ram[self.prg_ram_index(bank, addr)])
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // PRG RAMが選ばれている枠はNone
+    fn mapped_prg_bank(&self, slot: u8) -> Option<usize> {
+        match self.prg_slot(slot) {
+            (true, bank) => Some(bank % (self.prg_rom.len() / 0x2000)),
+            (false, _) => None,
+        }
+    }
+
+    // $5204はIRQを解除しない
+    fn prg_peek(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5204 => Some(self.irq_status()),
+            _ => self.prg_read(addr),
+        }
+    }
+
+    fn prg_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x5100 => self.prg_mode = data & 0b11,
+            0x5101 => self.chr_mode = data & 0b11,
+            0x5102 => self.prg_ram_protect[0] = data & 0b11,
+            0x5103 => self.prg_ram_protect[1] = data & 0b11,
+            0x5104 => self.exram_mode = data & 0b11,
+            0x5105 => self.nametable_mapping = data,
+            0x5106 => self.fill_tile = data,
+            0x5107 => self.fill_attribute = data & 0b11,
+            0x5113..=0x5117 => self.prg_banks[(addr - 0x5113) as usize] = data,
+            0x5120..=0x512b => {
+                let index = (addr - 0x5120) as usize;
+                self.chr_banks[index] = data as u16 | (self.chr_upper as u16) << 8;
+                self.last_chr_set_b = index >= 8;
+            }
+            0x5130 => self.chr_upper = data & 0b11,
+            0x5200 => self.split_mode = data,
+            0x5201 => self.split_scroll = data,
+            0x5202 => self.split_bank = data,
+            0x5203 => self.irq_compare = data,
+            0x5204 => self.irq_enabled = data & 0x80 != 0,
+            0x5205 => self.multiplicand = data,
+            0x5206 => self.multiplier = data,
+            0x5c00..=0x5fff => {
+                // モード3は読み込み専用
+                if self.exram_mode != 3 {
+                    self.exram[(addr - 0x5c00) as usize] = data;
+                }
+            }
+            0x6000..=0x7fff => {
+                if self.prg_ram_writable() {
+                    let index = self.prg_ram_index(self.prg_banks[0] as usize, addr);
+                    self.prg_ram[index] = data;
+                }
+            }
+            0x8000..=0xffff => {
+                let (is_rom, bank) = self.prg_slot(((addr - 0x8000) / 0x2000) as u8);
+                if is_rom || !self.prg_ram_writable() {
+                    return false;
+                }
+                let index = self.prg_ram_index(bank, addr);
+                self.prg_ram[index] = data;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    // $2007経由の読み書きは最後に書き込まれたセットを使う
+    fn chr_read(&mut self, addr: u16) -> u8 {
+        self.read_chr_with_set(addr, self.last_chr_set_b)
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let absolute =
+                self.chr_bank(addr, self.last_chr_set_b) * 0x400 + (addr as usize % 0x400);
+            let len = self.chr.len();
+            self.chr[absolute % len] = data;
+        }
+    }
+
+    // 8x16スプライトのときだけ背景はBセットを使う
+    fn chr_fetch(&mut self, addr: u16, kind: ChrFetch) -> u8 {
+        match kind {
+            ChrFetch::Sprite => self.read_chr_with_set(addr, false),
+            ChrFetch::Background => self.read_chr_with_set(addr, self.sprite_8x16),
+            ChrFetch::Cpu => self.chr_read(addr),
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.nametable_mapping {
+            0x44 => Mirroring::VERTICAL,
+            _ => Mirroring::HORIZONTAL,
+        }
+    }
+
+    fn nametable(&self, table: u8) -> Nametable {
+        match (self.nametable_mapping >> (table * 2)) & 0b11 {
+            0 => Nametable::Ciram(0),
+            1 => Nametable::Ciram(1),
+            _ => Nametable::Mapper,
+        }
+    }
+
+    fn nametable_read(&mut self, addr: u16) -> u8 {
+        let table = ((addr - 0x2000) / 0x400 % 4) as u8;
+        let offset = (addr & 0x3ff) as usize;
+        match (self.nametable_mapping >> (table * 2)) & 0b11 {
+            2 if self.exram_mode <= 1 => self.exram[offset],
+            2 => 0,
+            _ if offset < 0x3c0 => self.fill_tile,
+            // 4つの領域すべてに同じパレット
+            _ => self.fill_attribute * 0b0101_0101,
+        }
+    }
+
+    fn nametable_write(&mut self, addr: u16, data: u8) {
+        let table = ((addr - 0x2000) / 0x400 % 4) as u8;
+        if (self.nametable_mapping >> (table * 2)) & 0b11 == 2 && self.exram_mode <= 1 {
+            self.exram[(addr & 0x3ff) as usize] = data;
+        }
+    }
+
+    fn ppu_register_write(&mut self, addr: u16, data: u8) {
+        if addr == 0x2000 {
+            self.sprite_8x16 = data & 0b0010_0000 != 0;
+        }
+    }
+
+    fn scanline(&mut self, scanline: u16, rendering: bool) {
+        if !rendering || scanline >= 240 {
+            self.in_frame = false;
+            return;
+        }
+        if !self.in_frame {
+            self.in_frame = true;
+            // 描画をフレームの途中で有効にした場合も行番号に合わせる
+            self.scanline_counter = scanline as u8;
+            self.irq_pending = false;
+        } else {
+            self.scanline_counter = self.scanline_counter.wrapping_add(1);
+        }
+        if self.irq_compare != 0 && self.scanline_counter == self.irq_compare {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_enabled && self.irq_pending
+    }
+
+    fn extended_tile(&mut self, offset: u16, tile_idx: u8) -> Option<([u8; 16], u8)> {
+        if self.exram_mode != 1 || offset >= 0x3c0 {
+            return None;
+        }
+        let ex = self.exram[offset as usize];
+        let bank = ((ex & 0x3f) as usize | (self.chr_upper as usize) << 6) * 0x1000;
+        let mut tile = [0; 16];
+        for (i, b) in tile.iter_mut().enumerate() {
+            *b = self.chr_at(bank + tile_idx as usize * 16 + i);
+        }
+        Some((tile, ex >> 6))
+    }
+
+    fn split_tile_row(&mut self, column: u8, y: u8) -> Option<(u8, u8, u8)> {
+        if !self.split_enabled() {
+            return None;
+        }
+        let tiles = self.split_mode & 0x1f;
+        let inside = if self.split_mode & 0x40 != 0 {
+            column >= tiles
+        } else {
+            column < tiles
+        };
+        if !inside {
+            return None;
+        }
+
+        let src_y = (y as usize + self.split_scroll as usize) % 240;
+        let row = src_y / 8;
+        let column = column as usize;
+        let tile = self.exram[row * 32 + column] as usize;
+        let attribute = self.exram[0x3c0 + row / 4 * 8 + column / 4];
+        let shift = (row % 4 / 2) * 4 + (column % 4 / 2) * 2;
+        let palette = (attribute >> shift) & 0b11;
+
+        let addr = self.split_bank as usize * 0x1000 + tile * 16 + src_y % 8;
+        Some((self.chr_at(addr), self.chr_at(addr + 8), palette))
+    }
+
+    fn state(&mut self, state: &mut State) {
+        state.bytes(&mut self.prg_ram);
+        if self.chr_is_ram {
+            state.bytes(&mut self.chr);
+        }
+        state.bytes(&mut self.exram);
+        state.u8(&mut self.prg_mode);
+        state.u8(&mut self.chr_mode);
+        state.bytes(&mut self.prg_ram_protect);
+        state.u8(&mut self.exram_mode);
+        state.u8(&mut self.nametable_mapping);
+        state.u8(&mut self.fill_tile);
+        state.u8(&mut self.fill_attribute);
+        state.bytes(&mut self.prg_banks);
+        state.u16_slice(&mut self.chr_banks);
+        state.u8(&mut self.chr_upper);
+        state.bool(&mut self.last_chr_set_b);
+        state.bool(&mut self.sprite_8x16);
+        state.u8(&mut self.split_mode);
+        state.u8(&mut self.split_scroll);
+        state.u8(&mut self.split_bank);
+        state.u8(&mut self.irq_compare);
+        state.bool(&mut self.irq_enabled);
+        state.bool(&mut self.irq_pending);
+        state.bool(&mut self.in_frame);
+        state.u8(&mut self.scanline_counter);
+        state.u8(&mut self.multiplicand);
+        state.u8(&mut self.multiplier);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::rom_banks;
+
+    fn mmc5() -> Mmc5 {
+        // 8KBごとにバンク番号を書いたPRG 128KB、1KBごとに番号を書いたCHR 256KB
+        let prg_rom = rom_banks(16, 0x2000);
+        let chr_rom = rom_banks(256, 0x400);
+        Mmc5::new(prg_rom, chr_rom)
+    }
+
+    #[test]
+    fn test_prg_modes() {
+        let mut mapper = mmc5();
+        // 電源投入時はモード3で$E000-は最後のバンク
+        assert_eq!(mapper.prg_read(0xfffc), Some(15));
+
+        mapper.prg_write(0x5100, 0);
+        mapper.prg_write(0x5117, 0x80 | 5);
+        let banks: Vec<Option<u8>> = [0x8000, 0xa000, 0xc000, 0xe000]
+            .iter()
+            .map(|a| mapper.prg_read(*a))
+            .collect();
+        assert_eq!(banks, vec![Some(4), Some(5), Some(6), Some(7)]);
+
+        mapper.prg_write(0x5100, 2);
+        mapper.prg_write(0x5115, 0x80 | 2);
+        mapper.prg_write(0x5116, 0x80 | 9);
+        mapper.prg_write(0x5117, 0x80 | 12);
+        let banks: Vec<Option<u8>> = [0x8000, 0xa000, 0xc000, 0xe000]
+            .iter()
+            .map(|a| mapper.prg_read(*a))
+            .collect();
+        assert_eq!(banks, vec![Some(2), Some(3), Some(9), Some(12)]);
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut mapper = mmc5();
+        mapper.prg_write(0x5113, 1);
+        // 保護解除前は書き込めない
+        mapper.prg_write(0x6000, 0x42);
+        assert_eq!(mapper.prg_read(0x6000), Some(0));
+
+        mapper.prg_write(0x5102, 0b10);
+        mapper.prg_write(0x5103, 0b01);
+        mapper.prg_write(0x6000, 0x42);
+        assert_eq!(mapper.prg_read(0x6000), Some(0x42));
+
+        // 同じRAMバンクを$8000-にも出す
+        mapper.prg_write(0x5114, 1);
+        assert_eq!(mapper.prg_read(0x8000), Some(0x42));
+    }
+
+    #[test]
+    fn test_chr_sets() {
+        let mut mapper = mmc5();
+        mapper.prg_write(0x5101, 3);
+        for i in 0..8 {
+            mapper.prg_write(0x5120 + i, 10 + i as u8);
+        }
+        for i in 0..4 {
+            mapper.prg_write(0x5128 + i, 100 + i as u8);
+        }
+        mapper.ppu_register_write(0x2000, 0b0010_0000);
+
+        assert_eq!(mapper.chr_fetch(0x0400, ChrFetch::Sprite), 11);
+        assert_eq!(mapper.chr_fetch(0x1400, ChrFetch::Background), 101);
+        // $2007は最後に書いたBセット
+        assert_eq!(mapper.chr_read(0x0c00), 103);
+
+        // 8x8スプライトなら背景もAセット
+        mapper.ppu_register_write(0x2000, 0);
+        assert_eq!(mapper.chr_fetch(0x1400, ChrFetch::Background), 15);
+
+        mapper.prg_write(0x5101, 0);
+        mapper.prg_write(0x5130, 0b01);
+        mapper.prg_write(0x5127, 2);
+        assert_eq!(
+            mapper.chr_fetch(0x0800, ChrFetch::Sprite),
+            ((256 + 2) * 8 + 2) as u8
+        );
+    }
+
+    #[test]
+    fn test_nametables_and_fill() {
+        let mut mapper = mmc5();
+        // 0: CIRAM0, 1: CIRAM1, 2: ExRAM, 3: fill
+        mapper.prg_write(0x5105, 0b11_10_01_00);
+        mapper.prg_write(0x5106, 0x24);
+        mapper.prg_write(0x5107, 2);
+        assert_eq!(mapper.nametable(0), Nametable::Ciram(0));
+        assert_eq!(mapper.nametable(1), Nametable::Ciram(1));
+        assert_eq!(mapper.nametable(2), Nametable::Mapper);
+
+        mapper.nametable_write(0x2805, 0x77);
+        assert_eq!(mapper.nametable_read(0x2805), 0x77);
+        assert_eq!(mapper.nametable_read(0x2c10), 0x24);
+        assert_eq!(mapper.nametable_read(0x2fc0), 0b1010_1010);
+
+        // ExRAMをCPUのRAMとして使うとネームテーブルからは見えない
+        mapper.prg_write(0x5104, 2);
+        assert_eq!(mapper.prg_read(0x5c05), Some(0x77));
+        assert_eq!(mapper.nametable_read(0x2805), 0);
+    }
+
+    #[test]
+    fn test_extended_attributes() {
+        let mut mapper = mmc5();
+        mapper.prg_write(0x5104, 1);
+        mapper.prg_write(0x5c00 + 33, 0b10_000011);
+        let (tile, palette) = mapper.extended_tile(33, 5).unwrap();
+        assert_eq!(palette, 2);
+        // 4KBバンク3のタイル5 -> 1KBバンク12
+        assert_eq!(tile, [12; 16]);
+        assert_eq!(mapper.extended_tile(0x3c0, 0), None);
+    }
+
+    #[test]
+    fn test_split() {
+        let mut mapper = mmc5();
+        mapper.prg_write(0x5104, 1);
+        mapper.prg_write(0x5200, 0x80 | 4);
+        mapper.prg_write(0x5201, 8);
+        mapper.prg_write(0x5202, 2);
+        mapper.prg_write(0x5c00 + 32 + 1, 0x40);
+        mapper.prg_write(0x5c00 + 0x3c0, 0b0000_0011);
+
+        assert_eq!(mapper.split_tile_row(4, 0), None);
+        // スクロール8なので画面0行目はExRAMの1行目
+        let (lo, hi, palette) = mapper.split_tile_row(1, 0).unwrap();
+        // 4KBバンク2のタイル0x40 -> 1KBバンク9
+        assert_eq!((lo, hi, palette), (9, 9, 3));
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mapper = mmc5();
+        mapper.prg_write(0x5203, 3);
+        mapper.prg_write(0x5204, 0x80);
+        for scanline in 0..3 {
+            mapper.scanline(scanline, true);
+            assert!(!mapper.irq());
+        }
+        mapper.scanline(3, true);
+        assert!(mapper.irq());
+        assert_eq!(mapper.prg_read(0x5204), Some(0b1100_0000));
+        assert!(!mapper.irq());
+
+        mapper.scanline(240, true);
+        assert_eq!(mapper.prg_read(0x5204), Some(0));
+    }
+
+    #[test]
+    fn test_multiplier() {
+        let mut mapper = mmc5();
+        mapper.prg_write(0x5205, 200);
+        mapper.prg_write(0x5206, 100);
+        assert_eq!(mapper.prg_read(0x5205), Some((20000 & 0xff) as u8));
+        assert_eq!(mapper.prg_read(0x5206), Some((20000 >> 8) as u8));
+    }
+}
@@ -0,0 +1,200 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::Mapper;
+use crate::savestate::State;
+
+// ナムコ108系のバンク切り替え。
+// 偶数アドレスで番号 (R0-R7) を選び、奇数アドレスでその値を書く
+pub struct BankRegisters {
+    pub select: u8,
+    pub registers: [u8; 8],
+}
+
+impl BankRegisters {
+    pub fn new() -> Self {
+        BankRegisters {
+            select: 0,
+            registers: [0; 8],
+        }
+    }
+
+    pub fn write_select(&mut self, data: u8) {
+        self.select = data & 0b111;
+    }
+
+    pub fn write_data(&mut self, data: u8) {
+        self.registers[self.select as usize] = data;
+    }
+
+    // $8000-$FFFF の8KBスロットのバンク番号。後ろの2つは最後の16KBに固定
+    pub fn prg_bank(&self, slot: usize, banks: usize) -> usize {
+        let bank = match slot {
+            0 => self.registers[6] as usize,
+            1 => self.registers[7] as usize,
+            2 => banks.saturating_sub(2),
+            _ => banks - 1,
+        };
+        bank % banks
+    }
+
+    // 1KB単位のCHRバンク番号。R0/R1は2KBなので最下位ビットを無視する
+    pub fn chr_bank(&self, addr: u16) -> usize {
+        let slot = (addr / 0x400) as usize;
+        match slot {
+            0..=3 => (self.registers[slot / 2] & 0xfe) as usize + slot % 2,
+            _ => self.registers[slot - 2] as usize,
+        }
+    }
+
+    pub fn state(&mut self, state: &mut State) {
+        state.u8(&mut self.select);
+        state.bytes(&mut self.registers);
+    }
+}
+
+impl Default for BankRegisters {
+    fn default() -> Self {
+        BankRegisters::new()
+    }
+}
+
+// マッパー206 (ナムコ108 / DxROM)。MMC3からIRQとミラーリング制御を除いたもの
+pub struct Namco108 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+    pub banks: BankRegisters,
+}
+
+impl Namco108 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        Namco108 {
+            prg_rom,
+            chr: if chr_is_ram { vec![0; 0x2000] } else { chr_rom },
+            chr_is_ram,
+            mirroring,
+            banks: BankRegisters::new(),
+        }
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        (self.banks.chr_bank(addr) * 0x400 + (addr as usize % 0x400)) % self.chr.len()
+    }
+}
+
+impl Mapper for Namco108 {
+    fn prg_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xffff => {
+                let slot = ((addr - 0x8000) / 0x2000) as usize;
+                let bank = self.banks.prg_bank(slot, self.prg_rom.len() / 0x2000);
+                Some(self.prg_rom[bank * 0x2000 + (addr as usize & 0x1fff)])
+            }
+            _ => None,
+        }
+    }
+
+    fn mapped_prg_bank(&self, slot: u8) -> Option<usize> {
+        Some(
+            self.banks
+                .prg_bank(slot as usize, self.prg_rom.len() / 0x2000),
+        )
+    }
+
+    // $8000-$9FFF 以外のレジスタは無い。PRGは4bit、CHRは6bit
+    fn prg_write(&mut self, addr: u16, data: u8) -> bool {
+        match (addr, addr & 1) {
+            (0x8000..=0x9fff, 0) => self.banks.write_select(data),
+            (0x8000..=0x9fff, _) => {
+                let mask = if self.banks.select >= 6 { 0x0f } else { 0x3f };
+                self.banks.write_data(data & mask);
+            }
+            (0xa000..=0xffff, _) => {}
+            _ => return false,
+        }
+        true
+    }
+
+    fn chr_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_index(addr)]
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let index = self.chr_index(addr);
+            self.chr[index] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn state(&mut self, state: &mut State) {
+        if self.chr_is_ram {
+            state.bytes(&mut self.chr);
+        }
+        self.banks.state(state);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::rom_banks;
+
+    fn namco108() -> Namco108 {
+        // 8KBごとにバンク番号を書いたPRG 128KB、1KBごとのCHR 64KB
+        let prg_rom = rom_banks(16, 0x2000);
+        let chr_rom = rom_banks(64, 0x400);
+        Namco108::new(prg_rom, chr_rom, Mirroring::VERTICAL)
+    }
+
+    fn write_bank(mapper: &mut Namco108, register: u8, value: u8) {
+        mapper.prg_write(0x8000, register);
+        mapper.prg_write(0x8001, value);
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut mapper = namco108();
+        write_bank(&mut mapper, 6, 3);
+        write_bank(&mut mapper, 7, 0x15);
+        let banks: Vec<Option<u8>> = [0x8000, 0xa000, 0xc000, 0xe000]
+            .iter()
+            .map(|a| mapper.prg_read(*a))
+            .collect();
+        assert_eq!(banks, vec![Some(3), Some(5), Some(14), Some(15)]);
+
+        // MMC3のPRGモードビットは無視される
+        mapper.prg_write(0x8000, 0x46);
+        assert_eq!(mapper.prg_read(0x8000), Some(3));
+    }
+
+    #[test]
+    fn test_chr_banks() {
+        let mut mapper = namco108();
+        write_bank(&mut mapper, 0, 7);
+        write_bank(&mut mapper, 1, 10);
+        for r in 2..6 {
+            write_bank(&mut mapper, r, 40 + r);
+        }
+        let banks: Vec<u8> = (0..8).map(|i| mapper.chr_read(i * 0x400)).collect();
+        assert_eq!(banks, vec![6, 7, 10, 11, 42, 43, 44, 45]);
+
+        // $A000-へのミラーリング設定は効かない
+        assert!(mapper.prg_write(0xa000, 1));
+        assert_eq!(mapper.mirroring(), Mirroring::VERTICAL);
+    }
+
+    #[test]
+    fn test_small_prg() {
+        // PRG 8KBなら全部のスロットが同じバンク
+        let mut mapper = Namco108::new(vec![7; 0x2000], vec![], Mirroring::VERTICAL);
+        write_bank(&mut mapper, 6, 3);
+        for addr in [0x8000, 0xa000, 0xc000, 0xe000] {
+            assert_eq!(mapper.prg_read(addr), Some(7));
+        }
+    }
+}
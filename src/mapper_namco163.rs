@@ -0,0 +1,441 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::{Mapper, Nametable};
+use crate::savestate::State;
+
+const PRG_RAM_SIZE: usize = 0x2000;
+// $E0以上のバンク番号は本体のVRAM (CIRAM) を指す
+const CIRAM_BANK: u8 = 0xe0;
+
+// 波形メモリ音源。音源用RAMの$40-$7Fに最大8チャンネル分のレジスタがある
+#[cfg(feature = "expansion-audio")]
+pub struct Namco163Audio {
+    cycles: u8,
+    channel: u8,
+    outputs: [i16; 8],
+}
+
+#[cfg(feature = "expansion-audio")]
+impl Namco163Audio {
+    pub fn new() -> Self {
+        Namco163Audio {
+            cycles: 0,
+            channel: 7,
+            outputs: [0; 8],
+        }
+    }
+
+    fn active_channels(ram: &[u8; 0x80]) -> u8 {
+        ((ram[0x7f] >> 4) & 0b111) + 1
+    }
+
+    // 15 CPUサイクルごとに1チャンネルずつ更新する
+    pub fn clock(&mut self, ram: &mut [u8; 0x80]) {
+        self.cycles += 1;
+        if self.cycles < 15 {
+            return;
+        }
+        self.cycles = 0;
+
+        let base = 0x40 + self.channel as usize * 8;
+        let frequency =
+            ram[base] as u32 | (ram[base + 2] as u32) << 8 | ((ram[base + 4] & 0b11) as u32) << 16;
+        let mut phase =
+            ram[base + 1] as u32 | (ram[base + 3] as u32) << 8 | (ram[base + 5] as u32) << 16;
+        let length = 256 - (ram[base + 4] & 0xfc) as u32;
+        phase = (phase + frequency) % (length << 16);
+        ram[base + 1] = phase as u8;
+        ram[base + 3] = (phase >> 8) as u8;
+        ram[base + 5] = (phase >> 16) as u8;
+
+        let index = ((phase >> 16) + ram[base + 6] as u32) & 0xff;
+        let byte = ram[(index / 2) as usize];
+        let sample = if index & 1 == 0 {
+            byte & 0x0f
+        } else {
+            byte >> 4
+        };
+        let volume = (ram[base + 7] & 0x0f) as i16;
+        self.outputs[self.channel as usize] = (sample as i16 - 8) * volume;
+
+        let first = 8 - Namco163Audio::active_channels(ram);
+        self.channel = if self.channel <= first {
+            7
+        } else {
+            self.channel - 1
+        };
+    }
+
+    // 有効なチャンネルの平均を -1.0-1.0 に正規化する
+    pub fn output(&self, ram: &[u8; 0x80]) -> f32 {
+        let active = Namco163Audio::active_channels(ram);
+        let sum: i16 = self.outputs[(8 - active) as usize..].iter().sum();
+        sum as f32 / active as f32 / 120.0
+    }
+
+    pub fn state(&mut self, state: &mut State) {
+        state.u8(&mut self.cycles);
+        state.u8(&mut self.channel);
+        for output in self.outputs.iter_mut() {
+            state.i16(output);
+        }
+    }
+}
+
+#[cfg(feature = "expansion-audio")]
+impl Default for Namco163Audio {
+    fn default() -> Self {
+        Namco163Audio::new()
+    }
+}
+
+// マッパー19 (ナムコ163)
+pub struct Namco163 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    nametable_banks: [u8; 4],
+    // $E800のbit6/7。立っていると$E0以上もCHR ROMとして扱う
+    ciram_disabled: [bool; 2],
+    sound_disabled: bool,
+
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    // 音源用RAM。$F800でアドレス、$4800で読み書きする
+    pub sound_ram: [u8; 0x80],
+    sound_addr: u8,
+    sound_auto_increment: bool,
+    #[cfg(feature = "expansion-audio")]
+    pub audio: Namco163Audio,
+}
+
+impl Namco163 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        Namco163 {
+            prg_rom,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            chr: if chr_is_ram { vec![0; 0x2000] } else { chr_rom },
+            chr_is_ram,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            nametable_banks: [CIRAM_BANK; 4],
+            ciram_disabled: [false; 2],
+            sound_disabled: false,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            sound_ram: [0; 0x80],
+            sound_addr: 0,
+            sound_auto_increment: false,
+            #[cfg(feature = "expansion-audio")]
+            audio: Namco163Audio::new(),
+        }
+    }
+
+    fn read_prg_rom(&self, bank: usize, addr: u16) -> u8 {
+        let index = bank * 0x2000 + (addr as usize & 0x1fff);
+        self.prg_rom[index % self.prg_rom.len()]
+    }
+
+    fn chr_index(&self, bank: u8, offset: u16) -> usize {
+        (bank as usize * 0x400 + (offset as usize % 0x400)) % self.chr.len()
+    }
+
+    fn sound_port(&mut self) -> usize {
+        let addr = self.sound_addr as usize;
+        if self.sound_auto_increment {
+            self.sound_addr = (self.sound_addr + 1) & 0x7f;
+        }
+        addr
+    }
+}
+
+impl Mapper for Namco163 {
+    fn prg_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800..=0x4fff => {
+                let index = self.sound_port();
+                Some(self.sound_ram[index])
+            }
+            0x5000..=0x57ff => Some(self.irq_counter as u8),
+            0x5800..=0x5fff => Some((self.irq_counter >> 8) as u8 | (self.irq_enabled as u8) << 7),
+            0x6000..=0x7fff => Some(self.prg_ram[addr as usize % PRG_RAM_SIZE]),
+            0x8000..=0xdfff => {
+                let slot = ((addr - 0x8000) / 0x2000) as usize;
+                Some(self.read_prg_rom(self.prg_banks[slot] as usize, addr))
+            }
+            0xe000..=0xffff => {
+                let last = self.prg_rom.len() / 0x2000 - 1;
+                Some(self.read_prg_rom(last, addr))
+            }
+            _ => None,
+        }
+    }
+
+    fn mapped_prg_bank(&self, slot: u8) -> Option<usize> {
+        let banks = self.prg_rom.len() / 0x2000;
+        match slot {
+            0..=2 => Some(self.prg_banks[slot as usize] as usize % banks),
+            _ => Some(banks - 1),
+        }
+    }
+
+    // 音源用RAMのポートはアドレスを進めない
+    fn prg_peek(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800..=0x4fff => Some(self.sound_ram[self.sound_addr as usize]),
+            _ => self.prg_read(addr),
+        }
+    }
+
+    fn prg_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x4800..=0x4fff => {
+                let index = self.sound_port();
+                self.sound_ram[index] = data;
+            }
+            // カウンタへの書き込みでIRQを解除する
+            0x5000..=0x57ff => {
+                self.irq_counter = (self.irq_counter & 0x7f00) | data as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5fff => {
+                self.irq_counter = (self.irq_counter & 0x00ff) | ((data & 0x7f) as u16) << 8;
+                self.irq_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x6000..=0x7fff => self.prg_ram[addr as usize % PRG_RAM_SIZE] = data,
+            0x8000..=0xbfff => self.chr_banks[((addr - 0x8000) / 0x800) as usize] = data,
+            0xc000..=0xdfff => self.nametable_banks[((addr - 0xc000) / 0x800) as usize] = data,
+            0xe000..=0xe7ff => {
+                self.prg_banks[0] = data & 0x3f;
+                self.sound_disabled = data & 0x40 != 0;
+            }
+            0xe800..=0xefff => {
+                self.prg_banks[1] = data & 0x3f;
+                self.ciram_disabled = [data & 0x40 != 0, data & 0x80 != 0];
+            }
+            0xf000..=0xf7ff => self.prg_banks[2] = data & 0x3f,
+            0xf800..=0xffff => {
+                self.sound_addr = data & 0x7f;
+                self.sound_auto_increment = data & 0x80 != 0;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    // パターンテーブルにCIRAMを割り当てる使い方 ($E0以上のバンク) は未対応で、
+    // 常にCHRから読む。CHR ROMへの書き込みは捨てる
+    fn chr_read(&mut self, addr: u16) -> u8 {
+        let bank = self.chr_banks[(addr / 0x400) as usize];
+        self.chr[self.chr_index(bank, addr)]
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let bank = self.chr_banks[(addr / 0x400) as usize];
+            let index = self.chr_index(bank, addr);
+            self.chr[index] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match (self.nametable(1), self.nametable(2)) {
+            (Nametable::Ciram(1), Nametable::Ciram(0)) => Mirroring::VERTICAL,
+            _ => Mirroring::HORIZONTAL,
+        }
+    }
+
+    // ネームテーブルごとにCIRAMかCHR ROMの1KBを選べる
+    fn nametable(&self, table: u8) -> Nametable {
+        let bank = self.nametable_banks[table as usize];
+        if bank >= CIRAM_BANK {
+            Nametable::Ciram(bank & 1)
+        } else {
+            Nametable::Mapper
+        }
+    }
+
+    fn nametable_read(&mut self, addr: u16) -> u8 {
+        let table = ((addr - 0x2000) / 0x400 % 4) as usize;
+        self.chr[self.chr_index(self.nametable_banks[table], addr)]
+    }
+
+    fn cpu_tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            if self.irq_enabled && self.irq_counter < 0x7fff {
+                self.irq_counter += 1;
+                if self.irq_counter == 0x7fff {
+                    self.irq_pending = true;
+                }
+            }
+            #[cfg(feature = "expansion-audio")]
+            if !self.sound_disabled {
+                self.audio.clock(&mut self.sound_ram);
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    #[cfg(feature = "expansion-audio")]
+    fn audio_output(&self) -> f32 {
+        if self.sound_disabled {
+            0.0
+        } else {
+            self.audio.output(&self.sound_ram)
+        }
+    }
+
+    fn state(&mut self, state: &mut State) {
+        state.bytes(&mut self.prg_ram);
+        if self.chr_is_ram {
+            state.bytes(&mut self.chr);
+        }
+        state.bytes(&mut self.prg_banks);
+        state.bytes(&mut self.chr_banks);
+        state.bytes(&mut self.nametable_banks);
+        for disabled in self.ciram_disabled.iter_mut() {
+            state.bool(disabled);
+        }
+        state.bool(&mut self.sound_disabled);
+        state.u16(&mut self.irq_counter);
+        state.bool(&mut self.irq_enabled);
+        state.bool(&mut self.irq_pending);
+        state.bytes(&mut self.sound_ram);
+        state.u8(&mut self.sound_addr);
+        state.bool(&mut self.sound_auto_increment);
+        #[cfg(feature = "expansion-audio")]
+        self.audio.state(state);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::rom_banks;
+
+    fn namco163() -> Namco163 {
+        // 8KBごとにバンク番号を書いたPRG 128KB、1KBごとのCHR 256KB
+        let prg_rom = rom_banks(16, 0x2000);
+        let chr_rom = rom_banks(256, 0x400);
+        Namco163::new(prg_rom, chr_rom)
+    }
+
+    #[test]
+    fn test_prg_and_chr_banks() {
+        let mut mapper = namco163();
+        mapper.prg_write(0xe000, 2);
+        mapper.prg_write(0xe800, 3);
+        mapper.prg_write(0xf000, 4);
+        let banks: Vec<Option<u8>> = [0x8000, 0xa000, 0xc000, 0xe000]
+            .iter()
+            .map(|a| mapper.prg_read(*a))
+            .collect();
+        assert_eq!(banks, vec![Some(2), Some(3), Some(4), Some(15)]);
+
+        mapper.prg_write(0x8800, 0x21);
+        mapper.prg_write(0xb800, 0x42);
+        assert_eq!(mapper.chr_read(0x0400), 0x21);
+        assert_eq!(mapper.chr_read(0x1c00), 0x42);
+
+        // CIRAMを選んでいても、CHR ROMは書き換えない
+        mapper.prg_write(0x8000, 0xe0);
+        mapper.prg_write(0xe800, 0);
+        mapper.chr_write(0x0010, 0x99);
+        assert_eq!(mapper.chr_read(0x0010), 0xe0);
+    }
+
+    #[test]
+    fn test_nametables() {
+        let mut mapper = namco163();
+        // 0,1: CIRAM 0/1, 2: CHR ROMの$10, 3: CIRAM 0
+        mapper.prg_write(0xc000, 0xe0);
+        mapper.prg_write(0xc800, 0xe1);
+        mapper.prg_write(0xd000, 0x10);
+        mapper.prg_write(0xd800, 0xe0);
+        assert_eq!(mapper.nametable(0), Nametable::Ciram(0));
+        assert_eq!(mapper.nametable(1), Nametable::Ciram(1));
+        assert_eq!(mapper.nametable(2), Nametable::Mapper);
+        assert_eq!(mapper.nametable_read(0x2812), 0x10);
+        assert_eq!(mapper.mirroring(), Mirroring::HORIZONTAL);
+
+        mapper.prg_write(0xd000, 0xe0);
+        mapper.prg_write(0xd800, 0xe1);
+        assert_eq!(mapper.mirroring(), Mirroring::VERTICAL);
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut mapper = namco163();
+        mapper.prg_write(0x5000, 0xfd);
+        mapper.prg_write(0x5800, 0xff);
+        assert_eq!(mapper.prg_read(0x5800), Some(0xff));
+        mapper.cpu_tick(1);
+        assert!(!mapper.irq());
+        mapper.cpu_tick(1);
+        assert!(mapper.irq());
+
+        // 上限で止まる
+        mapper.cpu_tick(10);
+        assert_eq!(mapper.prg_read(0x5000), Some(0xff));
+        mapper.prg_write(0x5000, 0);
+        assert!(!mapper.irq());
+    }
+
+    #[test]
+    fn test_sound_ram_port() {
+        let mut mapper = namco163();
+        mapper.prg_write(0xf800, 0x80 | 0x7f);
+        mapper.prg_write(0x4800, 0x11);
+        mapper.prg_write(0x4800, 0x22);
+        assert_eq!(mapper.sound_ram[0x7f], 0x11);
+        assert_eq!(mapper.sound_ram[0x00], 0x22);
+
+        mapper.prg_write(0xf800, 0x7f);
+        assert_eq!(mapper.prg_read(0x4800), Some(0x11));
+        assert_eq!(mapper.prg_read(0x4800), Some(0x11));
+
+        // peekは自動インクリメントでもアドレスを進めない
+        mapper.prg_write(0xf800, 0x80 | 0x7f);
+        assert_eq!(mapper.prg_peek(0x4800), Some(0x11));
+        assert_eq!(mapper.prg_read(0x4800), Some(0x11));
+        assert_eq!(mapper.prg_read(0x4800), Some(0x22));
+    }
+
+    #[cfg(feature = "expansion-audio")]
+    #[test]
+    fn test_wavetable() {
+        let mut mapper = namco163();
+        // 波形: 先頭4サンプルが F, 0, F, 0
+        mapper.sound_ram[0] = 0x0f;
+        mapper.sound_ram[1] = 0x0f;
+        // チャンネル7のみ有効。長さ4サンプル, 1回で1サンプル進む, 音量15
+        mapper.sound_ram[0x78] = 0x00;
+        mapper.sound_ram[0x7a] = 0x00;
+        mapper.sound_ram[0x7c] = 0xfc | 0x01;
+        mapper.sound_ram[0x7e] = 0;
+        mapper.sound_ram[0x7f] = 0x0f;
+
+        let mut samples = vec![];
+        for _ in 0..4 {
+            mapper.cpu_tick(15);
+            samples.push(mapper.audio_output());
+        }
+        // 位相1から始まるので 0, F, 0, F の順 ((0 - 8) * 15 = -120)
+        assert_eq!(samples, vec![-1.0, 0.875, -1.0, 0.875]);
+    }
+}
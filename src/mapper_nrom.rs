@@ -0,0 +1,89 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::Mapper;
+use crate::savestate::State;
+
+// マッパー0。PRG 16KB/32KB 固定、CHRが無ければ8KBのCHR RAM
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        Nrom {
+            prg_rom,
+            chr: if chr_is_ram { vec![0; 0x2000] } else { chr_rom },
+            chr_is_ram,
+            mirroring,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn prg_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xFFFF => {
+                // 16KBの場合は$C000-が$8000-のミラー
+                let index = (addr - 0x8000) as usize % self.prg_rom.len();
+                Some(self.prg_rom[index])
+            }
+            _ => None,
+        }
+    }
+
+    fn prg_write(&mut self, _addr: u16, _data: u8) -> bool {
+        false
+    }
+
+    fn chr_read(&mut self, addr: u16) -> u8 {
+        self.chr[addr as usize % self.chr.len()]
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            self.chr[addr as usize] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn state(&mut self, state: &mut State) {
+        if self.chr_is_ram {
+            state.bytes(&mut self.chr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prg_mirroring() {
+        let mut prg_rom = vec![0; 0x4000];
+        prg_rom[0] = 0xaa;
+        prg_rom[0x3fff] = 0xbb;
+        let mut nrom = Nrom::new(prg_rom, vec![0; 0x2000], Mirroring::VERTICAL);
+        assert_eq!(nrom.prg_read(0x8000), Some(0xaa));
+        assert_eq!(nrom.prg_read(0xc000), Some(0xaa));
+        assert_eq!(nrom.prg_read(0xffff), Some(0xbb));
+        assert_eq!(nrom.prg_read(0x6000), None);
+        assert!(!nrom.prg_write(0x8000, 1));
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let mut nrom = Nrom::new(vec![0; 0x4000], vec![], Mirroring::HORIZONTAL);
+        nrom.chr_write(0x1234, 0x56);
+        assert_eq!(nrom.chr_read(0x1234), 0x56);
+
+        let mut nrom = Nrom::new(vec![0; 0x4000], vec![7; 0x2000], Mirroring::HORIZONTAL);
+        nrom.chr_write(0x1234, 0x56);
+        assert_eq!(nrom.chr_read(0x1234), 7);
+    }
+}
@@ -0,0 +1,108 @@
+use crate::cartridge::{Mirroring, Nsf};
+use crate::mapper::Mapper;
+use crate::savestate::State;
+
+const BANK_SIZE: usize = 0x1000;
+
+// NSF用。$8000-$FFFFを4KBずつ8つのバンクに分け、$5FF8-$5FFFで切り替える
+pub struct NsfMapper {
+    prg: Vec<u8>,
+    banks: [u8; 8],
+    chr: Vec<u8>,
+}
+
+impl NsfMapper {
+    pub fn new(nsf: &Nsf) -> Self {
+        // バンク切り替えありならload_addrの下位12bit、なしなら$8000からの位置に置く
+        let (padding, banks) = if nsf.is_bank_switched() {
+            ((nsf.load_addr & 0x0fff) as usize, nsf.bankswitch_init)
+        } else {
+            (
+                nsf.load_addr.saturating_sub(0x8000) as usize,
+                [0, 1, 2, 3, 4, 5, 6, 7],
+            )
+        };
+        let mut prg = vec![0; padding];
+        prg.extend(&nsf.data);
+        prg.resize(prg.len().div_ceil(BANK_SIZE).max(1) * BANK_SIZE, 0);
+        NsfMapper {
+            prg,
+            banks,
+            chr: vec![0; 0x2000],
+        }
+    }
+}
+
+impl Mapper for NsfMapper {
+    fn prg_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xffff => {
+                let bank = self.banks[((addr - 0x8000) as usize) / BANK_SIZE] as usize;
+                let index = bank * BANK_SIZE + (addr as usize % BANK_SIZE);
+                Some(self.prg[index % self.prg.len()])
+            }
+            _ => None,
+        }
+    }
+
+    fn prg_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x5ff8..=0x5fff => {
+                self.banks[(addr - 0x5ff8) as usize] = data;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn chr_read(&mut self, addr: u16) -> u8 {
+        self.chr[addr as usize % self.chr.len()]
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        self.chr[addr as usize] = data;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::HORIZONTAL
+    }
+
+    fn state(&mut self, state: &mut State) {
+        state.bytes(&mut self.banks);
+        state.bytes(&mut self.chr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::nsf;
+
+    #[test]
+    fn test_load_addr() {
+        let mut nsf = Nsf::new(&nsf(1, &[1, 2, 3])).unwrap();
+        nsf.load_addr = 0x8100;
+        let mut mapper = NsfMapper::new(&nsf);
+        assert_eq!(mapper.prg_read(0x8000), Some(0));
+        assert_eq!(mapper.prg_read(0x8100), Some(1));
+        assert_eq!(mapper.prg_read(0x8102), Some(3));
+        assert_eq!(mapper.prg_read(0x6000), None);
+    }
+
+    #[test]
+    fn test_bank_switching() {
+        let mut data = vec![0; 3 * BANK_SIZE - 0x234];
+        data[0] = 0xaa;
+        data[2 * BANK_SIZE - 0x234] = 0xcc;
+        let mut nsf = Nsf::new(&nsf(1, &data)).unwrap();
+        nsf.load_addr = 0x8234;
+        nsf.bankswitch_init = [0, 0, 0, 0, 0, 0, 0, 2];
+        let mut mapper = NsfMapper::new(&nsf);
+        assert_eq!(mapper.prg_read(0x8234), Some(0xaa));
+        assert_eq!(mapper.prg_read(0xf000), Some(0xcc));
+
+        assert!(mapper.prg_write(0x5ff8, 2));
+        assert_eq!(mapper.prg_read(0x8000), Some(0xcc));
+        assert!(!mapper.prg_write(0x8000, 1));
+    }
+}
@@ -0,0 +1,392 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::{Mapper, Nametable};
+use crate::savestate::State;
+
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// コナミVRC系のIRQカウンタ。スキャンラインモードではCPU 341/3サイクルごと、
+// サイクルモードでは毎サイクルカウンタを進め、$FFから溢れるとIRQ
+pub struct VrcIrq {
+    pub latch: u8,
+    pub counter: u8,
+    enabled: bool,
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    prescaler: i16,
+    pending: bool,
+}
+
+impl VrcIrq {
+    pub fn new() -> Self {
+        VrcIrq {
+            latch: 0,
+            counter: 0,
+            enabled: false,
+            enable_after_ack: false,
+            cycle_mode: false,
+            prescaler: 341,
+            pending: false,
+        }
+    }
+
+    pub fn write_control(&mut self, data: u8) {
+        self.enable_after_ack = data & 0b001 != 0;
+        self.enabled = data & 0b010 != 0;
+        self.cycle_mode = data & 0b100 != 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = 341;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    pub fn cpu_tick(&mut self, cycles: u8) {
+        if !self.enabled {
+            return;
+        }
+        for _ in 0..cycles {
+            if self.cycle_mode {
+                self.clock_counter();
+                continue;
+            }
+            self.prescaler -= 3;
+            if self.prescaler <= 0 {
+                self.prescaler += 341;
+                self.clock_counter();
+            }
+        }
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xff {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+    pub fn state(&mut self, state: &mut State) {
+        state.u8(&mut self.latch);
+        state.u8(&mut self.counter);
+        state.bool(&mut self.enabled);
+        state.bool(&mut self.enable_after_ack);
+        state.bool(&mut self.cycle_mode);
+        state.i16(&mut self.prescaler);
+        state.bool(&mut self.pending);
+    }
+}
+
+impl Default for VrcIrq {
+    fn default() -> Self {
+        VrcIrq::new()
+    }
+}
+
+// ボードによってレジスタのA0/A1に繋がるCPUアドレス線が異なる。
+// 同じマッパー番号に複数の配線があるので両方のビットを見る
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VrcWiring {
+    pub a0: u16,
+    pub a1: u16,
+    // VRC2aはCHRバンク番号の最下位ビットが無視される
+    pub chr_shift: u8,
+    pub vrc2: bool,
+}
+
+impl VrcWiring {
+    pub fn for_mapper(mapper: u16) -> Option<Self> {
+        let wiring = |a0, a1, chr_shift, vrc2| VrcWiring {
+            a0,
+            a1,
+            chr_shift,
+            vrc2,
+        };
+        match mapper {
+            // VRC4a (A1, A2) / VRC4c (A6, A7)
+            21 => Some(wiring(0x02 | 0x40, 0x04 | 0x80, 0, false)),
+            // VRC2a (A1, A0)
+            22 => Some(wiring(0x02, 0x01, 1, true)),
+            // VRC2b, VRC4f (A0, A1) / VRC4e (A2, A3)
+            23 => Some(wiring(0x01 | 0x04, 0x02 | 0x08, 0, false)),
+            // VRC2c, VRC4b (A1, A0) / VRC4d (A3, A2)
+            25 => Some(wiring(0x02 | 0x08, 0x01 | 0x04, 0, false)),
+            _ => None,
+        }
+    }
+
+    // $x000-$x003 に正規化する
+    fn register(&self, addr: u16) -> u16 {
+        let a0 = (addr & self.a0 != 0) as u16;
+        let a1 = (addr & self.a1 != 0) as u16;
+        (addr & 0xf000) | a1 << 1 | a0
+    }
+}
+
+// マッパー21/22/23/25 (コナミ VRC2/VRC4)
+pub struct Vrc4 {
+    wiring: VrcWiring,
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+
+    prg_banks: [u8; 2],
+    prg_swap: bool,
+    chr_banks: [u16; 8],
+    nametable_mode: u8,
+    pub irq: VrcIrq,
+}
+
+impl Vrc4 {
+    pub fn new(wiring: VrcWiring, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        Vrc4 {
+            wiring,
+            prg_rom,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            chr: if chr_is_ram { vec![0; 0x2000] } else { chr_rom },
+            chr_is_ram,
+            prg_banks: [0; 2],
+            prg_swap: false,
+            chr_banks: [0; 8],
+            nametable_mode: 0,
+            irq: VrcIrq::new(),
+        }
+    }
+
+    fn prg_bank(&self, slot: usize) -> usize {
+        // 8KBしかないときは同じバンクを繰り返す
+        let last = self.prg_rom.len() / 0x2000 - 1;
+        match (slot, self.prg_swap) {
+            (0, false) | (2, true) => self.prg_banks[0] as usize,
+            (1, _) => self.prg_banks[1] as usize,
+            (0, true) | (2, false) => last.saturating_sub(1),
+            _ => last,
+        }
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        let bank = (self.chr_banks[(addr / 0x400) as usize] >> self.wiring.chr_shift) as usize;
+        (bank * 0x400 + (addr as usize % 0x400)) % self.chr.len()
+    }
+
+    // $B000-$E003: 偶数レジスタが下位4bit、奇数レジスタが上位
+    fn write_chr_bank(&mut self, register: u16, data: u8) {
+        let index = (((register >> 12) - 0xb) * 2 + ((register & 0b10) >> 1)) as usize;
+        let bank = self.chr_banks[index];
+        self.chr_banks[index] = if register & 1 == 0 {
+            (bank & 0x1f0) | (data & 0x0f) as u16
+        } else {
+            (bank & 0x00f) | ((data & 0x1f) as u16) << 4
+        };
+    }
+}
+
+impl Mapper for Vrc4 {
+    fn prg_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff => Some(self.prg_ram[addr as usize % PRG_RAM_SIZE]),
+            0x8000..=0xffff => {
+                let bank = self.prg_bank(((addr - 0x8000) / 0x2000) as usize);
+                let index = bank * 0x2000 + (addr as usize & 0x1fff);
+                Some(self.prg_rom[index % self.prg_rom.len()])
+            }
+            _ => None,
+        }
+    }
+
+    fn mapped_prg_bank(&self, slot: u8) -> Option<usize> {
+        Some(self.prg_bank(slot as usize) % (self.prg_rom.len() / 0x2000))
+    }
+
+    fn prg_write(&mut self, addr: u16, data: u8) -> bool {
+        if let 0x6000..=0x7fff = addr {
+            self.prg_ram[addr as usize % PRG_RAM_SIZE] = data;
+            return true;
+        }
+        if addr < 0x8000 {
+            return false;
+        }
+        let register = self.wiring.register(addr);
+        match register {
+            0x8000..=0x8003 => self.prg_banks[0] = data & 0x1f,
+            0x9000..=0x9003 if self.wiring.vrc2 => self.nametable_mode = data & 0b01,
+            0x9000..=0x9001 => self.nametable_mode = data & 0b11,
+            0x9002..=0x9003 => self.prg_swap = data & 0b10 != 0,
+            0xa000..=0xa003 => self.prg_banks[1] = data & 0x1f,
+            0xb000..=0xe003 => self.write_chr_bank(register, data),
+            0xf000 if !self.wiring.vrc2 => self.irq.latch = (self.irq.latch & 0xf0) | (data & 0x0f),
+            0xf001 if !self.wiring.vrc2 => self.irq.latch = (self.irq.latch & 0x0f) | data << 4,
+            0xf002 if !self.wiring.vrc2 => self.irq.write_control(data),
+            0xf003 if !self.wiring.vrc2 => self.irq.acknowledge(),
+            _ => {}
+        }
+        true
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn chr_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_index(addr)]
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let index = self.chr_index(addr);
+            self.chr[index] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.nametable_mode {
+            0 => Mirroring::VERTICAL,
+            _ => Mirroring::HORIZONTAL,
+        }
+    }
+
+    fn nametable(&self, table: u8) -> Nametable {
+        match self.nametable_mode {
+            0 => Nametable::Ciram(table & 1),
+            1 => Nametable::Ciram((table >> 1) & 1),
+            2 => Nametable::Ciram(0),
+            _ => Nametable::Ciram(1),
+        }
+    }
+
+    fn cpu_tick(&mut self, cycles: u8) {
+        self.irq.cpu_tick(cycles);
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn state(&mut self, state: &mut State) {
+        state.bytes(&mut self.prg_ram);
+        if self.chr_is_ram {
+            state.bytes(&mut self.chr);
+        }
+        state.bytes(&mut self.prg_banks);
+        state.bool(&mut self.prg_swap);
+        state.u16_slice(&mut self.chr_banks);
+        state.u8(&mut self.nametable_mode);
+        self.irq.state(state);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::rom_banks;
+
+    fn vrc4(mapper: u16) -> Vrc4 {
+        // 8KBごとにバンク番号を書いたPRG 128KB、1KBごとのCHR 256KB
+        let prg_rom = rom_banks(16, 0x2000);
+        let chr_rom = rom_banks(256, 0x400);
+        Vrc4::new(VrcWiring::for_mapper(mapper).unwrap(), prg_rom, chr_rom)
+    }
+
+    #[test]
+    fn test_wiring() {
+        let wiring = VrcWiring::for_mapper(21).unwrap();
+        assert_eq!(wiring.register(0xb002), 0xb001);
+        assert_eq!(wiring.register(0xb080), 0xb002);
+        assert_eq!(wiring.register(0xb0c0), 0xb003);
+
+        let wiring = VrcWiring::for_mapper(23).unwrap();
+        assert_eq!(wiring.register(0xb001), 0xb001);
+        assert_eq!(wiring.register(0xb008), 0xb002);
+
+        let wiring = VrcWiring::for_mapper(25).unwrap();
+        assert_eq!(wiring.register(0xb001), 0xb002);
+        assert_eq!(wiring.register(0xb008), 0xb001);
+        assert_eq!(VrcWiring::for_mapper(24), None);
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut mapper = vrc4(23);
+        mapper.prg_write(0x8000, 3);
+        mapper.prg_write(0xa000, 4);
+        let banks: Vec<Option<u8>> = [0x8000, 0xa000, 0xc000, 0xe000]
+            .iter()
+            .map(|a| mapper.prg_read(*a))
+            .collect();
+        assert_eq!(banks, vec![Some(3), Some(4), Some(14), Some(15)]);
+
+        // スワップモードでは$8000と$C000が入れ替わる
+        mapper.prg_write(0x9002, 0b10);
+        let banks: Vec<Option<u8>> = [0x8000, 0xa000, 0xc000, 0xe000]
+            .iter()
+            .map(|a| mapper.prg_read(*a))
+            .collect();
+        assert_eq!(banks, vec![Some(14), Some(4), Some(3), Some(15)]);
+    }
+
+    #[test]
+    fn test_chr_banks() {
+        let mut mapper = vrc4(25);
+        // VRC4bの配線ではA1がレジスタのA0
+        mapper.prg_write(0xb000, 0x05);
+        mapper.prg_write(0xb002, 0x01);
+        assert_eq!(mapper.chr_read(0x0000), 0x15);
+        mapper.prg_write(0xe001, 0x0a);
+        assert_eq!(mapper.chr_read(0x1c00), 0x0a);
+
+        // VRC2aはバンク番号を1bit右シフト
+        let mut mapper = vrc4(22);
+        mapper.prg_write(0xb000, 0x0a);
+        assert_eq!(mapper.chr_read(0x0000), 0x05);
+    }
+
+    #[test]
+    fn test_mirroring() {
+        let mut mapper = vrc4(21);
+        mapper.prg_write(0x9000, 1);
+        assert_eq!(mapper.nametable(1), Nametable::Ciram(0));
+        mapper.prg_write(0x9000, 3);
+        assert_eq!(mapper.nametable(0), Nametable::Ciram(1));
+
+        // VRC2は1bitだけ
+        let mut mapper = vrc4(22);
+        mapper.prg_write(0x9000, 3);
+        assert_eq!(mapper.nametable(2), Nametable::Ciram(1));
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut mapper = vrc4(23);
+        // サイクルモードでラッチ$FC -> 4サイクル目でIRQ
+        mapper.prg_write(0xf000, 0x0c);
+        mapper.prg_write(0xf004, 0x0f);
+        mapper.prg_write(0xf008, 0b111);
+        mapper.cpu_tick(3);
+        assert!(!mapper.irq());
+        mapper.cpu_tick(1);
+        assert!(mapper.irq());
+        assert_eq!(mapper.irq.counter, 0xfc);
+
+        mapper.prg_write(0xf00c, 0);
+        assert!(!mapper.irq());
+
+        // スキャンラインモードでは113.667サイクルごとに1つ進む
+        mapper.prg_write(0xf000, 0x0e);
+        mapper.prg_write(0xf008, 0b010);
+        mapper.cpu_tick(227);
+        assert!(!mapper.irq());
+        mapper.cpu_tick(1);
+        assert!(mapper.irq());
+    }
+}
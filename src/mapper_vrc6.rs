@@ -0,0 +1,484 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::{Mapper, Nametable};
+use crate::mapper_vrc4::VrcIrq;
+use crate::savestate::State;
+
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// VRC6の矩形波。デューティは16段階
+#[cfg(feature = "expansion-audio")]
+pub struct Vrc6Pulse {
+    pub enabled: bool,
+    // 1ならデューティを無視して常に音量を出す
+    pub digitized: bool,
+    pub duty: u8,
+    pub volume: u8,
+    pub period: u16,
+    timer: u16,
+    step: u8,
+}
+
+#[cfg(feature = "expansion-audio")]
+impl Vrc6Pulse {
+    pub fn new() -> Self {
+        Vrc6Pulse {
+            enabled: false,
+            digitized: false,
+            duty: 0,
+            volume: 0,
+            period: 0,
+            timer: 0,
+            step: 15,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.digitized = data & 0x80 != 0;
+                self.duty = (data >> 4) & 0b111;
+                self.volume = data & 0x0f;
+            }
+            1 => self.period = (self.period & 0x0f00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00ff) | ((data & 0x0f) as u16) << 8;
+                self.enabled = data & 0x80 != 0;
+                // 無効にするとデューティの位置がリセットされる
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = self.step.wrapping_sub(1) & 0x0f;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.enabled && (self.digitized || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+
+    pub fn state(&mut self, state: &mut State) {
+        state.bool(&mut self.enabled);
+        state.bool(&mut self.digitized);
+        state.u8(&mut self.duty);
+        state.u8(&mut self.volume);
+        state.u16(&mut self.period);
+        state.u16(&mut self.timer);
+        state.u8(&mut self.step);
+    }
+}
+
+#[cfg(feature = "expansion-audio")]
+impl Default for Vrc6Pulse {
+    fn default() -> Self {
+        Vrc6Pulse::new()
+    }
+}
+
+// のこぎり波。2ステップごとにアキュムレータへ加算し、14ステップで0に戻る
+#[cfg(feature = "expansion-audio")]
+pub struct Vrc6Sawtooth {
+    pub enabled: bool,
+    pub rate: u8,
+    pub period: u16,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+#[cfg(feature = "expansion-audio")]
+impl Vrc6Sawtooth {
+    pub fn new() -> Self {
+        Vrc6Sawtooth {
+            enabled: false,
+            rate: 0,
+            period: 0,
+            timer: 0,
+            step: 0,
+            accumulator: 0,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => self.rate = data & 0x3f,
+            1 => self.period = (self.period & 0x0f00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00ff) | ((data & 0x0f) as u16) << 8;
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step & 1 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    // アキュムレータの上位5bit
+    pub fn output(&self) -> u8 {
+        if self.enabled {
+            self.accumulator >> 3
+        } else {
+            0
+        }
+    }
+
+    pub fn state(&mut self, state: &mut State) {
+        state.bool(&mut self.enabled);
+        state.u8(&mut self.rate);
+        state.u16(&mut self.period);
+        state.u16(&mut self.timer);
+        state.u8(&mut self.step);
+        state.u8(&mut self.accumulator);
+    }
+}
+
+#[cfg(feature = "expansion-audio")]
+impl Default for Vrc6Sawtooth {
+    fn default() -> Self {
+        Vrc6Sawtooth::new()
+    }
+}
+
+// マッパー24/26 (コナミ VRC6)。26はA0とA1が入れ替わっている
+pub struct Vrc6 {
+    swap_lines: bool,
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+
+    prg_bank_16k: u8,
+    prg_bank_8k: u8,
+    chr_banks: [u8; 8],
+    // $B003
+    banking_control: u8,
+    pub irq: VrcIrq,
+
+    #[cfg(feature = "expansion-audio")]
+    pub pulse1: Vrc6Pulse,
+    #[cfg(feature = "expansion-audio")]
+    pub pulse2: Vrc6Pulse,
+    #[cfg(feature = "expansion-audio")]
+    pub sawtooth: Vrc6Sawtooth,
+}
+
+impl Vrc6 {
+    pub fn new(mapper: u16, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        Vrc6 {
+            swap_lines: mapper == 26,
+            prg_rom,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            chr: if chr_is_ram { vec![0; 0x2000] } else { chr_rom },
+            chr_is_ram,
+            prg_bank_16k: 0,
+            prg_bank_8k: 0,
+            chr_banks: [0; 8],
+            banking_control: 0,
+            irq: VrcIrq::new(),
+            #[cfg(feature = "expansion-audio")]
+            pulse1: Vrc6Pulse::new(),
+            #[cfg(feature = "expansion-audio")]
+            pulse2: Vrc6Pulse::new(),
+            #[cfg(feature = "expansion-audio")]
+            sawtooth: Vrc6Sawtooth::new(),
+        }
+    }
+
+    fn register(&self, addr: u16) -> u16 {
+        let lines = addr & 0b11;
+        let lines = if self.swap_lines {
+            (lines & 1) << 1 | (lines >> 1)
+        } else {
+            lines
+        };
+        (addr & 0xf000) | lines
+    }
+
+    fn read_prg_rom(&self, bank_8k: usize, addr: u16) -> u8 {
+        let index = bank_8k * 0x2000 + (addr as usize & 0x1fff);
+        self.prg_rom[index % self.prg_rom.len()]
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.banking_control & 0x80 != 0
+    }
+
+    // 1KB単位のCHRバンク (モード0) のみ対応
+    fn chr_index(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr / 0x400) as usize] as usize;
+        (bank * 0x400 + (addr as usize % 0x400)) % self.chr.len()
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn prg_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff if self.prg_ram_enabled() => {
+                Some(self.prg_ram[addr as usize % PRG_RAM_SIZE])
+            }
+            0x8000..=0xbfff => {
+                let bank = self.prg_bank_16k as usize * 2 + ((addr - 0x8000) / 0x2000) as usize;
+                Some(self.read_prg_rom(bank, addr))
+            }
+            0xc000..=0xdfff => Some(self.read_prg_rom(self.prg_bank_8k as usize, addr)),
+            0xe000..=0xffff => {
+                let last = self.prg_rom.len() / 0x2000 - 1;
+                Some(self.read_prg_rom(last, addr))
+            }
+            _ => None,
+        }
+    }
+
+    fn mapped_prg_bank(&self, slot: u8) -> Option<usize> {
+        let banks = self.prg_rom.len() / 0x2000;
+        let bank = match slot {
+            0 | 1 => self.prg_bank_16k as usize * 2 + slot as usize,
+            2 => self.prg_bank_8k as usize,
+            _ => banks - 1,
+        };
+        Some(bank % banks)
+    }
+
+    fn prg_write(&mut self, addr: u16, data: u8) -> bool {
+        if let 0x6000..=0x7fff = addr {
+            if !self.prg_ram_enabled() {
+                return false;
+            }
+            self.prg_ram[addr as usize % PRG_RAM_SIZE] = data;
+            return true;
+        }
+        if addr < 0x8000 {
+            return false;
+        }
+        let register = self.register(addr);
+        match register {
+            0x8000..=0x8003 => self.prg_bank_16k = data & 0x0f,
+            0x9000..=0x9002 | 0xa000..=0xa002 | 0xb000..=0xb002 =>
+            {
+                #[cfg(feature = "expansion-audio")]
+                match register & 0xf000 {
+                    0x9000 => self.pulse1.write(register & 0b11, data),
+                    0xa000 => self.pulse2.write(register & 0b11, data),
+                    _ => self.sawtooth.write(register & 0b11, data),
+                }
+            }
+            0xb003 => self.banking_control = data,
+            0xc000..=0xc003 => self.prg_bank_8k = data & 0x1f,
+            0xd000..=0xd003 => self.chr_banks[(register & 0b11) as usize] = data,
+            0xe000..=0xe003 => self.chr_banks[4 + (register & 0b11) as usize] = data,
+            0xf000 => self.irq.latch = data,
+            0xf001 => self.irq.write_control(data),
+            0xf002 => self.irq.acknowledge(),
+            _ => {}
+        }
+        true
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn chr_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_index(addr)]
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let index = self.chr_index(addr);
+            self.chr[index] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match (self.banking_control >> 2) & 0b11 {
+            0 => Mirroring::VERTICAL,
+            _ => Mirroring::HORIZONTAL,
+        }
+    }
+
+    fn nametable(&self, table: u8) -> Nametable {
+        match (self.banking_control >> 2) & 0b11 {
+            0 => Nametable::Ciram(table & 1),
+            1 => Nametable::Ciram((table >> 1) & 1),
+            2 => Nametable::Ciram(0),
+            _ => Nametable::Ciram(1),
+        }
+    }
+
+    fn cpu_tick(&mut self, cycles: u8) {
+        self.irq.cpu_tick(cycles);
+        #[cfg(feature = "expansion-audio")]
+        for _ in 0..cycles {
+            self.pulse1.clock();
+            self.pulse2.clock();
+            self.sawtooth.clock();
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    // 矩形波2つ (0-15) とのこぎり波 (0-31) の単純な和
+    #[cfg(feature = "expansion-audio")]
+    fn audio_output(&self) -> f32 {
+        let sum = self.pulse1.output() + self.pulse2.output() + self.sawtooth.output();
+        sum as f32 / 61.0
+    }
+
+    fn state(&mut self, state: &mut State) {
+        state.bytes(&mut self.prg_ram);
+        if self.chr_is_ram {
+            state.bytes(&mut self.chr);
+        }
+        state.u8(&mut self.prg_bank_16k);
+        state.u8(&mut self.prg_bank_8k);
+        state.bytes(&mut self.chr_banks);
+        state.u8(&mut self.banking_control);
+        self.irq.state(state);
+        #[cfg(feature = "expansion-audio")]
+        {
+            self.pulse1.state(state);
+            self.pulse2.state(state);
+            self.sawtooth.state(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::rom_banks;
+
+    fn vrc6(mapper: u16) -> Vrc6 {
+        // 8KBごとにバンク番号を書いたPRG 256KB、1KBごとのCHR 256KB
+        let prg_rom = rom_banks(32, 0x2000);
+        let chr_rom = rom_banks(256, 0x400);
+        Vrc6::new(mapper, prg_rom, chr_rom)
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut mapper = vrc6(24);
+        mapper.prg_write(0x8000, 3);
+        mapper.prg_write(0xc000, 9);
+        let banks: Vec<Option<u8>> = [0x8000, 0xa000, 0xc000, 0xe000]
+            .iter()
+            .map(|a| mapper.prg_read(*a))
+            .collect();
+        assert_eq!(banks, vec![Some(6), Some(7), Some(9), Some(31)]);
+
+        assert_eq!(mapper.prg_read(0x6000), None);
+        mapper.prg_write(0xb003, 0x80);
+        assert!(mapper.prg_write(0x6000, 0x42));
+        assert_eq!(mapper.prg_read(0x6000), Some(0x42));
+    }
+
+    #[test]
+    fn test_chr_banks_and_swapped_lines() {
+        let mut mapper = vrc6(24);
+        mapper.prg_write(0xd001, 10);
+        mapper.prg_write(0xe003, 20);
+        assert_eq!(mapper.chr_read(0x0400), 10);
+        assert_eq!(mapper.chr_read(0x1c00), 20);
+
+        // マッパー26では$D001が3番目のバンク
+        let mut mapper = vrc6(26);
+        mapper.prg_write(0xd001, 10);
+        assert_eq!(mapper.chr_read(0x0800), 10);
+        mapper.prg_write(0xb003, 0b0000_1000);
+        assert_eq!(mapper.nametable(2), Nametable::Ciram(0));
+    }
+
+    #[cfg(feature = "expansion-audio")]
+    #[test]
+    fn test_pulse_duty() {
+        let mut pulse = Vrc6Pulse::new();
+        // デューティ3 (4/16), 音量10, 周期0
+        pulse.write(0, 0b0011_1010);
+        pulse.write(2, 0x80);
+        let wave: Vec<u8> = (0..16)
+            .map(|_| {
+                pulse.clock();
+                pulse.output()
+            })
+            .collect();
+        assert_eq!(wave.iter().filter(|v| **v == 10).count(), 4);
+        assert_eq!(&wave[11..15], &[10, 10, 10, 10]);
+
+        pulse.write(0, 0b1000_0101);
+        assert_eq!(pulse.output(), 5);
+        pulse.write(2, 0);
+        assert_eq!(pulse.output(), 0);
+    }
+
+    #[cfg(feature = "expansion-audio")]
+    #[test]
+    fn test_sawtooth() {
+        let mut saw = Vrc6Sawtooth::new();
+        saw.write(0, 8);
+        saw.write(2, 0x80);
+        let wave: Vec<u8> = (0..14)
+            .map(|_| {
+                saw.clock();
+                saw.output()
+            })
+            .collect();
+        assert_eq!(wave, vec![0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 0]);
+    }
+
+    #[cfg(feature = "expansion-audio")]
+    #[test]
+    fn test_audio_output() {
+        let mut mapper = vrc6(24);
+        mapper.prg_write(0x9000, 0x8f);
+        mapper.prg_write(0x9002, 0x80);
+        assert_eq!(mapper.audio_output(), 15.0 / 61.0);
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut mapper = vrc6(24);
+        // ラッチ$FE, サイクルモード
+        mapper.prg_write(0xf000, 0xfe);
+        mapper.prg_write(0xf001, 0b110);
+        mapper.cpu_tick(2);
+        assert!(mapper.irq());
+        mapper.prg_write(0xf002, 0);
+        assert!(!mapper.irq());
+    }
+}
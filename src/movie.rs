@@ -0,0 +1,225 @@
+use crate::cpu::CPU;
+use crate::desync::{state_hash, Desync, DesyncDetector};
+use crate::joypad::JoypadButton;
+
+// この間隔のフレームの開始時に状態のハッシュを記録する
+pub const HASH_INTERVAL: usize = 10;
+
+// 記録を始めた時点のマシンの状態
+#[derive(Clone)]
+pub enum MovieStart {
+    PowerOn,
+    // CPU::stateのセーブステートから始める。テキストには書き出せない
+    Snapshot { state: Vec<u8> },
+}
+
+// フレームごとのコントローラーの入力の列。同じ状態から同じ入力を与えれば
+// 同じ結果になるので、TASや再現手順の共有に使う
+#[derive(Clone)]
+pub struct Movie {
+    pub start: MovieStart,
+    // 記録したROMのSHA-1。再生時に違うROMなら断る
+    pub rom_sha1: Option<String>,
+    // 1P, 2Pの順
+    pub frames: Vec<[JoypadButton; 2]>,
+    // (フレーム, そのフレームの開始時のstate_hash)。再生時にずれを見つけるのに使う
+    pub hashes: Vec<(u64, u64)>,
+}
+
+impl Movie {
+    pub fn new(start: MovieStart, rom_sha1: Option<String>) -> Self {
+        Movie {
+            start,
+            rom_sha1,
+            frames: vec![],
+            hashes: vec![],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // start power-on
+    // rom <sha1>
+    // hash 0123456789abcdef    <- 次の行のフレームの開始時のstate_hash
+    // 08 00    <- 1フレーム1行で、1Pと2Pのボタンを16進で
+    pub fn to_text(&self) -> Result<String, String> {
+        if let MovieStart::Snapshot { .. } = self.start {
+            return Err("a movie starting from a snapshot can't be written as text".to_string());
+        }
+        let mut text = String::from("start power-on\n");
+        if let Some(sha1) = &self.rom_sha1 {
+            text.push_str(&format!("rom {}\n", sha1));
+        }
+        let mut hashes = self.hashes.iter().peekable();
+        for (frame, [p1, p2]) in self.frames.iter().enumerate() {
+            while let Some((_, hash)) = hashes.next_if(|(f, _)| *f <= frame as u64) {
+                text.push_str(&format!("hash {:016x}\n", hash));
+            }
+            text.push_str(&format!("{:02x} {:02x}\n", p1.bits(), p2.bits()));
+        }
+        Ok(text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut movie = Movie::new(MovieStart::PowerOn, None);
+        for line in text.lines().map(|line| line.trim()) {
+            if let Some(start) = line.strip_prefix("start ") {
+                if start != "power-on" {
+                    return Err(format!("unsupported movie start: {}", start));
+                }
+            } else if let Some(sha1) = line.strip_prefix("rom ") {
+                movie.rom_sha1 = Some(sha1.to_string());
+            } else if let Some(hash) = line.strip_prefix("hash ") {
+                let hash = u64::from_str_radix(hash.trim(), 16)
+                    .map_err(|e| format!("invalid movie line {:?}: {}", line, e))?;
+                movie.hashes.push((movie.frames.len() as u64, hash));
+            } else if !line.is_empty() {
+                let mut buttons = [JoypadButton::empty(); 2];
+                let mut fields = line.split_whitespace();
+                for port in buttons.iter_mut() {
+                    if let Some(field) = fields.next() {
+                        *port = u8::from_str_radix(field, 16)
+                            .map(JoypadButton::from_bits_truncate)
+                            .map_err(|e| format!("invalid movie line {:?}: {}", line, e))?;
+                    }
+                }
+                movie.frames.push(buttons);
+            }
+        }
+        Ok(movie)
+    }
+}
+
+pub struct MovieRecorder {
+    movie: Movie,
+}
+
+impl MovieRecorder {
+    pub fn new(start: MovieStart, rom_sha1: Option<String>) -> Self {
+        MovieRecorder {
+            movie: Movie::new(start, rom_sha1),
+        }
+    }
+
+    // 次のフレームの間ゲームに見えている入力。cpuはそのフレームを始める前の状態
+    pub fn record(&mut self, buttons: [JoypadButton; 2], cpu: &mut CPU) {
+        let frame = self.movie.frames.len();
+        if frame.is_multiple_of(HASH_INTERVAL) {
+            self.movie.hashes.push((frame as u64, state_hash(cpu)));
+        }
+        self.movie.frames.push(buttons);
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: usize,
+    detector: DesyncDetector,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> Self {
+        let detector = DesyncDetector::new(movie.hashes.iter().copied());
+        MoviePlayer {
+            movie,
+            frame: 0,
+            detector,
+        }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    // 再生したフレーム数
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+
+    // 次のフレームの入力。最後まで再生したらNone
+    pub fn next_frame(&mut self) -> Option<[JoypadButton; 2]> {
+        let buttons = self.movie.frames.get(self.frame).copied()?;
+        self.frame += 1;
+        Some(buttons)
+    }
+
+    // next_frameの入力を与えた後、そのフレームを始める前に呼ぶ。
+    // 記録されたハッシュと違えば、最初にずれたフレームを返し続ける
+    pub fn check(&mut self, cpu: &mut CPU) -> Option<&Desync> {
+        let frame = self.frame.checked_sub(1)? as u64;
+        self.detector.check(frame, cpu)
+    }
+
+    pub fn desync(&self) -> Option<&Desync> {
+        self.detector.first_desync()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::joypad::Joypad;
+    use crate::ppu::NesPPU;
+
+    #[test]
+    fn test_text_round_trip() {
+        let bus = Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        let hash = state_hash(&mut cpu);
+        let mut recorder = MovieRecorder::new(MovieStart::PowerOn, Some("abcd".to_string()));
+        recorder.record([JoypadButton::START, JoypadButton::empty()], &mut cpu);
+        recorder.record([JoypadButton::RIGHT, JoypadButton::BUTTON_A], &mut cpu);
+        let text = recorder.finish().to_text().unwrap();
+        assert_eq!(
+            text,
+            format!(
+                "start power-on\nrom abcd\nhash {:016x}\n08 00\n80 01\n",
+                hash
+            )
+        );
+
+        let movie = Movie::parse(&text).unwrap();
+        assert_eq!(movie.rom_sha1.as_deref(), Some("abcd"));
+        assert_eq!(movie.hashes, vec![(0, hash)]);
+        let mut player = MoviePlayer::new(movie);
+        assert_eq!(
+            player.next_frame(),
+            Some([JoypadButton::START, JoypadButton::empty()])
+        );
+        assert_eq!(
+            player.next_frame(),
+            Some([JoypadButton::RIGHT, JoypadButton::BUTTON_A])
+        );
+        assert!(player.is_finished());
+        assert_eq!(player.next_frame(), None);
+
+        // 2Pは省略できる
+        assert_eq!(
+            Movie::parse("01\n").unwrap().frames[0][1],
+            JoypadButton::empty()
+        );
+        assert!(Movie::parse("zz\n").is_err());
+        assert!(Movie::parse("hash zz\n").is_err());
+        assert!(Movie::parse("start savestate\n").is_err());
+    }
+}
@@ -0,0 +1,276 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::bus::Bus;
+use crate::cartridge::{Nsf, Timing};
+use crate::console::Region;
+use crate::cpu::{Mem, CPU};
+use crate::joypad::Joypad;
+use crate::mapper_nsf::NsfMapper;
+use crate::ppu::NesPPU;
+
+pub const NTSC_CPU_CLOCK: f64 = 1_789_773.0;
+pub const PAL_CPU_CLOCK: f64 = 1_662_607.0;
+
+// init/playから戻ってきたことを検出するための、何も置かれていないアドレス
+const RETURN_ADDR: u16 = 0x4100;
+// これ以上戻ってこなければ暴走とみなす (約1秒)
+const MAX_ROUTINE_CYCLES: usize = 1_789_773;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repeat {
+    // 曲の長さに達したら止まる
+    Off,
+    // 同じ曲を最初から
+    Track,
+    // 次の曲へ (最後の曲の次は最初の曲)
+    All,
+}
+
+// initを1回呼んだ後、ヘッダで指定された間隔でplayを呼び続ける
+pub struct NsfPlayer {
+    nsf: Nsf,
+    cpu: CPU<'static>,
+    song: u8,
+    pub repeat: Repeat,
+    // 1曲あたりのフレーム数。Noneならずっと再生する
+    pub track_frames: Option<u64>,
+    frame: u64,
+    next_play_cycle: f64,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: Nsf) -> Result<Self, String> {
+        let region = match nsf.timing {
+            Timing::Pal => Region::Pal,
+            _ => Region::Ntsc,
+        };
+        let mapper = Rc::new(RefCell::new(NsfMapper::new(&nsf)));
+        let bus = Bus::with_mapper(
+            mapper,
+            region,
+            |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {},
+        );
+        let song = nsf.starting_song;
+        let mut player = NsfPlayer {
+            nsf,
+            cpu: CPU::new(bus),
+            song,
+            repeat: Repeat::Off,
+            track_frames: None,
+            frame: 0,
+            next_play_cycle: 0.0,
+        };
+        player.select_track(song)?;
+        Ok(player)
+    }
+
+    pub fn nsf(&self) -> &Nsf {
+        &self.nsf
+    }
+
+    pub fn cpu(&self) -> &CPU<'static> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU<'static> {
+        &mut self.cpu
+    }
+
+    // 1始まりの曲番号
+    pub fn song(&self) -> u8 {
+        self.song
+    }
+
+    // 今の曲でplayを呼んだ回数
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // playを呼ぶ間隔 (CPUサイクル)
+    pub fn play_period(&self) -> f64 {
+        match self.cpu.bus.region() {
+            Region::Pal => speed_or(self.nsf.pal_speed, 19997) * PAL_CPU_CLOCK / 1e6,
+            _ => speed_or(self.nsf.ntsc_speed, 16639) * NTSC_CPU_CLOCK / 1e6,
+        }
+    }
+
+    pub fn select_track(&mut self, song: u8) -> Result<(), String> {
+        if song == 0 || song > self.nsf.total_songs {
+            return Err(format!(
+                "song {} is out of range (1-{})",
+                song, self.nsf.total_songs
+            ));
+        }
+        self.song = song;
+
+        let cpu = &mut self.cpu;
+        for addr in (0x0000..0x0800).chain(0x6000..0x8000) {
+            cpu.mem_write(addr, 0);
+        }
+        for addr in 0x4000..0x4014 {
+            cpu.mem_write(addr, 0);
+        }
+        cpu.mem_write(0x4015, 0x00);
+        cpu.mem_write(0x4015, 0x0f);
+        cpu.mem_write(0x4017, 0x40);
+        if self.nsf.is_bank_switched() {
+            for (i, bank) in self.nsf.bankswitch_init.iter().enumerate() {
+                cpu.mem_write(0x5ff8 + i as u16, *bank);
+            }
+        }
+
+        cpu.register_a = song - 1;
+        cpu.register_x = (cpu.bus.region() == Region::Pal) as u8;
+        cpu.register_y = 0;
+        cpu.stack_pointer = 0xfd;
+        cpu.status = 0b0010_0100;
+        self.call(self.nsf.init_addr)?;
+
+        self.frame = 0;
+        self.next_play_cycle = self.cpu.bus.cycles() as f64 + self.play_period();
+        Ok(())
+    }
+
+    pub fn next_track(&mut self) -> Result<(), String> {
+        let song = self.song % self.nsf.total_songs + 1;
+        self.select_track(song)
+    }
+
+    pub fn previous_track(&mut self) -> Result<(), String> {
+        let song = match self.song {
+            1 => self.nsf.total_songs,
+            song => song - 1,
+        };
+        self.select_track(song)
+    }
+
+    // playを1回呼び、次に呼ぶ時刻まで進める。再生が終わったらfalse
+    pub fn play_frame(&mut self) -> Result<bool, String> {
+        if self.track_frames.is_some_and(|frames| self.frame >= frames) {
+            match self.repeat {
+                Repeat::Off => return Ok(false),
+                Repeat::Track => self.select_track(self.song)?,
+                Repeat::All => self.next_track()?,
+            }
+        }
+
+        self.call(self.nsf.play_addr)?;
+        while (self.cpu.bus.cycles() as f64) < self.next_play_cycle {
+            self.cpu.bus.tick(1);
+        }
+        self.next_play_cycle += self.play_period();
+        self.frame += 1;
+        Ok(true)
+    }
+
+    // JSRと同じようにRETURN_ADDR-1を積んでおき、RTSで戻ってくるまで実行する
+    fn call(&mut self, addr: u16) -> Result<(), String> {
+        let cpu = &mut self.cpu;
+        let [lo, hi] = (RETURN_ADDR - 1).to_le_bytes();
+        cpu.mem_write(0x0100 + cpu.stack_pointer as u16, hi);
+        cpu.stack_pointer = cpu.stack_pointer.wrapping_sub(1);
+        cpu.mem_write(0x0100 + cpu.stack_pointer as u16, lo);
+        cpu.stack_pointer = cpu.stack_pointer.wrapping_sub(1);
+        cpu.program_counter = addr;
+
+        let start = cpu.bus.cycles();
+        while cpu.program_counter != RETURN_ADDR {
+            if cpu.bus.cycles() - start > MAX_ROUTINE_CYCLES {
+                return Err(format!("routine at {:04X} did not return", addr));
+            }
+            if !cpu.step() {
+                return Err(format!(
+                    "routine at {:04X} hit BRK at {:04X}",
+                    addr, cpu.program_counter
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn speed_or(speed: u16, default: u16) -> f64 {
+    if speed == 0 {
+        default as f64
+    } else {
+        speed as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::nsf;
+
+    // init ($8000): STA $00; RTS
+    // play ($8010): INC $01; RTS
+    fn player(songs: u8) -> NsfPlayer {
+        let mut program = vec![0; 0x13];
+        program[0..3].copy_from_slice(&[0x85, 0x00, 0x60]);
+        program[0x10..0x13].copy_from_slice(&[0xe6, 0x01, 0x60]);
+        NsfPlayer::new(Nsf::new(&nsf(songs, &program)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_init_and_play() {
+        let mut player = player(3);
+        assert_eq!(player.song(), 1);
+        assert_eq!(player.cpu_mut().mem_read(0x00), 0);
+
+        let start = player.cpu().bus.cycles();
+        for _ in 0..60 {
+            assert!(player.play_frame().unwrap());
+        }
+        assert_eq!(player.cpu_mut().mem_read(0x01), 60);
+        assert_eq!(player.frame(), 60);
+        // 16639us間隔なので60回でおよそ1秒
+        let elapsed = player.cpu().bus.cycles() - start;
+        assert!((elapsed as f64 - 60.0 * player.play_period()).abs() < 10.0);
+    }
+
+    #[test]
+    fn test_track_selection() {
+        let mut player = player(3);
+        player.play_frame().unwrap();
+        player.select_track(3).unwrap();
+        assert_eq!(player.cpu_mut().mem_read(0x00), 2);
+        assert_eq!(player.cpu_mut().mem_read(0x01), 0);
+
+        player.next_track().unwrap();
+        assert_eq!(player.song(), 1);
+        player.previous_track().unwrap();
+        assert_eq!(player.song(), 3);
+        assert!(player.select_track(0).is_err());
+        assert!(player.select_track(4).is_err());
+    }
+
+    #[test]
+    fn test_repeat() {
+        let mut player = player(2);
+        player.track_frames = Some(2);
+        assert!(player.play_frame().unwrap());
+        assert!(player.play_frame().unwrap());
+        assert!(!player.play_frame().unwrap());
+
+        player.repeat = Repeat::Track;
+        assert!(player.play_frame().unwrap());
+        assert_eq!(player.song(), 1);
+        assert_eq!(player.frame(), 1);
+
+        player.repeat = Repeat::All;
+        player.play_frame().unwrap();
+        player.play_frame().unwrap();
+        assert_eq!(player.song(), 2);
+        player.play_frame().unwrap();
+        player.play_frame().unwrap();
+        assert_eq!(player.song(), 1);
+    }
+
+    #[test]
+    fn test_runaway_routine() {
+        // init: JMP $8000
+        let raw = nsf(1, &[0x4c, 0x00, 0x80]);
+        assert!(NsfPlayer::new(Nsf::new(&raw).unwrap()).is_err());
+    }
+}
@@ -1,288 +1,333 @@
 use std::collections::HashMap;
+use std::fmt;
 
 use once_cell::sync::Lazy;
 
 use crate::cpu::AddressingMode;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpCategory {
+    Official,
+    StableUnofficial,
+    // 実機やロットによって結果が変わる命令
+    UnstableUnofficial,
+    // KIL。CPUが停止する
+    Jam,
+}
+
+const JAM_CODES: [u8; 12] = [
+    0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xb2, 0xd2, 0xf2,
+];
+
+const UNSTABLE_MNEMONICS: [&str; 6] = ["*AHX", "*LXA", "*SHX", "*SHY", "*TAS", "*XAA"];
+
+fn categorize(code: u8, mnemonic: &str) -> OpCategory {
+    if JAM_CODES.contains(&code) {
+        OpCategory::Jam
+    } else if UNSTABLE_MNEMONICS.contains(&mnemonic) {
+        OpCategory::UnstableUnofficial
+    } else if mnemonic.starts_with('*') {
+        OpCategory::StableUnofficial
+    } else {
+        OpCategory::Official
+    }
+}
+
 pub struct OpCode {
     pub code: u8,
     pub mnemonic: &'static str,
     pub len: u8,
     pub cycles: u8,
     pub mode: AddressingMode,
+    // インデックスでページをまたいだときに+1サイクルかかるか
+    pub page_cross_penalty: bool,
+    pub category: OpCategory,
 }
 
 impl OpCode {
-    fn new(code: u8, mnemonic: &'static str, len: u8, cycles: u8, mode: AddressingMode) -> Self {
+    fn new(
+        code: u8,
+        mnemonic: &'static str,
+        len: u8,
+        cycles: u8,
+        mode: AddressingMode,
+        page_cross_penalty: bool,
+    ) -> Self {
         OpCode {
             code: code,
             mnemonic: mnemonic,
             len: len,
             cycles: cycles,
             mode: mode,
+            page_cross_penalty,
+            category: categorize(code, mnemonic),
         }
     }
+
+    pub fn is_official(&self) -> bool {
+        self.category == OpCategory::Official
+    }
 }
 
 pub static CPU_OPS_CODES: Lazy<Vec<OpCode>> = Lazy::new(|| {
     vec![
-        OpCode::new(0x69, "ADC", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0x65, "ADC", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x75, "ADC", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x6d, "ADC", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x7d, "ADC", 3, 4, AddressingMode::Absolute_X),
-        OpCode::new(0x79, "ADC", 3, 4, AddressingMode::Absolute_Y),
-        OpCode::new(0x61, "ADC", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0x71, "ADC", 2, 5, AddressingMode::Indirect_Y),
-        OpCode::new(0x29, "AND", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0x25, "AND", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x35, "AND", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x2d, "AND", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x3d, "AND", 3, 4, AddressingMode::Absolute_X),
-        OpCode::new(0x39, "AND", 3, 4, AddressingMode::Absolute_Y),
-        OpCode::new(0x21, "AND", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0x31, "AND", 2, 5, AddressingMode::Indirect_Y),
-        OpCode::new(0x0a, "ASL", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x06, "ASL", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0x16, "ASL", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0x0e, "ASL", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0x1e, "ASL", 3, 7, AddressingMode::Absolute_X),
-        OpCode::new(0x90, "BCC", 2, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0xb0, "BCS", 2, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0xf0, "BEQ", 2, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x30, "BMI", 2, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0xd0, "BNE", 2, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x10, "BPL", 2, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x50, "BVC", 2, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x70, "BVS", 2, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x24, "BIT", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x2c, "BIT", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x00, "BRK", 1, 7, AddressingMode::NoneAddressing),
-        OpCode::new(0xc9, "CMP", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xc5, "CMP", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0xd5, "CMP", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0xcd, "CMP", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xdd, "CMP", 3, 4, AddressingMode::Absolute_X),
-        OpCode::new(0xd9, "CMP", 3, 4, AddressingMode::Absolute_Y),
-        OpCode::new(0xc1, "CMP", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0xd1, "CMP", 2, 5, AddressingMode::Indirect_Y),
-        OpCode::new(0xe0, "CPX", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xe4, "CPX", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0xec, "CPX", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xc0, "CPY", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xc4, "CPY", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0xcc, "CPY", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x18, "CLC", 1, 2, AddressingMode::ZeroPage),
-        OpCode::new(0xd8, "CLD", 1, 2, AddressingMode::ZeroPage),
-        OpCode::new(0x58, "CLI", 1, 2, AddressingMode::ZeroPage),
-        OpCode::new(0xb8, "CLV", 1, 2, AddressingMode::ZeroPage),
-        OpCode::new(0xc6, "DEC", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0xd6, "DEC", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0xce, "DEC", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0xde, "DEC", 3, 7, AddressingMode::Absolute_X),
-        OpCode::new(0xca, "DEX", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x88, "DEY", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x49, "EOR", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0x45, "EOR", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x55, "EOR", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x4d, "EOR", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x5d, "EOR", 3, 4, AddressingMode::Absolute_X),
-        OpCode::new(0x59, "EOR", 3, 4, AddressingMode::Absolute_Y),
-        OpCode::new(0x41, "EOR", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0x51, "EOR", 2, 5, AddressingMode::Indirect_Y),
-        OpCode::new(0xe6, "INC", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0xf6, "INC", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0xee, "INC", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0xfe, "INC", 3, 7, AddressingMode::Absolute_X),
-        OpCode::new(0xe8, "INX", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0xc8, "INY", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x4c, "JMP", 3, 3, AddressingMode::NoneAddressing), // Absolute but act as Immidiate
-        OpCode::new(0x6c, "JMP", 3, 5, AddressingMode::NoneAddressing),
-        OpCode::new(0x20, "JSR", 3, 6, AddressingMode::NoneAddressing),
-        OpCode::new(0xa9, "LDA", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xa5, "LDA", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0xb5, "LDA", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0xad, "LDA", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xbd, "LDA", 3, 4, AddressingMode::Absolute_X),
-        OpCode::new(0xb9, "LDA", 3, 4, AddressingMode::Absolute_Y),
-        OpCode::new(0xa1, "LDA", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0xb1, "LDA", 2, 5, AddressingMode::Indirect_Y),
-        OpCode::new(0xa2, "LDX", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xa6, "LDX", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0xb6, "LDX", 2, 4, AddressingMode::ZeroPage_Y),
-        OpCode::new(0xae, "LDX", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xbe, "LDX", 3, 4, AddressingMode::Absolute_Y),
-        OpCode::new(0xa0, "LDY", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xa4, "LDY", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0xb4, "LDY", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0xac, "LDY", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xbc, "LDY", 3, 4, AddressingMode::Absolute_X),
-        OpCode::new(0x4a, "LSR", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x46, "LSR", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0x56, "LSR", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0x4e, "LSR", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0x5e, "LSR", 3, 7, AddressingMode::Absolute_X),
-        OpCode::new(0xea, "NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x09, "ORA", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0x05, "ORA", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x15, "ORA", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x0d, "ORA", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x1d, "ORA", 3, 4, AddressingMode::Absolute_X),
-        OpCode::new(0x19, "ORA", 3, 4, AddressingMode::Absolute_Y),
-        OpCode::new(0x01, "ORA", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0x11, "ORA", 2, 5, AddressingMode::Indirect_Y),
-        OpCode::new(0x48, "PHA", 1, 3, AddressingMode::NoneAddressing),
-        OpCode::new(0x08, "PHP", 1, 3, AddressingMode::NoneAddressing),
-        OpCode::new(0x68, "PLA", 1, 4, AddressingMode::NoneAddressing),
-        OpCode::new(0x28, "PLP", 1, 4, AddressingMode::NoneAddressing),
-        OpCode::new(0x2a, "ROL", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x26, "ROL", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0x36, "ROL", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0x2e, "ROL", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0x3e, "ROL", 3, 7, AddressingMode::Absolute_X),
-        OpCode::new(0x6a, "ROR", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x66, "ROR", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0x76, "ROR", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0x6e, "ROR", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0x7e, "ROR", 3, 7, AddressingMode::Absolute_X),
-        OpCode::new(0x40, "RTI", 1, 6, AddressingMode::NoneAddressing),
-        OpCode::new(0x60, "RTS", 1, 6, AddressingMode::NoneAddressing),
-        OpCode::new(0xe9, "SBC", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xe5, "SBC", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0xf5, "SBC", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0xed, "SBC", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xfd, "SBC", 3, 4, AddressingMode::Absolute_X),
-        OpCode::new(0xf9, "SBC", 3, 4, AddressingMode::Absolute_Y),
-        OpCode::new(0xe1, "SBC", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0xf1, "SBC", 2, 5, AddressingMode::Indirect_Y),
-        OpCode::new(0x38, "SEC", 1, 2, AddressingMode::ZeroPage),
-        OpCode::new(0xf8, "SED", 1, 2, AddressingMode::ZeroPage),
-        OpCode::new(0x78, "SEI", 1, 2, AddressingMode::ZeroPage),
-        OpCode::new(0x85, "STA", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x95, "STA", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x8d, "STA", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x9d, "STA", 3, 5, AddressingMode::Absolute_X),
-        OpCode::new(0x99, "STA", 3, 5, AddressingMode::Absolute_Y),
-        OpCode::new(0x81, "STA", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0x91, "STA", 2, 6, AddressingMode::Indirect_Y),
-        OpCode::new(0x86, "STX", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x96, "STX", 2, 4, AddressingMode::ZeroPage_Y),
-        OpCode::new(0x8e, "STX", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x84, "STY", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x94, "STY", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x8c, "STY", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xaa, "TAX", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0xa8, "TAY", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0xba, "TSX", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x8a, "TXA", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x9a, "TXS", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x98, "TYA", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x69, "ADC", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0x65, "ADC", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0x75, "ADC", 2, 4, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x6d, "ADC", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0x7d, "ADC", 3, 4, AddressingMode::Absolute_X, true),
+        OpCode::new(0x79, "ADC", 3, 4, AddressingMode::Absolute_Y, true),
+        OpCode::new(0x61, "ADC", 2, 6, AddressingMode::Indirect_X, false),
+        OpCode::new(0x71, "ADC", 2, 5, AddressingMode::Indirect_Y, true),
+        OpCode::new(0x29, "AND", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0x25, "AND", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0x35, "AND", 2, 4, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x2d, "AND", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0x3d, "AND", 3, 4, AddressingMode::Absolute_X, true),
+        OpCode::new(0x39, "AND", 3, 4, AddressingMode::Absolute_Y, true),
+        OpCode::new(0x21, "AND", 2, 6, AddressingMode::Indirect_X, false),
+        OpCode::new(0x31, "AND", 2, 5, AddressingMode::Indirect_Y, true),
+        OpCode::new(0x0a, "ASL", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x06, "ASL", 2, 5, AddressingMode::ZeroPage, false),
+        OpCode::new(0x16, "ASL", 2, 6, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x0e, "ASL", 3, 6, AddressingMode::Absolute, false),
+        OpCode::new(0x1e, "ASL", 3, 7, AddressingMode::Absolute_X, false),
+        OpCode::new(0x90, "BCC", 2, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0xb0, "BCS", 2, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0xf0, "BEQ", 2, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x30, "BMI", 2, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0xd0, "BNE", 2, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x10, "BPL", 2, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x50, "BVC", 2, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x70, "BVS", 2, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x24, "BIT", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0x2c, "BIT", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0x00, "BRK", 1, 7, AddressingMode::NoneAddressing, false),
+        OpCode::new(0xc9, "CMP", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0xc5, "CMP", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0xd5, "CMP", 2, 4, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0xcd, "CMP", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0xdd, "CMP", 3, 4, AddressingMode::Absolute_X, true),
+        OpCode::new(0xd9, "CMP", 3, 4, AddressingMode::Absolute_Y, true),
+        OpCode::new(0xc1, "CMP", 2, 6, AddressingMode::Indirect_X, false),
+        OpCode::new(0xd1, "CMP", 2, 5, AddressingMode::Indirect_Y, true),
+        OpCode::new(0xe0, "CPX", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0xe4, "CPX", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0xec, "CPX", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0xc0, "CPY", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0xc4, "CPY", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0xcc, "CPY", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0x18, "CLC", 1, 2, AddressingMode::ZeroPage, false),
+        OpCode::new(0xd8, "CLD", 1, 2, AddressingMode::ZeroPage, false),
+        OpCode::new(0x58, "CLI", 1, 2, AddressingMode::ZeroPage, false),
+        OpCode::new(0xb8, "CLV", 1, 2, AddressingMode::ZeroPage, false),
+        OpCode::new(0xc6, "DEC", 2, 5, AddressingMode::ZeroPage, false),
+        OpCode::new(0xd6, "DEC", 2, 6, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0xce, "DEC", 3, 6, AddressingMode::Absolute, false),
+        OpCode::new(0xde, "DEC", 3, 7, AddressingMode::Absolute_X, false),
+        OpCode::new(0xca, "DEX", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x88, "DEY", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x49, "EOR", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0x45, "EOR", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0x55, "EOR", 2, 4, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x4d, "EOR", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0x5d, "EOR", 3, 4, AddressingMode::Absolute_X, true),
+        OpCode::new(0x59, "EOR", 3, 4, AddressingMode::Absolute_Y, true),
+        OpCode::new(0x41, "EOR", 2, 6, AddressingMode::Indirect_X, false),
+        OpCode::new(0x51, "EOR", 2, 5, AddressingMode::Indirect_Y, true),
+        OpCode::new(0xe6, "INC", 2, 5, AddressingMode::ZeroPage, false),
+        OpCode::new(0xf6, "INC", 2, 6, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0xee, "INC", 3, 6, AddressingMode::Absolute, false),
+        OpCode::new(0xfe, "INC", 3, 7, AddressingMode::Absolute_X, false),
+        OpCode::new(0xe8, "INX", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0xc8, "INY", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x4c, "JMP", 3, 3, AddressingMode::NoneAddressing, false), // Absolute but act as Immidiate
+        OpCode::new(0x6c, "JMP", 3, 5, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x20, "JSR", 3, 6, AddressingMode::NoneAddressing, false),
+        OpCode::new(0xa9, "LDA", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0xa5, "LDA", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0xb5, "LDA", 2, 4, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0xad, "LDA", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0xbd, "LDA", 3, 4, AddressingMode::Absolute_X, true),
+        OpCode::new(0xb9, "LDA", 3, 4, AddressingMode::Absolute_Y, true),
+        OpCode::new(0xa1, "LDA", 2, 6, AddressingMode::Indirect_X, false),
+        OpCode::new(0xb1, "LDA", 2, 5, AddressingMode::Indirect_Y, true),
+        OpCode::new(0xa2, "LDX", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0xa6, "LDX", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0xb6, "LDX", 2, 4, AddressingMode::ZeroPage_Y, false),
+        OpCode::new(0xae, "LDX", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0xbe, "LDX", 3, 4, AddressingMode::Absolute_Y, true),
+        OpCode::new(0xa0, "LDY", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0xa4, "LDY", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0xb4, "LDY", 2, 4, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0xac, "LDY", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0xbc, "LDY", 3, 4, AddressingMode::Absolute_X, true),
+        OpCode::new(0x4a, "LSR", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x46, "LSR", 2, 5, AddressingMode::ZeroPage, false),
+        OpCode::new(0x56, "LSR", 2, 6, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x4e, "LSR", 3, 6, AddressingMode::Absolute, false),
+        OpCode::new(0x5e, "LSR", 3, 7, AddressingMode::Absolute_X, false),
+        OpCode::new(0xea, "NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x09, "ORA", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0x05, "ORA", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0x15, "ORA", 2, 4, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x0d, "ORA", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0x1d, "ORA", 3, 4, AddressingMode::Absolute_X, true),
+        OpCode::new(0x19, "ORA", 3, 4, AddressingMode::Absolute_Y, true),
+        OpCode::new(0x01, "ORA", 2, 6, AddressingMode::Indirect_X, false),
+        OpCode::new(0x11, "ORA", 2, 5, AddressingMode::Indirect_Y, true),
+        OpCode::new(0x48, "PHA", 1, 3, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x08, "PHP", 1, 3, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x68, "PLA", 1, 4, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x28, "PLP", 1, 4, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x2a, "ROL", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x26, "ROL", 2, 5, AddressingMode::ZeroPage, false),
+        OpCode::new(0x36, "ROL", 2, 6, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x2e, "ROL", 3, 6, AddressingMode::Absolute, false),
+        OpCode::new(0x3e, "ROL", 3, 7, AddressingMode::Absolute_X, false),
+        OpCode::new(0x6a, "ROR", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x66, "ROR", 2, 5, AddressingMode::ZeroPage, false),
+        OpCode::new(0x76, "ROR", 2, 6, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x6e, "ROR", 3, 6, AddressingMode::Absolute, false),
+        OpCode::new(0x7e, "ROR", 3, 7, AddressingMode::Absolute_X, false),
+        OpCode::new(0x40, "RTI", 1, 6, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x60, "RTS", 1, 6, AddressingMode::NoneAddressing, false),
+        OpCode::new(0xe9, "SBC", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0xe5, "SBC", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0xf5, "SBC", 2, 4, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0xed, "SBC", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0xfd, "SBC", 3, 4, AddressingMode::Absolute_X, true),
+        OpCode::new(0xf9, "SBC", 3, 4, AddressingMode::Absolute_Y, true),
+        OpCode::new(0xe1, "SBC", 2, 6, AddressingMode::Indirect_X, false),
+        OpCode::new(0xf1, "SBC", 2, 5, AddressingMode::Indirect_Y, true),
+        OpCode::new(0x38, "SEC", 1, 2, AddressingMode::ZeroPage, false),
+        OpCode::new(0xf8, "SED", 1, 2, AddressingMode::ZeroPage, false),
+        OpCode::new(0x78, "SEI", 1, 2, AddressingMode::ZeroPage, false),
+        OpCode::new(0x85, "STA", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0x95, "STA", 2, 4, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x8d, "STA", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0x9d, "STA", 3, 5, AddressingMode::Absolute_X, false),
+        OpCode::new(0x99, "STA", 3, 5, AddressingMode::Absolute_Y, false),
+        OpCode::new(0x81, "STA", 2, 6, AddressingMode::Indirect_X, false),
+        OpCode::new(0x91, "STA", 2, 6, AddressingMode::Indirect_Y, false),
+        OpCode::new(0x86, "STX", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0x96, "STX", 2, 4, AddressingMode::ZeroPage_Y, false),
+        OpCode::new(0x8e, "STX", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0x84, "STY", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0x94, "STY", 2, 4, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x8c, "STY", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0xaa, "TAX", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0xa8, "TAY", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0xba, "TSX", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x8a, "TXA", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x9a, "TXS", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x98, "TYA", 1, 2, AddressingMode::NoneAddressing, false),
         // Unofficial opcodes
-        OpCode::new(0x04, "*NOP", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x44, "*NOP", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x64, "*NOP", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x14, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x34, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x54, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x74, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0xd4, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0xf4, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x0c, "*NOP", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x1c, "*NOP", 3, 4, AddressingMode::Absolute_X),
-        OpCode::new(0x3c, "*NOP", 3, 4, AddressingMode::Absolute_X),
-        OpCode::new(0x5c, "*NOP", 3, 4, AddressingMode::Absolute_X),
-        OpCode::new(0x7c, "*NOP", 3, 4, AddressingMode::Absolute_X),
-        OpCode::new(0xdc, "*NOP", 3, 4, AddressingMode::Absolute_X),
-        OpCode::new(0xfc, "*NOP", 3, 4, AddressingMode::Absolute_X),
-        OpCode::new(0x02, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x12, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x22, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x32, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x42, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x52, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x62, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x72, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x92, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0xb2, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0xd2, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0xf2, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x1a, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x3a, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x5a, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x7a, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0xda, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0xfa, "*NOP", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x80, "*NOP", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0x82, "*NOP", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0x89, "*NOP", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xc2, "*NOP", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xe2, "*NOP", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xa7, "*LAX", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0xb7, "*LAX", 2, 4, AddressingMode::ZeroPage_Y),
-        OpCode::new(0xaf, "*LAX", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xbf, "*LAX", 3, 4, AddressingMode::Absolute_Y),
-        OpCode::new(0xa3, "*LAX", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0xb3, "*LAX", 2, 5, AddressingMode::Indirect_Y),
-        OpCode::new(0x87, "*SAX", 2, 3, AddressingMode::ZeroPage),
-        OpCode::new(0x97, "*SAX", 2, 4, AddressingMode::ZeroPage_Y),
-        OpCode::new(0x8f, "*SAX", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x83, "*SAX", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0xeb, "*SBC", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xc7, "*DCP", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0xd7, "*DCP", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0xCF, "*DCP", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0xdF, "*DCP", 3, 7, AddressingMode::Absolute_X),
-        OpCode::new(0xdb, "*DCP", 3, 7, AddressingMode::Absolute_Y),
-        OpCode::new(0xd3, "*DCP", 2, 8, AddressingMode::Indirect_Y),
-        OpCode::new(0xc3, "*DCP", 2, 8, AddressingMode::Indirect_X),
-        OpCode::new(0xe7, "*ISB", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0xf7, "*ISB", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0xef, "*ISB", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0xff, "*ISB", 3, 7, AddressingMode::Absolute_X),
-        OpCode::new(0xfb, "*ISB", 3, 7, AddressingMode::Absolute_Y),
-        OpCode::new(0xe3, "*ISB", 2, 8, AddressingMode::Indirect_X),
-        OpCode::new(0xf3, "*ISB", 2, 8, AddressingMode::Indirect_Y),
-        OpCode::new(0x07, "*SLO", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0x17, "*SLO", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0x0F, "*SLO", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0x1f, "*SLO", 3, 7, AddressingMode::Absolute_X),
-        OpCode::new(0x1b, "*SLO", 3, 7, AddressingMode::Absolute_Y),
-        OpCode::new(0x03, "*SLO", 2, 8, AddressingMode::Indirect_X),
-        OpCode::new(0x13, "*SLO", 2, 8, AddressingMode::Indirect_Y),
-        OpCode::new(0x27, "*RLA", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0x37, "*RLA", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0x2F, "*RLA", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0x3F, "*RLA", 3, 7, AddressingMode::Absolute_X),
-        OpCode::new(0x3b, "*RLA", 3, 7, AddressingMode::Absolute_Y),
-        OpCode::new(0x33, "*RLA", 2, 8, AddressingMode::Indirect_Y),
-        OpCode::new(0x23, "*RLA", 2, 8, AddressingMode::Indirect_X),
-        OpCode::new(0x47, "*SRE", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0x57, "*SRE", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0x4F, "*SRE", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0x5f, "*SRE", 3, 7, AddressingMode::Absolute_X),
-        OpCode::new(0x5b, "*SRE", 3, 7, AddressingMode::Absolute_Y),
-        OpCode::new(0x43, "*SRE", 2, 8, AddressingMode::Indirect_X),
-        OpCode::new(0x53, "*SRE", 2, 8, AddressingMode::Indirect_Y),
-        OpCode::new(0x67, "*RRA", 2, 5, AddressingMode::ZeroPage),
-        OpCode::new(0x77, "*RRA", 2, 6, AddressingMode::ZeroPage_X),
-        OpCode::new(0x6f, "*RRA", 3, 6, AddressingMode::Absolute),
-        OpCode::new(0x7f, "*RRA", 3, 7, AddressingMode::Absolute_X),
-        OpCode::new(0x7b, "*RRA", 3, 7, AddressingMode::Absolute_Y),
-        OpCode::new(0x63, "*RRA", 2, 8, AddressingMode::Indirect_X),
-        OpCode::new(0x73, "*RRA", 2, 8, AddressingMode::Indirect_Y),
-        OpCode::new(0xCB, "*AXS", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0x6B, "*ARR", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0x0b, "*ANC", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0x2b, "*ANC", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0x4b, "*ALR", 2, 2, AddressingMode::Immediate),
-        OpCode::new(0xab, "*LXA", 2, 3, AddressingMode::Immediate),
-        OpCode::new(0x8b, "*XAA", 2, 3, AddressingMode::Immediate),
-        OpCode::new(0xbb, "*LAS", 3, 2, AddressingMode::Absolute_Y),
-        OpCode::new(0x9b, "*TAS", 3, 2, AddressingMode::Absolute_Y),
-        OpCode::new(0x93, "*AHX", 2, 8, AddressingMode::Indirect_Y),
-        OpCode::new(0x9f, "*AHX", 3, 4, AddressingMode::Absolute_Y),
-        OpCode::new(0x9e, "*SHX", 3, 4, AddressingMode::Absolute_Y),
-        OpCode::new(0x9c, "*SHY", 3, 4, AddressingMode::Absolute_X),
+        OpCode::new(0x04, "*NOP", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0x44, "*NOP", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0x64, "*NOP", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0x14, "*NOP", 2, 4, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x34, "*NOP", 2, 4, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x54, "*NOP", 2, 4, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x74, "*NOP", 2, 4, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0xd4, "*NOP", 2, 4, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0xf4, "*NOP", 2, 4, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x0c, "*NOP", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0x1c, "*NOP", 3, 4, AddressingMode::Absolute_X, true),
+        OpCode::new(0x3c, "*NOP", 3, 4, AddressingMode::Absolute_X, true),
+        OpCode::new(0x5c, "*NOP", 3, 4, AddressingMode::Absolute_X, true),
+        OpCode::new(0x7c, "*NOP", 3, 4, AddressingMode::Absolute_X, true),
+        OpCode::new(0xdc, "*NOP", 3, 4, AddressingMode::Absolute_X, true),
+        OpCode::new(0xfc, "*NOP", 3, 4, AddressingMode::Absolute_X, true),
+        OpCode::new(0x02, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x12, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x22, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x32, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x42, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x52, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x62, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x72, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x92, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0xb2, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0xd2, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0xf2, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x1a, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x3a, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x5a, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x7a, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0xda, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0xfa, "*NOP", 1, 2, AddressingMode::NoneAddressing, false),
+        OpCode::new(0x80, "*NOP", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0x82, "*NOP", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0x89, "*NOP", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0xc2, "*NOP", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0xe2, "*NOP", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0xa7, "*LAX", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0xb7, "*LAX", 2, 4, AddressingMode::ZeroPage_Y, false),
+        OpCode::new(0xaf, "*LAX", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0xbf, "*LAX", 3, 4, AddressingMode::Absolute_Y, true),
+        OpCode::new(0xa3, "*LAX", 2, 6, AddressingMode::Indirect_X, false),
+        OpCode::new(0xb3, "*LAX", 2, 5, AddressingMode::Indirect_Y, true),
+        OpCode::new(0x87, "*SAX", 2, 3, AddressingMode::ZeroPage, false),
+        OpCode::new(0x97, "*SAX", 2, 4, AddressingMode::ZeroPage_Y, false),
+        OpCode::new(0x8f, "*SAX", 3, 4, AddressingMode::Absolute, false),
+        OpCode::new(0x83, "*SAX", 2, 6, AddressingMode::Indirect_X, false),
+        OpCode::new(0xeb, "*SBC", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0xc7, "*DCP", 2, 5, AddressingMode::ZeroPage, false),
+        OpCode::new(0xd7, "*DCP", 2, 6, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0xCF, "*DCP", 3, 6, AddressingMode::Absolute, false),
+        OpCode::new(0xdF, "*DCP", 3, 7, AddressingMode::Absolute_X, false),
+        OpCode::new(0xdb, "*DCP", 3, 7, AddressingMode::Absolute_Y, false),
+        OpCode::new(0xd3, "*DCP", 2, 8, AddressingMode::Indirect_Y, false),
+        OpCode::new(0xc3, "*DCP", 2, 8, AddressingMode::Indirect_X, false),
+        OpCode::new(0xe7, "*ISB", 2, 5, AddressingMode::ZeroPage, false),
+        OpCode::new(0xf7, "*ISB", 2, 6, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0xef, "*ISB", 3, 6, AddressingMode::Absolute, false),
+        OpCode::new(0xff, "*ISB", 3, 7, AddressingMode::Absolute_X, false),
+        OpCode::new(0xfb, "*ISB", 3, 7, AddressingMode::Absolute_Y, false),
+        OpCode::new(0xe3, "*ISB", 2, 8, AddressingMode::Indirect_X, false),
+        OpCode::new(0xf3, "*ISB", 2, 8, AddressingMode::Indirect_Y, false),
+        OpCode::new(0x07, "*SLO", 2, 5, AddressingMode::ZeroPage, false),
+        OpCode::new(0x17, "*SLO", 2, 6, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x0F, "*SLO", 3, 6, AddressingMode::Absolute, false),
+        OpCode::new(0x1f, "*SLO", 3, 7, AddressingMode::Absolute_X, false),
+        OpCode::new(0x1b, "*SLO", 3, 7, AddressingMode::Absolute_Y, false),
+        OpCode::new(0x03, "*SLO", 2, 8, AddressingMode::Indirect_X, false),
+        OpCode::new(0x13, "*SLO", 2, 8, AddressingMode::Indirect_Y, false),
+        OpCode::new(0x27, "*RLA", 2, 5, AddressingMode::ZeroPage, false),
+        OpCode::new(0x37, "*RLA", 2, 6, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x2F, "*RLA", 3, 6, AddressingMode::Absolute, false),
+        OpCode::new(0x3F, "*RLA", 3, 7, AddressingMode::Absolute_X, false),
+        OpCode::new(0x3b, "*RLA", 3, 7, AddressingMode::Absolute_Y, false),
+        OpCode::new(0x33, "*RLA", 2, 8, AddressingMode::Indirect_Y, false),
+        OpCode::new(0x23, "*RLA", 2, 8, AddressingMode::Indirect_X, false),
+        OpCode::new(0x47, "*SRE", 2, 5, AddressingMode::ZeroPage, false),
+        OpCode::new(0x57, "*SRE", 2, 6, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x4F, "*SRE", 3, 6, AddressingMode::Absolute, false),
+        OpCode::new(0x5f, "*SRE", 3, 7, AddressingMode::Absolute_X, false),
+        OpCode::new(0x5b, "*SRE", 3, 7, AddressingMode::Absolute_Y, false),
+        OpCode::new(0x43, "*SRE", 2, 8, AddressingMode::Indirect_X, false),
+        OpCode::new(0x53, "*SRE", 2, 8, AddressingMode::Indirect_Y, false),
+        OpCode::new(0x67, "*RRA", 2, 5, AddressingMode::ZeroPage, false),
+        OpCode::new(0x77, "*RRA", 2, 6, AddressingMode::ZeroPage_X, false),
+        OpCode::new(0x6f, "*RRA", 3, 6, AddressingMode::Absolute, false),
+        OpCode::new(0x7f, "*RRA", 3, 7, AddressingMode::Absolute_X, false),
+        OpCode::new(0x7b, "*RRA", 3, 7, AddressingMode::Absolute_Y, false),
+        OpCode::new(0x63, "*RRA", 2, 8, AddressingMode::Indirect_X, false),
+        OpCode::new(0x73, "*RRA", 2, 8, AddressingMode::Indirect_Y, false),
+        OpCode::new(0xCB, "*AXS", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0x6B, "*ARR", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0x0b, "*ANC", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0x2b, "*ANC", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0x4b, "*ALR", 2, 2, AddressingMode::Immediate, false),
+        OpCode::new(0xab, "*LXA", 2, 3, AddressingMode::Immediate, false),
+        OpCode::new(0x8b, "*XAA", 2, 3, AddressingMode::Immediate, false),
+        OpCode::new(0xbb, "*LAS", 3, 2, AddressingMode::Absolute_Y, false),
+        OpCode::new(0x9b, "*TAS", 3, 2, AddressingMode::Absolute_Y, false),
+        OpCode::new(0x93, "*AHX", 2, 8, AddressingMode::Indirect_Y, false),
+        OpCode::new(0x9f, "*AHX", 3, 4, AddressingMode::Absolute_Y, false),
+        OpCode::new(0x9e, "*SHX", 3, 4, AddressingMode::Absolute_Y, false),
+        OpCode::new(0x9c, "*SHY", 3, 4, AddressingMode::Absolute_X, false),
     ]
 });
 
@@ -293,3 +338,448 @@ pub static OPCODES_MAP: Lazy<HashMap<u8, &'static OpCode>> = Lazy::new(|| {
     }
     map
 });
+
+pub fn lookup(code: u8) -> Option<&'static OpCode> {
+    OPCODES_MAP.get(&code).copied()
+}
+
+// opcodeの値の順に全命令を返す
+pub fn all() -> impl Iterator<Item = &'static OpCode> {
+    (0..=0xffu8).filter_map(lookup)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisasmLine {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str,
+    pub operand: String,
+    pub len: u8,
+    pub mode: AddressingMode,
+}
+
+impl fmt::Display for DisasmLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex_str = self
+            .bytes
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let line = format!(
+            "{:04X}  {:8} {: >4} {}",
+            self.addr, hex_str, self.mnemonic, self.operand
+        );
+        write!(f, "{}", line.trim_end())
+    }
+}
+
+fn operand_text(op: &OpCode, addr: u16, bytes: &[u8]) -> String {
+    match op.len {
+        1 => match op.code {
+            0x0a | 0x4a | 0x2a | 0x6a => String::from("A"),
+            _ => String::from(""),
+        },
+        2 => {
+            let value = bytes[1];
+            match op.mode {
+                AddressingMode::Immediate => format!("#${:02X}", value),
+                AddressingMode::ZeroPage => format!("${:02X}", value),
+                AddressingMode::ZeroPage_X => format!("${:02X},X", value),
+                AddressingMode::ZeroPage_Y => format!("${:02X},Y", value),
+                AddressingMode::Indirect_X => format!("(${:02X},X)", value),
+                AddressingMode::Indirect_Y => format!("(${:02X}),Y", value),
+                // relative branches: BNE, BVS, etc.
+                _ => {
+                    let target = addr.wrapping_add(2).wrapping_add((value as i8) as u16);
+                    format!("${:04X}", target)
+                }
+            }
+        }
+        _ => {
+            let value = (bytes[2] as u16) << 8 | (bytes[1] as u16);
+            match op.mode {
+                AddressingMode::Absolute_X => format!("${:04X},X", value),
+                AddressingMode::Absolute_Y => format!("${:04X},Y", value),
+                _ if op.code == 0x6c => format!("(${:04X})", value),
+                _ => format!("${:04X}", value),
+            }
+        }
+    }
+}
+
+pub fn disassemble(program: &[u8], origin: u16) -> Vec<DisasmLine> {
+    let mut lines = vec![];
+    let mut pos = 0;
+
+    while pos < program.len() {
+        let addr = origin.wrapping_add(pos as u16);
+        let op = OPCODES_MAP[&program[pos]];
+        let len = op.len as usize;
+
+        if pos + len > program.len() {
+            // not enough bytes left for the operand, dump them as data
+            let bytes = program[pos..].to_vec();
+            let operand = bytes
+                .iter()
+                .map(|b| format!("${:02X}", b))
+                .collect::<Vec<String>>()
+                .join(",");
+            lines.push(DisasmLine {
+                addr,
+                len: bytes.len() as u8,
+                bytes,
+                mnemonic: ".DB",
+                operand,
+                mode: AddressingMode::NoneAddressing,
+            });
+            break;
+        }
+
+        let bytes = program[pos..pos + len].to_vec();
+        lines.push(DisasmLine {
+            addr,
+            operand: operand_text(op, addr, &bytes),
+            bytes,
+            mnemonic: op.mnemonic,
+            len: op.len,
+            mode: op.mode,
+        });
+        pos += len;
+    }
+
+    lines
+}
+
+// 命令は改行か ';' で区切る。ラベルは "loop:" のように書き、分岐とJMP/JSRで参照できる
+#[cfg(feature = "assembler")]
+pub fn assemble(source: &str) -> Vec<u8> {
+    try_assemble(source, 0x0600).unwrap()
+}
+
+#[cfg(feature = "assembler")]
+enum AsmOperand {
+    Implied,
+    Value(AddressingMode, u16, bool),
+    Indirect(u16),
+    Label(String),
+}
+
+#[cfg(feature = "assembler")]
+fn parse_number(text: &str) -> Result<(u16, bool), String> {
+    let (digits, radix) = match text.strip_prefix('$') {
+        Some(hex) => (hex, 16),
+        None => (text, 10),
+    };
+    let value = u16::from_str_radix(digits, radix).map_err(|_| format!("bad number '{}'", text))?;
+    let wide = if radix == 16 {
+        digits.len() > 2
+    } else {
+        value > 0xff
+    };
+    Ok((value, wide))
+}
+
+#[cfg(feature = "assembler")]
+fn parse_operand(text: &str) -> Result<AsmOperand, String> {
+    let text = text.replace(' ', "").to_ascii_uppercase();
+    if text.is_empty() || text == "A" {
+        return Ok(AsmOperand::Implied);
+    }
+    if let Some(imm) = text.strip_prefix('#') {
+        let (value, _) = parse_number(imm)?;
+        return Ok(AsmOperand::Value(AddressingMode::Immediate, value, false));
+    }
+    if let Some(inner) = text.strip_prefix('(') {
+        if let Some(ptr) = inner.strip_suffix(",X)") {
+            let (value, _) = parse_number(ptr)?;
+            return Ok(AsmOperand::Value(AddressingMode::Indirect_X, value, false));
+        }
+        if let Some(ptr) = inner.strip_suffix("),Y") {
+            let (value, _) = parse_number(ptr)?;
+            return Ok(AsmOperand::Value(AddressingMode::Indirect_Y, value, false));
+        }
+        if let Some(ptr) = inner.strip_suffix(')') {
+            let (value, _) = parse_number(ptr)?;
+            return Ok(AsmOperand::Indirect(value));
+        }
+        return Err(format!("bad indirect operand '{}'", text));
+    }
+
+    let (base, index) = match text.split_once(',') {
+        Some((base, index)) => (base.to_string(), Some(index.to_string())),
+        None => (text.clone(), None),
+    };
+    if !base.starts_with('$') && !base.starts_with(|c: char| c.is_ascii_digit()) {
+        return Ok(AsmOperand::Label(base));
+    }
+    let (value, wide) = parse_number(&base)?;
+    let mode = match (wide, index.as_deref()) {
+        (false, None) => AddressingMode::ZeroPage,
+        (false, Some("X")) => AddressingMode::ZeroPage_X,
+        (false, Some("Y")) => AddressingMode::ZeroPage_Y,
+        (true, None) => AddressingMode::Absolute,
+        (true, Some("X")) => AddressingMode::Absolute_X,
+        (true, Some("Y")) => AddressingMode::Absolute_Y,
+        _ => return Err(format!("bad index register in '{}'", text)),
+    };
+    Ok(AsmOperand::Value(mode, value, wide))
+}
+
+#[cfg(feature = "assembler")]
+fn find_opcode(mnemonic: &str, len: u8, mode: Option<AddressingMode>) -> Option<&'static OpCode> {
+    // JMP indirect shares NoneAddressing with JMP absolute, so it is looked up separately
+    CPU_OPS_CODES
+        .iter()
+        .filter(|op| op.mnemonic == mnemonic && op.len == len && op.code != 0x6c)
+        .find(|op| match mode {
+            Some(mode) => op.mode == mode,
+            None => true,
+        })
+}
+
+#[cfg(feature = "assembler")]
+fn is_branch(mnemonic: &str) -> bool {
+    matches!(
+        mnemonic,
+        "BCC" | "BCS" | "BEQ" | "BMI" | "BNE" | "BPL" | "BVC" | "BVS"
+    )
+}
+
+#[cfg(feature = "assembler")]
+pub fn try_assemble(source: &str, origin: u16) -> Result<Vec<u8>, String> {
+    let statements: Vec<&str> = source
+        .split(['\n', ';'])
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    // 1st pass: resolve instruction sizes and label addresses
+    let mut labels = HashMap::new();
+    let mut parsed = vec![];
+    let mut pc = origin;
+    for statement in statements {
+        let statement = match statement.split_once(':') {
+            Some((label, rest)) => {
+                labels.insert(label.trim().to_ascii_uppercase(), pc);
+                rest.trim()
+            }
+            None => statement,
+        };
+        if statement.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operand) = match statement.split_once(char::is_whitespace) {
+            Some((mnemonic, operand)) => (mnemonic, operand.trim()),
+            None => (statement, ""),
+        };
+        let mnemonic = mnemonic.to_ascii_uppercase();
+        let operand = parse_operand(operand)?;
+
+        let op = match &operand {
+            AsmOperand::Implied => find_opcode(&mnemonic, 1, None),
+            AsmOperand::Indirect(_) => CPU_OPS_CODES
+                .iter()
+                .find(|op| op.mnemonic == mnemonic && op.code == 0x6c),
+            AsmOperand::Label(_) | AsmOperand::Value(_, _, _) if is_branch(&mnemonic) => {
+                find_opcode(&mnemonic, 2, Some(AddressingMode::NoneAddressing))
+            }
+            AsmOperand::Label(_) => find_opcode(&mnemonic, 3, Some(AddressingMode::NoneAddressing)),
+            AsmOperand::Value(mode, _, wide) => {
+                let len = if *wide { 3 } else { 2 };
+                find_opcode(&mnemonic, len, Some(*mode)).or_else(|| match mode {
+                    // ゼロページ版がない命令は絶対アドレスで代用する
+                    AddressingMode::ZeroPage => {
+                        find_opcode(&mnemonic, 3, Some(AddressingMode::Absolute)).or_else(|| {
+                            find_opcode(&mnemonic, 3, Some(AddressingMode::NoneAddressing))
+                        })
+                    }
+                    AddressingMode::ZeroPage_X => {
+                        find_opcode(&mnemonic, 3, Some(AddressingMode::Absolute_X))
+                    }
+                    AddressingMode::ZeroPage_Y => {
+                        find_opcode(&mnemonic, 3, Some(AddressingMode::Absolute_Y))
+                    }
+                    AddressingMode::Absolute => {
+                        find_opcode(&mnemonic, 3, Some(AddressingMode::NoneAddressing))
+                    }
+                    _ => None,
+                })
+            }
+        }
+        .ok_or_else(|| format!("no opcode for '{}'", statement))?;
+
+        parsed.push((pc, op, operand));
+        pc = pc.wrapping_add(op.len as u16);
+    }
+
+    // 2nd pass: emit bytes
+    let mut program = vec![];
+    for (pc, op, operand) in parsed {
+        let value = match operand {
+            AsmOperand::Implied => 0,
+            AsmOperand::Value(_, value, _) | AsmOperand::Indirect(value) => value,
+            AsmOperand::Label(label) => *labels
+                .get(&label)
+                .ok_or_else(|| format!("unknown label '{}'", label))?,
+        };
+
+        program.push(op.code);
+        match op.len {
+            2 if is_branch(op.mnemonic) => {
+                let offset = value as i32 - (pc as i32 + 2);
+                if !(-128..=127).contains(&offset) {
+                    return Err(format!("branch out of range at {:04X}", pc));
+                }
+                program.push(offset as i8 as u8);
+            }
+            2 => program.push(value as u8),
+            3 => {
+                program.push((value & 0xff) as u8);
+                program.push((value >> 8) as u8);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(program)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let program = vec![
+            0xa9, 0x01, // LDA #$01
+            0x8d, 0x00, 0x02, // STA $0200
+            0xb1, 0x33, // LDA ($33),Y
+            0x0a, // ASL A
+            0xd0, 0xf6, // BNE $8000
+            0x6c, 0xfc, 0xff, // JMP ($FFFC)
+        ];
+        let lines = disassemble(&program, 0x8000);
+
+        let text: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        assert_eq!(
+            text,
+            vec![
+                "8000  A9 01     LDA #$01",
+                "8002  8D 00 02  STA $0200",
+                "8005  B1 33     LDA ($33),Y",
+                "8007  0A        ASL A",
+                "8008  D0 F6     BNE $8000",
+                "800A  6C FC FF  JMP ($FFFC)",
+            ]
+        );
+        assert_eq!(lines[1].len, 3);
+        assert_eq!(lines[2].mode, AddressingMode::Indirect_Y);
+    }
+
+    #[test]
+    fn test_disassemble_truncated() {
+        let lines = disassemble(&[0xea, 0x4c, 0x00], 0xc000);
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].mnemonic, "NOP");
+        assert_eq!(lines[1].mnemonic, ".DB");
+        assert_eq!(lines[1].operand, "$4C,$00");
+        assert_eq!(lines[1].len, 2);
+    }
+
+    #[test]
+    fn test_all() {
+        let ops: Vec<&OpCode> = all().collect();
+        assert_eq!(ops.len(), 256);
+        assert!(ops.windows(2).all(|w| w[0].code < w[1].code));
+        assert_eq!(ops.iter().filter(|op| op.is_official()).count(), 151);
+
+        let lda = lookup(0xa9).unwrap();
+        assert_eq!(lda.mnemonic, "LDA");
+        assert_eq!(lda.mode, AddressingMode::Immediate);
+        assert!(!lookup(0xa7).unwrap().is_official());
+    }
+
+    #[test]
+    fn test_categories() {
+        let count = |category| all().filter(|op| op.category == category).count();
+        assert_eq!(count(OpCategory::Official), 151);
+        assert_eq!(count(OpCategory::Jam), 12);
+        assert_eq!(count(OpCategory::UnstableUnofficial), 7);
+        assert_eq!(lookup(0xa7).unwrap().category, OpCategory::StableUnofficial);
+        assert_eq!(
+            lookup(0x8b).unwrap().category,
+            OpCategory::UnstableUnofficial
+        );
+        assert_eq!(lookup(0x02).unwrap().category, OpCategory::Jam);
+    }
+
+    #[test]
+    fn test_page_cross_penalty_only_on_indexed_modes() {
+        for op in CPU_OPS_CODES.iter().filter(|op| op.page_cross_penalty) {
+            assert!(
+                matches!(
+                    op.mode,
+                    AddressingMode::Absolute_X
+                        | AddressingMode::Absolute_Y
+                        | AddressingMode::Indirect_Y
+                ),
+                "{:02x} {}",
+                op.code,
+                op.mnemonic
+            );
+        }
+    }
+
+    #[cfg(feature = "assembler")]
+    #[test]
+    fn test_assemble() {
+        assert_eq!(
+            assemble("LDA #$01 ; STA $0200"),
+            vec![0xa9, 0x01, 0x8d, 0x00, 0x02]
+        );
+        assert_eq!(
+            assemble("ldx $10,y; lda ($20),y; sta ($30,x); asl a; jmp ($fffc)"),
+            vec![0xb6, 0x10, 0xb1, 0x20, 0x81, 0x30, 0x0a, 0x6c, 0xfc, 0xff]
+        );
+    }
+
+    #[cfg(feature = "assembler")]
+    #[test]
+    fn test_assemble_labels() {
+        let program = assemble(
+            "
+            start: LDX #3
+            loop:  DEX
+                   BNE loop
+                   JSR start
+                   *NOP $10
+            ",
+        );
+        assert_eq!(
+            program,
+            vec![0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x20, 0x00, 0x06, 0x04, 0x10]
+        );
+    }
+
+    #[cfg(feature = "assembler")]
+    #[test]
+    fn test_assemble_round_trip() {
+        let program = assemble("LDA $1234,X; SBC ($44),Y; BEQ $0600; CLC");
+        let text: Vec<String> = disassemble(&program, 0x0600)
+            .iter()
+            .map(|l| format!("{} {}", l.mnemonic, l.operand).trim().to_string())
+            .collect();
+        assert_eq!(text, vec!["LDA $1234,X", "SBC ($44),Y", "BEQ $0600", "CLC"]);
+    }
+
+    #[cfg(feature = "assembler")]
+    #[test]
+    fn test_assemble_errors() {
+        assert!(try_assemble("FOO #$01", 0).is_err());
+        assert!(try_assemble("JMP nowhere", 0).is_err());
+        assert!(try_assemble("LDA ($10,Z)", 0).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::{Duration, Instant};
+
+// 押している間の早送りの速さ。Noneは上限なし
+pub const FAST_FORWARD_SPEEDS: [Option<f64>; 3] = [None, Some(2.0), Some(4.0)];
+// 押している間のスロー再生の速さ
+pub const SLOW_MOTION_SPEEDS: [f64; 2] = [0.5, 0.25];
+// BackgroundMode::Throttleでウィンドウが裏にあるときの速さ
+pub const BACKGROUND_SPEED: f64 = 0.25;
+
+// これ以上遅れたら追いつこうとせず、今から数え直す
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+// 実機と同じ間隔 (NTSCなら60.0988Hz) でフレームを出すために待つ
+pub struct FramePacer {
+    frame_rate: f64,
+    // 1.0で等速。Noneなら待たない
+    speed: Option<f64>,
+    // 次のフレームを始めてよい時刻
+    deadline: Option<Instant>,
+}
+
+impl FramePacer {
+    pub fn new(frame_rate: f64) -> Self {
+        FramePacer {
+            frame_rate,
+            speed: Some(1.0),
+            deadline: None,
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        self.frame_rate
+    }
+
+    pub fn set_frame_rate(&mut self, frame_rate: f64) {
+        self.frame_rate = frame_rate;
+        self.deadline = None;
+    }
+
+    pub fn speed(&self) -> Option<f64> {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Option<f64>) {
+        if self.speed != speed {
+            self.speed = speed;
+            self.deadline = None;
+        }
+    }
+
+    // 1フレームの長さ。上限なしならNone
+    pub fn frame_duration(&self) -> Option<Duration> {
+        self.speed
+            .map(|speed| Duration::from_secs_f64(1.0 / (self.frame_rate * speed)))
+    }
+
+    // nowから次のフレームを始めるまでに待つ時間
+    pub fn delay(&mut self, now: Instant) -> Duration {
+        let duration = match self.frame_duration() {
+            Some(duration) => duration,
+            None => {
+                self.deadline = None;
+                return Duration::ZERO;
+            }
+        };
+        let deadline = match self.deadline {
+            Some(deadline) if now <= deadline + MAX_LAG => deadline,
+            _ => now,
+        };
+        self.deadline = Some(deadline + duration);
+        deadline.saturating_duration_since(now)
+    }
+
+    // フレームの前に呼ぶ
+    pub fn wait(&mut self) {
+        let delay = self.delay(Instant::now());
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let mut pacer = FramePacer::new(60.0988);
+        let frame = pacer.frame_duration().unwrap();
+        assert_eq!(frame.as_micros(), 16639);
+
+        let start = Instant::now();
+        assert_eq!(pacer.delay(start), Duration::ZERO);
+        assert_eq!(pacer.delay(start), frame);
+        // 描くのに時間がかかった分は待つ時間から引く
+        let now = start + frame * 2 - Duration::from_millis(5);
+        assert_eq!(pacer.delay(now), Duration::from_millis(5));
+        // 少しの遅れは次のフレームで取り戻す
+        let now = start + frame * 3 + Duration::from_millis(2);
+        assert_eq!(pacer.delay(now), Duration::ZERO);
+        assert_eq!(pacer.delay(now), frame * 4 - (now - start));
+        // 大きく遅れたら数え直す
+        let now = start + Duration::from_secs(1);
+        assert_eq!(pacer.delay(now), Duration::ZERO);
+        assert_eq!(pacer.delay(now), frame);
+    }
+
+    #[test]
+    fn test_speed() {
+        let mut pacer = FramePacer::new(60.0);
+        pacer.set_speed(FAST_FORWARD_SPEEDS[2]);
+        assert_eq!(
+            pacer.frame_duration(),
+            Some(Duration::from_secs_f64(1.0 / 240.0))
+        );
+        pacer.set_speed(Some(SLOW_MOTION_SPEEDS[0]));
+        assert_eq!(
+            pacer.frame_duration(),
+            Some(Duration::from_secs_f64(1.0 / 30.0))
+        );
+
+        pacer.set_speed(FAST_FORWARD_SPEEDS[0]);
+        assert_eq!(pacer.frame_duration(), None);
+        let now = Instant::now();
+        assert_eq!(pacer.delay(now), Duration::ZERO);
+        assert_eq!(pacer.delay(now), Duration::ZERO);
+    }
+}
@@ -0,0 +1,311 @@
+// IPS/BPSパッチをメモリ上のROMイメージに当てる。元のファイルは変更しない
+
+const IPS_TAG: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_TAG: &[u8] = b"BPS1";
+
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(IPS_TAG) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(BPS_TAG) {
+        apply_bps(rom, patch)
+    } else {
+        Err("unknown patch format".to_string())
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or_else(|| format!("patch is truncated at offset {}", self.pos))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn be(&mut self, len: usize) -> Result<usize, String> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .fold(0, |acc, b| (acc << 8) | *b as usize))
+    }
+
+    // BPSの可変長整数
+    fn varint(&mut self) -> Result<usize, String> {
+        let mut data: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let x = self.byte()?;
+            data = data
+                .checked_add((x & 0x7f) as usize * shift)
+                .ok_or("BPS number is too large")?;
+            if x & 0x80 != 0 {
+                return Ok(data);
+            }
+            shift = shift.checked_shl(7).ok_or("BPS number is too large")?;
+            data = data.checked_add(shift).ok_or("BPS number is too large")?;
+        }
+    }
+}
+
+// "PATCH" + (オフセット3バイト, 長さ2バイト, データ)* + "EOF" [+ 切り詰め後のサイズ3バイト]
+pub fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = rom.to_vec();
+    let mut reader = Reader {
+        data: patch,
+        pos: IPS_TAG.len(),
+    };
+    loop {
+        if reader.bytes(3)? == IPS_EOF {
+            break;
+        }
+        reader.pos -= 3;
+        let offset = reader.be(3)?;
+        let size = reader.be(2)?;
+        // 長さ0ならRLE
+        let (len, data) = if size == 0 {
+            let len = reader.be(2)?;
+            (len, vec![reader.byte()?; len])
+        } else {
+            (size, reader.bytes(size)?.to_vec())
+        };
+        if out.len() < offset + len {
+            out.resize(offset + len, 0);
+        }
+        out[offset..offset + len].copy_from_slice(&data);
+    }
+    if let Ok(size) = reader.be(3) {
+        out.truncate(size);
+    }
+    Ok(out)
+}
+
+// "BPS1" + 元/先サイズ + メタデータ + 命令列 + CRC32 (元, 先, パッチ)
+pub fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.len() < BPS_TAG.len() + 12 {
+        return Err("BPS patch is truncated".to_string());
+    }
+    let (body, footer) = patch.split_at(patch.len() - 12);
+    let crc = |i: usize| u32::from_le_bytes(footer[i..i + 4].try_into().unwrap());
+    if crc32fast::hash(&patch[..patch.len() - 4]) != crc(8) {
+        return Err("BPS patch checksum mismatch".to_string());
+    }
+    if crc32fast::hash(rom) != crc(0) {
+        return Err("BPS patch is for a different ROM".to_string());
+    }
+
+    let mut reader = Reader {
+        data: body,
+        pos: BPS_TAG.len(),
+    };
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(format!(
+            "BPS patch expects a {} byte ROM, got {}",
+            source_size,
+            rom.len()
+        ));
+    }
+
+    // target_sizeはパッチに書かれた値なので、そのまま確保はしない
+    let mut out: Vec<u8> = Vec::with_capacity(target_size.min(rom.len() + patch.len()));
+    let mut source_rel: isize = 0;
+    let mut target_rel: isize = 0;
+    let out_of_range = || "BPS copy is out of range".to_string();
+    while reader.pos < body.len() {
+        let action = reader.varint()?;
+        let len = (action >> 2) + 1;
+        if len > target_size - out.len() {
+            return Err(out_of_range());
+        }
+        match action & 0b11 {
+            // SourceRead: 同じ位置の元データ
+            0 => {
+                let start = out.len();
+                out.extend(rom.get(start..start + len).ok_or_else(out_of_range)?);
+            }
+            // TargetRead: パッチ内のデータ
+            1 => out.extend(reader.bytes(len)?),
+            // SourceCopy: 元データの任意の位置
+            2 => {
+                source_rel = source_rel
+                    .checked_add(relative(reader.varint()?))
+                    .ok_or_else(out_of_range)?;
+                let start = usize::try_from(source_rel).map_err(|_| out_of_range())?;
+                let end = start.checked_add(len).ok_or_else(out_of_range)?;
+                out.extend(rom.get(start..end).ok_or_else(out_of_range)?);
+                source_rel = end as isize;
+            }
+            // TargetCopy: 出力済みのデータ (重なってもよいので1バイトずつ)
+            _ => {
+                target_rel = target_rel
+                    .checked_add(relative(reader.varint()?))
+                    .ok_or_else(out_of_range)?;
+                for _ in 0..len {
+                    let index = usize::try_from(target_rel).map_err(|_| out_of_range())?;
+                    let byte = *out.get(index).ok_or_else(out_of_range)?;
+                    out.push(byte);
+                    target_rel += 1;
+                }
+            }
+        }
+    }
+
+    if out.len() != target_size || crc32fast::hash(&out) != crc(4) {
+        return Err("BPS patch produced a different ROM than expected".to_string());
+    }
+    Ok(out)
+}
+
+// 最下位bitが符号
+fn relative(data: usize) -> isize {
+    let offset = (data >> 1) as isize;
+    if data & 1 != 0 {
+        -offset
+    } else {
+        offset
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn varint(mut data: usize) -> Vec<u8> {
+        let mut out = vec![];
+        loop {
+            let x = (data & 0x7f) as u8;
+            data >>= 7;
+            if data == 0 {
+                out.push(0x80 | x);
+                return out;
+            }
+            out.push(x);
+            data -= 1;
+        }
+    }
+
+    fn bps(source: &[u8], target: &[u8], actions: &[u8]) -> Vec<u8> {
+        bps_sized(source, target.len(), crc32fast::hash(target), actions)
+    }
+
+    fn bps_sized(source: &[u8], target_size: usize, target_crc: u32, actions: &[u8]) -> Vec<u8> {
+        let mut patch = BPS_TAG.to_vec();
+        patch.extend(varint(source.len()));
+        patch.extend(varint(target_size));
+        patch.extend(varint(0));
+        patch.extend(actions);
+        patch.extend(crc32fast::hash(source).to_le_bytes());
+        patch.extend(target_crc.to_le_bytes());
+        let crc = crc32fast::hash(&patch);
+        patch.extend(crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn test_ips() {
+        let rom = vec![0; 8];
+        let mut patch = IPS_TAG.to_vec();
+        patch.extend([0x00, 0x00, 0x02, 0x00, 0x02, 0xaa, 0xbb]);
+        // RLE: $06から4バイト$CC (ROMが伸びる)
+        patch.extend([0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x04, 0xcc]);
+        patch.extend(IPS_EOF);
+        assert_eq!(
+            apply(&rom, &patch).unwrap(),
+            vec![0, 0, 0xaa, 0xbb, 0, 0, 0xcc, 0xcc, 0xcc, 0xcc]
+        );
+
+        // 切り詰め
+        patch.extend([0x00, 0x00, 0x05]);
+        assert_eq!(apply(&rom, &patch).unwrap(), vec![0, 0, 0xaa, 0xbb, 0]);
+
+        assert!(apply(&rom, &patch[..10]).is_err());
+        assert!(apply(&rom, b"NOPE").is_err());
+    }
+
+    #[test]
+    fn test_bps() {
+        let source = b"ABCDEFGH".to_vec();
+        let target = b"ABCxyEFGHGHGH".to_vec();
+        let mut actions = vec![];
+        // SourceRead 3: ABC
+        actions.extend(varint((3 - 1) << 2));
+        // TargetRead 2: xy
+        actions.extend(varint((2 - 1) << 2 | 1));
+        actions.extend(b"xy");
+        // SourceCopy 4 (+4): EFGH
+        actions.extend(varint((4 - 1) << 2 | 2));
+        actions.extend(varint(4 << 1));
+        // TargetCopy 4 (+7): GHGH (重なりあり)
+        actions.extend(varint((4 - 1) << 2 | 3));
+        actions.extend(varint(7 << 1));
+        let patch = bps(&source, &target, &actions);
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+
+        // 別のROMには当てられない
+        assert!(apply(b"ABCDEFGX", &patch).is_err());
+        // パッチ自体の破損
+        let mut broken = patch.clone();
+        broken[6] ^= 1;
+        assert!(apply(&source, &broken).is_err());
+    }
+
+    // ヘッダのサイズや長さ、オフセットが大きすぎてもパニックせずにエラーになる
+    #[test]
+    fn test_bps_overflow() {
+        let source = b"ABCDEFGH".to_vec();
+        let crc = crc32fast::hash(&source);
+        let read_all = varint((8 - 1) << 2);
+        let patch = bps_sized(&source, usize::MAX / 2, crc, &read_all);
+        assert!(apply(&source, &patch).is_err());
+
+        let patch = bps_sized(&source, 8, crc, &varint(usize::MAX & !0b11));
+        assert!(apply(&source, &patch).is_err());
+
+        // SourceCopyとTargetCopyで1バイトコピーしてから、相対位置をisize::MAXだけ進める
+        for kind in [0b10, 0b11] {
+            let mut far_copy = varint(0);
+            far_copy.extend(varint(kind));
+            far_copy.extend(varint(0));
+            far_copy.extend(varint(kind));
+            far_copy.extend(varint((isize::MAX as usize) << 1));
+            let patch = bps_sized(&source, 8, crc, &far_copy);
+            assert!(apply(&source, &patch).is_err());
+        }
+
+        let mut metadata = BPS_TAG.to_vec();
+        metadata.extend(varint(8));
+        metadata.extend(varint(8));
+        metadata.extend(varint(usize::MAX));
+        metadata.extend(crc.to_le_bytes());
+        metadata.extend(crc.to_le_bytes());
+        metadata.extend(crc32fast::hash(&metadata).to_le_bytes());
+        assert!(apply(&source, &metadata).is_err());
+    }
+
+    #[test]
+    fn test_varint() {
+        for n in [0, 1, 127, 128, 129, 16511, 16512, 1 << 20] {
+            let bytes = varint(n);
+            let mut reader = Reader {
+                data: &bytes,
+                pos: 0,
+            };
+            assert_eq!(reader.varint().unwrap(), n);
+        }
+    }
+}
@@ -1,27 +1,137 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
 use crate::{
-    cartridge::Mirroring, ppu_addr_register::AddrRegister, ppu_control_register::ControlRegister,
-    ppu_mask_register::MaskRegister, ppu_scroll_register::ScrollRegister,
+    cartridge::Mirroring,
+    console::Region,
+    mapper::{ChrFetch, Nametable, SharedMapper},
+    mapper_nrom::Nrom,
+    ppu_control_register::ControlRegister,
+    ppu_loopy_register::LoopyRegister,
+    ppu_mask_register::MaskRegister,
     ppu_status_register::StatusRegister,
+    renderer_palette,
+    savestate::State,
 };
 
+// 電源投入からこのCPUサイクル数の間は$2000/$2001/$2005/$2006への書き込みが無視される
+pub const WARMUP_CPU_CYCLES: usize = 29658;
+
+// 1ラインに描けるスプライトの数
+pub const SPRITES_PER_LINE: usize = 8;
+
+// A12がこのドット数以上下がっていた後の立ち上がりだけをマッパーに知らせる。
+// MMC3はM2の立ち下がり3回分 (約9ドット) を待つので、同じラインの中の
+// ネームテーブルのフェッチによる短い立ち下がりは数えない
+const A12_FILTER_DOTS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Accuracy {
+    // ライン単位で状態を進め、画面はフレームの終わりにまとめて描く
+    Fast,
+    // 1ドットずつフェッチ、スプライト評価、描画を行う
+    Accurate,
+}
+
+// ドット単位で描いた1ピクセル。色はシステムパレットの番号
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DotPixel {
+    pub backdrop: u8,
+    pub background: Option<u8>,
+    // 色と、背景の後ろに回るかどうか
+    pub sprite: Option<(u8, bool)>,
+    // 描いた時点のPPUMASK (グレースケールと色の強調)
+    pub mask: u8,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct LineSprite {
+    x: u8,
+    // 8x16ではbit0でパターンテーブルを選ぶ
+    tile: u8,
+    attr: u8,
+    lo: u8,
+    hi: u8,
+    zero: bool,
+}
+
+// ドット単位の描画で使うラッチとシフトレジスタ
+#[derive(Debug, Clone, Default)]
+struct DotState {
+    tile: u8,
+    attr: u8,
+    lo: u8,
+    hi: u8,
+    // MMC5の拡張属性モードのタイルとパレット
+    extended: Option<([u8; 16], u8)>,
+    pattern_lo: u16,
+    pattern_hi: u16,
+    attr_lo: u16,
+    attr_hi: u16,
+    // 次のラインに描くスプライト
+    sprites: Vec<LineSprite>,
+    // スプライト評価を始めたときのOAMADDR
+    eval_addr: u8,
+    // CHRのアドレスバスのA12と、最後に下がったときのclock (ドット数)
+    a12: bool,
+    a12_low_since: u64,
+    clock: u64,
+}
+
+// OAMの1エントリを読みやすくしたもの
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sprite {
+    pub x: u8,
+    pub y: u8,
+    pub tile: u8,
+    pub palette: u8,
+    pub behind_background: bool,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+}
+
+// スキャンラインやフレームの区切りで呼ばれるフック。PPUを複製すると共有される
+pub type PpuHook = Rc<RefCell<dyn FnMut(&NesPPU)>>;
+
+#[derive(Clone)]
 pub struct NesPPU {
-    pub mirroring: Mirroring,
+    pub mapper: SharedMapper,
     pub ctrl: ControlRegister,
     pub mask: MaskRegister,
     pub status: StatusRegister,
-    pub scroll: ScrollRegister,
-    pub addr: AddrRegister,
+    // $2005/$2006の書き込みとVRAMアドレス
+    pub loopy: LoopyRegister,
     pub oam_addr: u8,
     pub oam_data: [u8; 256],
 
-    pub chr_rom: Vec<u8>,
-    pub vram: [u8; 2048],
+    // 後半2KBは4画面ミラーリングのカートリッジが持つ追加のVRAM
+    pub vram: [u8; 4096],
     pub palette_table: [u8; 32],
 
     internal_data_buf: u8,
+    // 各ラインの描画に使われたスクロール位置
+    line_scroll: [LoopyRegister; 240],
     scanline: u16,
     cycles: usize,
+    // 奇数フレームはプリレンダーラインが1ドット短い
+    odd_frame: bool,
+    // VBlankの直前に$2002を読まれたフレームはフラグもNMIも立てない
+    suppress_vblank: bool,
+    // 起動直後の書き込みを無視する残りのドット数
+    warmup_dots: usize,
     pub nmi_interrupt: Option<u8>,
+
+    pub accuracy: Accuracy,
+    // ライン数とVBlankの位置が変わる
+    pub region: Region,
+    // falseなら1ラインに9個以上のスプライトも描く。オーバーフローフラグは変わらない
+    pub sprite_limit: bool,
+    dot: DotState,
+    // Accurateで描いた画面
+    pub dot_pixels: Vec<DotPixel>,
+
+    scanline_hook: Option<PpuHook>,
+    frame_hook: Option<PpuHook>,
 }
 
 impl NesPPU {
@@ -30,80 +140,691 @@ impl NesPPU {
         NesPPU::new(vec![0; 2048], Mirroring::HORIZONTAL)
     }
 
+    // CHRだけを持つNROMとして作る
     pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        let mapper = Nrom::new(vec![0; 0x4000], chr_rom, mirroring);
+        NesPPU::with_mapper(Rc::new(RefCell::new(mapper)))
+    }
+
+    pub fn with_mapper(mapper: SharedMapper) -> Self {
         NesPPU {
-            chr_rom: chr_rom,
+            mapper,
             palette_table: [0; 32],
-            vram: [0; 2048],
+            vram: [0; 4096],
             oam_addr: 0,
             oam_data: [0; 64 * 4],
-            mirroring: mirroring,
-            addr: AddrRegister::new(),
+            loopy: LoopyRegister::new(),
             ctrl: ControlRegister::new(),
             mask: MaskRegister::new(),
             status: StatusRegister::new(),
-            scroll: ScrollRegister::new(),
             internal_data_buf: 0,
+            line_scroll: unscrolled_lines(),
             scanline: 0,
             cycles: 0,
+            odd_frame: false,
+            suppress_vblank: false,
+            warmup_dots: 0,
             nmi_interrupt: None,
+            accuracy: Accuracy::Fast,
+            region: Region::Ntsc,
+            sprite_limit: true,
+            dot: DotState::default(),
+            dot_pixels: vec![DotPixel::default(); 256 * 240],
+            scanline_hook: None,
+            frame_hook: None,
         }
     }
 
     // 概要:
-    //   PPUは262行を1フレームで描画する
+    //   PPUは262行 (PAL, Dendyは312行) を1フレームで描画する
     //   1行は341クロックで構成される
-    //   1クロックは3CPUクロックで構成される
+    //   1クロックは3CPUクロックで構成される (PALは3.2)
     //   241行目から262行目まではVBlank期間
     // やるべきこと:
     //   241行目にVBLANKが始まることをNMIで知らせる
     //   262行目にVBLANKが終わることをNMIで知らせる
+    pub fn mirroring(&self) -> Mirroring {
+        self.mapper.borrow().mirroring()
+    }
+
+    pub fn chr_read(&self, addr: u16) -> u8 {
+        self.mapper.borrow_mut().chr_read(addr)
+    }
+
+    // パターンテーブルの1タイル分 (16バイト)
+    pub fn chr_tile(&self, bank: u16, tile_idx: u16, kind: ChrFetch) -> [u8; 16] {
+        let mut tile = [0; 16];
+        let mut mapper = self.mapper.borrow_mut();
+        for (i, b) in tile.iter_mut().enumerate() {
+            *b = mapper.chr_fetch(bank + tile_idx * 16 + i as u16, kind);
+        }
+        tile
+    }
+
+    // OAMの4バイトで表されるスプライトのrow行目のパターン (下位, 上位)。
+    // 8x16ではOAMのタイル番号のbit0でバンクを選び、上下に2枚のタイルを並べる
+    pub fn sprite_pattern_row(&self, sprite: &[u8], row: usize) -> (u8, u8) {
+        let height = self.ctrl.sprite_size() as usize;
+        let row = if sprite[2] & 0x80 != 0 {
+            height - 1 - row
+        } else {
+            row
+        };
+        let (bank, tile_idx) = if height == 16 {
+            let bank = (sprite[1] as u16 & 1) * 0x1000;
+            (bank, (sprite[1] & 0xfe) as u16 + (row / 8) as u16)
+        } else {
+            (self.ctrl.sprt_pattern_addr(), sprite[1] as u16)
+        };
+        let tile = self.chr_tile(bank, tile_idx, ChrFetch::Sprite);
+        (tile[row % 8], tile[row % 8 + 8])
+    }
+
+    // 論理ネームテーブル (0-3) の内容
+    pub fn dump_nametable(&self, table: u8) -> [u8; 0x400] {
+        let mut data = [0; 0x400];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = self.nametable_read(0x2000 + table as u16 * 0x400 + i as u16);
+        }
+        data
+    }
+
+    // パターンテーブル (side 0: $0000, 1: $1000) を16x16タイルの128x128ピクセルのRGBにする。
+    // 色は背景のパレット0を使う
+    pub fn dump_pattern_table(&self, side: u8) -> Vec<u8> {
+        let colors = renderer_palette::default_palette();
+        let mut image = vec![0; 128 * 128 * 3];
+        for tile_idx in 0..256u16 {
+            let tile = self.chr_tile(side as u16 * 0x1000, tile_idx, ChrFetch::Background);
+            for row in 0..8 {
+                for column in 0..8 {
+                    let bit = 7 - column;
+                    let value = (tile[row + 8] >> bit & 1) << 1 | (tile[row] >> bit & 1);
+                    let (r, g, b) = colors.rgb(self.palette_table[value as usize], &self.mask);
+                    let x = (tile_idx % 16) as usize * 8 + column;
+                    let y = (tile_idx / 16) as usize * 8 + row;
+                    image[(y * 128 + x) * 3..(y * 128 + x) * 3 + 3].copy_from_slice(&[r, g, b]);
+                }
+            }
+        }
+        image
+    }
+
+    pub fn palette_snapshot(&self) -> [u8; 32] {
+        self.palette_table
+    }
+
+    // OAMの64個のスプライト
+    pub fn sprites(&self) -> Vec<Sprite> {
+        self.oam_data
+            .chunks(4)
+            .map(|sprite| Sprite {
+                y: sprite[0],
+                tile: sprite[1],
+                palette: sprite[2] & 0b11,
+                behind_background: sprite[2] & 0x20 != 0,
+                flip_horizontal: sprite[2] & 0x40 != 0,
+                flip_vertical: sprite[2] & 0x80 != 0,
+                x: sprite[3],
+            })
+            .collect()
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    pub fn cycle(&self) -> usize {
+        self.cycles
+    }
+
     pub fn tick(&mut self, cycles: u8) -> bool {
-        self.cycles += cycles as usize;
-        if self.cycles > 341 {
-            if self.is_sprite_0_hit(self.cycles) {
-                self.status.set_sprite_zero_hit(true);
+        self.warmup_dots = self.warmup_dots.saturating_sub(cycles as usize);
+        if self.accuracy == Accuracy::Accurate {
+            let mut new_frame = false;
+            for _ in 0..cycles {
+                new_frame |= self.step_dot();
             }
+            return new_frame;
+        }
 
-            self.cycles = self.cycles - 341;
+        let vblank_line = self.region.vblank_line();
+        let before = self.cycles;
+        self.cycles += cycles as usize;
+        self.update_scroll(before);
+        self.check_sprite_overflow(before);
+        self.check_sprite_zero_hit();
+        self.check_a12_rise(before);
+        if self.scanline == vblank_line && before <= 1 && self.cycles >= 2 {
+            self.start_vblank();
+        }
+        let line_length = self.line_length();
+        if self.cycles >= line_length {
+            self.cycles -= line_length;
             self.scanline += 1;
 
-            if self.scanline == 241 {
-                self.status.set_vblank_status(true);
-                self.status.set_sprite_zero_hit(false);
-                if self.ctrl.generate_vblank_nmi() {
-                    self.nmi_interrupt = Some(1);
-                }
+            if self.scanline == vblank_line && self.cycles >= 2 {
+                self.start_vblank();
             }
 
-            if self.scanline >= 262 {
+            if self.scanline >= self.region.scanlines() {
                 self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
                 self.nmi_interrupt = None;
                 self.status.set_sprite_zero_hit(false);
+                self.status.set_sprite_overflow(false);
                 self.status.reset_vblank_status();
+                self.notify_frame();
+                self.notify_scanline();
                 return true;
             }
+            self.notify_scanline();
+            self.check_sprite_zero_hit();
         }
         return false;
     }
 
+    // 背景もスプライトも描かないときの画面の色。
+    // vがパレットを指していればその色、それ以外は共通の背景色 ($3F00)
+    pub fn backdrop_color(&self) -> u8 {
+        let addr = self.loopy.addr();
+        if !self.rendering_enabled() && addr >= 0x3f00 {
+            self.palette_table[palette_index(addr)]
+        } else {
+            self.palette_table[0]
+        }
+    }
+
+    // ライン241 (Dendyは291) のドット1でVBlankに入る
+    fn start_vblank(&mut self) {
+        if self.suppress_vblank {
+            self.suppress_vblank = false;
+            return;
+        }
+        self.status.set_vblank_status(true);
+        if self.ctrl.generate_vblank_nmi() {
+            self.nmi_interrupt = Some(1);
+        }
+    }
+
+    // 1ラインは341ドット。NTSCの描画中の奇数フレームではプリレンダーラインの最後のドットを飛ばす
+    fn line_length(&self) -> usize {
+        if self.region == Region::Ntsc
+            && self.scanline == 261
+            && self.odd_frame
+            && self.rendering_enabled()
+        {
+            340
+        } else {
+            341
+        }
+    }
+
+    // 1ドット進める。フレームが終わったらtrue
+    fn step_dot(&mut self) -> bool {
+        let line = self.scanline;
+        let dot = self.cycles;
+        let pre_render = self.region.pre_render_line();
+        if (line < 240 || line == pre_render) && self.rendering_enabled() {
+            if (2..=257).contains(&dot) || (321..=337).contains(&dot) {
+                self.shift_background();
+                self.fetch_background(dot);
+            }
+            match dot {
+                // 評価はOAMADDRの位置から始まる
+                65 => self.dot.eval_addr = self.oam_addr,
+                256 => self.loopy.increment_y(),
+                257 => {
+                    self.loopy.copy_x();
+                    self.evaluate_sprites();
+                }
+                280..=304 if line == pre_render => self.loopy.copy_y(),
+                _ => {}
+            }
+            // スプライトのパターンを読む間はOAMADDRが0になる
+            if (257..=320).contains(&dot) {
+                self.oam_addr = 0;
+            }
+            self.track_a12(dot);
+        }
+        if line < 240 && (1..=256).contains(&dot) {
+            self.draw_dot(dot - 1);
+        }
+
+        if line == self.region.vblank_line() && dot == 1 {
+            self.start_vblank();
+        }
+        if line == pre_render && dot == 1 {
+            self.corrupt_oam();
+            self.nmi_interrupt = None;
+            self.status.reset_vblank_status();
+            self.status.set_sprite_zero_hit(false);
+            self.status.set_sprite_overflow(false);
+        }
+
+        self.dot.clock += 1;
+        self.cycles += 1;
+        if self.cycles >= self.line_length() {
+            self.cycles = 0;
+            self.scanline += 1;
+            if self.scanline > pre_render {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+                self.notify_frame();
+                self.notify_scanline();
+                return true;
+            }
+            self.notify_scanline();
+        }
+        false
+    }
+
+    // 描画中にCHRのアドレスバスに出るアドレスのA12を追う。ネームテーブルと属性 ($2xxx) の
+    // フェッチで下がり、パターンのフェッチではテーブルの選び方で決まる
+    fn track_a12(&mut self, dot: usize) {
+        let addr = match dot {
+            1..=256 | 321..=340 => match (dot - 1) % 8 {
+                0 => 0x2000,
+                4 | 6 => self.ctrl.bknd_pattern_addr(),
+                _ => return,
+            },
+            257..=320 => match (dot - 257) % 8 {
+                0 => 0x2000,
+                4 | 6 => self.sprite_slot_table((dot - 257) / 8),
+                _ => return,
+            },
+            _ => return,
+        };
+        let high = addr & 0x1000 != 0;
+        if high && !self.dot.a12 && self.dot.clock - self.dot.a12_low_since >= A12_FILTER_DOTS {
+            self.mapper.borrow_mut().a12_rise();
+        }
+        if !high && self.dot.a12 {
+            self.dot.a12_low_since = self.dot.clock;
+        }
+        self.dot.a12 = high;
+    }
+
+    // ドット257-320でslot番目のスプライトのパターンを読むテーブル。
+    // 8x16ではタイルのbit0で決まり、空きのスロットはタイル$FFを読む
+    fn sprite_slot_table(&self, slot: usize) -> u16 {
+        if self.ctrl.sprite_size() == 8 {
+            return self.ctrl.sprt_pattern_addr();
+        }
+        let tile = self.dot.sprites.get(slot).map_or(0xff, |s| s.tile);
+        (tile as u16 & 1) * 0x1000
+    }
+
+    // Fastではフェッチを追わないので、パターンテーブルの選び方から実機でA12が
+    // 立ち上がるドットを決める。背景$0000とスプライト$1000ならスプライトのフェッチ (ドット260)、
+    // 背景$1000とスプライト$0000なら次のラインの背景のフェッチ (ドット324)。
+    // 8x16では空きスロットのタイル$FFが$1000から読まれるので、スプライトは$1000とみなす。
+    // 背景が$1000なら、VBlankの間下がっていたA12がプリレンダーラインの最初のフェッチ (ドット5) でも上がる
+    fn check_a12_rise(&mut self, before: usize) {
+        let line = self.scanline;
+        let pre_render = line == self.region.pre_render_line();
+        if (line >= 240 && !pre_render) || !self.rendering_enabled() {
+            return;
+        }
+        let background = self.ctrl.bknd_pattern_addr() != 0;
+        let sprites = self.ctrl.sprite_size() == 16 || self.ctrl.sprt_pattern_addr() != 0;
+        let crossed = |dot: usize| before < dot && self.cycles >= dot;
+        let rises = (pre_render && background && crossed(5)) as u8
+            + match (background, sprites) {
+                (false, true) => crossed(260) as u8,
+                (true, false) => crossed(324) as u8,
+                _ => 0,
+            };
+        for _ in 0..rises {
+            self.mapper.borrow_mut().a12_rise();
+        }
+    }
+
+    fn shift_background(&mut self) {
+        self.dot.pattern_lo <<= 1;
+        self.dot.pattern_hi <<= 1;
+        self.dot.attr_lo <<= 1;
+        self.dot.attr_hi <<= 1;
+    }
+
+    // 8ドットごとにネームテーブル、属性、パターンの下位、上位の順に読む
+    fn fetch_background(&mut self, dot: usize) {
+        let v = self.loopy.v;
+        let fine_y = v >> 12;
+        match (dot - 1) % 8 {
+            0 => {
+                self.load_background();
+                self.dot.tile = self.nametable_read(0x2000 | (v & 0x0fff));
+                self.dot.extended = self
+                    .mapper
+                    .borrow_mut()
+                    .extended_tile(v & 0x03ff, self.dot.tile);
+            }
+            2 => {
+                let attr = self
+                    .nametable_read(0x23c0 | (v & 0x0c00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07));
+                let shift = ((v >> 4) & 0b100) | (v & 0b10);
+                self.dot.attr = match self.dot.extended {
+                    Some((_, palette)) => palette,
+                    None => (attr >> shift) & 0b11,
+                };
+            }
+            4 => {
+                self.dot.lo = match self.dot.extended {
+                    Some((tile, _)) => tile[fine_y as usize],
+                    None => self.background_pattern(fine_y),
+                };
+            }
+            6 => {
+                self.dot.hi = match self.dot.extended {
+                    Some((tile, _)) => tile[fine_y as usize + 8],
+                    None => self.background_pattern(fine_y + 8),
+                };
+            }
+            7 => self.loopy.increment_x(),
+            _ => {}
+        }
+    }
+
+    fn background_pattern(&self, offset: u16) -> u8 {
+        let addr = self.ctrl.bknd_pattern_addr() + self.dot.tile as u16 * 16 + offset;
+        self.mapper
+            .borrow_mut()
+            .chr_fetch(addr, ChrFetch::Background)
+    }
+
+    // 読み終えたタイルをシフトレジスタの下位8bitに入れる
+    fn load_background(&mut self) {
+        let fill = |bit: u8| if bit != 0 { 0xff } else { 0x00 };
+        self.dot.pattern_lo = (self.dot.pattern_lo & 0xff00) | self.dot.lo as u16;
+        self.dot.pattern_hi = (self.dot.pattern_hi & 0xff00) | self.dot.hi as u16;
+        self.dot.attr_lo = (self.dot.attr_lo & 0xff00) | fill(self.dot.attr & 0b01);
+        self.dot.attr_hi = (self.dot.attr_hi & 0xff00) | fill(self.dot.attr & 0b10);
+    }
+
+    // 描画の開始時にOAMADDRが8以上だと、OAMADDR & 0xF8からの8バイトがOAMの先頭に写る
+    fn corrupt_oam(&mut self) {
+        if !self.rendering_enabled() || self.oam_addr < 8 {
+            return;
+        }
+        let from = (self.oam_addr & 0xf8) as usize;
+        self.oam_data.copy_within(from..from + 8, 0);
+    }
+
+    // 今のラインに掛かるスプライトをOAMADDRの位置から探し、次のラインで描く。
+    // OAMADDRが4の倍数でなければずれた4バイトをスプライトとして読む。
+    // スプライト0として扱われるのは最初に読んだもの
+    fn evaluate_sprites(&mut self) {
+        self.dot.sprites.clear();
+        if self.scanline >= 240 {
+            return;
+        }
+        let line = self.scanline as usize;
+        let start = self.dot.eval_addr as usize;
+        for (n, addr) in (start..256).step_by(4).enumerate() {
+            if !self.sprite_on_line(addr, line) {
+                continue;
+            }
+            if self.dot.sprites.len() == SPRITES_PER_LINE {
+                self.status.set_sprite_overflow(true);
+                if self.sprite_limit {
+                    break;
+                }
+            }
+            let mut sprite = [0; 4];
+            for (j, b) in sprite.iter_mut().enumerate() {
+                *b = self.oam_data[(addr + j) & 0xff];
+            }
+            let top = sprite[0] as usize;
+            let (lo, hi) = self.sprite_pattern_row(&sprite, line - top);
+            self.dot.sprites.push(LineSprite {
+                x: sprite[3],
+                tile: sprite[1],
+                attr: sprite[2],
+                lo,
+                hi,
+                zero: n == 0,
+            });
+        }
+    }
+
+    // OAMのaddrの位置をY座標とするスプライトがlineに掛かるか
+    fn sprite_on_line(&self, addr: usize, line: usize) -> bool {
+        let top = self.oam_data[addr] as usize;
+        line >= top && line < top + self.ctrl.sprite_size() as usize
+    }
+
+    // ドット257で評価したラインに9個以上のスプライトがあればオーバーフロー
+    fn check_sprite_overflow(&mut self, before: usize) {
+        let line = self.scanline as usize;
+        if line >= 240 || before >= 257 || self.cycles < 257 || !self.rendering_enabled() {
+            return;
+        }
+        let on_line = (0..256)
+            .step_by(4)
+            .filter(|&addr| self.sprite_on_line(addr, line));
+        if on_line.count() > SPRITES_PER_LINE {
+            self.status.set_sprite_overflow(true);
+        }
+    }
+
+    // ドットx+1で画面のxのピクセルを描く
+    fn draw_dot(&mut self, x: usize) {
+        let mut background = None;
+        if self.mask.show_background() && (x >= 8 || self.mask.leftmost_8pxl_background()) {
+            let bit = 0x8000 >> self.loopy.x;
+            let value = ((self.dot.pattern_hi & bit != 0) as u8) << 1
+                | (self.dot.pattern_lo & bit != 0) as u8;
+            let palette =
+                ((self.dot.attr_hi & bit != 0) as u8) << 1 | (self.dot.attr_lo & bit != 0) as u8;
+            if value != 0 {
+                background = Some(palette << 2 | value);
+            }
+        }
+
+        let mut sprite = None;
+        if self.mask.show_sprites() && (x >= 8 || self.mask.leftmost_8pxl_sprite()) {
+            for s in self.dot.sprites.iter() {
+                let column = x.wrapping_sub(s.x as usize);
+                if column >= 8 {
+                    continue;
+                }
+                let bit = if s.attr & 0x40 != 0 {
+                    column
+                } else {
+                    7 - column
+                };
+                let value = (s.hi >> bit & 1) << 1 | (s.lo >> bit & 1);
+                if value != 0 {
+                    sprite = Some((*s, value));
+                    break;
+                }
+            }
+        }
+
+        if let (Some(_), Some((s, _))) = (background, sprite) {
+            if s.zero && x != 255 {
+                self.status.set_sprite_zero_hit(true);
+            }
+        }
+
+        self.dot_pixels[self.scanline as usize * 256 + x] = DotPixel {
+            backdrop: self.backdrop_color(),
+            background: background.map(|i| self.palette_table[i as usize]),
+            sprite: sprite.map(|(s, value)| {
+                let i = 0x10 | (s.attr & 0b11) << 2 | value;
+                (self.palette_table[i as usize], s.attr & 0x20 != 0)
+            }),
+            mask: self.mask.bits(),
+        };
+    }
+
+    // 各スキャンラインの開始 (ドット0) で呼ばれる。ラスター効果の確認やイベントビューア用
+    pub fn on_scanline<F: FnMut(&NesPPU) + 'static>(&mut self, hook: F) {
+        self.scanline_hook = Some(Rc::new(RefCell::new(hook)));
+    }
+
+    // フレームの終わり (プリレンダーラインの後) で呼ばれる
+    pub fn on_frame<F: FnMut(&NesPPU) + 'static>(&mut self, hook: F) {
+        self.frame_hook = Some(Rc::new(RefCell::new(hook)));
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.scanline_hook = None;
+        self.frame_hook = None;
+    }
+
+    // スキャンラインを数えるマッパーとフックに行の開始を知らせる
+    fn notify_scanline(&self) {
+        self.mapper
+            .borrow_mut()
+            .scanline(self.scanline, self.rendering_enabled());
+        if let Some(hook) = &self.scanline_hook {
+            (hook.borrow_mut())(self);
+        }
+    }
+
+    fn notify_frame(&self) {
+        if let Some(hook) = &self.frame_hook {
+            (hook.borrow_mut())(self);
+        }
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.mask.show_background() || self.mask.show_sprites()
+    }
+
+    // 描画中のvの更新をライン単位で真似る。
+    // ドット256で縦に1ライン進め、257でtの横成分を戻す。
+    // プリレンダーラインではtの縦成分も戻す
+    fn update_scroll(&mut self, before: usize) {
+        let crossed = |dot: usize| before < dot && self.cycles >= dot;
+        let (dot_256, dot_257, dot_280) = (crossed(256), crossed(257), crossed(280));
+        let line = self.scanline as usize;
+        let pre_render = self.region.pre_render_line() as usize;
+        if line < 240 && dot_256 {
+            self.line_scroll[line] = self.loopy;
+        }
+        if !self.rendering_enabled() {
+            return;
+        }
+        if line < 240 && dot_256 {
+            self.loopy.increment_y();
+        }
+        if (line < 240 || line == pre_render) && dot_257 {
+            self.loopy.copy_x();
+        }
+        if line == pre_render && dot_280 {
+            self.loopy.copy_y();
+        }
+    }
+
+    // 画面のラインを描き始めた時点の$2005/$2006の状態
+    pub fn line_scroll(&self, line: usize) -> LoopyRegister {
+        self.line_scroll[line]
+    }
+
     pub fn poll_nmi_interrupt(&mut self) -> Option<u8> {
         self.nmi_interrupt.take()
     }
 
+    // セーブステート。accuracyなどの設定と、毎フレーム描き直すdot_pixelsは含めない
+    pub fn state(&mut self, state: &mut State) {
+        state.map_u8(
+            &mut self.ctrl,
+            |r| r.bits(),
+            ControlRegister::from_bits_truncate,
+        );
+        state.map_u8(
+            &mut self.mask,
+            |r| r.bits(),
+            MaskRegister::from_bits_truncate,
+        );
+        state.map_u8(
+            &mut self.status,
+            |r| r.bits(),
+            StatusRegister::from_bits_truncate,
+        );
+        for loopy in std::iter::once(&mut self.loopy).chain(self.line_scroll.iter_mut()) {
+            state.u16(&mut loopy.v);
+            state.u16(&mut loopy.t);
+            state.u8(&mut loopy.x);
+            state.bool(&mut loopy.w);
+        }
+        state.u8(&mut self.oam_addr);
+        state.bytes(&mut self.oam_data);
+        state.bytes(&mut self.vram);
+        state.bytes(&mut self.palette_table);
+        state.u8(&mut self.internal_data_buf);
+        state.u16(&mut self.scanline);
+        state.usize(&mut self.cycles);
+        state.bool(&mut self.odd_frame);
+        state.bool(&mut self.suppress_vblank);
+        state.usize(&mut self.warmup_dots);
+        state.option(&mut self.nmi_interrupt, |state, value| state.u8(value));
+
+        let dot = &mut self.dot;
+        for value in [&mut dot.tile, &mut dot.attr, &mut dot.lo, &mut dot.hi] {
+            state.u8(value);
+        }
+        state.option(&mut dot.extended, |state, (tile, palette)| {
+            state.bytes(tile);
+            state.u8(palette);
+        });
+        for value in [
+            &mut dot.pattern_lo,
+            &mut dot.pattern_hi,
+            &mut dot.attr_lo,
+            &mut dot.attr_hi,
+        ] {
+            state.u16(value);
+        }
+        state.vec(&mut dot.sprites, |state, sprite| {
+            state.u8(&mut sprite.x);
+            state.u8(&mut sprite.tile);
+            state.u8(&mut sprite.attr);
+            state.u8(&mut sprite.lo);
+            state.u8(&mut sprite.hi);
+            state.bool(&mut sprite.zero);
+        });
+        state.u8(&mut dot.eval_addr);
+        state.bool(&mut dot.a12);
+        state.u64(&mut dot.a12_low_since);
+        state.u64(&mut dot.clock);
+    }
+
+    // 電源投入直後の期間を始める。falseなら最初から書き込める (デバッグ用)
+    pub fn set_warmup(&mut self, enabled: bool) {
+        self.warmup_dots = if enabled { WARMUP_CPU_CYCLES * 3 } else { 0 };
+    }
+
+    pub fn is_warming_up(&self) -> bool {
+        self.warmup_dots > 0
+    }
+
     pub fn write_to_ppu_addr(&mut self, value: u8) {
-        self.addr.update(value);
+        if self.is_warming_up() {
+            return;
+        }
+        self.loopy.write_addr(value);
     }
 
     pub fn write_to_ctrl(&mut self, value: u8) {
+        if self.is_warming_up() {
+            return;
+        }
         let before_nmi_status = self.ctrl.generate_vblank_nmi();
         self.ctrl.update(value);
+        self.loopy.write_ctrl(value);
         if !before_nmi_status && self.ctrl.generate_vblank_nmi() && self.status.is_in_vblank() {
             self.nmi_interrupt = Some(1);
         }
     }
 
     pub fn write_to_mask(&mut self, value: u8) {
+        if self.is_warming_up() {
+            return;
+        }
         self.mask.update(value);
     }
 
@@ -112,6 +833,15 @@ impl NesPPU {
     }
 
     pub fn write_to_oam_data(&mut self, value: u8) {
+        // 描画中は書き込まれず、OAMADDRの上位6ビットだけが進む
+        let line = self.scanline;
+        if self.accuracy == Accuracy::Accurate
+            && self.rendering_enabled()
+            && (line < 240 || line == self.region.pre_render_line())
+        {
+            self.oam_addr = self.oam_addr.wrapping_add(4);
+            return;
+        }
         self.oam_data[self.oam_addr as usize] = value;
         self.oam_addr = self.oam_addr.wrapping_add(1);
     }
@@ -121,95 +851,203 @@ impl NesPPU {
     }
 
     pub fn write_to_scroll(&mut self, value: u8) {
-        self.scroll.write(value);
+        if self.is_warming_up() {
+            return;
+        }
+        self.loopy.write_scroll(value);
     }
 
     pub fn write_oam_dma(&mut self, data: &[u8; 256]) {
         for x in data.iter() {
-            self.oam_data[self.oam_addr as usize] = *x;
-            self.oam_addr = self.oam_addr.wrapping_add(1);
+            self.write_oam_dma_byte(*x);
         }
     }
 
+    pub fn write_oam_dma_byte(&mut self, value: u8) {
+        self.oam_data[self.oam_addr as usize] = value;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
     pub fn read_status(&mut self) -> u8 {
-        let status = self.status.snapshot();
+        let mut status = self.status.snapshot();
+        // ドット1でVBlankフラグが立つのと競合した場合 (次に進むドットで判定する)
+        if self.scanline == self.region.vblank_line() {
+            match self.cycles {
+                // 1ドット前に読むと0が読め、このフレームはフラグもNMIも立たない
+                0 => self.suppress_vblank = true,
+                // 同時か1ドット後に読むと1が読めるが、NMIは起きない
+                1 => {
+                    status |= StatusRegister::VBLANK_STARTED.bits();
+                    self.suppress_vblank = true;
+                }
+                2 => self.nmi_interrupt = None,
+                _ => {}
+            }
+        }
         self.status.reset_vblank_status();
-        self.addr.reset_latch();
-        self.scroll.reset_latch();
+        self.loopy.reset_latch();
         status
     }
 
     pub fn read_data(&mut self) -> u8 {
-        let addr = self.addr.get();
+        let addr = self.loopy.addr();
         self.increment_vram_addr();
 
         match addr {
             0..=0x1fff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.chr_rom[addr as usize];
+                self.internal_data_buf = self.chr_read(addr);
                 result
             }
             0x2000..=0x2fff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                self.internal_data_buf = self.nametable_read(addr);
                 result
             }
-            0x3000..=0x3eff => panic!(
-                "addr space 0x3000..0x3eff is not expected to be used , requested = {}",
-                addr
-            ),
-            0x3f00..=0x3fff => self.palette_table[(addr - 0x3f00) as usize],
+            // $3000-$3EFFはネームテーブルのミラー
+            0x3000..=0x3eff => {
+                let result = self.internal_data_buf;
+                self.internal_data_buf = self.nametable_read(addr - 0x1000);
+                result
+            }
+            // パレットはバッファを通さずに読めるが、バッファには下にあるネームテーブルの値が入る
+            0x3f00..=0x3fff => {
+                self.internal_data_buf = self.nametable_read(addr - 0x1000);
+                self.palette_table[palette_index(addr)]
+            }
             _ => panic!("unexpected access to mirrored space {}", addr),
         }
     }
 
     pub fn write_to_data(&mut self, value: u8) {
-        let addr = self.addr.get();
+        let addr = self.loopy.addr();
         match addr {
-            0..=0x1fff => println!("attempt to write to chr rom space {}", addr),
-            0x2000..=0x2fff => {
-                self.vram[self.mirror_vram_addr(addr) as usize] = value;
-            }
-            0x3000..=0x3eff => unimplemented!("addr {} shouldn't be used in reallity", addr),
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize] = value;
-            }
-            0x3f00..=0x3fff => {
-                self.palette_table[(addr - 0x3f00) as usize] = value;
-            }
+            0..=0x1fff => self.mapper.borrow_mut().chr_write(addr, value),
+            0x2000..=0x2fff => self.nametable_write(addr, value),
+            0x3000..=0x3eff => self.nametable_write(addr - 0x1000, value),
+            // パレットは6bit
+            0x3f00..=0x3fff => self.palette_table[palette_index(addr)] = value & 0x3f,
             _ => panic!("unexpacted access to mirrored space {}", addr),
         }
         self.increment_vram_addr();
     }
 
-    fn mirror_vram_addr(&self, addr: u16) -> u16 {
-        let mirrored_vram = addr & 0b10_1111_1111_1111;
-        let vram_index = mirrored_vram - 0x2000;
-        let name_table = vram_index / 0x400;
-        match (&self.mirroring, name_table) {
-            (Mirroring::VERTICAL, 2) | (Mirroring::VERTICAL, 3) => vram_index - 0x800,
-            (Mirroring::HORIZONTAL, 2) => vram_index - 0x400,
-            (Mirroring::HORIZONTAL, 1) => vram_index - 0x400,
-            (Mirroring::HORIZONTAL, 3) => vram_index - 0x800,
-            _ => vram_index,
+    // $2000-$2FFF をCIRAMかカートリッジ側に振り分ける
+    fn nametable_index(&self, addr: u16) -> Option<usize> {
+        let table = ((addr - 0x2000) / 0x400 % 4) as u8;
+        let nametable = self.mapper.borrow().nametable(table);
+        match nametable {
+            Nametable::Ciram(page) => Some(page as usize * 0x400 + (addr & 0x3ff) as usize),
+            Nametable::Mapper => None,
+        }
+    }
+
+    fn nametable_read(&self, addr: u16) -> u8 {
+        match self.nametable_index(addr) {
+            Some(index) => self.vram[index],
+            None => self.mapper.borrow_mut().nametable_read(addr),
+        }
+    }
+
+    fn nametable_write(&mut self, addr: u16, value: u8) {
+        match self.nametable_index(addr) {
+            Some(index) => self.vram[index] = value,
+            None => self.mapper.borrow_mut().nametable_write(addr, value),
         }
     }
 
     fn increment_vram_addr(&mut self) {
-        self.addr.increment(self.ctrl.vram_addr_increment());
+        self.loopy.increment(self.ctrl.vram_addr_increment());
     }
 
-    fn is_sprite_0_hit(&self, cycle: usize) -> bool {
-        let y = self.oam_data[0] as usize;
-        let x = self.oam_data[3] as usize;
-        (y == self.scanline as usize) && x <= cycle && self.mask.show_sprites()
+    // 今のラインでドットがヒット位置を過ぎていればフラグを立てる
+    fn check_sprite_zero_hit(&mut self) {
+        if self.status.contains(StatusRegister::SPRITE_ZERO_HIT) {
+            return;
+        }
+        if let Some(dot) = self.sprite_zero_hit_dot() {
+            if dot <= self.cycles {
+                self.status.set_sprite_zero_hit(true);
+            }
+        }
+    }
+
+    // スプライト0の不透明なピクセルが不透明な背景と重なる最初のドット。
+    // x座標のピクセルはドットx+1で描かれ、フラグはその次のドットで立つ
+    fn sprite_zero_hit_dot(&self) -> Option<usize> {
+        if !self.mask.show_background() || !self.mask.show_sprites() || self.scanline >= 240 {
+            return None;
+        }
+        let line = self.scanline as usize;
+        let top = self.oam_data[0] as usize;
+        if line < top || line >= top + self.ctrl.sprite_size() as usize {
+            return None;
+        }
+        let attr = self.oam_data[2];
+        let (upper, lower) = self.sprite_pattern_row(&self.oam_data[0..4], line - top);
+        let clip_left = !self.mask.leftmost_8pxl_background() || !self.mask.leftmost_8pxl_sprite();
+
+        for column in 0..8 {
+            let x = self.oam_data[3] as usize + column;
+            // x=255では起きない
+            if x >= 255 {
+                break;
+            }
+            if x < 8 && clip_left {
+                continue;
+            }
+            let bit = if attr & 0x40 != 0 { column } else { 7 - column };
+            if (upper | lower) >> bit & 1 == 0 {
+                continue;
+            }
+            if self.background_opaque(x) {
+                return Some(x + 2);
+            }
+        }
+        None
+    }
+
+    // 今のラインで、画面上のxの背景ピクセルが不透明か
+    fn background_opaque(&self, x: usize) -> bool {
+        let (table, scroll_x, y) = self.loopy.position();
+        let mut table = table as u16;
+        let mut x = x + scroll_x;
+        if x >= 256 {
+            table ^= 1;
+            x -= 256;
+        }
+        let tile = self.nametable_read(0x2000 + table * 0x400 + (y / 8 * 32 + x / 8) as u16);
+        let addr = self.ctrl.bknd_pattern_addr() + tile as u16 * 16 + (y % 8) as u16;
+        let pixels = self.chr_read(addr) | self.chr_read(addr + 8);
+        pixels >> (7 - x % 8) & 1 != 0
+    }
+}
+
+// $3F00-$3FFFはパレットの32バイトのミラー。
+// $3F10/$3F14/$3F18/$3F1Cは$3F00/$3F04/$3F08/$3F0Cと同じ場所
+fn palette_index(addr: u16) -> usize {
+    let index = (addr & 0x1f) as usize;
+    if index & 0x13 == 0x10 {
+        index & 0x0f
+    } else {
+        index
     }
 }
 
+// 一度も描画していない間は、スクロール0で描いたことにしておく
+fn unscrolled_lines() -> [LoopyRegister; 240] {
+    let mut lines = [LoopyRegister::new(); 240];
+    for (y, line) in lines.iter_mut().enumerate() {
+        line.v = ((y as u16 % 8) << 12) | ((y as u16 / 8) << 5);
+    }
+    lines
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
+    use crate::mapper::Mapper;
+    use crate::mapper_fme7::Fme7;
 
     #[test]
     fn test_ppu_vram_writes() {
@@ -221,6 +1059,33 @@ pub mod test {
         assert_eq!(ppu.vram[0x0305], 0x66);
     }
 
+    #[test]
+    fn test_chr_ram_writes() {
+        // CHR ROMが0バンクのカートリッジは8KBのCHR RAMを持つ
+        let mut ppu = NesPPU::new(vec![], Mirroring::HORIZONTAL);
+        ppu.write_to_ppu_addr(0x1f);
+        ppu.write_to_ppu_addr(0xfe);
+        ppu.write_to_data(0x12);
+        ppu.write_to_data(0x34);
+        assert_eq!(
+            ppu.chr_tile(0x1000, 0xff, ChrFetch::Background)[14..],
+            [0x12, 0x34]
+        );
+
+        ppu.write_to_ppu_addr(0x1f);
+        ppu.write_to_ppu_addr(0xfe);
+        ppu.read_data(); //load_into_buffer
+        assert_eq!(ppu.read_data(), 0x12);
+        assert_eq!(ppu.read_data(), 0x34);
+
+        // CHR ROMには書き込めない
+        let mut ppu = NesPPU::new(vec![7; 0x2000], Mirroring::HORIZONTAL);
+        ppu.write_to_ppu_addr(0x00);
+        ppu.write_to_ppu_addr(0x00);
+        ppu.write_to_data(0x12);
+        assert_eq!(ppu.chr_read(0), 7);
+    }
+
     #[test]
     fn test_ppu_vram_reads() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -231,7 +1096,7 @@ pub mod test {
         ppu.write_to_ppu_addr(0x05);
 
         ppu.read_data(); //load_into_buffer
-        assert_eq!(ppu.addr.get(), 0x2306);
+        assert_eq!(ppu.loopy.addr(), 0x2306);
         assert_eq!(ppu.read_data(), 0x66);
     }
 
@@ -326,6 +1191,106 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x77); //read from B
     }
 
+    #[test]
+    fn test_vram_four_screen() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::FOUR_SCREEN);
+        for (i, table) in [0x20, 0x24, 0x28, 0x2c].iter().enumerate() {
+            ppu.write_to_ppu_addr(*table);
+            ppu.write_to_ppu_addr(0x05);
+            ppu.write_to_data(i as u8 + 1);
+        }
+        for (i, table) in [0x20, 0x24, 0x28, 0x2c].iter().enumerate() {
+            assert_eq!(
+                ppu.dump_nametable(i as u8)[5],
+                i as u8 + 1,
+                "table {:02X}",
+                table
+            );
+        }
+        assert_eq!(ppu.vram[0x0c05], 4);
+    }
+
+    // A12の立ち上がりを数えるだけのマッパー
+    struct A12Counter {
+        rises: u32,
+    }
+
+    impl Mapper for A12Counter {
+        fn prg_read(&mut self, _addr: u16) -> Option<u8> {
+            None
+        }
+        fn prg_write(&mut self, _addr: u16, _data: u8) -> bool {
+            false
+        }
+        fn chr_read(&mut self, _addr: u16) -> u8 {
+            0
+        }
+        fn chr_write(&mut self, _addr: u16, _data: u8) {}
+        fn mirroring(&self) -> Mirroring {
+            Mirroring::VERTICAL
+        }
+        fn state(&mut self, _state: &mut State) {}
+        fn a12_rise(&mut self) {
+            self.rises += 1;
+        }
+    }
+
+    #[test]
+    fn test_a12_rise() {
+        // (PPUCTRL, 1フレームの立ち上がりの数)。背景とスプライトで違うテーブルを使うと
+        // 描画する240ラインとプリレンダーラインで1回ずつ。背景が$1000なら
+        // VBlankの後の最初のフェッチでもう1回
+        let cases = [
+            (0b0000_1000, 241),
+            (0b0001_0000, 242),
+            (0b0010_0000, 241),
+            (0b0001_1000, 1),
+            (0b0000_0000, 0),
+        ];
+        for accuracy in [Accuracy::Fast, Accuracy::Accurate] {
+            for (ctrl, rises) in cases {
+                let mapper = Rc::new(RefCell::new(A12Counter { rises: 0 }));
+                let mut ppu = NesPPU::with_mapper(mapper.clone());
+                ppu.accuracy = accuracy;
+                ppu.write_to_ctrl(ctrl);
+                ppu.mask.update(0b0001_1000);
+                // スプライトはすべて画面外 (8x16の空きスロットはタイル$FF)
+                ppu.oam_data = [0xff; 256];
+                while !ppu.tick(1) {}
+                assert_eq!(
+                    mapper.borrow().rises,
+                    rises,
+                    "{:?} ctrl {:08b}",
+                    accuracy,
+                    ctrl
+                );
+            }
+        }
+    }
+
+    // マッパーのレジスタでミラーリングを切り替えると、すぐにPPUから見える
+    #[test]
+    fn test_mirroring_changed_by_mapper() {
+        let mapper = Rc::new(RefCell::new(Fme7::new(vec![0; 0x8000], vec![0; 0x2000])));
+        let mut ppu = NesPPU::with_mapper(mapper.clone());
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+
+        // 垂直: $2800が$2000のミラー
+        mapper.borrow_mut().prg_write(0x8000, 0x0c);
+        mapper.borrow_mut().prg_write(0xa000, 0);
+        assert_eq!(ppu.mirroring(), Mirroring::VERTICAL);
+        assert_eq!(ppu.dump_nametable(2)[5], 0x66);
+        assert_eq!(ppu.dump_nametable(1)[5], 0);
+
+        // 水平: $2400が$2000のミラー
+        mapper.borrow_mut().prg_write(0xa000, 1);
+        assert_eq!(ppu.mirroring(), Mirroring::HORIZONTAL);
+        assert_eq!(ppu.dump_nametable(1)[5], 0x66);
+        assert_eq!(ppu.dump_nametable(2)[5], 0);
+    }
+
     #[test]
     fn test_read_status_resets_latch() {
         let mut ppu = NesPPU::new_empty_rom();
@@ -405,4 +1370,468 @@ pub mod test {
         ppu.write_to_oam_addr(0x11);
         ppu.write_to_oam_addr(0x66);
     }
+
+    // 背景のタイル1は全面不透明、スプライトのタイル2は右半分だけ不透明
+    fn sprite_zero_ppu() -> NesPPU {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[0x10..0x18].fill(0xff);
+        chr_rom[0x20..0x28].fill(0x0f);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.mask.update(0b0001_1110);
+        ppu.oam_data[0..4].copy_from_slice(&[10, 2, 0, 20]);
+        // (24, 8) のタイルだけ不透明
+        ppu.vram[32 + 3] = 1;
+        ppu
+    }
+
+    fn tick_to(ppu: &mut NesPPU, scanline: u16, dot: usize) {
+        while ppu.scanline() < scanline || ppu.cycle() < dot {
+            ppu.tick(1);
+        }
+    }
+
+    // セーブステートから戻すと、描画の途中からでも同じように進む
+    #[test]
+    fn test_state_round_trip() {
+        let mut ppu = sprite_zero_ppu();
+        ppu.accuracy = Accuracy::Accurate;
+        ppu.write_to_ctrl(0x80);
+        tick_to(&mut ppu, 9, 300);
+        let mut saved = vec![];
+        ppu.state(&mut State::save(&mut saved));
+
+        let run = |ppu: &mut NesPPU| {
+            let mut status = vec![];
+            for _ in 0..3000 {
+                ppu.tick(1);
+                status.push(ppu.status.bits());
+            }
+            (ppu.scanline(), ppu.cycle(), status, ppu.dot_pixels.clone())
+        };
+        let first = run(&mut ppu);
+        ppu.vram[32 + 3] = 0;
+        ppu.oam_data[0] = 100;
+        ppu.write_to_ctrl(0);
+
+        let mut state = State::load(&saved);
+        ppu.state(&mut state);
+        state.finish().unwrap();
+        assert!(run(&mut ppu) == first);
+    }
+
+    #[test]
+    fn test_sprite_zero_hit_dot() {
+        let mut ppu = sprite_zero_ppu();
+        // スプライトの不透明な部分はx=24から。ドット26でヒット
+        tick_to(&mut ppu, 10, 25);
+        assert_eq!((ppu.scanline(), ppu.cycle()), (10, 25));
+        assert_eq!(ppu.read_status() & 0x40, 0);
+        ppu.tick(1);
+        assert_eq!(ppu.read_status() & 0x40, 0x40);
+
+        // VBlank中も残り、プリレンダーラインで消える
+        tick_to(&mut ppu, 245, 0);
+        assert_eq!(ppu.read_status() & 0x40, 0x40);
+        tick_to(&mut ppu, 261, 340);
+        ppu.tick(1);
+        assert_eq!(ppu.scanline(), 0);
+        assert_eq!(ppu.read_status() & 0x40, 0);
+    }
+
+    #[test]
+    fn test_sprite_zero_hit_needs_opaque_overlap() {
+        // 背景が透明
+        let mut ppu = sprite_zero_ppu();
+        ppu.vram[32 + 3] = 0;
+        tick_to(&mut ppu, 20, 0);
+        assert_eq!(ppu.read_status() & 0x40, 0);
+
+        // 背景の表示が無効
+        let mut ppu = sprite_zero_ppu();
+        ppu.mask.update(0b0001_0110);
+        tick_to(&mut ppu, 20, 0);
+        assert_eq!(ppu.read_status() & 0x40, 0);
+
+        // 左端8ピクセルを隠していると、その範囲では起きない
+        let mut ppu = sprite_zero_ppu();
+        ppu.oam_data[3] = 0;
+        ppu.vram[32] = 1;
+        ppu.mask.update(0b0001_1100);
+        tick_to(&mut ppu, 20, 0);
+        assert_eq!(ppu.read_status() & 0x40, 0);
+        ppu.mask.update(0b0001_1110);
+        ppu.oam_data[0] = 30;
+        ppu.vram[3 * 32] = 1;
+        tick_to(&mut ppu, 40, 0);
+        assert_eq!(ppu.read_status() & 0x40, 0x40);
+    }
+
+    #[test]
+    fn test_sprite_zero_hit_with_scroll() {
+        let mut ppu = sprite_zero_ppu();
+        ppu.vram[32 + 3] = 0;
+        // スクロール8で (32, 8) のタイルがx=24に来る
+        ppu.vram[32 + 4] = 1;
+        ppu.write_to_scroll(8);
+        ppu.write_to_scroll(0);
+        tick_to(&mut ppu, 20, 0);
+        assert_eq!(ppu.read_status() & 0x40, 0x40);
+    }
+
+    #[test]
+    fn test_mid_frame_scroll() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.mask.update(0b0000_1000);
+        tick_to(&mut ppu, 100, 0);
+        // 横スクロールの変更はドット257で次のラインに反映される
+        ppu.write_to_scroll(16);
+        ppu.write_to_scroll(0);
+        tick_to(&mut ppu, 102, 0);
+        assert_eq!(ppu.line_scroll(99).position(), (0, 0, 99));
+        assert_eq!(ppu.line_scroll(100).position(), (0, 0, 100));
+        assert_eq!(ppu.line_scroll(101).position(), (0, 16, 101));
+
+        // $2006の2回目の書き込みはvを直接書き換えるので縦の位置も変わる
+        ppu.write_to_ppu_addr(0x08);
+        ppu.write_to_ppu_addr(0x00);
+        tick_to(&mut ppu, 104, 0);
+        assert_eq!(ppu.line_scroll(102).position(), (2, 0, 0));
+        assert_eq!(ppu.line_scroll(103).position(), (2, 0, 1));
+
+        // 次のフレームはプリレンダーラインでtから始め直す
+        tick_to(&mut ppu, 261, 340);
+        ppu.tick(1);
+        tick_to(&mut ppu, 2, 0);
+        assert_eq!(ppu.line_scroll(0).position(), (2, 0, 0));
+        assert_eq!(ppu.line_scroll(1).position(), (2, 0, 1));
+    }
+
+    #[test]
+    fn test_accurate_dots() {
+        let mut ppu = sprite_zero_ppu();
+        ppu.accuracy = Accuracy::Accurate;
+        // スプライトは1ライン下に描かれる。x=24のピクセルはドット25
+        tick_to(&mut ppu, 11, 25);
+        assert_eq!(ppu.read_status() & 0x40, 0);
+        ppu.tick(1);
+        assert_eq!(ppu.read_status() & 0x40, 0x40);
+        assert!(ppu.dot_pixels[11 * 256 + 24].sprite.is_some());
+        assert!(ppu.dot_pixels[10 * 256 + 24].sprite.is_none());
+        assert!(ppu.dot_pixels[11 * 256 + 24].background.is_some());
+
+        // VBlankはライン241のドット1で立ち、プリレンダーラインのドット1で消える
+        tick_to(&mut ppu, 241, 1);
+        assert!(!ppu.status.is_in_vblank());
+        ppu.tick(1);
+        assert!(ppu.status.is_in_vblank());
+        tick_to(&mut ppu, 261, 1);
+        ppu.tick(1);
+        assert!(!ppu.status.is_in_vblank());
+        assert_eq!(ppu.status.snapshot() & 0x40, 0);
+
+        tick_to(&mut ppu, 261, 340);
+        assert!(ppu.tick(1));
+        assert_eq!((ppu.scanline(), ppu.cycle()), (0, 0));
+    }
+
+    #[test]
+    fn test_odd_frame_skip() {
+        fn frame_dots(ppu: &mut NesPPU) -> usize {
+            let mut dots = 1;
+            while !ppu.tick(1) {
+                dots += 1;
+            }
+            dots
+        }
+        for accuracy in [Accuracy::Fast, Accuracy::Accurate] {
+            let mut ppu = NesPPU::new_empty_rom();
+            ppu.accuracy = accuracy;
+            assert_eq!(frame_dots(&mut ppu), 262 * 341);
+            assert_eq!(frame_dots(&mut ppu), 262 * 341);
+
+            // 描画中は奇数フレームだけ1ドット短い
+            ppu.mask.update(0b0000_1000);
+            assert_eq!(frame_dots(&mut ppu), 262 * 341);
+            assert_eq!(frame_dots(&mut ppu), 262 * 341 - 1);
+            assert_eq!(frame_dots(&mut ppu), 262 * 341);
+        }
+    }
+
+    #[test]
+    fn test_vblank_race() {
+        for accuracy in [Accuracy::Fast, Accuracy::Accurate] {
+            let new_ppu = || {
+                let mut ppu = NesPPU::new_empty_rom();
+                ppu.accuracy = accuracy;
+                ppu.write_to_ctrl(0x80);
+                ppu
+            };
+
+            // 1ドット前: 0が読め、フラグもNMIも立たない
+            let mut ppu = new_ppu();
+            tick_to(&mut ppu, 241, 0);
+            assert_eq!(ppu.read_status() & 0x80, 0);
+            tick_to(&mut ppu, 242, 0);
+            assert!(!ppu.status.is_in_vblank());
+            assert!(ppu.poll_nmi_interrupt().is_none());
+
+            // 同時と1ドット後: 1が読めるが、NMIは起きない
+            for dot in [1, 2] {
+                let mut ppu = new_ppu();
+                tick_to(&mut ppu, 241, dot);
+                assert_eq!(ppu.read_status() & 0x80, 0x80);
+                tick_to(&mut ppu, 242, 0);
+                assert!(!ppu.status.is_in_vblank());
+                assert!(ppu.poll_nmi_interrupt().is_none());
+            }
+
+            // 2ドット後は普通に読める
+            let mut ppu = new_ppu();
+            tick_to(&mut ppu, 241, 3);
+            assert_eq!(ppu.read_status() & 0x80, 0x80);
+            assert!(ppu.poll_nmi_interrupt().is_some());
+
+            // 抑制されるのはそのフレームだけ
+            let mut ppu = new_ppu();
+            tick_to(&mut ppu, 241, 0);
+            ppu.read_status();
+            tick_to(&mut ppu, 261, 340);
+            ppu.tick(1);
+            tick_to(&mut ppu, 241, 3);
+            assert!(ppu.status.is_in_vblank());
+            assert!(ppu.poll_nmi_interrupt().is_some());
+        }
+    }
+
+    #[test]
+    fn test_palette_read_write() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.vram[0x705] = 0x66;
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x10);
+        ppu.write_to_data(0x21);
+        ppu.write_to_data(0x22);
+        assert_eq!(ppu.palette_table[0], 0x21);
+        assert_eq!(ppu.palette_table[0x11], 0x22);
+
+        // $3F24は$3F04のミラー。$3F14は$3F04とは別
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x24);
+        ppu.write_to_data(0xff);
+        assert_eq!(ppu.palette_table[4], 0x3f);
+        assert_eq!(ppu.palette_table[0x14], 0);
+
+        // パレットはすぐ読めて、バッファには下のネームテーブル ($2F05) の値が入る
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x12);
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x05);
+        assert_eq!(ppu.read_data(), 0x12);
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x00);
+        assert_eq!(ppu.read_data(), 0x66);
+    }
+
+    #[test]
+    fn test_backdrop_color() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[0x0d] = 0x21;
+        assert_eq!(ppu.backdrop_color(), 0x0f);
+        // 描画していない間にvがパレットを指していると、その色が出る
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x0d);
+        assert_eq!(ppu.backdrop_color(), 0x21);
+        ppu.mask.update(0b0000_1000);
+        assert_eq!(ppu.backdrop_color(), 0x0f);
+    }
+
+    #[test]
+    fn test_warmup_ignores_writes() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.set_warmup(true);
+        ppu.write_to_ctrl(0x80);
+        ppu.write_to_mask(0x1e);
+        ppu.write_to_scroll(0x10);
+        ppu.write_to_ppu_addr(0x21);
+        assert_eq!(ppu.ctrl.bits(), 0);
+        assert_eq!(ppu.mask.bits(), 0);
+        assert_eq!(ppu.loopy, LoopyRegister::new());
+
+        // OAMとデータポートは書ける
+        ppu.write_to_oam_data(0x55);
+        assert_eq!(ppu.oam_data[0], 0x55);
+
+        for _ in 0..WARMUP_CPU_CYCLES {
+            ppu.tick(3);
+        }
+        assert!(!ppu.is_warming_up());
+        ppu.write_to_ctrl(0x80);
+        assert_eq!(ppu.ctrl.bits(), 0x80);
+    }
+
+    #[test]
+    fn test_hooks() {
+        for accuracy in [Accuracy::Fast, Accuracy::Accurate] {
+            let mut ppu = NesPPU::new_empty_rom();
+            ppu.accuracy = accuracy;
+            let lines = Rc::new(RefCell::new(vec![]));
+            let frames = Rc::new(RefCell::new(0));
+            let sampled = lines.clone();
+            ppu.on_scanline(move |ppu| {
+                sampled
+                    .borrow_mut()
+                    .push((ppu.scanline(), ppu.loopy.scroll_x()))
+            });
+            let counted = frames.clone();
+            ppu.on_frame(move |ppu| {
+                assert_eq!(ppu.scanline(), 0);
+                *counted.borrow_mut() += 1;
+            });
+
+            tick_to(&mut ppu, 100, 0);
+            ppu.write_to_scroll(0x20);
+            while !ppu.tick(1) {}
+            assert_eq!(*frames.borrow(), 1);
+            let lines = lines.borrow();
+            // ライン1から261と、次のフレームのライン0
+            assert_eq!(lines.len(), 262);
+            assert_eq!(lines[0], (1, 0));
+            assert_eq!(lines[99], (100, 0));
+            assert_eq!(lines[100], (101, 0x20));
+            assert_eq!(lines[261], (0, 0x20));
+        }
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut chr_rom = vec![0; 0x2000];
+        // $1000側のタイル17の左上だけ色3
+        chr_rom[0x1110] = 0x80;
+        chr_rom[0x1118] = 0x80;
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::VERTICAL);
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[3] = 0x30;
+        let image = ppu.dump_pattern_table(1);
+        assert_eq!(image.len(), 128 * 128 * 3);
+        let (r, g, b) = renderer_palette::default_palette().rgb(0x30, &ppu.mask);
+        let at = |x: usize, y: usize| image[(y * 128 + x) * 3..(y * 128 + x) * 3 + 3].to_vec();
+        assert_eq!(at(8, 8), vec![r, g, b]);
+        assert_eq!(at(9, 8), at(0, 0));
+        assert!(ppu
+            .dump_pattern_table(0)
+            .iter()
+            .zip(image.iter())
+            .any(|(a, b)| a != b));
+
+        ppu.vram[0x400 + 5] = 0x42;
+        assert_eq!(ppu.dump_nametable(1)[5], 0x42);
+        assert_eq!(ppu.dump_nametable(3)[5], 0x42);
+        assert_eq!(ppu.palette_snapshot()[3], 0x30);
+
+        ppu.oam_data[4..8].copy_from_slice(&[10, 0x21, 0b1110_0010, 200]);
+        let sprites = ppu.sprites();
+        assert_eq!(sprites.len(), 64);
+        assert_eq!(
+            sprites[1],
+            Sprite {
+                x: 200,
+                y: 10,
+                tile: 0x21,
+                palette: 2,
+                behind_background: true,
+                flip_horizontal: true,
+                flip_vertical: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_region_frames() {
+        for accuracy in [Accuracy::Fast, Accuracy::Accurate] {
+            for (region, vblank_line) in [(Region::Pal, 241), (Region::Dendy, 291)] {
+                let mut ppu = NesPPU::new_empty_rom();
+                ppu.accuracy = accuracy;
+                ppu.region = region;
+                ppu.mask.update(0b0000_1000);
+                tick_to(&mut ppu, vblank_line, 0);
+                assert!(!ppu.status.is_in_vblank());
+                ppu.tick(2);
+                assert!(ppu.status.is_in_vblank());
+
+                // 奇数フレームでもドットを飛ばさない
+                for _ in 0..2 {
+                    tick_to(&mut ppu, 311, 340);
+                    assert!(ppu.tick(1));
+                }
+                assert!(!ppu.status.is_in_vblank());
+            }
+        }
+    }
+
+    #[test]
+    fn test_sprite_overflow() {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[0x10..0x18].fill(0xff);
+        for accuracy in [Accuracy::Fast, Accuracy::Accurate] {
+            for sprite_limit in [true, false] {
+                let mut ppu = NesPPU::new(chr_rom.clone(), Mirroring::HORIZONTAL);
+                ppu.accuracy = accuracy;
+                ppu.sprite_limit = sprite_limit;
+                ppu.mask.update(0b0001_1000);
+                ppu.oam_data.fill(0xff);
+                for i in 0..9 {
+                    ppu.oam_data[i * 4..i * 4 + 4].copy_from_slice(&[50, 1, 0, i as u8 * 16]);
+                }
+                tick_to(&mut ppu, 50, 256);
+                assert!(ppu.status.snapshot() & 0x20 == 0);
+                tick_to(&mut ppu, 50, 258);
+                // 制限を外してもフラグは同じように立つ
+                assert!(ppu.status.snapshot() & 0x20 != 0);
+
+                if accuracy == Accuracy::Accurate {
+                    tick_to(&mut ppu, 52, 0);
+                    let ninth = ppu.dot_pixels[51 * 256 + 8 * 16].sprite;
+                    assert_eq!(ninth.is_some(), !sprite_limit);
+                    assert!(ppu.dot_pixels[51 * 256 + 7 * 16].sprite.is_some());
+                }
+                tick_to(&mut ppu, 261, 340);
+                ppu.tick(1);
+                assert!(ppu.status.snapshot() & 0x20 == 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_oam_addr_quirks() {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[0x10..0x18].fill(0xff);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.accuracy = Accuracy::Accurate;
+        ppu.mask.update(0b0001_1110);
+        ppu.oam_data.fill(0xff);
+        ppu.oam_data[0..8].copy_from_slice(&[50, 1, 0, 0, 50, 1, 0, 16]);
+
+        // ライン50の評価がOAMADDR=4から始まると、スプライト0は見つからない
+        tick_to(&mut ppu, 49, 330);
+        ppu.write_to_oam_addr(4);
+        tick_to(&mut ppu, 52, 0);
+        assert!(ppu.dot_pixels[51 * 256].sprite.is_none());
+        assert!(ppu.dot_pixels[51 * 256 + 16].sprite.is_some());
+        // ドット257から320の間に0に戻る
+        assert_eq!(ppu.oam_addr, 0);
+
+        // 描画中の$2004への書き込みはOAMADDRを4進めるだけ
+        ppu.write_to_oam_data(0x12);
+        assert_eq!(ppu.oam_addr, 4);
+        assert_eq!(ppu.oam_data[0], 50);
+
+        // 描画の開始時にOAMADDRが8以上ならその8バイトが先頭に写る
+        ppu.oam_data[0x10..0x18].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        tick_to(&mut ppu, 250, 0);
+        ppu.write_to_oam_addr(0x13);
+        tick_to(&mut ppu, 261, 2);
+        assert_eq!(ppu.oam_data[0..8], [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
 }
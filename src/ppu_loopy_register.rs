@@ -0,0 +1,201 @@
+// $2005/$2006で共有されるPPU内部のレジスタ (loopyの命名)
+//   v, t: 0yyy NNYY YYYX XXXX
+//         (fine Y, ネームテーブル, coarse Y, coarse X)
+//   x: fine X (3bit)
+//   w: 1回目/2回目の書き込みを切り替えるラッチ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopyRegister {
+    pub v: u16,
+    pub t: u16,
+    pub x: u8,
+    pub w: bool,
+}
+
+impl LoopyRegister {
+    pub fn new() -> Self {
+        LoopyRegister {
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+        }
+    }
+
+    // $2000の下位2bitはtのネームテーブル選択に入る
+    pub fn write_ctrl(&mut self, data: u8) {
+        self.t = (self.t & !0x0c00) | ((data as u16 & 0b11) << 10);
+    }
+
+    pub fn write_scroll(&mut self, data: u8) {
+        if !self.w {
+            self.t = (self.t & !0x001f) | (data as u16 >> 3);
+            self.x = data & 0b111;
+        } else {
+            self.t =
+                (self.t & !0x73e0) | ((data as u16 & 0b111) << 12) | ((data as u16 & 0xf8) << 2);
+        }
+        self.w = !self.w;
+    }
+
+    pub fn write_addr(&mut self, data: u8) {
+        if !self.w {
+            // 1回目はbit14を0にする
+            self.t = (self.t & 0x00ff) | ((data as u16 & 0x3f) << 8);
+        } else {
+            self.t = (self.t & 0xff00) | data as u16;
+            self.v = self.t;
+        }
+        self.w = !self.w;
+    }
+
+    pub fn reset_latch(&mut self) {
+        self.w = false;
+    }
+
+    // $2007のアクセス後
+    pub fn increment(&mut self, inc: u8) {
+        self.v = (self.v + inc as u16) & 0x7fff;
+    }
+
+    // $2007でアクセスするアドレス
+    pub fn addr(&self) -> u16 {
+        self.v & 0x3fff
+    }
+
+    // 描画中にタイルを1つ進める。32を超えたら隣のネームテーブルへ
+    pub fn increment_x(&mut self) {
+        if self.v & 0x001f == 31 {
+            self.v &= !0x001f;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    // 描画中に1ライン進める。30行を超えたら下のネームテーブルへ
+    pub fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03e0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            // 属性テーブルの範囲に入っていた場合はネームテーブルを切り替えない
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03e0) | (coarse_y << 5);
+    }
+
+    // ドット257でtの横方向の成分をvに戻す
+    pub fn copy_x(&mut self) {
+        self.v = (self.v & !0x041f) | (self.t & 0x041f);
+    }
+
+    // プリレンダーラインでtの縦方向の成分をvに戻す
+    pub fn copy_y(&mut self) {
+        self.v = (self.v & !0x7be0) | (self.t & 0x7be0);
+    }
+
+    // vが指す描画位置。ネームテーブルと、その中のピクセル座標 (x, y)
+    pub fn position(&self) -> (u8, usize, usize) {
+        let x = (self.v & 0x001f) as usize * 8 + self.x as usize;
+        let y = ((self.v >> 5) & 0x1f) as usize * 8 + (self.v >> 12) as usize;
+        (((self.v >> 10) & 0b11) as u8, x, y)
+    }
+
+    // tが表す画面左上の位置 (フレーム単位の描画用)
+    pub fn nametable(&self) -> u8 {
+        ((self.t >> 10) & 0b11) as u8
+    }
+
+    pub fn scroll_x(&self) -> u8 {
+        ((self.t & 0x001f) << 3) as u8 | self.x
+    }
+
+    pub fn scroll_y(&self) -> u8 {
+        ((((self.t >> 5) & 0x1f) << 3) | ((self.t >> 12) & 0b111)) as u8
+    }
+}
+
+impl Default for LoopyRegister {
+    fn default() -> Self {
+        LoopyRegister::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // nesdev wikiの "PPU scrolling" の例
+    #[test]
+    fn test_register_writes() {
+        let mut loopy = LoopyRegister::new();
+        loopy.t = 0x7fff;
+        loopy.write_ctrl(0x00);
+        assert_eq!(loopy.t, 0x73ff);
+
+        loopy.t = 0;
+        loopy.write_scroll(0x7d);
+        assert_eq!((loopy.t, loopy.x, loopy.w), (0x000f, 5, true));
+        loopy.write_scroll(0x5e);
+        assert_eq!((loopy.t, loopy.w), (0x616f, false));
+        assert_eq!((loopy.scroll_x(), loopy.scroll_y()), (0x7d, 0x5e));
+
+        loopy.write_addr(0x3d);
+        assert_eq!((loopy.t, loopy.w), (0x3d6f, true));
+        loopy.write_addr(0xf0);
+        assert_eq!((loopy.t, loopy.v, loopy.w), (0x3df0, 0x3df0, false));
+    }
+
+    #[test]
+    fn test_latch_is_shared() {
+        let mut loopy = LoopyRegister::new();
+        loopy.write_scroll(0x08);
+        // 2回目の書き込みとして扱われ、vが更新される
+        loopy.write_addr(0x20);
+        assert_eq!(loopy.v, 0x0020);
+
+        loopy.write_scroll(0x08);
+        loopy.reset_latch();
+        loopy.write_addr(0x21);
+        loopy.write_addr(0x08);
+        assert_eq!(loopy.addr(), 0x2108);
+    }
+
+    #[test]
+    fn test_increment() {
+        let mut loopy = LoopyRegister::new();
+        loopy.v = 0x001f;
+        loopy.increment_x();
+        assert_eq!(loopy.v, 0x0400);
+
+        loopy.v = 0x7000 | (29 << 5);
+        loopy.increment_y();
+        assert_eq!(loopy.v, 0x0800);
+        loopy.v = 0x7000 | (31 << 5);
+        loopy.increment_y();
+        assert_eq!(loopy.v, 0x0000);
+
+        loopy.t = 0x7fff;
+        loopy.v = 0;
+        loopy.copy_x();
+        assert_eq!(loopy.v, 0x041f);
+        loopy.copy_y();
+        assert_eq!(loopy.v, 0x7fff);
+
+        loopy.v = 0x741f;
+        loopy.x = 3;
+        assert_eq!(loopy.position(), (1, 251, 7));
+
+        loopy.v = 0x3fff;
+        loopy.increment(1);
+        assert_eq!(loopy.addr(), 0x0000);
+    }
+}
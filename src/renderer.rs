@@ -1,21 +1,24 @@
-use crate::{cartridge::Mirroring, ppu::NesPPU, renderer_frame::Frame, renderer_palette};
+use crate::{
+    mapper::ChrFetch,
+    ppu::{Accuracy, NesPPU, SPRITES_PER_LINE},
+    ppu_mask_register::MaskRegister,
+    renderer_frame::{Frame, IndexedFrame},
+    renderer_palette::{self, indexed_color, Palette},
+};
 
-fn bg_pallette(
-    ppu: &NesPPU,
-    attribute_table: &[u8],
-    tile_column: usize,
-    tile_row: usize,
-) -> [u8; 4] {
+fn bg_pallette_idx(attribute_table: &[u8], tile_column: usize, tile_row: usize) -> u8 {
     let attr_table_idx = tile_row / 4 * 8 + tile_column / 4;
     let attr_byte = attribute_table[attr_table_idx];
-    let pallete_idx = match (tile_column % 4 / 2, tile_row % 4 / 2) {
+    match (tile_column % 4 / 2, tile_row % 4 / 2) {
         (0, 0) => attr_byte & 0b11,
         (1, 0) => (attr_byte >> 2) & 0b11,
         (0, 1) => (attr_byte >> 4) & 0b11,
         (1, 1) => (attr_byte >> 6) & 0b11,
         (_, _) => panic!("should not happen"),
-    };
+    }
+}
 
+fn bg_pallette(ppu: &NesPPU, pallete_idx: u8) -> [u8; 4] {
     let pallete_start: usize = 1 + (pallete_idx as usize) * 4;
     [
         ppu.palette_table[0],
@@ -35,175 +38,552 @@ fn sprite_palette(ppu: &NesPPU, pallete_idx: u8) -> [u8; 4] {
     ]
 }
 
-struct Rect {
-    x1: usize,
-    y1: usize,
-    x2: usize,
-    y2: usize,
+// ゲーム側のmaskレジスタとは独立して、デバッグや素材撮り用にレイヤーを隠す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layers {
+    pub background: bool,
+    pub sprites: bool,
+    // 1ラインに8個までの制限。外すとちらつきが無くなる
+    // (Accurateでは描画中に決まるので、NesPPU::sprite_limitも合わせる)
+    pub sprite_limit: bool,
+    // 指定したネームテーブル (0-3) だけをスクロールなしで表示する
+    pub nametable: Option<u8>,
+}
+
+impl Default for Layers {
+    fn default() -> Self {
+        Layers {
+            background: true,
+            sprites: true,
+            sprite_limit: true,
+            nametable: None,
+        }
+    }
+}
+
+pub fn render(ppu: &NesPPU, frame: &mut Frame) {
+    render_layers(ppu, frame, &Layers::default());
+}
+
+pub fn render_layers(ppu: &NesPPU, frame: &mut Frame, layers: &Layers) {
+    render_with_palette(ppu, frame, layers, renderer_palette::default_palette());
+}
+
+pub fn render_with_palette(ppu: &NesPPU, frame: &mut Frame, layers: &Layers, colors: &Palette) {
+    let mut indexed = IndexedFrame::new();
+    render_indexed(ppu, &mut indexed, layers);
+    indexed.to_rgb(colors, frame);
+}
+
+// パレットを通す前の画面を描く
+pub fn render_indexed(ppu: &NesPPU, frame: &mut IndexedFrame, layers: &Layers) {
+    if let Some(table) = layers.nametable {
+        let mut bg_opaque = vec![false; 256 * 240];
+        render_background(ppu, frame, &mut bg_opaque, Some(table));
+        return;
+    }
+    if ppu.accuracy == Accuracy::Accurate {
+        render_dots(ppu, frame, layers);
+        return;
+    }
+
+    // 背景の不透明なピクセル。スプライトの優先度の判定に使う
+    let mut bg_opaque = vec![false; 256 * 240];
+    if layers.background && ppu.mask.show_background() {
+        render_background(ppu, frame, &mut bg_opaque, None);
+        // 左端8ピクセルの背景を隠す設定
+        if !ppu.mask.leftmost_8pxl_background() {
+            fill_backdrop(ppu, frame, 0..8);
+            for line in bg_opaque.chunks_mut(256) {
+                line[..8].fill(false);
+            }
+        }
+    } else {
+        fill_backdrop(ppu, frame, 0..256);
+    }
+
+    if layers.sprites && ppu.mask.show_sprites() {
+        render_sprites(ppu, frame, &bg_opaque, layers.sprite_limit);
+    }
+}
+
+// PPUがドット単位で描いた画面を写す。スプライトの数の制限はPPU側で掛かる
+fn render_dots(ppu: &NesPPU, frame: &mut IndexedFrame, layers: &Layers) {
+    for (i, pixel) in ppu.dot_pixels.iter().enumerate() {
+        let background = pixel.background.filter(|_| layers.background);
+        let sprite = pixel.sprite.filter(|_| layers.sprites);
+        let color = match (background, sprite) {
+            (Some(background), Some((_, true))) => background,
+            (_, Some((sprite, _))) => sprite,
+            (Some(background), None) => background,
+            (None, None) => pixel.backdrop,
+        };
+        let mask = MaskRegister::from_bits_truncate(pixel.mask);
+        frame.set_pixel(i % 256, i / 256, indexed_color(color, &mask));
+    }
 }
 
-impl Rect {
-    fn new(x1: usize, y1: usize, x2: usize, y2: usize) -> Self {
-        Rect {
-            x1: x1,
-            y1: y1,
-            x2: x2,
-            y2: y2,
+fn fill_backdrop(ppu: &NesPPU, frame: &mut IndexedFrame, columns: std::ops::Range<usize>) {
+    let backdrop = indexed_color(ppu.backdrop_color(), &ppu.mask);
+    for y in 0..240 {
+        for x in columns.clone() {
+            frame.set_pixel(x, y, backdrop);
         }
     }
 }
 
-fn render_name_table(
+// ラインごとに、そのラインを描き始めた時点のスクロール位置で描く。
+// ステータスバーの分割やラスタースクロールはこれで表示される。
+// nametableを指定したらそのネームテーブルだけを描く
+fn render_background(
     ppu: &NesPPU,
-    frame: &mut Frame,
-    name_table: &[u8],
-    view_port: Rect,
-    shift_x: isize,
-    shift_y: isize,
+    frame: &mut IndexedFrame,
+    bg_opaque: &mut [bool],
+    nametable: Option<u8>,
 ) {
     let bank = ppu.ctrl.bknd_pattern_addr();
+    let name_tables = [
+        ppu.dump_nametable(0),
+        ppu.dump_nametable(1),
+        ppu.dump_nametable(2),
+        ppu.dump_nametable(3),
+    ];
 
-    let attribute_table = &name_table[0x3c0..0x400];
+    for y in 0..240 {
+        let (main, scroll_x, line) = match nametable {
+            Some(table) => (table & 0b11, 0, y),
+            None => ppu.line_scroll(y).position(),
+        };
+        let tile_row = line / 8;
+        let mut row = (0, 0, [0; 4]);
+        for screen_x in 0..256 {
+            // 右にはみ出した分は隣のネームテーブル
+            let x = scroll_x + screen_x;
+            let table = (main as usize ^ (x / 256)) & 0b11;
+            let x = x % 256;
+            let tile_column = x / 8;
+            if screen_x == 0 || x % 8 == 0 {
+                let name_table = &name_tables[table];
+                let i = tile_row * 32 + tile_column;
+                let tile_idx = name_table[i];
+                // MMC5の拡張属性モードではタイルごとにバンクとパレットが決まる
+                let extended = ppu.mapper.borrow_mut().extended_tile(i as u16, tile_idx);
+                let (tile, palette) = match extended {
+                    Some((tile, pallete_idx)) => (tile, bg_pallette(ppu, pallete_idx)),
+                    None => (
+                        ppu.chr_tile(bank, tile_idx as u16, ChrFetch::Background),
+                        bg_pallette(
+                            ppu,
+                            bg_pallette_idx(&name_table[0x3c0..0x400], tile_column, tile_row),
+                        ),
+                    ),
+                };
+                row = (tile[line % 8], tile[line % 8 + 8], palette);
+            }
 
-    for i in 0..0x3c0 {
-        let tile_column = i % 32;
-        let tile_row = i / 32;
-        let tile_idx = name_table[i] as u16;
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
-        let palette = bg_pallette(ppu, attribute_table, tile_column, tile_row);
+            let (upper, lower, palette) = row;
+            let bit = 7 - x % 8;
+            let value = (lower >> bit & 1) << 1 | (upper >> bit & 1);
+            let color = indexed_color(palette[value as usize], &ppu.mask);
+            frame.set_pixel(screen_x, y, color);
+            bg_opaque[y * 256 + screen_x] = value != 0;
+        }
+    }
 
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
+    if nametable.is_none() {
+        render_split(ppu, frame, bg_opaque);
+    }
+}
 
+// MMC5の縦分割画面はExRAMの内容で上書きする
+fn render_split(ppu: &NesPPU, frame: &mut IndexedFrame, bg_opaque: &mut [bool]) {
+    for column in 0..32u8 {
+        for y in 0..240u8 {
+            let row = ppu.mapper.borrow_mut().split_tile_row(column, y);
+            let (mut upper, mut lower, pallete_idx) = match row {
+                Some(row) => row,
+                None => continue,
+            };
+            let palette = bg_pallette(ppu, pallete_idx);
             for x in (0..=7).rev() {
                 let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match value {
-                    0 => renderer_palette::SYSTEM_PALLETE[ppu.palette_table[0] as usize],
-                    1 => renderer_palette::SYSTEM_PALLETE[palette[1] as usize],
-                    2 => renderer_palette::SYSTEM_PALLETE[palette[2] as usize],
-                    3 => renderer_palette::SYSTEM_PALLETE[palette[3] as usize],
-                    _ => panic!("can't be"),
-                };
-                let pixel_x = tile_column * 8 + x;
-                let pixel_y = tile_row * 8 + y;
-
-                if pixel_x >= view_port.x1
-                    && pixel_x < view_port.x2
-                    && pixel_y >= view_port.y1
-                    && pixel_y < view_port.y2
-                {
-                    frame.set_pixel(
-                        (shift_x + pixel_x as isize) as usize,
-                        (shift_y + pixel_y as isize) as usize,
-                        rgb,
-                    );
+                upper >>= 1;
+                lower >>= 1;
+                let color = indexed_color(palette[value as usize], &ppu.mask);
+                let pixel_x = column as usize * 8 + x;
+                frame.set_pixel(pixel_x, y as usize, color);
+                bg_opaque[y as usize * 256 + pixel_x] = value != 0;
+            }
+        }
+    }
+}
+
+// 色と、背景の後ろに回るかどうか
+type SpritePixel = Option<(u16, bool)>;
+
+// 1ラインずつ、OAMの先頭から見つかった順にスプライトを並べる。
+// 重なったピクセルは番号の小さいスプライトが勝ち、その優先度ビットが背景の前後を決める
+fn render_sprites(ppu: &NesPPU, frame: &mut IndexedFrame, bg_opaque: &[bool], sprite_limit: bool) {
+    let height = ppu.ctrl.sprite_size() as usize;
+    let limit = if sprite_limit { SPRITES_PER_LINE } else { 64 };
+    let show_left = ppu.mask.leftmost_8pxl_sprite();
+
+    for y in 0..240 {
+        let mut line: [SpritePixel; 256] = [None; 256];
+        let visible = ppu.oam_data.chunks(4).filter(|sprite| {
+            let top = sprite[0] as usize;
+            y >= top && y < top + height
+        });
+        for sprite in visible.take(limit) {
+            let flip_horizontal = sprite[2] >> 6 & 1 == 1;
+            let behind_background = sprite[2] >> 5 & 1 == 1;
+            let palette = sprite_palette(ppu, sprite[2] & 0b11);
+            let (upper, lower) = ppu.sprite_pattern_row(sprite, y - sprite[0] as usize);
+            for column in 0..8 {
+                let bit = if flip_horizontal { column } else { 7 - column };
+                let value = ((lower >> bit) & 1) << 1 | ((upper >> bit) & 1);
+                let x = sprite[3] as usize + column;
+                if value == 0 || x >= 256 || (x < 8 && !show_left) || line[x].is_some() {
+                    continue;
+                }
+                let color = indexed_color(palette[value as usize], &ppu.mask);
+                line[x] = Some((color, behind_background));
+            }
+        }
+
+        for (x, pixel) in line.iter().enumerate() {
+            if let Some((color, behind_background)) = pixel {
+                if !(*behind_background && bg_opaque[y * 256 + x]) {
+                    frame.set_pixel(x, y, *color);
                 }
             }
         }
     }
 }
 
-pub fn render(ppu: &NesPPU, frame: &mut Frame) {
-    let scroll_x = (ppu.scroll.scroll_x) as usize;
-    let scroll_y = (ppu.scroll.scroll_y) as usize;
-
-    let (main_nametable, second_nametable) = match (&ppu.mirroring, ppu.ctrl.nametable_addr()) {
-        (Mirroring::VERTICAL, 0x2000)
-        | (Mirroring::VERTICAL, 0x2800)
-        | (Mirroring::HORIZONTAL, 0x2000)
-        | (Mirroring::HORIZONTAL, 0x2400) => (&ppu.vram[0..0x400], &ppu.vram[0x400..0x800]),
-        (Mirroring::VERTICAL, 0x2400)
-        | (Mirroring::VERTICAL, 0x2C00)
-        | (Mirroring::HORIZONTAL, 0x2800)
-        | (Mirroring::HORIZONTAL, 0x2C00) => (&ppu.vram[0x400..0x800], &ppu.vram[0..0x400]),
-        (_, _) => {
-            panic!("Not supported mirroring type {:?}", ppu.mirroring);
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Mirroring;
+    use crate::mapper_nrom::Nrom;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn solid_tile_ppu() -> NesPPU {
+        // tile 0 is fully opaque (color 1), used by both the nametable and sprite 0
+        let mut chr_rom = vec![0; 0x2000];
+        for b in chr_rom.iter_mut().take(8) {
+            *b = 0xff;
         }
-    };
-
-    render_name_table(
-        ppu,
-        frame,
-        main_nametable,
-        Rect::new(scroll_x, scroll_y, 256, 240),
-        -(scroll_x as isize),
-        -(scroll_y as isize),
-    );
-    if scroll_x > 0 {
-        render_name_table(
-            ppu,
-            frame,
-            second_nametable,
-            Rect::new(0, 0, scroll_x, 240),
-            (256 - scroll_x) as isize,
-            0,
-        );
-    } else if scroll_y > 0 {
-        render_name_table(
-            ppu,
-            frame,
-            second_nametable,
-            Rect::new(0, 0, 256, scroll_y),
-            0,
-            (240 - scroll_y) as isize,
-        );
-    }
-
-    for i in (0..ppu.oam_data.len()).step_by(4).rev() {
-        let tile_idx = ppu.oam_data[i + 1] as u16;
-        let tile_x = ppu.oam_data[i + 3] as usize;
-        let tile_y = ppu.oam_data[i] as usize;
-
-        let flip_vertical = if ppu.oam_data[i + 2] >> 7 & 1 == 1 {
-            true
-        } else {
-            false
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x16;
+        ppu.palette_table[0x11] = 0x2a;
+        ppu.oam_data = [0xff; 256];
+        ppu.oam_data[0] = 100;
+        ppu.oam_data[1] = 0;
+        ppu.oam_data[2] = 0;
+        ppu.oam_data[3] = 100;
+        ppu.mask.update(0b0001_1110);
+        ppu
+    }
+
+    fn color(index: u8) -> (u8, u8, u8) {
+        renderer_palette::default_palette().rgb(index, &MaskRegister::new())
+    }
+
+    fn pixel(frame: &Frame, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = y * 3 * 256 + x * 3;
+        (frame.data[base], frame.data[base + 1], frame.data[base + 2])
+    }
+
+    #[test]
+    fn test_render_all_layers() {
+        let ppu = solid_tile_ppu();
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        assert_eq!(pixel(&frame, 0, 0), color(0x16));
+        assert_eq!(pixel(&frame, 100, 100), color(0x2a));
+    }
+
+    #[test]
+    fn test_render_hide_background() {
+        let ppu = solid_tile_ppu();
+        let mut frame = Frame::new();
+        let layers = Layers {
+            background: false,
+            ..Layers::default()
         };
-        let flip_horizontal = if ppu.oam_data[i + 2] >> 6 & 1 == 1 {
-            true
-        } else {
-            false
+        render_layers(&ppu, &mut frame, &layers);
+
+        assert_eq!(pixel(&frame, 0, 0), color(0x0f));
+        assert_eq!(pixel(&frame, 100, 100), color(0x2a));
+    }
+
+    #[test]
+    fn test_render_hide_sprites() {
+        let ppu = solid_tile_ppu();
+        let mut frame = Frame::new();
+        let layers = Layers {
+            sprites: false,
+            ..Layers::default()
         };
-        let pallette_idx = ppu.oam_data[i + 2] & 0b11;
-        let sprite_palette = sprite_palette(ppu, pallette_idx);
-        let bank: u16 = ppu.ctrl.sprt_pattern_addr();
+        render_layers(&ppu, &mut frame, &layers);
+
+        assert_eq!(pixel(&frame, 100, 100), color(0x16));
+    }
 
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+    #[test]
+    fn test_render_single_nametable() {
+        let mut ppu = solid_tile_ppu();
+        // 下のネームテーブルは透明なタイル1で埋める
+        ppu.vram[0x400..0x7c0].fill(1);
+        let mut frame = Frame::new();
+        let mut layers = Layers {
+            nametable: Some(0),
+            ..Layers::default()
+        };
+        render_layers(&ppu, &mut frame, &layers);
+        // スプライトは描かない
+        assert_eq!(pixel(&frame, 100, 100), color(0x16));
 
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
-            'ololo: for x in (0..=7).rev() {
-                let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match value {
-                    0 => continue 'ololo,
-                    1 => renderer_palette::SYSTEM_PALLETE[sprite_palette[1] as usize],
-                    2 => renderer_palette::SYSTEM_PALLETE[sprite_palette[2] as usize],
-                    3 => renderer_palette::SYSTEM_PALLETE[sprite_palette[3] as usize],
-                    _ => panic!("can't be"),
-                };
-                match (flip_horizontal, flip_vertical) {
-                    (false, false) => {
-                        frame.set_pixel(tile_x + x, tile_y + y, rgb);
-                    }
-                    (true, false) => {
-                        frame.set_pixel(tile_x + 7 - x, tile_y + y, rgb);
-                    }
-                    (false, true) => {
-                        frame.set_pixel(tile_x + x, tile_y + 7 - y, rgb);
-                    }
-                    (true, true) => {
-                        frame.set_pixel(tile_x + 7 - x, tile_y + 7 - y, rgb);
-                    }
-                }
-            }
+        layers.nametable = Some(2);
+        render_layers(&ppu, &mut frame, &layers);
+        assert_eq!(pixel(&frame, 0, 0), color(0x0f));
+        assert_eq!(pixel(&frame, 100, 100), color(0x0f));
+    }
+
+    #[test]
+    fn test_render_background_mask() {
+        let mut ppu = solid_tile_ppu();
+        let mut frame = Frame::new();
+        // 左端8ピクセルだけ背景を隠す
+        ppu.mask.update(0b0001_1100);
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 7, 0), color(0x0f));
+        assert_eq!(pixel(&frame, 8, 0), color(0x16));
+
+        // 背景の表示自体が無効
+        ppu.mask.update(0b0001_0110);
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 8, 0), color(0x0f));
+    }
+
+    #[test]
+    fn test_render_background_pattern_and_attributes() {
+        // $1000側のタイル0だけが色2で埋まっている
+        let mut chr_rom = vec![0; 0x2000];
+        for b in chr_rom[0x1008..0x1010].iter_mut() {
+            *b = 0xff;
+        }
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.mask.update(0b0000_1010);
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[2] = 0x11;
+        ppu.palette_table[14] = 0x22;
+        // 右下の16x16ピクセルの領域はパレット3
+        ppu.vram[0x3c0] = 0b1100_0000;
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), color(0x0f));
+
+        ppu.ctrl.update(0b0001_0000);
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), color(0x11));
+        assert_eq!(pixel(&frame, 16, 16), color(0x22));
+        assert_eq!(pixel(&frame, 32, 16), color(0x11));
+    }
+
+    // タイル1: 左上の1ピクセルだけ色1、タイル2: 全面色1
+    fn sprite_ppu() -> NesPPU {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[0x10] = 0x80;
+        for b in chr_rom[0x20..0x28].iter_mut() {
+            *b = 0xff;
         }
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.mask.update(0b0001_1110);
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[0x11] = 0x2a;
+        ppu.palette_table[0x15] = 0x16;
+        ppu.oam_data = [0xff; 256];
+        ppu
+    }
+
+    fn set_sprite(ppu: &mut NesPPU, i: usize, x: u8, y: u8, tile: u8, attr: u8) {
+        ppu.oam_data[i * 4..i * 4 + 4].copy_from_slice(&[y, tile, attr, x]);
+    }
+
+    #[test]
+    fn test_sprite_flip() {
+        let mut ppu = sprite_ppu();
+        set_sprite(&mut ppu, 0, 16, 16, 1, 0b0000_0000);
+        set_sprite(&mut ppu, 1, 32, 16, 1, 0b0100_0000);
+        set_sprite(&mut ppu, 2, 48, 16, 1, 0b1000_0000);
+        set_sprite(&mut ppu, 3, 64, 16, 1, 0b1100_0001);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        let sprite = color(0x2a);
+        assert_eq!(pixel(&frame, 16, 16), sprite);
+        assert_eq!(pixel(&frame, 32 + 7, 16), sprite);
+        assert_eq!(pixel(&frame, 48, 16 + 7), sprite);
+        assert_eq!(pixel(&frame, 64 + 7, 16 + 7), color(0x16));
+        assert_eq!(pixel(&frame, 17, 16), color(0x0f));
+    }
+
+    #[test]
+    fn test_sprite_8x16() {
+        let mut ppu = sprite_ppu();
+        // $1000側のタイル2の左上とタイル3の右下だけ色1
+        let mut chr = vec![0; 0x2000];
+        chr[0x1020] = 0x80;
+        chr[0x1037] = 0x01;
+        ppu.mapper = Rc::new(RefCell::new(Nrom::new(
+            vec![0; 0x4000],
+            chr,
+            Mirroring::HORIZONTAL,
+        )));
+        // パターンテーブルの選択ビットは8x16では使われない
+        ppu.ctrl.update(0b0010_0000);
+        set_sprite(&mut ppu, 0, 16, 16, 3, 0b0000_0000);
+        set_sprite(&mut ppu, 1, 32, 16, 3, 0b1000_0000);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        let sprite = color(0x2a);
+        let backdrop = color(0x0f);
+        assert_eq!(pixel(&frame, 16, 16), sprite);
+        assert_eq!(pixel(&frame, 16 + 7, 16 + 15), sprite);
+        assert_eq!(pixel(&frame, 16 + 7, 16 + 7), backdrop);
+        // 上下反転すると2枚のタイルも入れ替わる
+        assert_eq!(pixel(&frame, 32 + 7, 16), sprite);
+        assert_eq!(pixel(&frame, 32, 16 + 15), sprite);
+    }
+
+    #[test]
+    fn test_sprite_priority() {
+        let mut ppu = sprite_ppu();
+        // (0,0)の背景タイルだけ不透明
+        ppu.vram[0] = 2;
+        ppu.palette_table[1] = 0x11;
+        set_sprite(&mut ppu, 0, 0, 0, 2, 0b0010_0000);
+        set_sprite(&mut ppu, 1, 8, 0, 2, 0b0010_0000);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        // 背景の後ろ: 不透明な背景の上では隠れ、透明な背景の上では見える
+        assert_eq!(pixel(&frame, 0, 0), color(0x11));
+        assert_eq!(pixel(&frame, 8, 0), color(0x2a));
+
+        // 番号の小さい背景側のスプライトが、後ろの前面スプライトを隠す
+        set_sprite(&mut ppu, 1, 0, 0, 2, 0b0000_0001);
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), color(0x11));
+
+        set_sprite(&mut ppu, 0, 0, 0, 2, 0b0000_0000);
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), color(0x2a));
+
+        // 左端8ピクセルのスプライトを隠す
+        ppu.mask.update(0b0001_1010);
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), color(0x11));
+    }
+
+    #[test]
+    fn test_sprite_limit() {
+        let mut ppu = sprite_ppu();
+        for i in 0..9 {
+            set_sprite(&mut ppu, i, i as u8 * 16, 50, 2, 0);
+        }
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        let sprite = color(0x2a);
+        assert_eq!(pixel(&frame, 7 * 16, 50), sprite);
+        assert_eq!(pixel(&frame, 8 * 16, 50), color(0x0f));
+
+        let layers = Layers {
+            sprite_limit: false,
+            ..Layers::default()
+        };
+        render_layers(&ppu, &mut frame, &layers);
+        assert_eq!(pixel(&frame, 8 * 16, 50), sprite);
+    }
+
+    #[test]
+    fn test_render_split_screen() {
+        // タイル1は全面色1。下のネームテーブルだけタイル1で埋める
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[0x10..0x18].fill(0xff);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x16;
+        ppu.vram[0x400..0x7c0].fill(1);
+        ppu.mask.update(0b0000_1010);
+
+        // 100ラインの水平帰線期間で$2006から下のネームテーブルに切り替える
+        while ppu.scanline() < 100 || ppu.cycle() < 300 {
+            ppu.tick(1);
+        }
+        ppu.write_to_ppu_addr(0x08);
+        ppu.write_to_ppu_addr(0x00);
+        while ppu.scanline() < 240 {
+            ppu.tick(1);
+        }
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        let backdrop = color(0x0f);
+        let tile = color(0x16);
+        assert_eq!(pixel(&frame, 0, 0), backdrop);
+        assert_eq!(pixel(&frame, 255, 100), backdrop);
+        assert_eq!(pixel(&frame, 0, 101), tile);
+        assert_eq!(pixel(&frame, 255, 239), tile);
+    }
+
+    #[test]
+    fn test_grayscale_and_emphasis() {
+        let mut ppu = solid_tile_ppu();
+        let mut frame = Frame::new();
+        ppu.mask.update(0b0001_1111);
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), color(0x10));
+
+        ppu.mask.update(0b0011_1110);
+        render(&ppu, &mut frame);
+        let emphasized = renderer_palette::default_palette().rgb(0x16, &ppu.mask);
+        assert_eq!(pixel(&frame, 0, 0), emphasized);
+        assert!(emphasized != color(0x16));
+    }
+
+    #[test]
+    fn test_render_with_palette() {
+        let ppu = solid_tile_ppu();
+        let mut data = vec![0; 192];
+        data[0x16 * 3..0x16 * 3 + 3].copy_from_slice(&[1, 2, 3]);
+        let colors = Palette::from_pal(&data).unwrap();
+        let mut frame = Frame::new();
+        render_with_palette(&ppu, &mut frame, &Layers::default(), &colors);
+        assert_eq!(pixel(&frame, 0, 0), (1, 2, 3));
+        assert_eq!(pixel(&frame, 100, 100), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_render_indexed() {
+        let mut ppu = solid_tile_ppu();
+        // 赤を強調
+        ppu.mask.update(0b0011_1110);
+        let mut indexed = IndexedFrame::new();
+        render_indexed(&ppu, &mut indexed, &Layers::default());
+        assert_eq!(indexed.pixel(0, 0), 0b001 << 6 | 0x16);
+        assert_eq!(indexed.pixel(100, 100), 0b001 << 6 | 0x2a);
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        let mut converted = Frame::new();
+        indexed.to_rgb(renderer_palette::default_palette(), &mut converted);
+        assert!(frame.data == converted.data);
     }
 }
@@ -0,0 +1,231 @@
+use crate::renderer_frame::{Frame, Image};
+
+// 元の1ピクセルを縦横何倍にして効果をかけるか
+pub const SCALE: usize = 3;
+
+const WIDTH: usize = 256;
+const HEIGHT: usize = 240;
+// 曲面の強さ。端ほど外側へ引き伸ばす
+const CURVATURE: f64 = 0.08;
+
+// ブラウン管っぽく見せる後処理。シェーダーを使えないフロントエンドでも
+// 使えるように、CPUで拡大した画像に対して行う
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CrtOptions {
+    // 走査線の間を暗くする
+    pub scanlines: bool,
+    // RGBの縦縞 (アパーチャーグリル)
+    pub phosphor_mask: bool,
+    // 画面の丸み
+    pub curvature: bool,
+}
+
+impl CrtOptions {
+    // 切り替える順
+    pub const PRESETS: [CrtOptions; 4] = [
+        CrtOptions {
+            scanlines: false,
+            phosphor_mask: false,
+            curvature: false,
+        },
+        CrtOptions {
+            scanlines: true,
+            phosphor_mask: false,
+            curvature: false,
+        },
+        CrtOptions {
+            scanlines: true,
+            phosphor_mask: true,
+            curvature: false,
+        },
+        CrtOptions {
+            scanlines: true,
+            phosphor_mask: true,
+            curvature: true,
+        },
+    ];
+
+    pub fn is_enabled(&self) -> bool {
+        self.scanlines || self.phosphor_mask || self.curvature
+    }
+
+    // "scanlines+mask" のような表示用の名前
+    pub fn name(&self) -> String {
+        let names: Vec<&str> = [
+            (self.scanlines, "scanlines"),
+            (self.phosphor_mask, "mask"),
+            (self.curvature, "curvature"),
+        ]
+        .iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, name)| *name)
+        .collect();
+        if names.is_empty() {
+            "off".to_string()
+        } else {
+            names.join("+")
+        }
+    }
+
+    // PRESETSの次のもの
+    pub fn next(&self) -> CrtOptions {
+        let index = CrtOptions::PRESETS
+            .iter()
+            .position(|preset| preset == self)
+            .map_or(0, |i| (i + 1) % CrtOptions::PRESETS.len());
+        CrtOptions::PRESETS[index]
+    }
+}
+
+// 出力の1ピクセルが拾う元の画素と、元の行の中での位置 (0..SCALE)。
+// 画面の外ならNone
+type Source = Option<(u32, u8)>;
+
+pub struct CrtFilter {
+    options: CrtOptions,
+    // 曲面の計算は重いので、設定が変わったときだけ作り直す
+    sources: Vec<Source>,
+    image: Image,
+}
+
+impl CrtFilter {
+    pub fn new(options: CrtOptions) -> Self {
+        CrtFilter {
+            options,
+            sources: sources(options.curvature),
+            image: Image {
+                width: WIDTH * SCALE,
+                height: HEIGHT * SCALE,
+                data: vec![0; WIDTH * SCALE * HEIGHT * SCALE * 3],
+            },
+        }
+    }
+
+    pub fn options(&self) -> CrtOptions {
+        self.options
+    }
+
+    pub fn set_options(&mut self, options: CrtOptions) {
+        if options.curvature != self.options.curvature {
+            self.sources = sources(options.curvature);
+        }
+        self.options = options;
+    }
+
+    // 縦横SCALE倍の画像を返す
+    pub fn apply(&mut self, frame: &Frame) -> &Image {
+        let width = WIDTH * SCALE;
+        for (i, (source, out)) in self
+            .sources
+            .iter()
+            .zip(self.image.data.chunks_mut(3))
+            .enumerate()
+        {
+            let (index, phase) = match source {
+                Some(source) => *source,
+                None => {
+                    out.fill(0);
+                    continue;
+                }
+            };
+            let rgb = &frame.data[index as usize * 3..index as usize * 3 + 3];
+            // 明るさは1/256単位
+            let mut gain = [256u32; 3];
+            if self.options.scanlines && phase as usize == SCALE - 1 {
+                gain = gain.map(|g| g / 2);
+            }
+            if self.options.phosphor_mask {
+                let lit = i % width % 3;
+                for (channel, g) in gain.iter_mut().enumerate() {
+                    if channel != lit {
+                        *g = *g * 3 / 4;
+                    }
+                }
+            }
+            for channel in 0..3 {
+                out[channel] = (rgb[channel] as u32 * gain[channel] / 256) as u8;
+            }
+        }
+        &self.image
+    }
+}
+
+fn sources(curvature: bool) -> Vec<Source> {
+    let (width, height) = (WIDTH * SCALE, HEIGHT * SCALE);
+    let mut sources = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let (mut u, mut v) = (x as f64 + 0.5, y as f64 + 0.5);
+            if curvature {
+                // -1.0-1.0にして、中心から離れるほど外側を拾う
+                let cu = u / width as f64 * 2.0 - 1.0;
+                let cv = v / height as f64 * 2.0 - 1.0;
+                let du = cu * (1.0 + cv * cv * CURVATURE);
+                let dv = cv * (1.0 + cu * cu * CURVATURE);
+                if du.abs() >= 1.0 || dv.abs() >= 1.0 {
+                    sources.push(None);
+                    continue;
+                }
+                u = (du + 1.0) / 2.0 * width as f64;
+                v = (dv + 1.0) / 2.0 * height as f64;
+            }
+            let (sx, sy) = (u as usize / SCALE, v as usize / SCALE);
+            let phase = (v as usize % SCALE) as u8;
+            sources.push(Some(((sy * WIDTH + sx) as u32, phase)));
+        }
+    }
+    sources
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pixel(image: &Image, x: usize, y: usize) -> [u8; 3] {
+        let base = (y * image.width + x) * 3;
+        [image.data[base], image.data[base + 1], image.data[base + 2]]
+    }
+
+    #[test]
+    fn test_crt_filter() {
+        let mut frame = Frame::new();
+        for y in 0..240 {
+            for x in 0..256 {
+                frame.set_pixel(x, y, (200, 200, 200));
+            }
+        }
+        frame.set_pixel(1, 0, (100, 0, 0));
+
+        let mut filter = CrtFilter::new(CrtOptions::default());
+        let image = filter.apply(&frame);
+        assert_eq!((image.width, image.height), (768, 720));
+        assert_eq!(pixel(image, 3, 2), [100, 0, 0]);
+        assert_eq!(pixel(image, 2, 2), [200, 200, 200]);
+
+        filter.set_options(CrtOptions::PRESETS[1]);
+        let image = filter.apply(&frame);
+        assert_eq!(pixel(image, 0, 1), [200, 200, 200]);
+        assert_eq!(pixel(image, 0, 2), [100, 100, 100]);
+
+        filter.set_options(CrtOptions::PRESETS[2]);
+        let image = filter.apply(&frame);
+        assert_eq!(pixel(image, 0, 0), [200, 150, 150]);
+        assert_eq!(pixel(image, 1, 0), [150, 200, 150]);
+
+        filter.set_options(CrtOptions::PRESETS[3]);
+        let image = filter.apply(&frame);
+        // 角は画面の外、中心は曲げてもずれない
+        assert_eq!(pixel(image, 0, 0), [0, 0, 0]);
+        assert_eq!(pixel(image, 384, 361), [200, 150, 150]);
+    }
+
+    #[test]
+    fn test_presets() {
+        let mut options = CrtOptions::default();
+        assert!(!options.is_enabled());
+        assert_eq!(options.name(), "off");
+        options = options.next().next();
+        assert_eq!(options.name(), "scanlines+mask");
+        assert_eq!(options.next().next(), CrtOptions::default());
+    }
+}
@@ -0,0 +1,341 @@
+use crate::renderer_frame::Image;
+
+// PPUの画面と描画先の間に挟む拡大フィルタ。ドット絵の斜めの線を滑らかにする
+pub trait Filter {
+    fn name(&self) -> &'static str;
+    // 縦横何倍になるか
+    fn scale(&self) -> usize;
+    fn apply(&self, input: &Image, output: &mut Image);
+}
+
+// 選べるフィルタ。名前はCLIや設定で使う。
+// hqxやxBRZは大きな表や多段の判定が要るので、同じ系統のScaleNxとxBR (level 1) にしている
+pub fn all() -> Vec<Box<dyn Filter>> {
+    vec![
+        Box::new(Scale2x),
+        Box::new(Scale3x),
+        Box::new(Scale4x),
+        Box::new(Xbr2x),
+    ]
+}
+
+pub fn by_name(name: &str) -> Result<Box<dyn Filter>, String> {
+    all()
+        .into_iter()
+        .find(|filter| filter.name() == name)
+        .ok_or_else(|| format!("unknown filter: {}", name))
+}
+
+type Rgb = [u8; 3];
+
+// 端は一番外側の画素を繰り返す
+fn pixel(image: &Image, x: isize, y: isize) -> Rgb {
+    let x = x.clamp(0, image.width as isize - 1) as usize;
+    let y = y.clamp(0, image.height as isize - 1) as usize;
+    let base = (y * image.width + x) * 3;
+    [image.data[base], image.data[base + 1], image.data[base + 2]]
+}
+
+fn put(image: &mut Image, x: usize, y: usize, rgb: Rgb) {
+    let base = (y * image.width + x) * 3;
+    image.data[base..base + 3].copy_from_slice(&rgb);
+}
+
+fn resize(output: &mut Image, width: usize, height: usize) {
+    output.width = width;
+    output.height = height;
+    output.data.resize(width * height * 3, 0);
+}
+
+// AdvanceMAMEのScale2x (EPX)
+pub struct Scale2x;
+
+impl Filter for Scale2x {
+    fn name(&self) -> &'static str {
+        "scale2x"
+    }
+
+    fn scale(&self) -> usize {
+        2
+    }
+
+    fn apply(&self, input: &Image, output: &mut Image) {
+        resize(output, input.width * 2, input.height * 2);
+        for y in 0..input.height {
+            for x in 0..input.width {
+                let (xi, yi) = (x as isize, y as isize);
+                let b = pixel(input, xi, yi - 1);
+                let d = pixel(input, xi - 1, yi);
+                let e = pixel(input, xi, yi);
+                let f = pixel(input, xi + 1, yi);
+                let h = pixel(input, xi, yi + 1);
+                let mut out = [e; 4];
+                if b != h && d != f {
+                    if d == b {
+                        out[0] = d;
+                    }
+                    if b == f {
+                        out[1] = f;
+                    }
+                    if d == h {
+                        out[2] = d;
+                    }
+                    if h == f {
+                        out[3] = f;
+                    }
+                }
+                for (i, rgb) in out.into_iter().enumerate() {
+                    put(output, x * 2 + i % 2, y * 2 + i / 2, rgb);
+                }
+            }
+        }
+    }
+}
+
+pub struct Scale3x;
+
+impl Filter for Scale3x {
+    fn name(&self) -> &'static str {
+        "scale3x"
+    }
+
+    fn scale(&self) -> usize {
+        3
+    }
+
+    fn apply(&self, input: &Image, output: &mut Image) {
+        resize(output, input.width * 3, input.height * 3);
+        for y in 0..input.height {
+            for x in 0..input.width {
+                let (xi, yi) = (x as isize, y as isize);
+                let a = pixel(input, xi - 1, yi - 1);
+                let b = pixel(input, xi, yi - 1);
+                let c = pixel(input, xi + 1, yi - 1);
+                let d = pixel(input, xi - 1, yi);
+                let e = pixel(input, xi, yi);
+                let f = pixel(input, xi + 1, yi);
+                let g = pixel(input, xi - 1, yi + 1);
+                let h = pixel(input, xi, yi + 1);
+                let i = pixel(input, xi + 1, yi + 1);
+                let mut out = [e; 9];
+                if b != h && d != f {
+                    let pick = |cond: bool, rgb: Rgb| if cond { rgb } else { e };
+                    out[0] = pick(d == b, d);
+                    out[1] = pick((d == b && e != c) || (b == f && e != a), b);
+                    out[2] = pick(b == f, f);
+                    out[3] = pick((d == b && e != g) || (d == h && e != a), d);
+                    out[5] = pick((b == f && e != i) || (h == f && e != c), f);
+                    out[6] = pick(d == h, d);
+                    out[7] = pick((d == h && e != i) || (h == f && e != g), h);
+                    out[8] = pick(h == f, f);
+                }
+                for (n, rgb) in out.into_iter().enumerate() {
+                    put(output, x * 3 + n % 3, y * 3 + n / 3, rgb);
+                }
+            }
+        }
+    }
+}
+
+// Scale2xを2回
+pub struct Scale4x;
+
+impl Filter for Scale4x {
+    fn name(&self) -> &'static str {
+        "scale4x"
+    }
+
+    fn scale(&self) -> usize {
+        4
+    }
+
+    fn apply(&self, input: &Image, output: &mut Image) {
+        let mut middle = Image {
+            width: 0,
+            height: 0,
+            data: vec![],
+        };
+        Scale2x.apply(input, &mut middle);
+        Scale2x.apply(&middle, output);
+    }
+}
+
+// 色の違い。人の目に合わせて明るさの差を重く見る
+fn distance(a: Rgb, b: Rgb) -> u32 {
+    let yuv = |[r, g, b]: Rgb| {
+        let (r, g, b) = (r as i32, g as i32, b as i32);
+        (
+            (r * 299 + g * 587 + b * 114) / 1000,
+            (-r * 169 - g * 331 + b * 500) / 1000,
+            (r * 500 - g * 419 - b * 81) / 1000,
+        )
+    };
+    let (ya, ua, va) = yuv(a);
+    let (yb, ub, vb) = yuv(b);
+    (48 * (ya - yb).unsigned_abs())
+        + (7 * (ua - ub).unsigned_abs())
+        + (6 * (va - vb).unsigned_abs())
+}
+
+fn blend(a: Rgb, b: Rgb) -> Rgb {
+    [0, 1, 2].map(|i| ((a[i] as u16 + b[i] as u16) / 2) as u8)
+}
+
+// HyllianのxBR (level 1) の2倍。周りの5x5から辺の向きを見て、
+// 斜めの辺にかかる角だけ隣の色と混ぜる
+pub struct Xbr2x;
+
+impl Filter for Xbr2x {
+    fn name(&self) -> &'static str {
+        "xbr2x"
+    }
+
+    fn scale(&self) -> usize {
+        2
+    }
+
+    fn apply(&self, input: &Image, output: &mut Image) {
+        resize(output, input.width * 2, input.height * 2);
+        for y in 0..input.height {
+            for x in 0..input.width {
+                let e = pixel(input, x as isize, y as isize);
+                // 右下の角を基準に、左右上下を反転して4つの角を求める
+                for (n, (sx, sy)) in [(-1, -1), (1, -1), (-1, 1), (1, 1)].into_iter().enumerate() {
+                    let p = |dx: isize, dy: isize| {
+                        pixel(input, x as isize + dx * sx, y as isize + dy * sy)
+                    };
+                    let (b, c, d, f, g, h, i) = (
+                        p(0, -1),
+                        p(1, -1),
+                        p(-1, 0),
+                        p(1, 0),
+                        p(-1, 1),
+                        p(0, 1),
+                        p(1, 1),
+                    );
+                    let (f4, i4, h5, i5) = (p(2, 0), p(2, 1), p(0, 2), p(1, 2));
+                    let wd1 = distance(e, c)
+                        + distance(e, g)
+                        + distance(i, f4)
+                        + distance(i, h5)
+                        + 4 * distance(h, f);
+                    let wd2 = distance(h, d)
+                        + distance(h, i5)
+                        + distance(f, i4)
+                        + distance(f, b)
+                        + 4 * distance(e, i);
+                    let rgb = if wd1 < wd2 {
+                        let near = if distance(e, f) <= distance(e, h) {
+                            f
+                        } else {
+                            h
+                        };
+                        blend(e, near)
+                    } else {
+                        e
+                    };
+                    put(output, x * 2 + n % 2, y * 2 + n / 2, rgb);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // '#'を白、それ以外を黒にした画像
+    fn image(rows: &[&str]) -> Image {
+        Image {
+            width: rows[0].len(),
+            height: rows.len(),
+            data: rows
+                .iter()
+                .flat_map(|row| row.chars())
+                .flat_map(|c| if c == '#' { [255; 3] } else { [0; 3] })
+                .collect(),
+        }
+    }
+
+    fn rows(image: &Image) -> Vec<String> {
+        (0..image.height)
+            .map(|y| {
+                (0..image.width)
+                    .map(|x| match pixel(image, x as isize, y as isize) {
+                        [255, 255, 255] => '#',
+                        [0, 0, 0] => '.',
+                        _ => '+',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn apply(filter: &dyn Filter, input: &Image) -> Image {
+        let mut output = Image {
+            width: 0,
+            height: 0,
+            data: vec![],
+        };
+        filter.apply(input, &mut output);
+        assert_eq!(output.width, input.width * filter.scale());
+        assert_eq!(output.height, input.height * filter.scale());
+        output
+    }
+
+    #[test]
+    fn test_scale2x() {
+        let input = image(&["#...", "##..", "###.", "####"]);
+        assert_eq!(
+            rows(&apply(&Scale2x, &input)),
+            vec![
+                "##......", "###.....", "###.....", "#####...", "#####...", "#######.", "########",
+                "########",
+            ]
+        );
+        // 一色なら変わらない
+        let flat = image(&["##", "##"]);
+        assert!(rows(&apply(&Scale4x, &flat))
+            .iter()
+            .all(|row| row == "########"));
+    }
+
+    #[test]
+    fn test_scale3x() {
+        let input = image(&["#...", "##..", "###.", "####"]);
+        assert_eq!(
+            rows(&apply(&Scale3x, &input)),
+            vec![
+                "###.........",
+                "####........",
+                "####........",
+                "#####.......",
+                "######......",
+                "#######.....",
+                "########....",
+                "#########...",
+                "###########.",
+                "############",
+                "############",
+                "############",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_xbr2x() {
+        let input = image(&["#...", "##..", "###.", "####"]);
+        let output = rows(&apply(&Xbr2x, &input));
+        // 階段の角だけが半分の明るさで混ざって斜めの線になる
+        assert_eq!(
+            output,
+            vec![
+                "##......", "##+.....", "###+....", "####+...", "#####+..", "######+.", "########",
+                "########",
+            ]
+        );
+        assert_eq!(by_name("xbr2x").unwrap().scale(), 2);
+        assert!(by_name("hq2x").is_err());
+    }
+}
@@ -1,3 +1,10 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use crate::renderer_palette::Palette;
+
+#[derive(Clone)]
 pub struct Frame {
     pub data: Vec<u8>,
 }
@@ -20,4 +27,133 @@ impl Frame {
             self.data[base + 2] = rgb.2;
         }
     }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        write_png(path, Frame::WIDTH, Frame::HIGHT, &self.data)
+    }
+
+    // 端を切り落とした画像
+    pub fn crop(&self, overscan: Overscan) -> Image {
+        let width = Frame::WIDTH.saturating_sub(overscan.left + overscan.right);
+        let height = Frame::HIGHT.saturating_sub(overscan.top + overscan.bottom);
+        let mut data = Vec::with_capacity(width * height * 3);
+        for y in overscan.top..overscan.top + height {
+            let base = (y * Frame::WIDTH + overscan.left) * 3;
+            data.extend_from_slice(&self.data[base..base + width * 3]);
+        }
+        Image {
+            width,
+            height,
+            data,
+        }
+    }
+}
+
+fn write_png<P: AsRef<Path>>(
+    path: P,
+    width: usize,
+    height: usize,
+    data: &[u8],
+) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| e.to_string())?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer.write_image_data(data).map_err(|e| e.to_string())
+}
+
+// 画面の端で隠す幅 (ピクセル)。ブラウン管では上下8ラインほどが映らない
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Overscan {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+impl Overscan {
+    pub const NTSC: Overscan = Overscan {
+        top: 8,
+        bottom: 8,
+        left: 0,
+        right: 0,
+    };
+}
+
+// 大きさの決まっていないRGBの画像。スクリーンショット用
+#[derive(Clone)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        write_png(path, self.width, self.height, &self.data)
+    }
+}
+
+// 1ピクセルごとにパレットの番号と色の強調 (renderer_palette::indexed_color) を持つ画面。
+// RGBへの変換は最後にパレットを選んで行う
+#[derive(Clone)]
+pub struct IndexedFrame {
+    pub data: Vec<u16>,
+}
+
+impl IndexedFrame {
+    pub fn new() -> Self {
+        IndexedFrame {
+            data: vec![0; Frame::WIDTH * Frame::HIGHT],
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: u16) {
+        if let Some(pixel) = self.data.get_mut(y * Frame::WIDTH + x) {
+            *pixel = color;
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u16 {
+        self.data[y * Frame::WIDTH + x]
+    }
+
+    pub fn to_rgb(&self, colors: &Palette, frame: &mut Frame) {
+        for (i, color) in self.data.iter().enumerate() {
+            frame.set_pixel(
+                i % Frame::WIDTH,
+                i / Frame::WIDTH,
+                colors.rgb_indexed(*color),
+            );
+        }
+    }
+}
+
+impl Default for IndexedFrame {
+    fn default() -> Self {
+        IndexedFrame::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crop() {
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 8, (1, 2, 3));
+        frame.set_pixel(255, 231, (4, 5, 6));
+        frame.set_pixel(0, 7, (9, 9, 9));
+
+        let image = frame.crop(Overscan::NTSC);
+        assert_eq!((image.width, image.height), (256, 224));
+        assert_eq!(image.data.len(), 256 * 224 * 3);
+        assert_eq!(&image.data[..3], &[1, 2, 3]);
+        assert_eq!(&image.data[image.data.len() - 3..], &[4, 5, 6]);
+
+        let image = frame.crop(Overscan::default());
+        assert_eq!(image.data, frame.data);
+    }
 }
@@ -0,0 +1,78 @@
+use crate::joypad::JoypadButton;
+use crate::renderer_frame::Frame;
+
+const WIDTH: usize = 35;
+const HEIGHT: usize = 11;
+// 画面の端からの余白
+const MARGIN: usize = 8;
+
+const BACKGROUND: (u8, u8, u8) = (0, 0, 0);
+const RELEASED: (u8, u8, u8) = (80, 80, 80);
+const PRESSED: (u8, u8, u8) = (255, 255, 255);
+
+// ボタンごとの (x, y, 幅, 高さ)。左に十字キー、中央にSELECT/START、右にB/A
+const LAYOUT: [(JoypadButton, usize, usize, usize, usize); 8] = [
+    (JoypadButton::UP, 4, 1, 3, 3),
+    (JoypadButton::LEFT, 1, 4, 3, 3),
+    (JoypadButton::RIGHT, 7, 4, 3, 3),
+    (JoypadButton::DOWN, 4, 7, 3, 3),
+    (JoypadButton::SELECT, 12, 6, 4, 2),
+    (JoypadButton::START, 18, 6, 4, 2),
+    (JoypadButton::BUTTON_B, 25, 4, 3, 3),
+    (JoypadButton::BUTTON_A, 30, 4, 3, 3),
+];
+
+// 押されているボタンを画面の下に小さなコントローラーとして描く。
+// port 0 (1P) は左下、port 1 (2P) は右下
+pub fn draw_input(frame: &mut Frame, port: usize, buttons: JoypadButton) {
+    let left = if port == 0 {
+        MARGIN
+    } else {
+        256 - MARGIN - WIDTH
+    };
+    let top = 240 - MARGIN - HEIGHT;
+    fill(frame, left, top, WIDTH, HEIGHT, BACKGROUND);
+    for (button, x, y, w, h) in LAYOUT {
+        let color = if buttons.contains(button) {
+            PRESSED
+        } else {
+            RELEASED
+        };
+        fill(frame, left + x, top + y, w, h, color);
+    }
+}
+
+fn fill(frame: &mut Frame, x: usize, y: usize, w: usize, h: usize, rgb: (u8, u8, u8)) {
+    for dy in 0..h {
+        for dx in 0..w {
+            frame.set_pixel(x + dx, y + dy, rgb);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pixel(frame: &Frame, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * 256 + x) * 3;
+        (frame.data[base], frame.data[base + 1], frame.data[base + 2])
+    }
+
+    #[test]
+    fn test_draw_input() {
+        let mut frame = Frame::new();
+        draw_input(&mut frame, 0, JoypadButton::BUTTON_A);
+        let top = 240 - MARGIN - HEIGHT;
+        // A (右端) だけ明るい
+        assert_eq!(pixel(&frame, MARGIN + 31, top + 5), PRESSED);
+        assert_eq!(pixel(&frame, MARGIN + 26, top + 5), RELEASED);
+        assert_eq!(pixel(&frame, MARGIN + 5, top + 2), RELEASED);
+
+        draw_input(&mut frame, 1, JoypadButton::UP);
+        let left = 256 - MARGIN - WIDTH;
+        assert_eq!(pixel(&frame, left + 5, top + 2), PRESSED);
+        // 枠の外は触らない
+        assert_eq!(pixel(&frame, left - 1, top), (0, 0, 0));
+    }
+}
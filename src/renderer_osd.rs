@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::renderer_frame::Frame;
+
+// 画面の端からの余白。上下はオーバースキャンで隠れる8ラインより内側にする
+const MARGIN: usize = 10;
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+// 文字と行の間隔 (影の分も含む)
+const ADVANCE: usize = GLYPH_WIDTH + 1;
+const LINE_HEIGHT: usize = GLYPH_HEIGHT + 2;
+
+const TEXT: (u8, u8, u8) = (255, 255, 255);
+const SHADOW: (u8, u8, u8) = (0, 0, 0);
+
+// メッセージを出しておく時間と、一度に出す数
+const MESSAGE_DURATION: Duration = Duration::from_secs(3);
+const MAX_MESSAGES: usize = 4;
+
+// 3x5のフォント。各行の下位3ビットで、左の点が0b100
+const GLYPHS: [(char, [u8; 5]); 50] = [
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b001, 0b001, 0b001]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('A', [0b010, 0b101, 0b111, 0b101, 0b101]),
+    ('B', [0b110, 0b101, 0b110, 0b101, 0b110]),
+    ('C', [0b011, 0b100, 0b100, 0b100, 0b011]),
+    ('D', [0b110, 0b101, 0b101, 0b101, 0b110]),
+    ('E', [0b111, 0b100, 0b110, 0b100, 0b111]),
+    ('F', [0b111, 0b100, 0b110, 0b100, 0b100]),
+    ('G', [0b011, 0b100, 0b101, 0b101, 0b011]),
+    ('H', [0b101, 0b101, 0b111, 0b101, 0b101]),
+    ('I', [0b111, 0b010, 0b010, 0b010, 0b111]),
+    ('J', [0b001, 0b001, 0b001, 0b101, 0b010]),
+    ('K', [0b101, 0b101, 0b110, 0b101, 0b101]),
+    ('L', [0b100, 0b100, 0b100, 0b100, 0b111]),
+    ('M', [0b101, 0b111, 0b111, 0b101, 0b101]),
+    ('N', [0b110, 0b101, 0b101, 0b101, 0b101]),
+    ('O', [0b010, 0b101, 0b101, 0b101, 0b010]),
+    ('P', [0b110, 0b101, 0b110, 0b100, 0b100]),
+    ('Q', [0b010, 0b101, 0b101, 0b110, 0b011]),
+    ('R', [0b110, 0b101, 0b110, 0b101, 0b101]),
+    ('S', [0b011, 0b100, 0b010, 0b001, 0b110]),
+    ('T', [0b111, 0b010, 0b010, 0b010, 0b010]),
+    ('U', [0b101, 0b101, 0b101, 0b101, 0b111]),
+    ('V', [0b101, 0b101, 0b101, 0b101, 0b010]),
+    ('W', [0b101, 0b101, 0b111, 0b111, 0b101]),
+    ('X', [0b101, 0b101, 0b010, 0b101, 0b101]),
+    ('Y', [0b101, 0b101, 0b010, 0b010, 0b010]),
+    ('Z', [0b111, 0b001, 0b010, 0b100, 0b111]),
+    (' ', [0b000, 0b000, 0b000, 0b000, 0b000]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+    (',', [0b000, 0b000, 0b000, 0b010, 0b100]),
+    (':', [0b000, 0b010, 0b000, 0b010, 0b000]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('+', [0b000, 0b010, 0b111, 0b010, 0b000]),
+    ('=', [0b000, 0b111, 0b000, 0b111, 0b000]),
+    ('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+    ('%', [0b101, 0b001, 0b010, 0b100, 0b101]),
+    ('_', [0b000, 0b000, 0b000, 0b000, 0b111]),
+    ('!', [0b010, 0b010, 0b010, 0b000, 0b010]),
+    ('?', [0b111, 0b001, 0b010, 0b000, 0b010]),
+    ('(', [0b001, 0b010, 0b010, 0b010, 0b001]),
+    (')', [0b100, 0b010, 0b010, 0b010, 0b100]),
+];
+
+// 小文字は大文字で、フォントにない文字は?で描く
+fn glyph(c: char) -> [u8; 5] {
+    let c = c.to_ascii_uppercase();
+    let find = |c: char| GLYPHS.iter().find(|(g, _)| *g == c);
+    find(c)
+        .or_else(|| find('?'))
+        .map(|(_, rows)| *rows)
+        .unwrap()
+}
+
+pub fn text_width(text: &str) -> usize {
+    text.chars().count() * ADVANCE
+}
+
+// (x, y)を左上として1行描く。読みやすいように右下に影を付ける。画面の外ははみ出した分を捨てる
+pub fn draw_text(frame: &mut Frame, x: usize, y: usize, text: &str) {
+    for (shadow, color) in [(1, SHADOW), (0, TEXT)] {
+        for (i, c) in text.chars().enumerate() {
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for column in 0..GLYPH_WIDTH {
+                    if bits & (0b100 >> column) == 0 {
+                        continue;
+                    }
+                    let px = x + i * ADVANCE + column + shadow;
+                    let py = y + row + shadow;
+                    if px < 256 && py < 240 {
+                        frame.set_pixel(px, py, color);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// 画面に重ねて出す情報。FPSは右上、「screenshot saved」のような知らせは左上に
+// しばらく出して消す。フレームそのものではなく表示する直前のコピーに描くので、
+// スクリーンショットや録画には入らない
+#[derive(Default)]
+pub struct Osd {
+    pub show_fps: bool,
+    fps: Option<f64>,
+    // FPSを数え始めた時刻とそれからのフレーム数
+    since: Option<Instant>,
+    frames: u32,
+    // 文字と消す時刻
+    messages: VecDeque<(String, Instant)>,
+}
+
+impl Osd {
+    pub fn new() -> Self {
+        Osd::default()
+    }
+
+    pub fn message<S: Into<String>>(&mut self, text: S, now: Instant) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages
+            .push_back((text.into(), now + MESSAGE_DURATION));
+    }
+
+    // 表示したフレームごとに呼ぶ
+    pub fn tick(&mut self, now: Instant) {
+        self.messages.retain(|(_, until)| *until > now);
+        let since = *self.since.get_or_insert(now);
+        self.frames += 1;
+        let elapsed = now.duration_since(since);
+        // 1秒ごとに更新する
+        if elapsed >= Duration::from_secs(1) {
+            self.fps = Some((self.frames - 1) as f64 / elapsed.as_secs_f64());
+            self.since = Some(now);
+            self.frames = 1;
+        }
+    }
+
+    // 直近1秒の表示フレーム数。まだ測れていなければNone
+    pub fn fps(&self) -> Option<f64> {
+        self.fps
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.show_fps || !self.messages.is_empty()
+    }
+
+    pub fn draw(&self, frame: &mut Frame) {
+        if self.show_fps {
+            let text = match self.fps {
+                Some(fps) => format!("{:.1} FPS", fps),
+                None => "-- FPS".to_string(),
+            };
+            draw_text(frame, 256 - MARGIN - text_width(&text), MARGIN, &text);
+        }
+        for (i, (text, _)) in self.messages.iter().enumerate() {
+            draw_text(frame, MARGIN, MARGIN + i * LINE_HEIGHT, text);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pixel(frame: &Frame, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * 256 + x) * 3;
+        (frame.data[base], frame.data[base + 1], frame.data[base + 2])
+    }
+
+    #[test]
+    fn test_draw_text() {
+        let mut frame = Frame::new();
+        draw_text(&mut frame, 0, 0, "1a");
+        // 1の縦棒と影
+        assert_eq!(pixel(&frame, 1, 0), TEXT);
+        assert_eq!(pixel(&frame, 2, 1), SHADOW);
+        assert_eq!(pixel(&frame, 0, 4), TEXT);
+        // aはAとして描く
+        assert_eq!(pixel(&frame, ADVANCE + 1, 0), TEXT);
+        assert_eq!(pixel(&frame, ADVANCE, 0), (0, 0, 0));
+        assert_eq!(glyph('~'), glyph('?'));
+        assert_eq!(text_width("60.0 FPS"), 32);
+
+        // 画面の端からはみ出しても落ちない
+        draw_text(&mut frame, 250, 238, "WWW");
+        assert_eq!(pixel(&frame, 250, 238), TEXT);
+    }
+
+    #[test]
+    fn test_osd() {
+        let start = Instant::now();
+        let mut osd = Osd::new();
+        assert!(!osd.is_visible());
+
+        osd.message("screenshot saved", start);
+        assert!(osd.is_visible());
+        for i in 0..=60 {
+            osd.tick(start + Duration::from_millis(i * 1000 / 60));
+        }
+        assert_eq!(osd.fps().map(|fps| fps.round()), Some(60.0));
+
+        let mut frame = Frame::new();
+        osd.show_fps = true;
+        osd.draw(&mut frame);
+        assert_eq!(pixel(&frame, MARGIN, MARGIN + 1), TEXT);
+        assert_eq!(pixel(&frame, 256 - MARGIN - 2, MARGIN), TEXT);
+
+        // 時間が経つとメッセージは消える
+        osd.tick(start + MESSAGE_DURATION);
+        osd.show_fps = false;
+        assert!(!osd.is_visible());
+
+        for i in 0..6 {
+            osd.message(format!("slot {}", i), start);
+        }
+        assert_eq!(osd.messages.len(), MAX_MESSAGES);
+        assert_eq!(osd.messages[0].0, "slot 2");
+    }
+}
@@ -1,17 +1,236 @@
-#[rustfmt::skip]
-
-pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
-   (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96), (0xA1, 0x00, 0x5E),
-   (0xC7, 0x00, 0x28), (0xBA, 0x06, 0x00), (0x8C, 0x17, 0x00), (0x5C, 0x2F, 0x00), (0x10, 0x45, 0x00),
-   (0x05, 0x4A, 0x00), (0x00, 0x47, 0x2E), (0x00, 0x41, 0x66), (0x00, 0x00, 0x00), (0x05, 0x05, 0x05),
-   (0x05, 0x05, 0x05), (0xC7, 0xC7, 0xC7), (0x00, 0x77, 0xFF), (0x21, 0x55, 0xFF), (0x82, 0x37, 0xFA),
-   (0xEB, 0x2F, 0xB5), (0xFF, 0x29, 0x50), (0xFF, 0x22, 0x00), (0xD6, 0x32, 0x00), (0xC4, 0x62, 0x00),
-   (0x35, 0x80, 0x00), (0x05, 0x8F, 0x00), (0x00, 0x8A, 0x55), (0x00, 0x99, 0xCC), (0x21, 0x21, 0x21),
-   (0x09, 0x09, 0x09), (0x09, 0x09, 0x09), (0xFF, 0xFF, 0xFF), (0x0F, 0xD7, 0xFF), (0x69, 0xA2, 0xFF),
-   (0xD4, 0x80, 0xFF), (0xFF, 0x45, 0xF3), (0xFF, 0x61, 0x8B), (0xFF, 0x88, 0x33), (0xFF, 0x9C, 0x12),
-   (0xFA, 0xBC, 0x20), (0x9F, 0xE3, 0x0E), (0x2B, 0xF0, 0x35), (0x0C, 0xF0, 0xA4), (0x05, 0xFB, 0xFF),
-   (0x5E, 0x5E, 0x5E), (0x0D, 0x0D, 0x0D), (0x0D, 0x0D, 0x0D), (0xFF, 0xFF, 0xFF), (0xA6, 0xFC, 0xFF),
-   (0xB3, 0xEC, 0xFF), (0xDA, 0xAB, 0xEB), (0xFF, 0xA8, 0xF9), (0xFF, 0xAB, 0xB3), (0xFF, 0xD2, 0xB0),
-   (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
-   (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
-];
+use std::f64::consts::PI;
+
+use once_cell::sync::Lazy;
+
+use crate::ppu_mask_register::{Color, MaskRegister};
+
+// NTSCの信号レベル (V)。明るさ0-3ごとの低い側と高い側
+const SIGNAL_LOW: [f64; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f64; 4] = [1.094, 1.506, 1.962, 1.962];
+const BLACK: f64 = 0.518;
+const WHITE: f64 = 1.962;
+// 強調したときに、強調していない色の位相の信号が弱まる割合
+const EMPHASIS_ATTENUATION: f64 = 0.746;
+// 色相0-12のうち、強調ビット (赤, 緑, 青) が弱めない位相の色相
+const EMPHASIS_HUES: [usize; 3] = [0, 4, 8];
+
+static DEFAULT: Lazy<Palette> = Lazy::new(Palette::ntsc);
+
+// パレットの番号 (0-63) からRGBへの変換表。
+// 64色か、強調ビットの組み合わせ8通りを含めた512色を持つ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    colors: Vec<(u8, u8, u8)>,
+}
+
+impl Palette {
+    // .palファイル。64色 (192バイト) か512色 (1536バイト)
+    pub fn from_pal(data: &[u8]) -> Result<Palette, String> {
+        if data.len() != 64 * 3 && data.len() != 512 * 3 {
+            return Err(format!(
+                "invalid .pal size: {} bytes (expected 192 or 1536)",
+                data.len()
+            ));
+        }
+        Ok(Palette {
+            colors: data.chunks(3).map(|c| (c[0], c[1], c[2])).collect(),
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Palette, String> {
+        let data = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        Palette::from_pal(&data)
+    }
+
+    pub fn to_pal(&self) -> Vec<u8> {
+        self.colors
+            .iter()
+            .flat_map(|&(r, g, b)| [r, g, b])
+            .collect()
+    }
+
+    // PPUが出すNTSC信号を1周期12サンプルで作り、YIQに復調して512色を求める
+    pub fn ntsc() -> Palette {
+        let mut colors = Vec::with_capacity(512);
+        for emphasis in 0..8 {
+            for index in 0..64 {
+                colors.push(ntsc_color(index, emphasis));
+            }
+        }
+        Palette { colors }
+    }
+
+    pub fn has_emphasis(&self) -> bool {
+        self.colors.len() == 512
+    }
+
+    // PPUMASKのグレースケールと色の強調を掛けた色
+    pub fn rgb(&self, index: u8, mask: &MaskRegister) -> (u8, u8, u8) {
+        self.rgb_indexed(indexed_color(index, mask))
+    }
+
+    // indexed_colorで作った9ビットの色
+    pub fn rgb_indexed(&self, color: u16) -> (u8, u8, u8) {
+        let index = (color & 0x3f) as usize;
+        let emphasis = (color >> 6 & 0b111) as usize;
+        if self.has_emphasis() {
+            return self.colors[emphasis * 64 + index];
+        }
+        dim(
+            self.colors[index],
+            &MaskRegister::from_bits_truncate((emphasis << 5) as u8),
+        )
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        DEFAULT.clone()
+    }
+}
+
+// パレットの番号 (下位6ビット) に色の強調 (上位3ビット) を付けた色。
+// グレースケールはここで掛ける
+pub fn indexed_color(index: u8, mask: &MaskRegister) -> u16 {
+    let index = if mask.is_grayscale() {
+        index & 0x30
+    } else {
+        index & 0x3f
+    };
+    (mask.bits() as u16 >> 5) << 6 | index as u16
+}
+
+// 生成したNTSCパレット
+pub fn default_palette() -> &'static Palette {
+    &DEFAULT
+}
+
+fn ntsc_color(index: usize, emphasis: usize) -> (u8, u8, u8) {
+    let hue = index & 0x0f;
+    // $xE, $xFは黒
+    let level = if hue >= 0x0e { 1 } else { (index >> 4) & 0b11 };
+    let low = if hue == 0 {
+        SIGNAL_HIGH[level]
+    } else {
+        SIGNAL_LOW[level]
+    };
+    let high = if hue > 0x0c { low } else { SIGNAL_HIGH[level] };
+    let in_phase = |hue: usize, phase: usize| (hue + phase) % 12 < 6;
+
+    let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+    for phase in 0..12 {
+        let mut signal = if in_phase(hue, phase) { high } else { low };
+        let attenuated = EMPHASIS_HUES
+            .iter()
+            .enumerate()
+            .any(|(bit, &h)| emphasis >> bit & 1 != 0 && in_phase(h, phase));
+        if hue < 0x0e && attenuated {
+            signal *= EMPHASIS_ATTENUATION;
+        }
+        let value = (signal - BLACK) / (WHITE - BLACK);
+        // 色相の基準をカラーバーストに合わせる
+        let angle = PI * (phase as f64 + 4.0) / 6.0;
+        y += value;
+        i += value * angle.cos();
+        q += value * angle.sin();
+    }
+    let (y, i, q) = (y / 12.0, i / 6.0, q / 6.0);
+
+    let to_u8 = |v: f64| (v * 255.0).round().clamp(0.0, 255.0) as u8;
+    (
+        to_u8(y + 0.946882 * i + 0.623557 * q),
+        to_u8(y - 0.274788 * i - 0.635691 * q),
+        to_u8(y - 1.108545 * i + 1.709007 * q),
+    )
+}
+
+// 64色のパレットでの強調の近似。
+// 強調されていない成分はおよそ3/4の明るさになる。3色とも強調すると全体が暗くなる
+fn dim((r, g, b): (u8, u8, u8), mask: &MaskRegister) -> (u8, u8, u8) {
+    let emphasis = mask.emphasize();
+    if emphasis.is_empty() {
+        return (r, g, b);
+    }
+    let (mut keep_r, mut keep_g, mut keep_b) = (false, false, false);
+    for color in emphasis.iter() {
+        match color {
+            Color::Red => keep_r = true,
+            Color::Green => keep_g = true,
+            Color::Blue => keep_b = true,
+        }
+    }
+    let all = emphasis.len() == 3;
+    let dim = |value: u8, keep: bool| {
+        if keep && !all {
+            value
+        } else {
+            (value as u16 * 3 / 4) as u8
+        }
+    };
+    (dim(r, keep_r), dim(g, keep_g), dim(b, keep_b))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ntsc_palette() {
+        let palette = Palette::ntsc();
+        let mask = MaskRegister::new();
+        assert!(palette.has_emphasis());
+        assert_eq!(palette.rgb(0x0f, &mask), (0, 0, 0));
+        assert_eq!(palette.rgb(0x30, &mask), (255, 255, 255));
+        // 0x16は赤、0x1Aは緑、0x12は青が強い
+        let (r, g, b) = palette.rgb(0x16, &mask);
+        assert!(r > g && r > b);
+        let (r, g, b) = palette.rgb(0x1a, &mask);
+        assert!(g > r && g > b);
+        let (r, g, b) = palette.rgb(0x12, &mask);
+        assert!(b > r && b > g);
+
+        // 赤を強調すると緑と青が弱まる
+        let mut red = MaskRegister::new();
+        red.update(0b0010_0000);
+        let (r0, g0, b0) = palette.rgb(0x20, &mask);
+        let (r1, g1, b1) = palette.rgb(0x20, &red);
+        assert!(g1 < g0 && b1 < b0 && r1 >= g1);
+        assert_eq!(r0, 255);
+    }
+
+    #[test]
+    fn test_pal_file() {
+        assert!(Palette::from_pal(&[0; 100]).is_err());
+
+        let mut data = vec![0; 192];
+        data[0x16 * 3..0x16 * 3 + 3].copy_from_slice(&[200, 100, 40]);
+        let palette = Palette::from_pal(&data).unwrap();
+        assert!(!palette.has_emphasis());
+        assert_eq!(palette.to_pal(), data);
+        let mut mask = MaskRegister::new();
+        assert_eq!(palette.rgb(0x16, &mask), (200, 100, 40));
+        mask.update(0b0010_0000);
+        assert_eq!(palette.rgb(0x16, &mask), (200, 75, 30));
+
+        // 512色のファイルは強調ビットで後ろの64色を使う
+        let mut data = vec![0; 1536];
+        data[64 * 3 + 0x16 * 3] = 99;
+        let palette = Palette::from_pal(&data).unwrap();
+        assert_eq!(palette.rgb(0x16, &mask), (99, 0, 0));
+    }
+
+    #[test]
+    fn test_indexed_color() {
+        let mut mask = MaskRegister::new();
+        mask.update(0b1010_0001);
+        // グレースケールと青、赤の強調
+        assert_eq!(indexed_color(0x16, &mask), 0b101 << 6 | 0x10);
+        let small = Palette::from_pal(&default_palette().to_pal()[..192]).unwrap();
+        for palette in [default_palette(), &small] {
+            assert_eq!(
+                palette.rgb_indexed(indexed_color(0x16, &mask)),
+                palette.rgb(0x16, &mask)
+            );
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use once_cell::sync::Lazy;
+
+use crate::cartridge::{Mirroring, Rom, Timing};
+use crate::console::Region;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbEntry {
+    pub crc32: u32,
+    pub mapper: Option<u16>,
+    pub submapper: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    pub has_battery: Option<bool>,
+    pub region: Option<Region>,
+}
+
+// 同梱のrom_db.txtにはエントリが無い。使う人が書き足す
+static DATABASE: Lazy<Vec<DbEntry>> =
+    Lazy::new(|| parse(include_str!("rom_db.txt")).expect("rom_db.txt is invalid"));
+
+// 1行1エントリ。#以降はコメント
+pub fn parse(text: &str) -> Result<Vec<DbEntry>, String> {
+    let mut entries = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let crc = fields.next().unwrap();
+        let crc32 = u32::from_str_radix(crc, 16)
+            .map_err(|_| format!("line {}: invalid CRC32 {:?}", i + 1, crc))?;
+        let mut entry = DbEntry {
+            crc32,
+            mapper: None,
+            submapper: None,
+            mirroring: None,
+            has_battery: None,
+            region: None,
+        };
+        for field in fields {
+            let invalid = || format!("line {}: invalid field {:?}", i + 1, field);
+            let (key, value) = field.split_once('=').ok_or_else(invalid)?;
+            match key {
+                "mapper" => entry.mapper = Some(value.parse().map_err(|_| invalid())?),
+                "submapper" => entry.submapper = Some(value.parse().map_err(|_| invalid())?),
+                "mirroring" => {
+                    entry.mirroring = Some(match value {
+                        "horizontal" => Mirroring::HORIZONTAL,
+                        "vertical" => Mirroring::VERTICAL,
+                        "four-screen" => Mirroring::FOUR_SCREEN,
+                        _ => return Err(invalid()),
+                    })
+                }
+                "battery" => {
+                    entry.has_battery = Some(match value {
+                        "yes" => true,
+                        "no" => false,
+                        _ => return Err(invalid()),
+                    })
+                }
+                "region" => {
+                    entry.region = Some(match value {
+                        "ntsc" => Region::Ntsc,
+                        "pal" => Region::Pal,
+                        "dendy" => Region::Dendy,
+                        _ => return Err(invalid()),
+                    })
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+pub fn lookup(crc32: u32) -> Option<&'static DbEntry> {
+    DATABASE.iter().find(|entry| entry.crc32 == crc32)
+}
+
+impl DbEntry {
+    pub fn apply(&self, rom: &mut Rom) {
+        if let Some(mapper) = self.mapper {
+            rom.mapper = mapper;
+            rom.header.mapper = mapper;
+        }
+        if let Some(submapper) = self.submapper {
+            rom.header.submapper = submapper;
+        }
+        if let Some(mirroring) = self.mirroring {
+            rom.screen_mirroring = mirroring;
+            rom.header.mirroring = mirroring;
+        }
+        if let Some(has_battery) = self.has_battery {
+            rom.header.has_battery = has_battery;
+        }
+        if let Some(region) = self.region {
+            rom.region = region;
+            rom.header.timing = match region {
+                Region::Ntsc => Timing::Ntsc,
+                Region::Pal => Timing::Pal,
+                Region::Dendy => Timing::Dendy,
+            };
+        }
+    }
+}
+
+// 読み込んだROMがデータベースにあれば設定を上書きする
+pub fn apply(rom: &mut Rom) -> bool {
+    match lookup(rom.info().crc32) {
+        Some(entry) => {
+            entry.apply(rom);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_embedded_database_parses() {
+        assert!(parse(include_str!("rom_db.txt")).is_ok());
+        assert_eq!(lookup(test_rom().info().crc32), None);
+    }
+
+    #[test]
+    fn test_parse() {
+        let entries = parse(
+            "# comment\n\n1a2b3c4d mapper=66 mirroring=vertical # fix\nDEADBEEF battery=yes region=pal submapper=1\n",
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].crc32, 0x1a2b3c4d);
+        assert_eq!(entries[0].mapper, Some(66));
+        assert_eq!(entries[0].mirroring, Some(Mirroring::VERTICAL));
+        assert_eq!(entries[0].has_battery, None);
+        assert_eq!(entries[1].has_battery, Some(true));
+        assert_eq!(entries[1].region, Some(Region::Pal));
+        assert_eq!(entries[1].submapper, Some(1));
+
+        assert!(parse("xyz mapper=1").is_err());
+        assert!(parse("1a2b3c4d mapper").is_err());
+        assert!(parse("1a2b3c4d mirroring=diagonal").is_err());
+        assert!(parse("1a2b3c4d colour=red").is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut rom = test_rom();
+        let entry = &parse("0 mapper=66 mirroring=horizontal battery=yes region=pal").unwrap()[0];
+        entry.apply(&mut rom);
+        assert_eq!(rom.mapper, 66);
+        assert_eq!(rom.header.mapper, 66);
+        assert_eq!(rom.screen_mirroring, Mirroring::HORIZONTAL);
+        assert!(rom.header.has_battery);
+        assert_eq!(rom.region, Region::Pal);
+        assert_eq!(rom.header.timing, Timing::Pal);
+    }
+}
@@ -0,0 +1,198 @@
+// マシン全体の状態を書き出す/読み込むための入れ物。各部品は同じstate関数で
+// 書き出しと読み込みの両方を行うので、フィールドの順番がずれることがない。
+// 書き出し先のVecを使い回せば、2回目からはメモリを確保しない
+pub enum State<'a> {
+    Save(&'a mut Vec<u8>),
+    // 読み込むたびに先頭を進める。足りなければtruncatedを立てて0を読む
+    Load { data: &'a [u8], truncated: bool },
+}
+
+impl<'a> State<'a> {
+    pub fn save(buf: &'a mut Vec<u8>) -> Self {
+        buf.clear();
+        State::Save(buf)
+    }
+
+    pub fn load(data: &'a [u8]) -> Self {
+        State::Load {
+            data,
+            truncated: false,
+        }
+    }
+
+    // 読み込みで、データがちょうど使い切られたか
+    pub fn finish(self) -> Result<(), String> {
+        match self {
+            State::Save(_) => Ok(()),
+            State::Load {
+                truncated: true, ..
+            } => Err("save state is truncated".to_string()),
+            State::Load { data, .. } if !data.is_empty() => {
+                Err(format!("save state has {} extra bytes", data.len()))
+            }
+            State::Load { .. } => Ok(()),
+        }
+    }
+
+    pub fn bytes(&mut self, value: &mut [u8]) {
+        match self {
+            State::Save(buf) => buf.extend_from_slice(value),
+            State::Load { data, truncated } => {
+                if data.len() < value.len() {
+                    *truncated = true;
+                    *data = &[];
+                    value.fill(0);
+                    return;
+                }
+                let (head, rest) = data.split_at(value.len());
+                value.copy_from_slice(head);
+                *data = rest;
+            }
+        }
+    }
+
+    pub fn u8(&mut self, value: &mut u8) {
+        self.bytes(std::slice::from_mut(value));
+    }
+
+    pub fn bool(&mut self, value: &mut bool) {
+        let mut byte = *value as u8;
+        self.u8(&mut byte);
+        *value = byte != 0;
+    }
+
+    pub fn u16(&mut self, value: &mut u16) {
+        let mut bytes = value.to_le_bytes();
+        self.bytes(&mut bytes);
+        *value = u16::from_le_bytes(bytes);
+    }
+
+    pub fn u32(&mut self, value: &mut u32) {
+        let mut bytes = value.to_le_bytes();
+        self.bytes(&mut bytes);
+        *value = u32::from_le_bytes(bytes);
+    }
+
+    pub fn u64(&mut self, value: &mut u64) {
+        let mut bytes = value.to_le_bytes();
+        self.bytes(&mut bytes);
+        *value = u64::from_le_bytes(bytes);
+    }
+
+    pub fn usize(&mut self, value: &mut usize) {
+        let mut wide = *value as u64;
+        self.u64(&mut wide);
+        *value = wide as usize;
+    }
+
+    pub fn i16(&mut self, value: &mut i16) {
+        let mut bits = *value as u16;
+        self.u16(&mut bits);
+        *value = bits as i16;
+    }
+
+    pub fn f32(&mut self, value: &mut f32) {
+        let mut bits = value.to_bits();
+        self.u32(&mut bits);
+        *value = f32::from_bits(bits);
+    }
+
+    pub fn f64(&mut self, value: &mut f64) {
+        let mut bits = value.to_bits();
+        self.u64(&mut bits);
+        *value = f64::from_bits(bits);
+    }
+
+    // u8で表せる列挙型など。読み込んだ値はfromで戻す
+    pub fn map_u8<T: Copy>(&mut self, value: &mut T, to: impl Fn(T) -> u8, from: impl Fn(u8) -> T) {
+        let mut byte = to(*value);
+        self.u8(&mut byte);
+        *value = from(byte);
+    }
+
+    // 長さの変わらない配列やVec
+    pub fn u16_slice(&mut self, values: &mut [u16]) {
+        for value in values {
+            self.u16(value);
+        }
+    }
+
+    pub fn option<T: Default>(
+        &mut self,
+        value: &mut Option<T>,
+        mut each: impl FnMut(&mut State, &mut T),
+    ) {
+        let mut some = value.is_some();
+        self.bool(&mut some);
+        if !some {
+            *value = None;
+            return;
+        }
+        each(self, value.get_or_insert_with(T::default));
+    }
+
+    // 長さの変わるVec。読み込むときは中身を作り直す。
+    // 壊れたデータで巨大なVecを作らないよう、要素は1バイト以上として残りの長さで抑える
+    pub fn vec<T: Default>(
+        &mut self,
+        values: &mut Vec<T>,
+        mut each: impl FnMut(&mut State, &mut T),
+    ) {
+        let mut len = values.len() as u32;
+        self.u32(&mut len);
+        if let State::Load { data, truncated } = self {
+            if len as usize > data.len() {
+                *truncated = true;
+                *data = &[];
+                len = 0;
+            }
+        }
+        values.resize_with(len as usize, T::default);
+        for value in values.iter_mut() {
+            each(self, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sync(state: &mut State, values: &mut (u8, bool, u16, u64, f32, Vec<u16>)) {
+        state.u8(&mut values.0);
+        state.bool(&mut values.1);
+        state.u16(&mut values.2);
+        state.u64(&mut values.3);
+        state.f32(&mut values.4);
+        state.vec(&mut values.5, |state, value| state.u16(value));
+    }
+
+    #[test]
+    fn test_state() {
+        let mut original = (1, true, 0x1234, u64::MAX, -0.5, vec![1, 2, 3]);
+        let mut buf = vec![];
+        let mut state = State::save(&mut buf);
+        sync(&mut state, &mut original);
+        state.finish().unwrap();
+        assert_eq!(buf.len(), 1 + 1 + 2 + 8 + 4 + 4 + 6);
+
+        let mut loaded = (0, false, 0, 0, 0.0, vec![]);
+        let mut state = State::load(&buf);
+        sync(&mut state, &mut loaded);
+        state.finish().unwrap();
+        assert_eq!(loaded, original);
+
+        // 書き出し先は使い回せる
+        let capacity = buf.capacity();
+        sync(&mut State::save(&mut buf), &mut original);
+        assert_eq!(buf.capacity(), capacity);
+
+        let mut state = State::load(&buf[..10]);
+        sync(&mut state, &mut loaded);
+        assert!(state.finish().is_err());
+        buf.push(0);
+        let mut state = State::load(&buf);
+        sync(&mut state, &mut loaded);
+        assert!(state.finish().is_err());
+    }
+}
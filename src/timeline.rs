@@ -0,0 +1,139 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimelineEvent {
+    Nmi,
+    Irq,
+    BankSwitch { slot: u8, bank: u8 },
+    ScrollWrite(u8),
+    InputChange(u8),
+}
+
+impl TimelineEvent {
+    fn to_json(self) -> String {
+        match self {
+            TimelineEvent::Nmi => r#""type":"nmi""#.to_string(),
+            TimelineEvent::Irq => r#""type":"irq""#.to_string(),
+            TimelineEvent::BankSwitch { slot, bank } => {
+                format!(r#""type":"bank_switch","slot":{},"bank":{}"#, slot, bank)
+            }
+            TimelineEvent::ScrollWrite(value) => {
+                format!(r#""type":"scroll_write","value":{}"#, value)
+            }
+            TimelineEvent::InputChange(buttons) => {
+                format!(r#""type":"input_change","buttons":{}"#, buttons)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimelineEntry {
+    pub frame: u64,
+    pub scanline: u16,
+    pub dot: usize,
+    pub event: TimelineEvent,
+}
+
+// 外部の可視化ツール向けにフレームごとのイベントを記録する。
+// 記録はデフォルトで無効
+pub struct Timeline {
+    pub enabled: bool,
+    entries: Vec<TimelineEntry>,
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Timeline {
+            enabled: false,
+            entries: vec![],
+        }
+    }
+
+    pub fn record(&mut self, frame: u64, scanline: u16, dot: usize, event: TimelineEvent) {
+        if self.enabled {
+            self.entries.push(TimelineEntry {
+                frame,
+                scanline,
+                dot,
+                event,
+            });
+        }
+    }
+
+    pub fn entries(&self) -> &[TimelineEntry] {
+        &self.entries
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    // {"frames":[{"frame":0,"events":[{"scanline":241,"dot":3,"type":"nmi"}]}]}
+    pub fn to_json(&self) -> String {
+        let mut frames: Vec<String> = vec![];
+        let mut events: Vec<String> = vec![];
+        let mut current = None;
+        for entry in self.entries.iter() {
+            if current != Some(entry.frame) {
+                if let Some(frame) = current {
+                    frames.push(frame_json(frame, &events));
+                    events.clear();
+                }
+                current = Some(entry.frame);
+            }
+            events.push(format!(
+                r#"{{"scanline":{},"dot":{},{}}}"#,
+                entry.scanline,
+                entry.dot,
+                entry.event.to_json()
+            ));
+        }
+        if let Some(frame) = current {
+            frames.push(frame_json(frame, &events));
+        }
+        format!(r#"{{"frames":[{}]}}"#, frames.join(","))
+    }
+}
+
+fn frame_json(frame: u64, events: &[String]) -> String {
+    format!(r#"{{"frame":{},"events":[{}]}}"#, frame, events.join(","))
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Timeline::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_disabled_by_default() {
+        let mut timeline = Timeline::new();
+        timeline.record(0, 0, 0, TimelineEvent::Nmi);
+        assert!(timeline.entries().is_empty());
+        assert_eq!(timeline.to_json(), r#"{"frames":[]}"#);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut timeline = Timeline::new();
+        timeline.enabled = true;
+        timeline.record(1, 10, 20, TimelineEvent::ScrollWrite(8));
+        timeline.record(1, 241, 3, TimelineEvent::Nmi);
+        timeline.record(2, 0, 5, TimelineEvent::InputChange(0b1000_0001));
+        timeline.record(2, 30, 6, TimelineEvent::BankSwitch { slot: 1, bank: 3 });
+        assert_eq!(
+            timeline.to_json(),
+            concat!(
+                r#"{"frames":["#,
+                r#"{"frame":1,"events":[{"scanline":10,"dot":20,"type":"scroll_write","value":8},"#,
+                r#"{"scanline":241,"dot":3,"type":"nmi"}]},"#,
+                r#"{"frame":2,"events":[{"scanline":0,"dot":5,"type":"input_change","buttons":129},"#,
+                r#"{"scanline":30,"dot":6,"type":"bank_switch","slot":1,"bank":3}]}"#,
+                r#"]}"#
+            )
+        );
+    }
+}
@@ -1,13 +1,73 @@
 use crate::cpu::AddressingMode;
-use crate::cpu::Mem;
 use crate::cpu::CPU;
 use crate::opcodes;
-use std::collections::HashMap;
+use crate::opcodes::OpCategory;
+use crate::trace_binary::{self, BinaryRecord};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
 
-pub fn trace(cpu: &mut CPU) -> String {
+// 1命令分の実行前の状態。文字列化はDisplayで行う
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEvent {
+    pub pc: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str,
+    // 実効アドレスとその値を含むオペランド表記 (nestest.log形式)
+    pub operand: String,
+    pub register_a: u8,
+    pub register_x: u8,
+    pub register_y: u8,
+    pub status: u8,
+    pub stack_pointer: u8,
+    pub cycles: usize,
+    pub scanline: u16,
+    pub dot: usize,
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex_str = self
+            .bytes
+            .iter()
+            .map(|z| format!("{:02x}", z))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let asm_str = format!(
+            "{:04x}  {:8} {: >4} {}",
+            self.pc, hex_str, self.mnemonic, self.operand
+        )
+        .trim()
+        .to_string();
+
+        let line = format!(
+            "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x} PPU:{:>3},{:>3} CYC:{}",
+            asm_str,
+            self.register_a,
+            self.register_x,
+            self.register_y,
+            self.status,
+            self.stack_pointer,
+            self.scanline,
+            self.dot,
+            self.cycles,
+        );
+        write!(f, "{}", line.to_ascii_uppercase())
+    }
+}
+
+pub fn trace(cpu: &CPU) -> String {
+    trace_event(cpu).to_string()
+}
+
+// メモリはBus::peekで読むので、トレースしてもエミュレーションは変わらない
+pub fn trace_event(cpu: &CPU) -> TraceEvent {
     let ref opscodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
 
-    let code = cpu.mem_read(cpu.program_counter);
+    let code = cpu.bus.peek(cpu.program_counter);
     let ops = opscodes.get(&code).unwrap();
 
     let begin = cpu.program_counter;
@@ -16,9 +76,10 @@ pub fn trace(cpu: &mut CPU) -> String {
 
     let (mem_addr, stored_value) = match ops.mode {
         AddressingMode::Immediate | AddressingMode::NoneAddressing => (0, 0),
+        // PPUやAPU、I/OのレジスタはNintendulator (nestest.log) と同じくFFと表示される
         _ => {
             let addr = cpu.get_absolute_address(&ops.mode, begin + 1);
-            (addr, cpu.mem_read(addr))
+            (addr, cpu.bus.peek(addr))
         }
     };
 
@@ -28,7 +89,7 @@ pub fn trace(cpu: &mut CPU) -> String {
             _ => String::from(""),
         },
         2 => {
-            let address: u8 = cpu.mem_read(begin + 1);
+            let address: u8 = cpu.bus.peek(begin + 1);
             // let value = cpu.mem_read(address));
             hex_dump.push(address);
 
@@ -70,23 +131,23 @@ pub fn trace(cpu: &mut CPU) -> String {
             }
         }
         3 => {
-            let address_lo = cpu.mem_read(begin + 1);
-            let address_hi = cpu.mem_read(begin + 2);
+            let address_lo = cpu.bus.peek(begin + 1);
+            let address_hi = cpu.bus.peek(begin + 2);
             hex_dump.push(address_lo);
             hex_dump.push(address_hi);
 
-            let address = cpu.mem_read_u16(begin + 1);
+            let address = cpu.bus.peek_u16(begin + 1);
 
             match ops.mode {
                 AddressingMode::NoneAddressing => {
                     if ops.code == 0x6c {
                         //jmp indirect
                         let jmp_addr = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.mem_read(address);
-                            let hi = cpu.mem_read(address & 0xFF00);
+                            let lo = cpu.bus.peek(address);
+                            let hi = cpu.bus.peek(address & 0xFF00);
                             (hi as u16) << 8 | (lo as u16)
                         } else {
-                            cpu.mem_read_u16(address)
+                            cpu.bus.peek_u16(address)
                         };
 
                         // let jmp_addr = cpu.mem_read_u16(address);
@@ -113,20 +174,204 @@ pub fn trace(cpu: &mut CPU) -> String {
         _ => String::from(""),
     };
 
-    let hex_str = hex_dump
-        .iter()
-        .map(|z| format!("{:02x}", z))
-        .collect::<Vec<String>>()
-        .join(" ");
-    let asm_str = format!("{:04x}  {:8} {: >4} {}", begin, hex_str, ops.mnemonic, tmp)
-        .trim()
-        .to_string();
+    let ppu = cpu.bus.ppu();
+    TraceEvent {
+        pc: begin,
+        bytes: hex_dump,
+        mnemonic: ops.mnemonic,
+        operand: tmp,
+        register_a: cpu.register_a,
+        register_x: cpu.register_x,
+        register_y: cpu.register_y,
+        status: cpu.status,
+        stack_pointer: cpu.stack_pointer,
+        cycles: cpu.bus.cycles(),
+        scanline: ppu.scanline(),
+        dot: ppu.cycle(),
+    }
+}
+
+const COMPARE_CONTEXT: usize = 5;
+
+// 参照ログにない列 (nestest_no_cycle.logのPPU/CYCなど) は比較しない
+fn normalize(actual: &str, expected: &str) -> String {
+    let mut line = actual.to_string();
+    for label in [" CYC:", " PPU:"] {
+        if !expected.contains(label) {
+            if let Some(pos) = line.find(label) {
+                line.truncate(pos);
+            }
+        }
+    }
+    line
+}
+
+// 参照ログと1行ずつ突き合わせながらCPUを実行する。
+// 一致した行数を返し、ずれたら直前の数行を添えたレポートを返す
+pub fn compare<R: BufRead>(reader: R, cpu: &mut CPU) -> Result<usize, String> {
+    let mut context: VecDeque<String> = VecDeque::with_capacity(COMPARE_CONTEXT);
+    let mut matched = 0;
+    let mut halted = false;
+
+    for expected in reader.lines() {
+        let expected = expected.map_err(|e| e.to_string())?;
+        let mut actual = None;
+        if !halted {
+            halted = !cpu.step_with_callback(|cpu| actual = Some(trace(cpu)));
+        }
+        let actual = match actual {
+            Some(line) => normalize(&line, &expected),
+            None => "<cpu halted>".to_string(),
+        };
+
+        if actual != expected {
+            let mut report = vec![format!("divergence at line {}", matched + 1)];
+            report.extend(context.iter().map(|line| format!("  {}", line)));
+            report.push(format!("- {}", expected));
+            report.push(format!("+ {}", actual));
+            return Err(report.join("\n"));
+        }
+
+        if context.len() == COMPARE_CONTEXT {
+            context.pop_front();
+        }
+        context.push_back(expected);
+        matched += 1;
+    }
+    Ok(matched)
+}
+
+// 指定したアドレス範囲・命令の種類だけを記録する。空なら全て
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    pub pc_ranges: Vec<RangeInclusive<u16>>,
+    pub categories: Vec<OpCategory>,
+}
+
+impl TraceFilter {
+    pub fn unofficial_only() -> Self {
+        TraceFilter {
+            pc_ranges: vec![],
+            categories: vec![
+                OpCategory::StableUnofficial,
+                OpCategory::UnstableUnofficial,
+                OpCategory::Jam,
+            ],
+        }
+    }
+
+    pub fn matches(&self, pc: u16, category: OpCategory) -> bool {
+        (self.pc_ranges.is_empty() || self.pc_ranges.iter().any(|r| r.contains(&pc)))
+            && (self.categories.is_empty() || self.categories.contains(&category))
+    }
+}
+
+enum TraceSink {
+    File(BufWriter<File>),
+    Binary(BufWriter<File>),
+    Ring {
+        capacity: usize,
+        events: VecDeque<TraceEvent>,
+    },
+}
+
+// 長時間のトレースはファイルに流すか、直近N命令だけメモリに残す
+pub struct Tracer {
+    pub filter: TraceFilter,
+    sink: TraceSink,
+}
+
+impl Tracer {
+    pub fn to_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| e.to_string())?;
+        Ok(Tracer {
+            filter: TraceFilter::default(),
+            sink: TraceSink::File(BufWriter::new(file)),
+        })
+    }
+
+    // trace_binaryの固定長レコードで書き出す
+    pub fn to_binary_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| e.to_string())?;
+        Ok(Tracer {
+            filter: TraceFilter::default(),
+            sink: TraceSink::Binary(BufWriter::new(file)),
+        })
+    }
+
+    pub fn ring(capacity: usize) -> Self {
+        Tracer {
+            filter: TraceFilter::default(),
+            sink: TraceSink::Ring {
+                capacity,
+                events: VecDeque::with_capacity(capacity),
+            },
+        }
+    }
+
+    pub fn with_filter(mut self, filter: TraceFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    // run_with_callbackのコールバックから呼ぶ
+    pub fn record(&mut self, cpu: &CPU) -> Result<(), String> {
+        let pc = cpu.program_counter;
+        let code = cpu.bus.peek(pc);
+        let category = match opcodes::lookup(code) {
+            Some(op) => op.category,
+            None => return Ok(()),
+        };
+        if !self.filter.matches(pc, category) {
+            return Ok(());
+        }
+
+        if let TraceSink::Binary(writer) = &mut self.sink {
+            return trace_binary::write_record(writer, &BinaryRecord::capture(cpu));
+        }
+
+        let event = trace_event(cpu);
+        match &mut self.sink {
+            TraceSink::File(writer) => writeln!(writer, "{}", event).map_err(|e| e.to_string()),
+            TraceSink::Binary(_) => unreachable!(),
+            TraceSink::Ring { capacity, events } => {
+                if *capacity == 0 {
+                    return Ok(());
+                }
+                if events.len() == *capacity {
+                    events.pop_front();
+                }
+                events.push_back(event);
+                Ok(())
+            }
+        }
+    }
+
+    // リングバッファの中身を古い順に返す。ファイルモードでは空
+    pub fn events(&self) -> impl Iterator<Item = &TraceEvent> {
+        let events = match &self.sink {
+            TraceSink::File(_) | TraceSink::Binary(_) => None,
+            TraceSink::Ring { events, .. } => Some(events.iter()),
+        };
+        events.into_iter().flatten()
+    }
+
+    // クラッシュ後などにリングバッファの中身を書き出す
+    pub fn dump<W: Write>(&self, writer: &mut W) -> Result<(), String> {
+        for event in self.events() {
+            writeln!(writer, "{}", event).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
 
-    format!(
-        "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x}",
-        asm_str, cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer,
-    )
-    .to_ascii_uppercase()
+    pub fn flush(&mut self) -> Result<(), String> {
+        match &mut self.sink {
+            TraceSink::File(writer) | TraceSink::Binary(writer) => {
+                writer.flush().map_err(|e| e.to_string())
+            }
+            TraceSink::Ring { .. } => Ok(()),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -134,12 +379,18 @@ mod test {
     use super::*;
     use crate::bus::Bus;
     use crate::cartridge::test::test_rom;
+    use crate::cartridge::Rom;
+    use crate::cpu::Mem;
     use crate::joypad::Joypad;
     use crate::ppu::NesPPU;
 
     #[test]
     fn test_format_trace() {
-        let mut bus = Bus::new(test_rom(), |ppu: &NesPPU, joypad: &mut Joypad| {});
+        let mut bus = Bus::new(
+            test_rom(),
+            |ppu: &NesPPU, joypad: &mut Joypad, _: &mut Joypad| {},
+        )
+        .unwrap();
         bus.mem_write(100, 0xa2);
         bus.mem_write(101, 0x01);
         bus.mem_write(102, 0xca);
@@ -156,22 +407,26 @@ mod test {
             result.push(trace(cpu));
         });
         assert_eq!(
-            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD",
+            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD PPU:  0,  0 CYC:0",
             result[0]
         );
         assert_eq!(
-            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD",
+            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD PPU:  0,  6 CYC:2",
             result[1]
         );
         assert_eq!(
-            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD",
+            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD PPU:  0, 12 CYC:4",
             result[2]
         );
     }
 
     #[test]
     fn test_format_mem_access() {
-        let mut bus = Bus::new(test_rom(), |ppu: &NesPPU, joypad: &mut Joypad| {});
+        let mut bus = Bus::new(
+            test_rom(),
+            |ppu: &NesPPU, joypad: &mut Joypad, _: &mut Joypad| {},
+        )
+        .unwrap();
         // ORA ($33), Y
         bus.mem_write(100, 0x11);
         bus.mem_write(101, 0x33);
@@ -191,8 +446,188 @@ mod test {
             result.push(trace(cpu));
         });
         assert_eq!(
-            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD",
+            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0",
             result[0]
         );
     }
+
+    #[test]
+    fn test_trace_event() {
+        let mut bus =
+            Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        // STA $0200,X
+        bus.mem_write(100, 0x9d);
+        bus.mem_write(101, 0x00);
+        bus.mem_write(102, 0x02);
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x64;
+        cpu.register_x = 5;
+        let event = trace_event(&mut cpu);
+        assert_eq!(event.pc, 0x64);
+        assert_eq!(event.bytes, vec![0x9d, 0x00, 0x02]);
+        assert_eq!(event.mnemonic, "STA");
+        assert_eq!(event.operand, "$0200,X @ 0205 = 00");
+        assert_eq!(event.register_x, 5);
+        assert_eq!(event.cycles, 0);
+        assert_eq!(event.to_string(), trace(&mut cpu));
+    }
+
+    #[test]
+    fn test_trace_has_no_side_effects() {
+        let mut bus =
+            Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        // LDA $2002
+        bus.mem_write(100, 0xad);
+        bus.mem_write(101, 0x02);
+        bus.mem_write(102, 0x20);
+        bus.ppu_mut().status.set_vblank_status(true);
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x64;
+        assert_eq!(trace_event(&cpu).operand, "$2002 = ff");
+        // 読むと落ちるVBlankのフラグが残っていて、統計にも数えない
+        assert!(cpu.bus.ppu().status.is_in_vblank());
+        assert_eq!(cpu.bus.current_stats(), Default::default());
+    }
+
+    fn tracer_cpu<'a>() -> CPU<'a> {
+        let mut bus =
+            Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        // LDX #$05; DEX; BNE -3; BRK
+        for (i, b) in [0xa2, 0x05, 0xca, 0xd0, 0xfd, 0x00].iter().enumerate() {
+            bus.mem_write(0x64 + i as u16, *b);
+        }
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x64;
+        cpu
+    }
+
+    #[test]
+    fn test_tracer_ring() {
+        let mut cpu = tracer_cpu();
+        let mut tracer = Tracer::ring(3);
+        cpu.run_with_callback(|cpu| tracer.record(cpu).unwrap());
+
+        let pcs: Vec<u16> = tracer.events().map(|e| e.pc).collect();
+        assert_eq!(pcs, vec![0x66, 0x67, 0x69]);
+
+        let mut dump = vec![];
+        tracer.dump(&mut dump).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+        assert_eq!(dump.lines().count(), 3);
+        assert!(dump
+            .lines()
+            .last()
+            .unwrap()
+            .starts_with("0069  00        BRK"));
+    }
+
+    #[test]
+    fn test_tracer_filter() {
+        let mut cpu = tracer_cpu();
+        let filter = TraceFilter {
+            pc_ranges: vec![0x66..=0x66],
+            categories: vec![],
+        };
+        let mut tracer = Tracer::ring(100).with_filter(filter);
+        cpu.run_with_callback(|cpu| tracer.record(cpu).unwrap());
+        assert_eq!(tracer.events().count(), 5);
+        assert!(tracer.events().all(|e| e.mnemonic == "DEX"));
+
+        let mut cpu = tracer_cpu();
+        // DEX; BNE を非公式の *DCP $00; BRK に置き換える
+        cpu.mem_write(0x66, 0xc7);
+        cpu.mem_write(0x67, 0x00);
+        cpu.mem_write(0x68, 0x00);
+        let mut tracer = Tracer::ring(100).with_filter(TraceFilter::unofficial_only());
+        cpu.run_with_callback(|cpu| tracer.record(cpu).unwrap());
+        let pcs: Vec<u16> = tracer.events().map(|e| e.pc).collect();
+        assert_eq!(pcs, vec![0x66]);
+    }
+
+    #[test]
+    fn test_tracer_to_file() {
+        let path = std::env::temp_dir().join(format!("nes-rs-trace-{}.log", std::process::id()));
+        let mut cpu = tracer_cpu();
+        let mut tracer = Tracer::to_file(&path).unwrap();
+        cpu.run_with_callback(|cpu| tracer.record(cpu).unwrap());
+        tracer.flush().unwrap();
+        assert_eq!(tracer.events().count(), 0);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // LDX + (DEX, BNE) x 5 + BRK
+        assert_eq!(log.lines().count(), 12);
+        assert!(log.starts_with("0064  A2 05     LDX #$05"));
+    }
+
+    fn nestest_cpu<'a>() -> CPU<'a> {
+        let root = env!("CARGO_MANIFEST_DIR");
+        let bytes = std::fs::read(format!("{}/nestest.nes", root)).unwrap();
+        let bus = Bus::new(
+            Rom::new(&bytes).unwrap(),
+            |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {},
+        )
+        .unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        cpu.program_counter = 0xc000;
+        cpu
+    }
+
+    #[test]
+    fn test_compare_nestest_no_cycle_log() {
+        let root = env!("CARGO_MANIFEST_DIR");
+        let log = std::fs::read_to_string(format!("{}/nestest_no_cycle.log", root)).unwrap();
+        let mut cpu = nestest_cpu();
+        assert_eq!(compare(log.as_bytes(), &mut cpu), Ok(8991));
+    }
+
+    #[test]
+    fn test_compare_reports_divergence() {
+        let root = env!("CARGO_MANIFEST_DIR");
+        let log = std::fs::read_to_string(format!("{}/nestest_no_cycle.log", root)).unwrap();
+        let mut cpu = nestest_cpu();
+        // C5F5: LDX #$00 を LDX #$01 に書き換えたことにする
+        let log = log.replacen("A2 00     LDX #$00", "A2 01     LDX #$01", 1);
+        let report = compare(log.as_bytes(), &mut cpu).unwrap_err();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "divergence at line 2");
+        assert!(lines[1].starts_with("  C000  4C F5 C5  JMP $C5F5"));
+        assert!(lines[2].starts_with("- C5F5  A2 01     LDX #$01"));
+        assert!(lines[3].starts_with("+ C5F5  A2 00     LDX #$00"));
+    }
+
+    #[test]
+    fn test_tracer_to_binary_file() {
+        let path = std::env::temp_dir().join(format!("nes-rs-trace-{}.bin", std::process::id()));
+        let mut cpu = tracer_cpu();
+        let mut tracer = Tracer::to_binary_file(&path).unwrap();
+        cpu.run_with_callback(|cpu| tracer.record(cpu).unwrap());
+        tracer.flush().unwrap();
+
+        let binary = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(binary.len(), 12 * trace_binary::RECORD_SIZE);
+    }
+
+    #[test]
+    fn test_nestest_log() {
+        let root = env!("CARGO_MANIFEST_DIR");
+        let log = std::fs::read_to_string(format!("{}/nestest.log", root)).unwrap();
+        let mut cpu = nestest_cpu();
+
+        let mut expected = log.lines();
+        let mut actual = vec![];
+        cpu.run_with_callback(|cpu| {
+            if expected.next().is_some() {
+                actual.push(trace(cpu));
+            }
+        });
+        for (n, (actual, expected)) in actual.iter().zip(log.lines()).enumerate() {
+            assert_eq!(actual, expected, "line {}", n + 1);
+        }
+        assert_eq!(actual.len(), 8991);
+    }
 }
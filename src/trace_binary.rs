@@ -0,0 +1,200 @@
+use std::io::{ErrorKind, Read, Write};
+
+use crate::cpu::CPU;
+use crate::opcodes;
+
+// 1命令あたり固定24バイト (リトルエンディアン)
+//   0: pc(2) 2: 命令バイト(3) 5: 長さ(1) 6: A X Y P SP(5) 11: 予備(1)
+//  12: scanline(2) 14: dot(2) 16: cycles(8)
+pub const RECORD_SIZE: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BinaryRecord {
+    pub pc: u16,
+    pub bytes: [u8; 3],
+    pub len: u8,
+    pub register_a: u8,
+    pub register_x: u8,
+    pub register_y: u8,
+    pub status: u8,
+    pub stack_pointer: u8,
+    pub scanline: u16,
+    pub dot: u16,
+    pub cycles: u64,
+}
+
+impl BinaryRecord {
+    // 文字列を作らずに実行直前の状態を取る。trace_eventと同じくBus::peekで読む
+    pub fn capture(cpu: &CPU) -> Self {
+        let pc = cpu.program_counter;
+        let code = cpu.bus.peek(pc);
+        let len = opcodes::lookup(code).map_or(1, |op| op.len);
+        let mut bytes = [code, 0, 0];
+        for (i, byte) in bytes.iter_mut().enumerate().take(len as usize).skip(1) {
+            *byte = cpu.bus.peek(pc.wrapping_add(i as u16));
+        }
+        let ppu = cpu.bus.ppu();
+        BinaryRecord {
+            pc,
+            bytes,
+            len,
+            register_a: cpu.register_a,
+            register_x: cpu.register_x,
+            register_y: cpu.register_y,
+            status: cpu.status,
+            stack_pointer: cpu.stack_pointer,
+            scanline: ppu.scanline(),
+            dot: ppu.cycle() as u16,
+            cycles: cpu.bus.cycles() as u64,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut buf = [0; RECORD_SIZE];
+        buf[0..2].copy_from_slice(&self.pc.to_le_bytes());
+        buf[2..5].copy_from_slice(&self.bytes);
+        buf[5] = self.len;
+        buf[6] = self.register_a;
+        buf[7] = self.register_x;
+        buf[8] = self.register_y;
+        buf[9] = self.status;
+        buf[10] = self.stack_pointer;
+        buf[12..14].copy_from_slice(&self.scanline.to_le_bytes());
+        buf[14..16].copy_from_slice(&self.dot.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.cycles.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8; RECORD_SIZE]) -> Self {
+        BinaryRecord {
+            pc: u16::from_le_bytes([buf[0], buf[1]]),
+            bytes: [buf[2], buf[3], buf[4]],
+            len: buf[5],
+            register_a: buf[6],
+            register_x: buf[7],
+            register_y: buf[8],
+            status: buf[9],
+            stack_pointer: buf[10],
+            scanline: u16::from_le_bytes([buf[12], buf[13]]),
+            dot: u16::from_le_bytes([buf[14], buf[15]]),
+            cycles: u64::from_le_bytes(buf[16..24].try_into().unwrap()),
+        }
+    }
+
+    // メモリの値は記録していないので、オペランドは逆アセンブル結果になる
+    pub fn to_text(&self) -> String {
+        let len = (self.len as usize).clamp(1, 3);
+        let asm = opcodes::disassemble(&self.bytes[..len], self.pc)
+            .first()
+            .map(|line| line.to_string())
+            .unwrap_or_default();
+        format!(
+            "{:47} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+            asm,
+            self.register_a,
+            self.register_x,
+            self.register_y,
+            self.status,
+            self.stack_pointer,
+            self.scanline,
+            self.dot,
+            self.cycles
+        )
+    }
+}
+
+pub fn write_record<W: Write>(writer: &mut W, record: &BinaryRecord) -> Result<(), String> {
+    writer
+        .write_all(&record.encode())
+        .map_err(|e| e.to_string())
+}
+
+// バイナリトレースを読み、テキストにして書き出す。変換した命令数を返す
+pub fn to_text<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<usize, String> {
+    let mut buf = [0; RECORD_SIZE];
+    let mut count = 0;
+    loop {
+        match reader.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(count),
+            Err(e) => return Err(e.to_string()),
+        }
+        let record = BinaryRecord::decode(&buf);
+        writeln!(writer, "{}", record.to_text()).map_err(|e| e.to_string())?;
+        count += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::Mem;
+    use crate::joypad::Joypad;
+    use crate::ppu::NesPPU;
+
+    #[test]
+    fn test_round_trip() {
+        let record = BinaryRecord {
+            pc: 0xc5f5,
+            bytes: [0xa2, 0x00, 0x00],
+            len: 2,
+            register_a: 1,
+            register_x: 2,
+            register_y: 3,
+            status: 0x24,
+            stack_pointer: 0xfd,
+            scanline: 261,
+            dot: 340,
+            cycles: 0x1_0000_0007,
+        };
+        assert_eq!(BinaryRecord::decode(&record.encode()), record);
+        assert_eq!(
+            record.to_text(),
+            "C5F5  A2 00     LDX #$00                        A:01 X:02 Y:03 P:24 SP:FD PPU:261,340 CYC:4294967303"
+        );
+    }
+
+    #[test]
+    fn test_capture_has_no_side_effects() {
+        let bus = Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        let mut cpu = CPU::new(bus);
+        // 何も繋がっていない$5000や、読むとVBlankが落ちる$2002を指していても状態を変えない
+        cpu.bus.ppu_mut().status.set_vblank_status(true);
+        for pc in [0x5000, 0x2002] {
+            cpu.program_counter = pc;
+            let record = BinaryRecord::capture(&cpu);
+            assert_eq!(record.pc, pc);
+        }
+        assert!(cpu.bus.ppu().status.is_in_vblank());
+        assert_eq!(cpu.bus.current_stats(), Default::default());
+    }
+
+    #[test]
+    fn test_to_text() {
+        let mut bus =
+            Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {}).unwrap();
+        // LDX #$02; DEX; BNE -3; BRK
+        for (i, b) in [0xa2, 0x02, 0xca, 0xd0, 0xfd, 0x00].iter().enumerate() {
+            bus.mem_write(0x64 + i as u16, *b);
+        }
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x64;
+
+        let mut binary = vec![];
+        cpu.run_with_callback(|cpu| {
+            write_record(&mut binary, &BinaryRecord::capture(cpu)).unwrap();
+        });
+        assert_eq!(binary.len(), 6 * RECORD_SIZE);
+
+        let mut text = vec![];
+        assert_eq!(to_text(&mut binary.as_slice(), &mut text).unwrap(), 6);
+        let text = String::from_utf8(text).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[0].starts_with("0064  A2 02     LDX #$02"));
+        assert!(lines[2].starts_with("0067  D0 FD     BNE $0066"));
+        assert!(lines[5].starts_with("0069  00        BRK"));
+        assert!(lines[5].ends_with("CYC:11"));
+    }
+}
@@ -0,0 +1,182 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use crate::avi::AviWriter;
+use crate::wav::WavWriter;
+
+// 録画先。1フレームごとに画面とそのフレームの間に作られた音声を受け取る
+pub trait VideoWriter {
+    // 256x240のRGB
+    fn write_frame(&mut self, rgb: &[u8], samples: &[f32]) -> Result<(), String>;
+    fn finish(self: Box<Self>) -> Result<(), String>;
+}
+
+impl VideoWriter for AviWriter {
+    fn write_frame(&mut self, rgb: &[u8], samples: &[f32]) -> Result<(), String> {
+        self.write_video_frame(rgb)?;
+        self.write_audio(samples)
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), String> {
+        AviWriter::finish(*self)
+    }
+}
+
+// 拡張子が.aviなら無圧縮のAVI、それ以外はffmpegでエンコードする
+pub fn create<P: AsRef<Path>>(
+    path: P,
+    frame_rate: f64,
+    sample_rate: u32,
+) -> Result<Box<dyn VideoWriter>, String> {
+    let path = path.as_ref();
+    let is_avi = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("avi"));
+    if is_avi {
+        Ok(Box::new(AviWriter::create(
+            path,
+            256,
+            240,
+            frame_rate,
+            sample_rate,
+        )?))
+    } else {
+        Ok(Box::new(FfmpegWriter::spawn(
+            path,
+            frame_rate,
+            sample_rate,
+        )?))
+    }
+}
+
+// ffmpegの標準入力へ画面を流し込む。音声は同時には渡せないので隣のWAVに書いておき、
+// 終わってから映像と合わせる
+pub struct FfmpegWriter {
+    child: Child,
+    stdin: ChildStdin,
+    audio: WavWriter,
+    path: PathBuf,
+    video_path: PathBuf,
+    audio_path: PathBuf,
+}
+
+// out.mp4 -> out.video.mp4
+fn temp_path(path: &Path, name: &str) -> PathBuf {
+    match path.extension() {
+        Some(ext) => path.with_extension(format!("{}.{}", name, ext.to_string_lossy())),
+        None => path.with_extension(name),
+    }
+}
+
+fn ffmpeg(args: &[&str]) -> Command {
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-hide_banner", "-loglevel", "error", "-y"])
+        .args(args)
+        .stdout(Stdio::null());
+    command
+}
+
+impl FfmpegWriter {
+    pub fn spawn<P: AsRef<Path>>(
+        path: P,
+        frame_rate: f64,
+        sample_rate: u32,
+    ) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let video_path = temp_path(&path, "video");
+        let audio_path = path.with_extension("audio.wav");
+        let mut child = ffmpeg(&[
+            "-f",
+            "rawvideo",
+            "-pixel_format",
+            "rgb24",
+            "-video_size",
+            "256x240",
+            "-framerate",
+            &frame_rate.to_string(),
+            "-i",
+            "-",
+        ])
+        .arg(&video_path)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to run ffmpeg: {}", e))?;
+        let stdin = child.stdin.take().unwrap();
+        let audio = WavWriter::create(&audio_path, 1, sample_rate)?;
+        Ok(FfmpegWriter {
+            child,
+            stdin,
+            audio,
+            path,
+            video_path,
+            audio_path,
+        })
+    }
+}
+
+impl VideoWriter for FfmpegWriter {
+    fn write_frame(&mut self, rgb: &[u8], samples: &[f32]) -> Result<(), String> {
+        self.stdin
+            .write_all(rgb)
+            .map_err(|e| format!("ffmpeg: {}", e))?;
+        for sample in samples {
+            self.audio.write_frame(&[*sample])?;
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), String> {
+        let FfmpegWriter {
+            mut child,
+            stdin,
+            audio,
+            path,
+            video_path,
+            audio_path,
+        } = *self;
+        drop(stdin);
+        let has_audio = audio.frames() > 0;
+        let run = |command: &mut Command| match command.status() {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(format!("ffmpeg exited with {}", status)),
+            Err(e) => Err(format!("failed to run ffmpeg: {}", e)),
+        };
+        let result = audio.finish().and_then(|_| {
+            match child.wait() {
+                Ok(status) if status.success() => {}
+                Ok(status) => return Err(format!("ffmpeg exited with {}", status)),
+                Err(e) => return Err(e.to_string()),
+            }
+            if has_audio {
+                let video = video_path.to_string_lossy();
+                let audio = audio_path.to_string_lossy();
+                run(ffmpeg(&["-i", &video, "-i", &audio, "-c:v", "copy", "-shortest"]).arg(&path))
+            } else {
+                fs::rename(&video_path, &path).map_err(|e| e.to_string())
+            }
+        });
+        let _ = fs::remove_file(&video_path);
+        let _ = fs::remove_file(&audio_path);
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_temp_path() {
+        assert_eq!(
+            temp_path(Path::new("out/smb.mp4"), "video"),
+            PathBuf::from("out/smb.video.mp4")
+        );
+        assert_eq!(
+            temp_path(Path::new("smb"), "video"),
+            PathBuf::from("smb.video")
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::Clamped;
+
+use crate::cartridge::{Rom, RomError};
+use crate::emulator::{Emulator, EmulatorConfig};
+use crate::joypad::JoypadButton;
+
+// ブラウザから使うためのEmulatorのラッパー。JavaScriptからは
+//   const nes = new Nes(romBytes);
+//   nes.run_frame();
+//   ctx.putImageData(new ImageData(nes.frame_rgba(), 256, 240), 0, 0);
+// のように使う。例はweb/index.html
+#[wasm_bindgen]
+pub struct Nes {
+    emulator: Emulator,
+    rgba: Vec<u8>,
+    samples: Vec<f32>,
+}
+
+#[wasm_bindgen]
+impl Nes {
+    // iNES/NES 2.0/UNIFのROMのバイト列から作る
+    #[wasm_bindgen(constructor)]
+    pub fn new(rom: Vec<u8>) -> Result<Nes, JsValue> {
+        let to_js = |e: RomError| JsValue::from_str(&e.to_string());
+        let rom = Rom::new(&rom).map_err(to_js)?;
+        Ok(Nes {
+            emulator: Emulator::new(rom, EmulatorConfig::default()).map_err(to_js)?,
+            rgba: vec![255; 256 * 240 * 4],
+            samples: vec![],
+        })
+    }
+
+    // 1フレーム進める。CPUが止まったらfalse
+    pub fn run_frame(&mut self) -> bool {
+        self.emulator.run_frame()
+    }
+
+    // 今の画面をImageDataにそのまま渡せるRGBA (Uint8ClampedArray) で返す
+    pub fn frame_rgba(&mut self) -> Clamped<Vec<u8>> {
+        let rgb = &self.emulator.frame().data;
+        for (rgba, rgb) in self.rgba.chunks_exact_mut(4).zip(rgb.chunks_exact(3)) {
+            rgba[..3].copy_from_slice(rgb);
+        }
+        Clamped(self.rgba.clone())
+    }
+
+    // 前回から作られた音声 (モノラル、-1.0..1.0)
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.samples.clear();
+        self.emulator.take_samples(&mut self.samples);
+        self.samples.clone()
+    }
+
+    // AudioContext.sampleRateに合わせる
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.emulator
+            .cpu_mut()
+            .bus
+            .apu_mut()
+            .set_sample_rate(sample_rate);
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        self.emulator.frame_rate()
+    }
+
+    // buttonsはJoypadButtonのビット (Aが0x01、Rightが0x80)
+    pub fn set_buttons(&mut self, port: usize, buttons: u8) -> Result<(), JsValue> {
+        self.emulator
+            .set_controller_state(port, JoypadButton::from_bits_truncate(buttons))
+            .map_err(|e| JsValue::from_str(&e))
+    }
+
+    pub fn reset(&mut self) {
+        self.emulator.cpu_mut().reset();
+    }
+}
@@ -0,0 +1,116 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const HEADER_SIZE: u32 = 44;
+
+// 16bit PCMのWAVファイルを書く。サイズはfinishでヘッダーに書き戻す
+pub struct WavWriter {
+    writer: BufWriter<File>,
+    channels: u16,
+    frames: u32,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        channels: u16,
+        sample_rate: u32,
+    ) -> Result<Self, String> {
+        let path = path.as_ref();
+        let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut wav = WavWriter {
+            writer: BufWriter::new(file),
+            channels,
+            frames: 0,
+        };
+        wav.write_header(sample_rate)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(wav)
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    fn data_size(&self) -> u32 {
+        self.frames * self.channels as u32 * 2
+    }
+
+    fn write_header(&mut self, sample_rate: u32) -> std::io::Result<()> {
+        let block_align = self.channels * 2;
+        let w = &mut self.writer;
+        w.write_all(b"RIFF")?;
+        w.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        w.write_all(b"WAVEfmt ")?;
+        w.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        w.write_all(&1u16.to_le_bytes())?;
+        w.write_all(&self.channels.to_le_bytes())?;
+        w.write_all(&sample_rate.to_le_bytes())?;
+        w.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        w.write_all(&block_align.to_le_bytes())?;
+        w.write_all(&16u16.to_le_bytes())?;
+        w.write_all(b"data")?;
+        w.write_all(&0u32.to_le_bytes())
+    }
+
+    // チャンネルの数だけ並べた1フレーム分。-1.0-1.0の外は切り詰める
+    pub fn write_frame(&mut self, samples: &[f32]) -> Result<(), String> {
+        assert_eq!(samples.len(), self.channels as usize);
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.writer
+                .write_all(&value.to_le_bytes())
+                .map_err(|e| e.to_string())?;
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        let data_size = self.data_size();
+        let w = &mut self.writer;
+        let result = (|| {
+            w.seek(SeekFrom::Start(4))?;
+            w.write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+            w.seek(SeekFrom::Start(40))?;
+            w.write_all(&data_size.to_le_bytes())?;
+            w.flush()
+        })();
+        result.map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_wav() {
+        let path = std::env::temp_dir().join(format!("nes-rs-wav-{}.wav", std::process::id()));
+        let mut wav = WavWriter::create(&path, 2, 44100).unwrap();
+        wav.write_frame(&[0.0, 1.0]).unwrap();
+        wav.write_frame(&[-2.0, 0.5]).unwrap();
+        assert_eq!(wav.frames(), 2);
+        wav.finish().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 44);
+        assert_eq!(u16::from_le_bytes([data[22], data[23]]), 2);
+        assert_eq!(u32::from_le_bytes(data[24..28].try_into().unwrap()), 44100);
+        assert_eq!(u32::from_le_bytes(data[40..44].try_into().unwrap()), 8);
+        let samples: Vec<i16> = data[44..]
+            .chunks(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(samples, vec![0, 32767, -32767, 16383]);
+    }
+}